@@ -156,6 +156,8 @@ async fn main() {
             false,
             ActiveGamepads::empty(),
             false,
+            None,
+            None,
             Colorspace::Rec2020,
             ColorRange::Full,
             4000,
@@ -5,10 +5,11 @@
 use std::{
     io,
     net::{Ipv4Addr, SocketAddrV4},
+    time::Duration,
 };
 
 use pem::Pem;
-use tokio::{net::UdpSocket, task::JoinError};
+use tokio::{net::UdpSocket, task::JoinError, time::sleep};
 use uuid::Uuid;
 
 use crate::{
@@ -23,6 +24,21 @@ use crate::{
 };
 
 pub async fn broadcast_magic_packet(mac: MacAddress) -> Result<(), io::Error> {
+    send_magic_packets(mac, Ipv4Addr::BROADCAST, 1, Duration::ZERO).await?;
+
+    Ok(())
+}
+
+/// Sends `count` magic packets with `interval` in between to the broadcast address.
+/// A directed broadcast address like `192.168.1.255` reaches hosts in other subnets.
+///
+/// Returns how many packets were sent.
+pub async fn send_magic_packets(
+    mac: MacAddress,
+    broadcast_address: Ipv4Addr,
+    count: u32,
+    interval: Duration,
+) -> Result<u32, io::Error> {
     let mut magic_packet = [0u8; 6 * 17];
 
     magic_packet[0..6].copy_from_slice(&[255, 255, 255, 255, 255, 255]);
@@ -30,14 +46,20 @@ pub async fn broadcast_magic_packet(mac: MacAddress) -> Result<(), io::Error> {
         magic_packet[(i * 6)..((i + 1) * 6)].copy_from_slice(&mac.to_bytes());
     }
 
-    let broadcast = SocketAddrV4::new(Ipv4Addr::new(255, 255, 255, 255), 9);
+    let broadcast = SocketAddrV4::new(broadcast_address, 9);
 
     let socket = UdpSocket::bind("0.0.0.0:0").await?;
 
     socket.set_broadcast(true)?;
-    socket.send_to(&magic_packet, &broadcast).await?;
+    for i in 0..count {
+        if i > 0 {
+            sleep(interval).await;
+        }
 
-    Ok(())
+        socket.send_to(&magic_packet, &broadcast).await?;
+    }
+
+    Ok(count)
 }
 
 #[derive(Debug, Error)]
@@ -480,7 +502,7 @@ mod stream {
         high::{HostError, MoonlightHost, StreamConfigError},
         network::{
             ClientInfo,
-            launch::{ClientStreamRequest, host_launch, host_resume},
+            launch::{ClientStreamRequest, DisplayMode, LaunchMode, host_launch, host_resume},
             request_client::RequestClient,
         },
         pair::PairError,
@@ -575,6 +597,10 @@ mod stream {
             local_audio_play_mode: bool,
             gamepads_attached: ActiveGamepads,
             gamepads_persist_after_disconnect: bool,
+            // The display of the host is set to the stream resolution if none
+            display_mode: Option<DisplayMode>,
+            // Resumes if any game is running when none
+            launch_mode: Option<LaunchMode>,
             color_space: Colorspace,
             color_range: ColorRange,
             bitrate: u32,
@@ -629,11 +655,12 @@ mod stream {
             rand_bytes(&mut aes_iv).map_err(PairError::from)?;
             let aes_iv = u32::from_be_bytes(aes_iv);
 
+            let display_mode = display_mode.unwrap_or(DisplayMode { width, height, fps });
             let request = ClientStreamRequest {
                 app_id,
-                mode_width: width,
-                mode_height: height,
-                mode_fps: fps,
+                mode_width: display_mode.width,
+                mode_height: display_mode.height,
+                mode_fps: display_mode.fps,
                 hdr,
                 sops,
                 local_audio_play_mode,
@@ -648,7 +675,12 @@ mod stream {
                 uuid: Uuid::new_v4(),
             };
 
-            let rtsp_session_url = if current_game == 0 {
+            let launch_mode = launch_mode.unwrap_or(if current_game == 0 {
+                LaunchMode::Launch
+            } else {
+                LaunchMode::Resume
+            });
+            let rtsp_session_url = if launch_mode == LaunchMode::Launch {
                 let launch_response = host_launch(
                     instance,
                     &mut self.client,
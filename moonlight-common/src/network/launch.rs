@@ -13,6 +13,22 @@ use crate::{
     stream::MoonlightInstance,
 };
 
+/// The mode the display of the host is set to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayMode {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+}
+
+/// Whether the host starts the app or continues the running one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LaunchMode {
+    Launch,
+    Resume,
+}
+
 #[derive(Debug, Clone)]
 pub struct ClientStreamRequest {
     pub app_id: u32,
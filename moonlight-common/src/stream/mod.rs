@@ -10,14 +10,14 @@ use std::{
 use moonlight_common_sys::limelight::{
     _SERVER_INFORMATION, _STREAM_CONFIGURATION, LI_BATTERY_PERCENTAGE_UNKNOWN, LI_ERR_UNSUPPORTED,
     LI_ROT_UNKNOWN, LiGetEstimatedRttInfo, LiGetHostFeatureFlags, LiGetLaunchUrlQueryParameters,
-    LiInterruptConnection, LiSendControllerArrivalEvent, LiSendControllerBatteryEvent,
-    LiSendControllerEvent, LiSendControllerMotionEvent, LiSendControllerTouchEvent,
-    LiSendHScrollEvent, LiSendHighResHScrollEvent, LiSendHighResScrollEvent, LiSendKeyboardEvent,
-    LiSendKeyboardEvent2, LiSendMouseButtonEvent, LiSendMouseMoveAsMousePositionEvent,
-    LiSendMouseMoveEvent, LiSendMousePositionEvent, LiSendMultiControllerEvent, LiSendScrollEvent,
-    LiSendTouchEvent, LiSendUtf8TextEvent, LiStartConnection, LiStopConnection,
-    PAUDIO_RENDERER_CALLBACKS, PCONNECTION_LISTENER_CALLBACKS, PDECODER_RENDERER_CALLBACKS,
-    PSERVER_INFORMATION, PSTREAM_CONFIGURATION,
+    LiInterruptConnection, LiRequestIdrFrame, LiSendControllerArrivalEvent,
+    LiSendControllerBatteryEvent, LiSendControllerEvent, LiSendControllerMotionEvent,
+    LiSendControllerTouchEvent, LiSendHScrollEvent, LiSendHighResHScrollEvent,
+    LiSendHighResScrollEvent, LiSendKeyboardEvent, LiSendKeyboardEvent2, LiSendMouseButtonEvent,
+    LiSendMouseMoveAsMousePositionEvent, LiSendMouseMoveEvent, LiSendMousePositionEvent,
+    LiSendMultiControllerEvent, LiSendScrollEvent, LiSendTouchEvent, LiSendUtf8TextEvent,
+    LiStartConnection, LiStopConnection, PAUDIO_RENDERER_CALLBACKS, PCONNECTION_LISTENER_CALLBACKS,
+    PDECODER_RENDERER_CALLBACKS, PSERVER_INFORMATION, PSTREAM_CONFIGURATION,
 };
 
 use crate::{
@@ -257,6 +257,14 @@ impl MoonlightStream {
         }
     }
 
+    /// This function requests an IDR frame from the host. The IDR arrives after the request
+    /// reached the host and the encoder produced the next frame.
+    pub fn request_idr_frame(&self) {
+        unsafe {
+            LiRequestIdrFrame();
+        }
+    }
+
     fn send_event_error(error: i32) -> Option<MoonlightError> {
         match error {
             0 => None,
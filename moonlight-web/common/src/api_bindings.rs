@@ -2,9 +2,10 @@ use std::fmt::{Display, Formatter};
 
 use moonlight_common::{
     ServerState,
+    network::launch::DisplayMode,
     stream::bindings::{
         Colorspace, ControllerButtons, ControllerCapabilities, KeyModifiers, MouseButton,
-        SupportedVideoFormats,
+        ServerCodeModeSupport, SupportedVideoFormats,
     },
 };
 use serde::{Deserialize, Serialize};
@@ -135,6 +136,50 @@ pub struct DetailedHost {
     /// Remote access configuration for internet streaming.
     /// Present if server has discovered its external address.
     pub remote_access: Option<RemoteAccessInfo>,
+    /// Used for the stream settings which the client doesn't send
+    pub default_stream_settings: Option<HostStreamSettings>,
+}
+
+/// Stream settings of a single host, unset values fall back to the server defaults
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct HostStreamSettings {
+    #[serde(default)]
+    pub bitrate: Option<u32>,
+    #[serde(default)]
+    pub packet_size: Option<u32>,
+    #[serde(default)]
+    pub fps: Option<u32>,
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+    #[serde(default)]
+    pub video_frame_queue_size: Option<u32>,
+    #[serde(default)]
+    pub audio_sample_queue_size: Option<u32>,
+    #[serde(default)]
+    pub play_audio_local: Option<bool>,
+}
+
+impl HostStreamSettings {
+    /// Takes every value that isn't set in self from the fallback
+    pub fn or(self, fallback: Self) -> Self {
+        Self {
+            bitrate: self.bitrate.or(fallback.bitrate),
+            packet_size: self.packet_size.or(fallback.packet_size),
+            fps: self.fps.or(fallback.fps),
+            width: self.width.or(fallback.width),
+            height: self.height.or(fallback.height),
+            video_frame_queue_size: self
+                .video_frame_queue_size
+                .or(fallback.video_frame_queue_size),
+            audio_sample_queue_size: self
+                .audio_sample_queue_size
+                .or(fallback.audio_sample_queue_size),
+            play_audio_local: self.play_audio_local.or(fallback.play_audio_local),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, TS)]
@@ -161,6 +206,50 @@ pub struct GetHostsResponse {
     pub hosts: Vec<UndetailedHost>,
 }
 
+/// A host which advertised itself on the local network and isn't added yet
+#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct DiscoveredHost {
+    pub name: String,
+    /// The mDNS hostname, e.g. `gaming-pc.local`
+    pub hostname: String,
+    pub address: String,
+    pub http_port: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetDiscoveredHostsResponse {
+    pub hosts: Vec<DiscoveredHost>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetIceServersResponse {
+    pub ice_servers: Vec<RtcIceServer>,
+    /// When the generated TURN credentials stop working, null if they don't expire
+    pub expires_in_secs: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetHostLogsQuery {
+    pub host_id: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub enum GetHostLogsResponse {
+    /// The most recent part of the host log
+    Logs {
+        text: String,
+        /// If older lines were cut off
+        truncated: bool,
+    },
+    /// Only Backlight hosts expose their logs
+    NotAvailable,
+}
+
 #[derive(Serialize, Deserialize, Debug, TS)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct GetHostQuery {
@@ -194,6 +283,23 @@ pub struct PatchHostRequest {
     /// Option<Option<u32>> are not supported
     pub change_owner: bool,
     pub owner: Option<u32>,
+    /// Directed broadcast address for wake on lan, e.g. `192.168.1.255`
+    #[serde(default)]
+    pub change_wake_broadcast_address: bool,
+    #[serde(default)]
+    pub wake_broadcast_address: Option<String>,
+    #[serde(default)]
+    pub change_default_stream_settings: bool,
+    #[serde(default)]
+    pub default_stream_settings: Option<HostStreamSettings>,
+    /// A new address of the host, e.g. after its ip changed. The pairing is kept
+    #[serde(default)]
+    pub address: Option<String>,
+    #[serde(default)]
+    pub http_port: Option<u16>,
+    /// Only save the new address if the host answers on it
+    #[serde(default)]
+    pub probe_address: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, TS)]
@@ -208,6 +314,12 @@ pub struct PostPairRequest {
     pub host_id: u32,
 }
 
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct DeletePairQuery {
+    pub host_id: u32,
+}
+
 /// Host type detection (Standard Sunshine vs Backlight)
 #[derive(Serialize, Deserialize, Debug, TS, Clone, Copy, PartialEq, Eq)]
 #[ts(export, export_to = EXPORT_PATH)]
@@ -227,6 +339,10 @@ pub enum PostPairResponse1 {
     Pin(String),
     /// Backlight host - auto-pairing in progress, no PIN needed
     BacklightAutoPairing,
+    /// The host didn't answer over http, no PIN was generated
+    HostOffline,
+    /// The host answered over http but not on its https port, pairing would fail after entering the PIN
+    HttpsUnreachable,
 }
 
 #[derive(Serialize, Deserialize, Debug, TS)]
@@ -242,6 +358,122 @@ pub struct PostWakeUpRequest {
     pub host_id: u32,
 }
 
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct PostWakeUpResponse {
+    pub packets_sent: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct PostHostTestRequest {
+    pub host_id: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct HostTestResponse {
+    pub host_id: u32,
+    /// If the host answered the host info request, it can be online with an unknown server state
+    pub online: bool,
+    pub paired: PairStatus,
+    pub server_state: Option<HostState>,
+    pub current_game: u32,
+    pub max_luma_pixels_hevc: u32,
+    pub server_codec_mode_support: u32,
+    /// Round trip time of the host info request, null if the host is offline
+    pub latency_ms: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetStreamHistoryQuery {
+    #[serde(default)]
+    pub offset: Option<u32>,
+    /// The server caps this
+    #[serde(default)]
+    pub limit: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct StreamHistoryEntry {
+    pub user_id: u32,
+    pub host_id: u32,
+    pub host_name: String,
+    pub app_title: String,
+    /// Unix timestamp in seconds
+    pub started_at: u64,
+    pub duration_secs: u64,
+    pub end_reason: String,
+    /// Selects the stats timeline of the stream, null for streams recorded by older versions
+    pub session_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetStreamHistoryResponse {
+    /// Newest first
+    pub sessions: Vec<StreamHistoryEntry>,
+    /// The amount of sessions in the history, not only in this page
+    pub total: u32,
+}
+
+/// A state transition or signaling event of the peer connection of a stream
+#[derive(Serialize, Deserialize, Debug, TS, Clone, PartialEq, Eq)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct ConnectionLogEntry {
+    /// Unix timestamp in milliseconds
+    pub timestamp_ms: u64,
+    /// The states after the event
+    pub ice_state: String,
+    pub peer_state: String,
+    pub event: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, Clone)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct FailedConnectionLog {
+    pub user_id: u32,
+    pub host_id: u32,
+    pub app_title: String,
+    /// Unix timestamp in seconds
+    pub failed_at: u64,
+    /// Oldest first
+    pub entries: Vec<ConnectionLogEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetConnectionLogsResponse {
+    /// Newest first
+    pub sessions: Vec<FailedConnectionLog>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsTimelineFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetStatsTimelineQuery {
+    #[serde(default)]
+    pub format: StatsTimelineFormat,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetStatsTimelineResponse {
+    pub session_id: String,
+    /// Oldest first
+    pub samples: Vec<StatsSample>,
+}
+
 #[derive(Serialize, Deserialize, Debug, TS)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct GetAppsQuery {
@@ -254,6 +486,21 @@ pub struct GetAppsResponse {
     pub apps: Vec<App>,
 }
 
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetAppQuery {
+    pub host_id: u32,
+    pub app_id: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetAppResponse {
+    pub app: App,
+    /// The path of the box art including the url path prefix, it requires the same authorization as the other endpoints
+    pub box_art_url: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, TS)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct GetAppImageQuery {
@@ -275,6 +522,29 @@ pub struct PostCancelResponse {
     pub success: bool,
 }
 
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct PostStreamProbeRequest {
+    pub host_id: u32,
+}
+
+/// What a stream with the host would be set up with, nothing is launched for this
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct PostStreamProbeResponse {
+    pub ice_servers: Vec<RtcIceServer>,
+    /// When the generated TURN credentials stop working, null if they don't expire
+    pub expires_in_secs: Option<u32>,
+    /// The client should only use relay candidates
+    pub force_relay: bool,
+    /// The codecs the host can encode, in the order they're negotiated
+    pub video_codecs: Vec<StreamCodec>,
+    /// The host can encode a 10 bit codec
+    pub hdr_supported: bool,
+    /// The sdp offer of a peer which was created like the one of the stream, without gathered candidates
+    pub offer: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, TS)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub enum UserRole {
@@ -297,6 +567,10 @@ pub struct DetailedUser {
     pub name: String,
     pub role: UserRole,
     pub client_unique_id: String,
+    /// None = the configured default is used when pairing
+    pub pair_device_name: Option<String>,
+    /// SHA-256 fingerprints of the client certificates which authenticate as this user, lowercase hex
+    pub client_certificate_fingerprints: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, TS)]
@@ -316,6 +590,12 @@ pub struct PatchUserRequest {
     pub password: Option<String>,
     pub role: Option<UserRole>,
     pub client_unique_id: Option<String>,
+    /// The name shown in the device list of the host after pairing, an empty name uses the configured default
+    #[serde(default)]
+    pub pair_device_name: Option<String>,
+    /// Replaces the client certificates of the user, the fingerprints are hex and may contain colons
+    #[serde(default)]
+    pub client_certificate_fingerprints: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, TS)]
@@ -324,10 +604,112 @@ pub struct DeleteUserRequest {
     pub id: u32,
 }
 
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetUsersQuery {
+    #[serde(default)]
+    pub offset: Option<u32>,
+    /// None = all users after the offset
+    #[serde(default)]
+    pub limit: Option<u32>,
+    #[serde(default)]
+    pub role: Option<UserRole>,
+    /// Case insensitive part of the user name
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, TS)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct GetUsersResponse {
     pub users: Vec<DetailedUser>,
+    /// The amount of users matching the query
+    pub total: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetAuditLogQuery {
+    #[serde(default)]
+    pub offset: Option<u32>,
+    /// The server caps this
+    #[serde(default)]
+    pub limit: Option<u32>,
+}
+
+/// An admin action, the timestamp is a unix timestamp in seconds
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct AuditLogEntry {
+    pub timestamp: u64,
+    pub actor_user_id: u32,
+    /// e.g. add_user, delete_user, patch_user or change_host_owner
+    pub action: String,
+    pub target: String,
+    /// None if the action succeeded
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetAuditLogResponse {
+    /// Newest first
+    pub entries: Vec<AuditLogEntry>,
+    pub total: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct DetailedStreamer {
+    pub id: u32,
+    pub pid: Option<u32>,
+    pub running_secs: u32,
+    /// None if the streamer never answered a ping
+    pub last_pong_secs_ago: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetStreamersResponse {
+    pub process_count: u32,
+    pub streamers: Vec<DetailedStreamer>,
+    pub hybrid_sessions: Vec<HybridSessionStatus>,
+}
+
+/// A stream whose input runs over a separate connection
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct HybridSessionStatus {
+    pub session_id: String,
+    pub running_secs: u32,
+    pub input_connected: bool,
+    /// Time left to join with the current token, none if the input already used it
+    pub token_expires_in_secs: Option<u32>,
+    /// How often the input connection disconnected and got a new token
+    pub input_reconnections: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct PostInputHandoffRequest {
+    pub session_id: String,
+}
+
+/// The new input client joins with this token, the current one is closed once it did
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct PostInputHandoffResponse {
+    pub session_token: String,
+    pub token_expires_in_secs: u32,
+}
+
+/// Body of every failed api request
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct ApiErrorResponse {
+    /// Stable identifier of the error, e.g. "host_offline"
+    pub code: String,
+    pub message: String,
 }
 
 // -- Stream
@@ -369,6 +751,8 @@ ts_consts!(
     pub const CONTROLLER13: u8 = 23;
     pub const CONTROLLER14: u8 = 24;
     pub const CONTROLLER15: u8 = 25;
+    pub const CLIPBOARD: u8 = 26;
+    pub const FILE_TRANSFER: u8 = 27;
 );
 
 #[derive(Serialize, Deserialize, Debug, TS, Clone, Copy, PartialEq, Eq)]
@@ -411,14 +795,30 @@ pub enum StreamClientMessage {
     Init {
         host_id: u32,
         app_id: u32,
-        bitrate: u32,
-        packet_size: u32,
-        fps: u32,
-        width: u32,
-        height: u32,
-        video_frame_queue_size: u32,
-        play_audio_local: bool,
-        audio_sample_queue_size: u32,
+        /// Settings which are null use the defaults of the host, then the defaults of the server
+        #[serde(default)]
+        bitrate: Option<u32>,
+        #[serde(default)]
+        packet_size: Option<u32>,
+        #[serde(default)]
+        fps: Option<u32>,
+        #[serde(default)]
+        width: Option<u32>,
+        #[serde(default)]
+        height: Option<u32>,
+        #[serde(default)]
+        video_frame_queue_size: Option<u32>,
+        /// Send video frames at a steady cadence instead of when they arrive from the host,
+        /// adds up to a frame of latency
+        #[serde(default)]
+        video_frame_pacing: bool,
+        #[serde(default)]
+        play_audio_local: Option<bool>,
+        #[serde(default)]
+        audio_sample_queue_size: Option<u32>,
+        /// How long audio samples are buffered before they're sent, 0 = disabled
+        #[serde(default)]
+        audio_jitter_buffer_ms: u32,
         video_supported_formats: u32,
         video_colorspace: StreamColorspace,
         video_color_range_full: bool,
@@ -427,6 +827,36 @@ pub enum StreamClientMessage {
         /// and should return a session_token for the input connection to use.
         #[serde(default)]
         hybrid_mode: bool,
+        /// When true, the server hands out a spectator token once the stream is connected
+        #[serde(default)]
+        allow_spectators: bool,
+        /// Only use TURN relay candidates, null = decided by the server
+        #[serde(default)]
+        force_relay: Option<bool>,
+        /// Ignored if the host, app or client doesn't support a 10 bit codec
+        #[serde(default)]
+        hdr_enabled: bool,
+        /// Codecs which should be negotiated first, null = the default of the server
+        #[serde(default)]
+        video_codec_preference: Option<Vec<StreamCodec>>,
+        /// Passed to the host when the app is launched, null = launch it like Moonlight does
+        #[serde(default)]
+        launch_options: Option<LaunchOptions>,
+        /// Quit a different app which is running on the host without asking with GameRunningConflict
+        #[serde(default)]
+        cancel_running_app: bool,
+    },
+    /// Answer to GameRunningConflict, true quits the running app and launches the requested one
+    ConfirmCancel {
+        confirm: bool,
+    },
+    /// Sent instead of Init to take over a running stream after the WebSocket was lost
+    Reconnect {
+        reconnection_token: String,
+    },
+    /// Sent instead of Init to watch a running stream, spectators can't send input
+    Spectate {
+        spectator_token: String,
     },
     WebRtc(StreamSignalingMessage),
 }
@@ -459,6 +889,8 @@ impl Display for RtcIceServer {
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct StreamCapabilities {
     pub touch: bool,
+    /// Files can be uploaded over the file transfer channel
+    pub file_transfer: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, TS)]
@@ -471,6 +903,9 @@ pub enum StreamServerMessage {
         /// The native input client uses this token to join the same session.
         #[serde(skip_serializing_if = "Option::is_none")]
         session_token: Option<String>,
+        /// The client should also only use relay candidates
+        #[serde(default)]
+        force_relay: bool,
     },
     WebRtc(StreamSignalingMessage),
     // Optional Info
@@ -482,6 +917,11 @@ pub enum StreamServerMessage {
     AppNotFound,
     HostNotPaired,
     AlreadyStreaming,
+    /// A different app is running on the host, the client answers with ConfirmCancel
+    GameRunningConflict {
+        /// None if the running app isn't in the app list of the host
+        current_title: Option<String>,
+    },
     StageStarting {
         stage: String,
     },
@@ -503,15 +943,86 @@ pub enum StreamServerMessage {
     ConnectionTerminated {
         error_code: i32,
     },
+    /// The video codec which was selected in the sdp, e.g. "H264", "H265" or "AV1"
+    NegotiatedFormat {
+        codec: String,
+        /// The fmtp parameters of the codec
+        profile: Option<String>,
+    },
+    /// The host set up the video again mid-stream, e.g. because the game changed the resolution
+    VideoChanged {
+        /// Use VideoSupportedCodec to figure this out
+        format: u32,
+        width: u32,
+        height: u32,
+        fps: u32,
+    },
     /// Notification that the input connection has disconnected (hybrid mode)
     InputDisconnected,
     /// Notification that the input connection has joined (hybrid mode)
     InputJoined,
+    /// ICE of the input connection failed, reconnecting the input client may work (hybrid mode)
+    InputFailed,
+    /// ICE of the stream connection failed, the stream will be terminated and can be retried
+    PeerFailed,
     /// New reconnection token available after input disconnection (hybrid mode)
     /// The primary client should pass this to the native input client for reconnection
     ReconnectionTokenAvailable {
         session_token: String,
     },
+    /// The requested stream settings were outside of the configured limits, these are the values used instead
+    SettingsAdjusted {
+        bitrate: u32,
+        packet_size: u32,
+        fps: u32,
+        width: u32,
+        height: u32,
+        hdr_enabled: bool,
+    },
+    /// The session will be ended by the server soon, e.g. because the maximum session duration is almost reached
+    SessionEnding {
+        reason: String,
+        remaining_secs: u64,
+    },
+    /// The session was ended by the server, the streamer is stopping
+    Ended {
+        reason: String,
+    },
+    /// Sent right before the server closes the web socket of a running stream
+    Disconnected {
+        reason: DisconnectReason,
+    },
+    /// Token which allows a new WebSocket to take over this stream if the connection is lost
+    StreamReconnectionToken {
+        reconnection_token: String,
+    },
+    /// The WebSocket took over the running stream
+    Reconnected,
+    /// The stream couldn't be taken over, e.g. because the token expired
+    ReconnectFailed,
+    /// Token which allows other clients to watch this stream
+    SpectatorToken {
+        spectator_token: String,
+    },
+    /// The stream couldn't be joined as a spectator, e.g. because it ended or is full
+    SpectateFailed,
+}
+
+/// A coarse reason for the end of a stream which the client can show to the user.
+///
+/// `Ended` contains the more detailed reason if the server ended the session itself.
+#[derive(Serialize, Deserialize, Debug, TS, Clone, Copy, PartialEq, Eq)]
+#[ts(export, export_to = EXPORT_PATH)]
+#[serde(rename_all = "snake_case")]
+pub enum DisconnectReason {
+    UserRequested,
+    HostOffline,
+    Timeout,
+    Error,
+    /// Another web socket took over the stream with the reconnection token
+    ReplacedByNewSession,
+    /// The app on the host was closed
+    GameEnded,
 }
 
 // -- Input-Only Connection Messages (Hybrid Mode)
@@ -525,6 +1036,8 @@ pub enum InputErrorCode {
     SessionNotFound,
     InputAlreadyConnected,
     InternalError,
+    /// ICE failed, the input client should reconnect
+    IceFailed,
 }
 
 /// Messages sent from the input client to the server
@@ -537,6 +1050,10 @@ pub enum InputClientMessage {
     },
     /// WebRTC signaling messages (answer, ICE candidates)
     WebRtc(StreamSignalingMessage),
+    /// Text which is pushed to the newest clipboard channel of the stream
+    Clipboard {
+        text: String,
+    },
 }
 
 /// Messages sent from the server to the input client
@@ -556,6 +1073,8 @@ pub enum InputServerMessage {
     },
     /// Notification that the primary stream has disconnected
     PrimaryDisconnected,
+    /// Another input client took over the session with a handoff token
+    HandedOff,
 }
 
 #[derive(Serialize, Deserialize, Debug, TS)]
@@ -566,7 +1085,10 @@ pub enum GeneralServerMessage {
 
 #[derive(Serialize, Deserialize, Debug, TS)]
 #[ts(export, export_to = EXPORT_PATH)]
-pub enum GeneralClientMessage {}
+pub enum GeneralClientMessage {
+    /// The client can't decode the video anymore and needs a new keyframe
+    RequestKeyframe,
+}
 
 #[derive(Serialize, Deserialize, Debug, TS)]
 #[ts(export, export_to = EXPORT_PATH)]
@@ -593,6 +1115,35 @@ pub struct StatsHostProcessingLatency {
     pub avg_host_processing_latency_ms: f64,
 }
 
+/// The stats of a stream at one point, the streamer records them while streaming
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct StatsSample {
+    /// Milliseconds since the stream started
+    pub elapsed_ms: u64,
+    /// Of the video sent since the previous stats update
+    pub bitrate_kbps: u32,
+    /// Video packets the primary client lost, null until it sent a receiver report
+    pub packet_loss_percent: Option<f64>,
+    pub rtt_ms: Option<f64>,
+    pub queued_frames: u32,
+    pub queue_delay_ms: f64,
+    /// Frames dropped by the video queue since the stream started
+    pub frames_dropped: u32,
+}
+
+/// Taken from the last RTCP receiver report of a peer
+#[derive(Serialize, Deserialize, Debug, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct StatsReception {
+    pub ssrc: u32,
+    /// Packets lost since the previous report
+    pub fraction_lost_percent: f64,
+    /// Packets lost since the stream started
+    pub cumulative_lost: u32,
+    pub jitter_ms: f64,
+}
+
 #[derive(Serialize, Deserialize, Debug, TS)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub enum StreamerStatsUpdate {
@@ -606,6 +1157,40 @@ pub enum StreamerStatsUpdate {
         max_streamer_processing_time_ms: f64,
         avg_streamer_processing_time_ms: f64,
     },
+    /// Totals since the stream started
+    VideoQueue {
+        frames_dropped: u32,
+        idrs_forced: u32,
+        /// Size of the frames which are currently queued, not a total
+        queued_bytes: u32,
+        queued_frames: u32,
+        /// How long the last sent frame waited in the queue, this includes the frame pacing
+        queue_delay_ms: f64,
+    },
+    /// What the client reported back about the streams, one entry for every peer.
+    /// The primary peer is first, the others are spectators.
+    Reception {
+        video: Vec<StatsReception>,
+        audio: Vec<StatsReception>,
+    },
+    /// Sent every second by a streamer in benchmark mode
+    Benchmark {
+        target_kbps: u32,
+        /// The bitrate of the frames which weren't dropped by the video queue
+        achieved_kbps: u32,
+        frames_sent: u32,
+        frames_dropped: u32,
+        /// How long the transport took to accept a frame into its queue, not until it was written to the network
+        avg_enqueue_latency_ms: f64,
+        max_enqueue_latency_ms: f64,
+    },
+    /// Progress of an upload on the file transfer channel
+    FileTransfer {
+        id: u32,
+        file_name: String,
+        transferred_bytes: u64,
+        size: u64,
+    },
 }
 
 // Virtual-Key Codes
@@ -1131,6 +1716,94 @@ pub enum StreamColorspace {
     Rec2020,
 }
 
+/// A codec family, each contains the profiles of [StreamSupportedVideoFormats]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub enum StreamCodec {
+    H264,
+    H265,
+    Av1,
+}
+
+impl StreamCodec {
+    /// The order used for codecs without a preference
+    pub const ALL: [Self; 3] = [Self::H264, Self::H265, Self::Av1];
+
+    pub fn formats(&self) -> SupportedVideoFormats {
+        match self {
+            Self::H264 => SupportedVideoFormats::MASK_H264,
+            Self::H265 => SupportedVideoFormats::MASK_H265,
+            Self::Av1 => SupportedVideoFormats::MASK_AV1,
+        }
+    }
+
+    /// Whether the host can encode this codec in any of its modes
+    pub fn host_supports(&self, server_support: ServerCodeModeSupport) -> bool {
+        let modes = match self {
+            Self::H264 => ServerCodeModeSupport::H264 | ServerCodeModeSupport::H264_HIGH8_444,
+            Self::H265 => {
+                ServerCodeModeSupport::HEVC
+                    | ServerCodeModeSupport::HEVC_MAIN10
+                    | ServerCodeModeSupport::HEVC_REXT8_444
+                    | ServerCodeModeSupport::HEVC_REXT10_444
+            }
+            Self::Av1 => {
+                ServerCodeModeSupport::AV1_MAIN8
+                    | ServerCodeModeSupport::AV1_MAIN10
+                    | ServerCodeModeSupport::AV1_HIGH8_444
+                    | ServerCodeModeSupport::AV1_HIGH10_444
+            }
+        };
+
+        server_support.intersects(modes)
+    }
+
+    /// The preferred codecs first, then every other codec so they can still be negotiated
+    pub fn ordered(preference: &[Self]) -> Vec<Self> {
+        let mut codecs = Vec::with_capacity(Self::ALL.len());
+        for codec in preference.iter().chain(Self::ALL.iter()) {
+            if !codecs.contains(codec) {
+                codecs.push(*codec);
+            }
+        }
+
+        codecs
+    }
+}
+
+/// How the host launches the app, these don't change the stream itself
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct LaunchOptions {
+    /// Let the host change the game settings for the stream, null = true
+    #[serde(default)]
+    pub sops: Option<bool>,
+    /// Keep the virtual gamepads of the host after the stream ended, null = false
+    #[serde(default)]
+    pub gamepads_persist_after_disconnect: Option<bool>,
+    /// The mode the display of the host is set to, null = the stream resolution and fps
+    #[serde(default)]
+    pub display_mode: Option<LaunchDisplayMode>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, TS)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct LaunchDisplayMode {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+}
+
+impl From<LaunchDisplayMode> for DisplayMode {
+    fn from(value: LaunchDisplayMode) -> Self {
+        Self {
+            width: value.width,
+            height: value.height,
+            fps: value.fps,
+        }
+    }
+}
+
 impl From<StreamColorspace> for Colorspace {
     fn from(value: StreamColorspace) -> Self {
         match value {
@@ -1,15 +1,17 @@
 use std::{
+    io,
     marker::PhantomData,
     sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
 };
 
 use log::{LevelFilter, debug, info, warn};
+use moonlight_common::network::launch::LaunchMode;
 use pem::Pem;
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use thiserror::Error;
 use tokio::{
-    io::{
-        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader, Lines, Stdin, Stdout,
-    },
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines},
     process::{ChildStderr, ChildStdin, ChildStdout},
     spawn,
     sync::mpsc::{Receiver, Sender, channel},
@@ -17,14 +19,44 @@ use tokio::{
 
 use crate::{
     StreamSettings,
-    api_bindings::{StreamClientMessage, StreamServerMessage},
-    config::WebRtcConfig,
+    api_bindings::{
+        ConnectionLogEntry, LaunchOptions, StatsSample, StreamClientMessage, StreamServerMessage,
+    },
+    config::{FileTransferConfig, RecordingConfig, WebRtcConfig},
 };
 
+/// Sent by both sides as the first line of the ipc, the other side refuses to continue on a mismatch.
+/// Bump it whenever ServerIpcMessage, StreamerIpcMessage or anything they contain changes.
+pub const IPC_PROTOCOL_VERSION: u32 = 4;
+
+/// Cli argument of the streamer which runs the synthetic benchmark instead of contacting the host,
+/// the target resolution, fps and bitrate are taken from the stream settings of the Init message
+pub const STREAMER_BENCHMARK_ARG: &str = "--benchmark";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct StreamerConfig {
     pub webrtc: WebRtcConfig,
     pub log_level: LevelFilter,
+    pub pidfile_dir: String,
+    /// The streamer sends Idle once it didn't receive input for this long (None = never)
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub file_transfer: FileTransferConfig,
+    #[serde(default)]
+    pub recording: RecordingConfig,
+    /// The streamer stops itself once it didn't receive an ipc message for this long (None = never)
+    #[serde(default)]
+    pub ipc_timeout_secs: Option<u64>,
+}
+
+impl StreamerConfig {
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout_secs.map(Duration::from_secs)
+    }
+    pub fn ipc_timeout(&self) -> Option<Duration> {
+        self.ipc_timeout_secs.map(Duration::from_secs)
+    }
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -44,6 +76,17 @@ pub enum ServerIpcMessage {
         /// Only set when hybrid_mode is enabled in stream_settings.
         #[serde(default)]
         session_token: Option<String>,
+        /// Generate synthetic frames instead of connecting to the host
+        #[serde(default)]
+        loopback: bool,
+        /// Identifies the streamer in its pidfile
+        session_id: String,
+        /// Already clamped to the stream limits by the web server
+        #[serde(default)]
+        launch_options: Option<LaunchOptions>,
+        /// None if the web server couldn't check the running game, the streamer decides then
+        #[serde(default)]
+        launch_mode: Option<LaunchMode>,
     },
     WebSocket(StreamClientMessage),
     /// Input connection has joined (hybrid mode)
@@ -52,6 +95,27 @@ pub enum ServerIpcMessage {
     InputWebSocket(crate::api_bindings::StreamSignalingMessage),
     /// Input connection has disconnected
     InputDisconnected,
+    /// A spectator wants to watch the stream, the streamer creates a peer for it
+    SpectatorJoined {
+        spectator_id: u32,
+    },
+    /// WebRTC signaling message from a spectator
+    SpectatorSignaling {
+        spectator_id: u32,
+        signaling: crate::api_bindings::StreamSignalingMessage,
+    },
+    /// Spectator has disconnected
+    SpectatorLeft {
+        spectator_id: u32,
+    },
+    /// Heartbeat, the streamer must answer with a Pong of the same sequence
+    Ping {
+        sequence: u32,
+    },
+    /// Text which is pushed to the clipboard channel of the client
+    Clipboard {
+        text: String,
+    },
     Stop,
 }
 
@@ -63,9 +127,54 @@ pub enum StreamerIpcMessage {
     InputSignaling(crate::api_bindings::StreamSignalingMessage),
     /// Input peer connection is ready to receive data
     InputReady,
+    /// ICE of the input peer failed, the input client should reconnect
+    InputFailed,
+    /// WebRTC signaling message for a spectator
+    SpectatorSignaling {
+        spectator_id: u32,
+        signaling: crate::api_bindings::StreamSignalingMessage,
+    },
+    /// Answer to a Ping
+    Pong {
+        sequence: u32,
+        /// Bytes the transport sent since the stream started
+        #[serde(default)]
+        bytes_sent: u64,
+    },
+    /// Neither the primary nor the input client sent input for the idle timeout
+    Idle {
+        idle_secs: u64,
+    },
+    /// The peer connection failed, these are its last transitions for diagnostics
+    ConnectionLog {
+        entries: Vec<ConnectionLogEntry>,
+    },
+    /// The stats recorded over the whole stream, sent right before Stop
+    StatsTimeline {
+        samples: Vec<StatsSample>,
+    },
     Stop,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct IpcHandshake {
+    ipc_version: u32,
+}
+
+#[derive(Debug, Error)]
+pub enum IpcHandshakeError {
+    #[error("failed to send the ipc version: {0}")]
+    Write(io::Error),
+    #[error("failed to read the ipc version: {0}")]
+    Read(io::Error),
+    #[error("the ipc was closed before the version was received")]
+    Closed,
+    #[error("the first ipc message wasn't a version, the other side is probably outdated: {0}")]
+    Malformed(serde_json::Error),
+    #[error("incompatible ipc version {remote}, expected {IPC_PROTOCOL_VERSION}")]
+    Incompatible { remote: u32 },
+}
+
 // We're using the:
 // Stdin: message passing
 // Stdout: message passing
@@ -75,10 +184,10 @@ static CHILD_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
 pub async fn create_child_ipc<Message, ChildMessage>(
     log_target: &str,
-    stdin: ChildStdin,
+    mut stdin: ChildStdin,
     stdout: ChildStdout,
     stderr: Option<ChildStderr>,
-) -> (IpcSender<Message>, IpcReceiver<ChildMessage>)
+) -> Result<(IpcSender<Message>, IpcReceiver<ChildMessage>), IpcHandshakeError>
 where
     Message: Send + Serialize + 'static,
     ChildMessage: DeserializeOwned,
@@ -99,6 +208,9 @@ where
         });
     }
 
+    let mut read = create_lines(stdout);
+    ipc_handshake(&mut stdin, &mut read).await?;
+
     let (sender, receiver) = channel::<Message>(10);
 
     let sender_log_format = format!("{log_target}: ");
@@ -107,47 +219,86 @@ where
     });
 
     let log_target = format!("{log_target}: ");
-    (
+    Ok((
         IpcSender {
             sender,
             log_target: log_target.clone(),
         },
         IpcReceiver {
             errored: false,
-            read: create_lines(stdout),
+            read,
             phantom: Default::default(),
             log_target,
         },
-    )
+    ))
 }
 
+/// The streamer passes its stdin and stdout, tests can pass both ends of an in memory pipe
 pub async fn create_process_ipc<ParentMessage, Message>(
-    stdin: Stdin,
-    stdout: Stdout,
-) -> (IpcSender<Message>, IpcReceiver<ParentMessage>)
+    stdin: impl AsyncRead + Send + Unpin + 'static,
+    mut stdout: impl AsyncWrite + Send + Unpin + 'static,
+) -> Result<(IpcSender<Message>, IpcReceiver<ParentMessage>), IpcHandshakeError>
 where
     ParentMessage: DeserializeOwned,
     Message: Send + Serialize + 'static,
 {
+    let mut read = create_lines(stdin);
+    ipc_handshake(&mut stdout, &mut read).await?;
+
     let (sender, receiver) = channel::<Message>(10);
 
     spawn(async move {
         ipc_sender(stdout, receiver, "").await;
     });
 
-    (
+    Ok((
         IpcSender {
             sender,
             log_target: "".to_string(),
         },
         IpcReceiver {
             errored: false,
-            read: create_lines(stdin),
+            read,
             phantom: Default::default(),
             log_target: "".to_string(),
         },
-    )
+    ))
 }
+
+/// Both sides write their version before reading, so neither waits on the other
+async fn ipc_handshake(
+    write: &mut (impl AsyncWriteExt + Unpin),
+    read: &mut Lines<Box<dyn AsyncBufRead + Send + Unpin + 'static>>,
+) -> Result<(), IpcHandshakeError> {
+    let mut json = serde_json::to_string(&IpcHandshake {
+        ipc_version: IPC_PROTOCOL_VERSION,
+    })
+    .expect("failed to encode the ipc handshake");
+    json.push('\n');
+
+    write
+        .write_all(json.as_bytes())
+        .await
+        .map_err(IpcHandshakeError::Write)?;
+    write.flush().await.map_err(IpcHandshakeError::Write)?;
+
+    let line = read
+        .next_line()
+        .await
+        .map_err(IpcHandshakeError::Read)?
+        .ok_or(IpcHandshakeError::Closed)?;
+    let remote =
+        serde_json::from_str::<IpcHandshake>(&line).map_err(IpcHandshakeError::Malformed)?;
+
+    if remote.ipc_version != IPC_PROTOCOL_VERSION {
+        return Err(IpcHandshakeError::Incompatible {
+            remote: remote.ipc_version,
+        });
+    }
+
+    Ok(())
+}
+
 fn create_lines(
     read: impl AsyncRead + Send + Unpin + 'static,
 ) -> Lines<Box<dyn AsyncBufRead + Send + Unpin + 'static>> {
@@ -260,3 +411,68 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncWriteExt, DuplexStream, duplex, split},
+        join,
+    };
+
+    use crate::ipc::{IPC_PROTOCOL_VERSION, IpcHandshakeError, create_lines, ipc_handshake};
+
+    async fn handshake(stream: DuplexStream) -> Result<(), IpcHandshakeError> {
+        let (read, mut write) = split(stream);
+        let mut read = create_lines(read);
+
+        ipc_handshake(&mut write, &mut read).await
+    }
+
+    #[tokio::test]
+    async fn test_ipc_handshake() {
+        let (server, streamer) = duplex(1024);
+
+        let (server, streamer) = join!(handshake(server), handshake(streamer));
+        assert!(server.is_ok());
+        assert!(streamer.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_ipc_handshake_version_mismatch() {
+        let (server, mut streamer) = duplex(1024);
+
+        streamer
+            .write_all(format!("{{\"ipc_version\":{}}}\n", IPC_PROTOCOL_VERSION + 1).as_bytes())
+            .await
+            .unwrap();
+
+        let Err(IpcHandshakeError::Incompatible { remote }) = handshake(server).await else {
+            panic!("expected an incompatible ipc version");
+        };
+        assert_eq!(remote, IPC_PROTOCOL_VERSION + 1);
+    }
+
+    #[tokio::test]
+    async fn test_ipc_handshake_malformed() {
+        let (server, mut streamer) = duplex(1024);
+
+        // A streamer without the handshake starts with its first message
+        streamer
+            .write_all(b"{\"WebSocket\":{\"UpdateApp\":{}}}\n")
+            .await
+            .unwrap();
+
+        let result = handshake(server).await;
+        assert!(matches!(result, Err(IpcHandshakeError::Malformed(_))));
+    }
+
+    #[tokio::test]
+    async fn test_ipc_handshake_closed() {
+        let (server, mut streamer) = duplex(1024);
+
+        streamer.shutdown().await.unwrap();
+
+        let result = handshake(server).await;
+        assert!(matches!(result, Err(IpcHandshakeError::Closed)));
+    }
+}
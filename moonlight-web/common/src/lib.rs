@@ -1,11 +1,20 @@
-use log::warn;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use log::{info, warn};
 use moonlight_common::stream::bindings::{Colorspace, SupportedVideoFormats};
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    api_bindings::{LaunchOptions, StreamCodec},
+    config::StreamLimitsConfig,
+};
+
 pub mod api_bindings;
 pub mod api_bindings_consts;
 pub mod config;
 pub mod ipc;
+pub mod media_api;
+pub mod pidfile;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StreamSettings {
@@ -15,7 +24,16 @@ pub struct StreamSettings {
     pub width: u32,
     pub height: u32,
     pub video_frame_queue_size: u32,
+    /// Bounds the memory of the video queue because keyframes can be much larger than other frames (0 = unlimited)
+    #[serde(default)]
+    pub video_frame_queue_max_bytes: u32,
+    /// Release video frames at the stream fps instead of when they arrive from the host
+    #[serde(default)]
+    pub video_frame_pacing: bool,
     pub audio_sample_queue_size: u32,
+    /// Audio samples are held back this long to smooth out irregular delivery from the host (0 = disabled)
+    #[serde(default)]
+    pub audio_jitter_buffer_ms: u32,
     pub play_audio_local: bool,
     pub video_supported_formats: SupportedVideoFormats,
     pub video_colorspace: Colorspace,
@@ -24,6 +42,92 @@ pub struct StreamSettings {
     /// The server should NOT create input data channels on the primary connection.
     #[serde(default)]
     pub hybrid_mode: bool,
+    /// When true, other clients can watch the stream without sending input
+    #[serde(default)]
+    pub allow_spectators: bool,
+    /// Only gather TURN relay candidates
+    #[serde(default)]
+    pub force_relay: bool,
+    /// Stream with a 10 bit codec and the Rec. 2020 colorspace
+    #[serde(default)]
+    pub hdr_enabled: bool,
+    /// These codecs are offered first, the others are still offered after them
+    #[serde(default)]
+    pub video_codec_preference: Vec<StreamCodec>,
+}
+
+impl StreamSettings {
+    /// Clamps the values requested by the client into the limits.
+    /// Returns true if any value was changed.
+    pub fn clamp_to_limits(&mut self, limits: &StreamLimitsConfig) -> bool {
+        let mut adjusted = false;
+
+        adjusted |= clamp_setting(
+            "packet size",
+            &mut self.packet_size,
+            limits.min_packet_size,
+            limits.max_packet_size,
+        );
+        adjusted |= clamp_setting("bitrate", &mut self.bitrate, 1, limits.max_bitrate);
+        adjusted |= clamp_setting("fps", &mut self.fps, 1, limits.max_fps);
+        adjusted |= clamp_setting("width", &mut self.width, 1, limits.max_width);
+        adjusted |= clamp_setting("height", &mut self.height, 1, limits.max_height);
+
+        adjusted
+    }
+}
+
+impl LaunchOptions {
+    /// Clamps the display mode into the limits, the host would reject or misbehave with a
+    /// display it can't set. Returns true if any value was changed.
+    pub fn clamp_to_limits(&mut self, limits: &StreamLimitsConfig) -> bool {
+        let Some(display_mode) = &mut self.display_mode else {
+            return false;
+        };
+
+        let mut adjusted = false;
+
+        adjusted |= clamp_setting("display fps", &mut display_mode.fps, 1, limits.max_fps);
+        adjusted |= clamp_setting(
+            "display width",
+            &mut display_mode.width,
+            1,
+            limits.max_width,
+        );
+        adjusted |= clamp_setting(
+            "display height",
+            &mut display_mode.height,
+            1,
+            limits.max_height,
+        );
+
+        adjusted
+    }
+}
+
+fn clamp_setting(name: &str, value: &mut u32, min: u32, max: u32) -> bool {
+    // A misconfigured max below the min shouldn't panic
+    let clamped = (*value).clamp(min, max.max(min));
+    if clamped == *value {
+        return false;
+    }
+
+    info!("[Stream]: Clamped the requested {name} from {value} to {clamped}");
+    *value = clamped;
+
+    true
+}
+
+/// Removes the part of the address which identifies the device: the last octet of IPv4
+/// and the last 80 bits of IPv6 addresses
+pub fn anonymize_ip(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            Ipv4Addr::new(a, b, c, 0).to_string()
+        }
+        IpAddr::V6(ip) => Ipv6Addr::from(u128::from(ip) & !((1u128 << 80) - 1)).to_string(),
+    }
 }
 
 pub fn serialize_json<T>(message: &T) -> Option<String>
@@ -37,3 +141,95 @@ where
 
     Some(json)
 }
+
+#[cfg(test)]
+mod tests {
+    use moonlight_common::stream::bindings::{Colorspace, SupportedVideoFormats};
+
+    use std::net::IpAddr;
+
+    use crate::{
+        StreamSettings, anonymize_ip,
+        api_bindings::{LaunchDisplayMode, LaunchOptions},
+        config::StreamLimitsConfig,
+    };
+
+    fn settings() -> StreamSettings {
+        StreamSettings {
+            bitrate: 10_000,
+            packet_size: 2048,
+            fps: 60,
+            width: 1920,
+            height: 1080,
+            video_frame_queue_size: 3,
+            video_frame_queue_max_bytes: 0,
+            video_frame_pacing: false,
+            audio_sample_queue_size: 20,
+            audio_jitter_buffer_ms: 0,
+            play_audio_local: false,
+            video_supported_formats: SupportedVideoFormats::H264,
+            video_colorspace: Colorspace::Rec709,
+            video_color_range_full: false,
+            hybrid_mode: false,
+            allow_spectators: false,
+            force_relay: false,
+            hdr_enabled: false,
+            video_codec_preference: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_clamp_within_limits() {
+        let mut settings = settings();
+
+        assert!(!settings.clamp_to_limits(&StreamLimitsConfig::default()));
+        assert_eq!(settings.packet_size, 2048);
+    }
+
+    #[test]
+    fn test_clamp_out_of_limits() {
+        let mut settings = settings();
+        settings.packet_size = 1;
+        settings.fps = 1000;
+        settings.width = 0;
+
+        assert!(settings.clamp_to_limits(&StreamLimitsConfig::default()));
+        assert_eq!(settings.packet_size, 512);
+        assert_eq!(settings.fps, 240);
+        assert_eq!(settings.width, 1);
+        assert_eq!(settings.height, 1080);
+    }
+
+    #[test]
+    fn test_clamp_launch_display_mode() {
+        let mut options = LaunchOptions::default();
+        assert!(!options.clamp_to_limits(&StreamLimitsConfig::default()));
+
+        options.display_mode = Some(LaunchDisplayMode {
+            width: 0,
+            height: 1440,
+            fps: 1000,
+        });
+
+        assert!(options.clamp_to_limits(&StreamLimitsConfig::default()));
+        assert_eq!(
+            options.display_mode,
+            Some(LaunchDisplayMode {
+                width: 1,
+                height: 1440,
+                fps: 240,
+            })
+        );
+    }
+
+    #[test]
+    fn test_anonymize_ip() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        assert_eq!(anonymize_ip(ip("192.168.1.42")), "192.168.1.0");
+        assert_eq!(
+            anonymize_ip(ip("2001:db8:85a3:1234:5678:8a2e:370:7334")),
+            "2001:db8:85a3::"
+        );
+    }
+}
@@ -0,0 +1,480 @@
+//! The WebRTC api of the streamer. The web server creates a peer with it too when probing a stream.
+
+use std::{net::IpAddr, sync::Arc};
+
+use log::{debug, info, warn};
+use moonlight_common::stream::bindings::{SupportedVideoFormats, VideoFormat};
+use webrtc::{
+    api::{
+        API, APIBuilder,
+        interceptor_registry::register_default_interceptors,
+        media_engine::{
+            MIME_TYPE_AV1, MIME_TYPE_H264, MIME_TYPE_HEVC, MIME_TYPE_OPUS, MediaEngine,
+        },
+        setting_engine::SettingEngine,
+    },
+    ice::{
+        network_type::NetworkType,
+        udp_mux::UDPMuxDefault,
+        udp_network::{EphemeralUDP, UDPNetwork},
+    },
+    ice_transport::{ice_candidate_type::RTCIceCandidateType, ice_server::RTCIceServer},
+    interceptor::registry::Registry,
+    rtp_transceiver::{
+        RTCPFeedback,
+        rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType},
+    },
+    util::ifaces,
+};
+
+use crate::{
+    api_bindings::{RtcIceServer, StreamCodec},
+    config::{PortRange, WebRtcConfig, WebRtcNat1To1IceCandidateType, WebRtcNetworkType},
+};
+
+/// The urls are passed as they are, webrtc-rs parses the scheme (turn / turns) and transport itself
+pub fn into_webrtc_ice(value: RtcIceServer) -> RTCIceServer {
+    RTCIceServer {
+        urls: value.urls,
+        username: value.username,
+        credential: value.credential,
+    }
+}
+
+pub fn into_webrtc_ice_candidate(value: WebRtcNat1To1IceCandidateType) -> RTCIceCandidateType {
+    match value {
+        WebRtcNat1To1IceCandidateType::Host => RTCIceCandidateType::Host,
+        WebRtcNat1To1IceCandidateType::Srflx => RTCIceCandidateType::Srflx,
+    }
+}
+
+pub fn into_webrtc_network_type(value: WebRtcNetworkType) -> NetworkType {
+    match value {
+        WebRtcNetworkType::Udp4 => NetworkType::Udp4,
+        WebRtcNetworkType::Udp6 => NetworkType::Udp6,
+        WebRtcNetworkType::Tcp4 => NetworkType::Tcp4,
+        WebRtcNetworkType::Tcp6 => NetworkType::Tcp6,
+    }
+}
+
+/// Registers the codecs of the stream and the default interceptors
+pub fn create_media_api(
+    config: &WebRtcConfig,
+    udp_mux: Option<Arc<UDPMuxDefault>>,
+    video_supported_formats: SupportedVideoFormats,
+    hdr_enabled: bool,
+    video_codec_preference: &[StreamCodec],
+) -> API {
+    let api_settings = create_setting_engine(config, udp_mux);
+
+    // -- Register media codecs
+    // TODO: register them based on the sdp
+    let mut api_media = MediaEngine::default();
+    register_audio_codecs(&mut api_media).expect("failed to register audio codecs");
+    register_video_codecs(
+        &mut api_media,
+        video_supported_formats,
+        hdr_enabled,
+        video_codec_preference,
+    )
+    .expect("failed to register video codecs");
+
+    // -- Build Api
+    let mut api_registry = Registry::new();
+
+    // Use the default set of Interceptors
+    api_registry = register_default_interceptors(api_registry, &mut api_media)
+        .expect("failed to register webrtc default interceptors");
+
+    APIBuilder::new()
+        .with_setting_engine(api_settings)
+        .with_media_engine(api_media)
+        .with_interceptor_registry(api_registry)
+        .build()
+}
+
+pub fn create_setting_engine(
+    config: &WebRtcConfig,
+    udp_mux: Option<Arc<UDPMuxDefault>>,
+) -> SettingEngine {
+    let mut api_settings = SettingEngine::default();
+
+    if let Some(udp_mux) = udp_mux {
+        api_settings.set_udp_network(UDPNetwork::Muxed(udp_mux));
+    } else if let Some(PortRange { min, max }) = config.port_range {
+        match EphemeralUDP::new(min, max) {
+            Ok(udp) => {
+                api_settings.set_udp_network(UDPNetwork::Ephemeral(udp));
+            }
+            Err(err) => {
+                warn!("[Stream]: Invalid port range in config: {err:?}");
+            }
+        }
+    }
+    if let Some(mapping) = config.nat_1to1.as_ref() {
+        api_settings.set_nat_1to1_ips(
+            mapping.ips.clone(),
+            into_webrtc_ice_candidate(mapping.ice_candidate_type),
+        );
+    }
+    api_settings.set_network_types(
+        config
+            .network_types
+            .iter()
+            .copied()
+            .map(into_webrtc_network_type)
+            .collect(),
+    );
+
+    api_settings.set_include_loopback_candidate(config.include_loopback_candidates);
+
+    if !config.bind_interfaces.is_empty() {
+        info!(
+            "[Stream]: Restricting WebRTC candidates to the interfaces {:?}",
+            config.bind_interfaces
+        );
+
+        let named_interface_ips = if config.binds_interface_names_and_ips() {
+            bind_interface_ips(config)
+        } else {
+            Vec::new()
+        };
+
+        let ip_config = config.clone();
+        api_settings.set_ip_filter(Box::new(move |ip| {
+            ip_config.allows_ip(ip, &named_interface_ips)
+        }));
+    }
+    if !config.bind_interfaces.is_empty() || !config.ice_interface_exclude.is_empty() {
+        let interface_config = config.clone();
+        api_settings.set_interface_filter(Box::new(move |name| {
+            if interface_config.excludes_interface(name) {
+                debug!("[Stream]: Excluding the ICE candidates of the interface {name}");
+                return false;
+            }
+
+            interface_config.allows_interface(name)
+        }));
+    }
+
+    api_settings
+}
+
+/// The ips of the interfaces which are in bind_interfaces by name, they're looked up once per stream
+fn bind_interface_ips(config: &WebRtcConfig) -> Vec<IpAddr> {
+    match ifaces::ifaces() {
+        Ok(interfaces) => interfaces
+            .into_iter()
+            .filter(|interface| config.bind_interfaces.contains(&interface.name))
+            .filter_map(|interface| interface.addr)
+            .map(|addr| addr.ip())
+            .collect(),
+        Err(err) => {
+            warn!(
+                "[Stream]: Failed to list the network interfaces, only the ips in bind_interfaces are used: {err:?}"
+            );
+            Vec::new()
+        }
+    }
+}
+
+/// The clock rate of the registered opus codec
+pub const OPUS_CLOCK_RATE: u32 = 48000;
+
+pub fn register_audio_codecs(media_engine: &mut MediaEngine) -> Result<(), webrtc::Error> {
+    media_engine.register_codec(
+        RTCRtpCodecParameters {
+            capability: RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_OPUS.to_owned(),
+                clock_rate: OPUS_CLOCK_RATE,
+                channels: 2,
+                sdp_fmtp_line: "minptime=10;useinbandfec=1".to_owned(),
+                rtcp_feedback: vec![],
+            },
+            payload_type: 111,
+            ..Default::default()
+        },
+        RTPCodecType::Audio,
+    )?;
+
+    Ok(())
+}
+
+/// With hdr only the 10 bit HEVC / AV1 profiles are offered
+pub fn register_video_codecs(
+    media_engine: &mut MediaEngine,
+    supported_video_formats: SupportedVideoFormats,
+    hdr_enabled: bool,
+    codec_preference: &[StreamCodec],
+) -> Result<(), webrtc::Error> {
+    // The registration order is the order of the payload types in the m-line
+    for format in ordered_video_formats(codec_preference) {
+        if !format.contained_in(supported_video_formats) {
+            continue;
+        }
+        if hdr_enabled && !format.contained_in(SupportedVideoFormats::MASK_10BIT) {
+            continue;
+        }
+
+        let Some(codec) = video_format_to_codec(format) else {
+            continue;
+        };
+        debug!(
+            "Registering Video Format {format:?}, Codec: {:?}",
+            codec.capability
+        );
+
+        media_engine.register_codec(codec, RTPCodecType::Video)?;
+    }
+
+    Ok(())
+}
+
+fn ordered_video_formats(codec_preference: &[StreamCodec]) -> Vec<VideoFormat> {
+    StreamCodec::ordered(codec_preference)
+        .into_iter()
+        .flat_map(|codec| {
+            VideoFormat::all()
+                .into_iter()
+                .filter(move |format| format.contained_in(codec.formats()))
+        })
+        .collect()
+}
+
+pub fn video_format_to_codec(format: VideoFormat) -> Option<RTCRtpCodecParameters> {
+    let rtcp_feedback = vec![
+        RTCPFeedback {
+            typ: "nack".to_string(),
+            parameter: "".to_string(),
+        },
+        RTCPFeedback {
+            typ: "nack".to_string(),
+            parameter: "pli".to_string(),
+        },
+        RTCPFeedback {
+            typ: "goog-remb".to_string(),
+            parameter: "".to_string(),
+        },
+    ];
+
+    match format {
+        // -- H264 Constrained Baseline Profile
+        VideoFormat::H264 => Some(RTCRtpCodecParameters {
+            capability: RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_H264.to_owned(),
+                clock_rate: 90000,
+                channels: 0,
+                sdp_fmtp_line:
+                    "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f"
+                        .to_owned(),
+                rtcp_feedback: rtcp_feedback.clone(),
+            },
+            payload_type: 96,
+            ..Default::default()
+        }),
+        // -- H264 High Profile
+        VideoFormat::H264High8_444 => Some(RTCRtpCodecParameters {
+            capability: RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_H264.to_owned(),
+                clock_rate: 90000,
+                channels: 0,
+                sdp_fmtp_line:
+                    "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=640032"
+                        .to_owned(),
+                rtcp_feedback: rtcp_feedback.clone(),
+            },
+            payload_type: 97,
+            ..Default::default()
+        }),
+
+        // TODO: h265 requires resolution in the level-id field, set it based on resolution and fps
+        // -- H265 Main Profile
+        VideoFormat::H265 => Some(RTCRtpCodecParameters {
+            capability: RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_HEVC.to_owned(),
+                clock_rate: 90000,
+                channels: 0,
+                // They're the same
+                // sdp_fmtp_line: "profile-id=1;level-id=93;tier-flag=0;tx-mode=1".to_owned(),
+                sdp_fmtp_line: "".to_owned(),
+                rtcp_feedback: rtcp_feedback.clone(),
+            },
+            payload_type: 98,
+            ..Default::default()
+        }),
+        // -- H265 Main10 Profile
+        VideoFormat::H265Main10 => Some(RTCRtpCodecParameters {
+            capability: RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_HEVC.to_owned(),
+                clock_rate: 90000,
+                channels: 0,
+                sdp_fmtp_line: "profile-id=2;tier-flag=0;level-id=93;tx-mode=SRST".to_owned(),
+                rtcp_feedback: rtcp_feedback.clone(),
+            },
+            payload_type: 99,
+            ..Default::default()
+        }),
+        // -- H265 RExt 4:4:4 8-bit
+        VideoFormat::H265Rext8_444 => Some(RTCRtpCodecParameters {
+            capability: RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_HEVC.to_owned(),
+                clock_rate: 90000,
+                channels: 0,
+                sdp_fmtp_line: "profile-id=4;tier-flag=0;level-id=120;tx-mode=SRST".to_owned(),
+                rtcp_feedback: rtcp_feedback.clone(),
+            },
+            payload_type: 100,
+            ..Default::default()
+        }),
+        // -- H265 RExt 4:4:4 10-bit
+        VideoFormat::H265Rext10_444 => Some(RTCRtpCodecParameters {
+            capability: RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_HEVC.to_owned(),
+                clock_rate: 90000,
+                channels: 0,
+                sdp_fmtp_line: "profile-id=5;tier-flag=0;level-id=93;tx-mode=SRST".to_owned(),
+                rtcp_feedback: rtcp_feedback.clone(),
+            },
+            payload_type: 101,
+            ..Default::default()
+        }),
+
+        // -- Av1
+        VideoFormat::Av1Main8 | VideoFormat::Av1Main10 => Some(RTCRtpCodecParameters {
+            capability: RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_AV1.to_owned(),
+                clock_rate: 90000,
+                channels: 0,
+                sdp_fmtp_line: "profile=0".to_owned(),
+                rtcp_feedback: rtcp_feedback.clone(),
+            },
+            payload_type: 102,
+            ..Default::default()
+        }),
+        VideoFormat::Av1High8_444 | VideoFormat::Av1High10_444 => Some(RTCRtpCodecParameters {
+            capability: RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_AV1.to_owned(),
+                clock_rate: 90000,
+                channels: 0,
+                sdp_fmtp_line: "profile=1".to_owned(),
+                rtcp_feedback: rtcp_feedback.clone(),
+            },
+            payload_type: 103,
+            ..Default::default()
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use moonlight_common::stream::bindings::VideoFormat;
+    use webrtc::{api::APIBuilder, peer_connection::configuration::RTCConfiguration, util::ifaces};
+
+    use crate::{
+        api_bindings::StreamCodec,
+        config::WebRtcConfig,
+        media_api::{create_setting_engine, ordered_video_formats},
+    };
+
+    fn codecs(formats: &[VideoFormat]) -> Vec<StreamCodec> {
+        let mut codecs = Vec::new();
+        for format in formats {
+            let codec = StreamCodec::ALL
+                .into_iter()
+                .find(|codec| format.contained_in(codec.formats()))
+                .unwrap();
+            if codecs.last() != Some(&codec) {
+                codecs.push(codec);
+            }
+        }
+
+        codecs
+    }
+
+    #[test]
+    fn test_video_formats_default_order() {
+        let formats = ordered_video_formats(&[]);
+
+        assert_eq!(formats.len(), VideoFormat::all().len());
+        assert_eq!(codecs(&formats), StreamCodec::ALL);
+    }
+
+    #[test]
+    fn test_video_formats_preferred_first() {
+        let formats = ordered_video_formats(&[StreamCodec::H265, StreamCodec::H265]);
+
+        assert_eq!(formats.len(), VideoFormat::all().len());
+        assert_eq!(
+            codecs(&formats),
+            [StreamCodec::H265, StreamCodec::H264, StreamCodec::Av1]
+        );
+    }
+
+    /// Gathers the host candidates of a peer with the setting engine of the config
+    async fn gather_host_ips(bind_interfaces: &[&str]) -> Vec<IpAddr> {
+        let config = WebRtcConfig {
+            bind_interfaces: bind_interfaces.iter().map(|i| i.to_string()).collect(),
+            ..Default::default()
+        };
+
+        let api = APIBuilder::new()
+            .with_setting_engine(create_setting_engine(&config, None))
+            .build();
+        let peer = api
+            .new_peer_connection(RTCConfiguration::default())
+            .await
+            .unwrap();
+        peer.create_data_channel("test", None).await.unwrap();
+
+        let offer = peer.create_offer(None).await.unwrap();
+        let mut gathering_complete = peer.gathering_complete_promise().await;
+        peer.set_local_description(offer).await.unwrap();
+        let _ = gathering_complete.recv().await;
+
+        let sdp = peer.local_description().await.unwrap().sdp;
+        peer.close().await.unwrap();
+
+        // a=candidate:<foundation> <component> <protocol> <priority> <address> <port> typ host
+        let mut ips = sdp
+            .lines()
+            .filter_map(|line| line.strip_prefix("a=candidate:"))
+            .filter(|candidate| candidate.contains(" typ host"))
+            .filter_map(|candidate| candidate.split_whitespace().nth(4)?.parse().ok())
+            .collect::<Vec<IpAddr>>();
+        ips.sort();
+        ips.dedup();
+        ips
+    }
+
+    #[tokio::test]
+    async fn test_bind_interfaces_restrict_candidates() {
+        // No interface has this ip
+        assert!(gather_host_ips(&["203.0.113.7"]).await.is_empty());
+
+        let interfaces = ifaces::ifaces().unwrap();
+        let Some((name, ip)) = interfaces.iter().find_map(|interface| {
+            let ip = interface.addr?.ip();
+            (ip.is_ipv4() && !ip.is_loopback()).then_some((interface.name.as_str(), ip))
+        }) else {
+            // Without a network interface there's nothing to gather
+            return;
+        };
+        let interface_ips = interfaces
+            .iter()
+            .filter(|interface| interface.name == name)
+            .filter_map(|interface| interface.addr)
+            .map(|addr| addr.ip())
+            .collect::<Vec<_>>();
+
+        let gathered = gather_host_ips(&[name]).await;
+        assert!(gathered.contains(&ip));
+        assert!(gathered.iter().all(|ip| interface_ips.contains(ip)));
+
+        // Names and ips allow the candidates of both
+        assert_eq!(gather_host_ips(&[name, "203.0.113.7"]).await, gathered);
+        let ip_entry = ip.to_string();
+        assert_eq!(gather_host_ips(&[ip_entry.as_str()]).await, vec![ip]);
+    }
+}
@@ -0,0 +1,89 @@
+//! Every streamer writes a pidfile into the configured directory so that the web server
+//! can find streamers which outlived a previous web server process.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+const PIDFILE_PREFIX: &str = "streamer-";
+const PIDFILE_EXTENSION: &str = "pid";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamerPidFile {
+    pub pid: u32,
+    pub session_id: String,
+}
+
+impl StreamerPidFile {
+    pub fn path(dir: &Path, session_id: &str) -> PathBuf {
+        dir.join(format!("{PIDFILE_PREFIX}{session_id}.{PIDFILE_EXTENSION}"))
+    }
+
+    /// Returns true if the file name looks like one written by [StreamerPidFile::write]
+    pub fn is_pidfile(path: &Path) -> bool {
+        path.extension()
+            .is_some_and(|extension| extension == PIDFILE_EXTENSION)
+            && path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(PIDFILE_PREFIX))
+    }
+
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+
+        serde_json::from_str(&text).map_err(io::Error::other)
+    }
+
+    /// Writes the pidfile and returns its path
+    pub fn write(&self, dir: &Path) -> io::Result<PathBuf> {
+        fs::create_dir_all(dir)?;
+
+        let path = Self::path(dir, &self.session_id);
+        let text = serde_json::to_string(self).map_err(io::Error::other)?;
+        fs::write(&path, text)?;
+
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::Path};
+
+    use crate::pidfile::StreamerPidFile;
+
+    #[test]
+    fn test_write_read() {
+        let dir = env::temp_dir().join("moonlight-web-test-pidfile");
+
+        let pidfile = StreamerPidFile {
+            pid: 1234,
+            session_id: "abc".to_string(),
+        };
+        let path = pidfile.write(&dir).expect("failed to write pidfile");
+
+        assert!(StreamerPidFile::is_pidfile(&path));
+        assert_eq!(
+            StreamerPidFile::read(&path).expect("failed to read pidfile"),
+            pidfile
+        );
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_is_pidfile() {
+        assert!(StreamerPidFile::is_pidfile(Path::new(
+            "server/streamers/streamer-abc.pid"
+        )));
+        assert!(!StreamerPidFile::is_pidfile(Path::new(
+            "server/streamers/streamer-abc.json"
+        )));
+        assert!(!StreamerPidFile::is_pidfile(Path::new("server/data.json")));
+        assert!(!StreamerPidFile::is_pidfile(Path::new("server/other.pid")));
+    }
+}
@@ -0,0 +1,160 @@
+//! Synthetic load test of the transport.
+//!
+//! Started with the `--benchmark` cli argument the streamer doesn't contact the host. It streams
+//! loopback frames which are padded to the bitrate of the stream settings and reports the
+//! achieved throughput and how long the transport took to accept each frame.
+
+use std::{
+    env,
+    time::{Duration, Instant},
+};
+
+use common::{api_bindings::StreamerStatsUpdate, ipc::STREAMER_BENCHMARK_ARG};
+
+/// How often the results are reported
+const BENCHMARK_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn is_benchmark_requested() -> bool {
+    env::args().skip(1).any(|arg| arg == STREAMER_BENCHMARK_ARG)
+}
+
+/// Size of a frame so the frames of one second add up to the bitrate
+pub fn benchmark_frame_size(bitrate_kbps: u32, fps: u32) -> usize {
+    (bitrate_kbps as usize * 1000 / 8) / fps.max(1) as usize
+}
+
+#[derive(Debug)]
+pub struct BenchmarkStats {
+    target_kbps: u32,
+    interval_start: Instant,
+    interval_bytes: usize,
+    interval_frames: u32,
+    total_enqueue_latency: Duration,
+    max_enqueue_latency: Duration,
+    /// Total of the video queue at the last report
+    last_frames_dropped: u32,
+}
+
+impl BenchmarkStats {
+    pub fn new(target_kbps: u32) -> Self {
+        Self {
+            target_kbps,
+            interval_start: Instant::now(),
+            interval_bytes: 0,
+            interval_frames: 0,
+            total_enqueue_latency: Duration::ZERO,
+            max_enqueue_latency: Duration::ZERO,
+            last_frames_dropped: 0,
+        }
+    }
+
+    pub fn target_kbps(&self) -> u32 {
+        self.target_kbps
+    }
+
+    pub fn record_frame(&mut self, bytes: usize, enqueue_latency: Duration) {
+        self.interval_bytes += bytes;
+        self.interval_frames += 1;
+        self.total_enqueue_latency += enqueue_latency;
+        self.max_enqueue_latency = self.max_enqueue_latency.max(enqueue_latency);
+    }
+
+    pub fn is_report_due(&self, now: Instant) -> bool {
+        now - self.interval_start >= BENCHMARK_REPORT_INTERVAL
+    }
+
+    /// Takes the results since the last report, `frames_dropped` is the total of the video queue
+    pub fn report(&mut self, now: Instant, frames_dropped: u32) -> StreamerStatsUpdate {
+        let elapsed = (now - self.interval_start).max(Duration::from_millis(1));
+
+        let frames_sent = self.interval_frames;
+        let frames_dropped = frames_dropped
+            .saturating_sub(self.last_frames_dropped)
+            .min(frames_sent);
+
+        // Every frame of the benchmark has about the same size
+        let delivered_bytes = self
+            .interval_bytes
+            .checked_div(frames_sent as usize)
+            .unwrap_or(0)
+            * (frames_sent - frames_dropped) as usize;
+        let achieved_kbps = (delivered_bytes as f64 * 8.0 / 1000.0) / elapsed.as_secs_f64();
+
+        let avg_enqueue_latency = self
+            .total_enqueue_latency
+            .checked_div(frames_sent)
+            .unwrap_or(Duration::ZERO);
+
+        let report = StreamerStatsUpdate::Benchmark {
+            target_kbps: self.target_kbps,
+            achieved_kbps: achieved_kbps as u32,
+            frames_sent,
+            frames_dropped,
+            avg_enqueue_latency_ms: avg_enqueue_latency.as_secs_f64() * 1000.0,
+            max_enqueue_latency_ms: self.max_enqueue_latency.as_secs_f64() * 1000.0,
+        };
+
+        self.interval_start = now;
+        self.interval_bytes = 0;
+        self.interval_frames = 0;
+        self.total_enqueue_latency = Duration::ZERO;
+        self.max_enqueue_latency = Duration::ZERO;
+        self.last_frames_dropped += frames_dropped;
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use common::api_bindings::StreamerStatsUpdate;
+
+    use crate::benchmark::{BenchmarkStats, benchmark_frame_size};
+
+    #[test]
+    fn test_frame_size() {
+        // 20 Mbps at 60 fps
+        assert_eq!(benchmark_frame_size(20_000, 60), 41_666);
+        assert_eq!(benchmark_frame_size(1000, 0), 125_000);
+    }
+
+    #[test]
+    fn test_report() {
+        let mut stats = BenchmarkStats::new(8000);
+        let start = stats.interval_start;
+
+        for latency in [1, 3] {
+            stats.record_frame(500_000, Duration::from_millis(latency));
+        }
+        assert!(!stats.is_report_due(start));
+        assert!(stats.is_report_due(start + Duration::from_secs(1)));
+
+        let StreamerStatsUpdate::Benchmark {
+            achieved_kbps,
+            frames_sent,
+            frames_dropped,
+            avg_enqueue_latency_ms,
+            max_enqueue_latency_ms,
+            ..
+        } = stats.report(start + Duration::from_secs(1), 1)
+        else {
+            panic!("expected a benchmark report");
+        };
+        assert_eq!(frames_sent, 2);
+        assert_eq!(frames_dropped, 1);
+        assert_eq!(achieved_kbps, 4000);
+        assert_eq!(avg_enqueue_latency_ms, 2.0);
+        assert_eq!(max_enqueue_latency_ms, 3.0);
+
+        // Only the frames dropped since the last report count
+        stats.record_frame(1000, Duration::from_millis(1));
+        let StreamerStatsUpdate::Benchmark { frames_dropped, .. } =
+            stats.report(Instant::now() + Duration::from_secs(2), 1)
+        else {
+            panic!("expected a benchmark report");
+        };
+        assert_eq!(frames_dropped, 0);
+    }
+}
@@ -109,6 +109,16 @@ where
             u32::from_be_bytes(buffer)
         }
     }
+    pub fn get_u64(&mut self) -> u64 {
+        let mut buffer = [0u8; 8];
+        self.get_u8_array(&mut buffer);
+
+        if self.little_endian {
+            u64::from_le_bytes(buffer)
+        } else {
+            u64::from_be_bytes(buffer)
+        }
+    }
 
     pub fn get_f32(&mut self) -> f32 {
         let mut buffer = [0u8; 4];
@@ -121,6 +131,10 @@ where
         }
     }
 
+    pub fn remaining(&self) -> usize {
+        self.buffer.as_ref().len().saturating_sub(self.position)
+    }
+
     pub fn reset(&mut self) {
         self.position = 0;
         self.limit = 0;
@@ -158,6 +172,15 @@ where
 
         self.put_u8_array(&bytes)
     }
+    pub fn put_u32(&mut self, data: u32) -> bool {
+        let bytes: [u8; 4] = if self.little_endian {
+            u32::to_le_bytes(data)
+        } else {
+            u32::to_be_bytes(data)
+        };
+
+        self.put_u8_array(&bytes)
+    }
 
     pub fn put_utf8_raw(&mut self, text: &str) -> bool {
         self.put_u8_array(text.as_bytes())
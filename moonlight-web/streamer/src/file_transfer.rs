@@ -0,0 +1,513 @@
+//! Uploads of the client over the file transfer channel.
+//!
+//! GameStream hosts have no file api, so the files are written on the machine of the streamer
+//! into one of the directories of the FileTransferConfig. A file is written next to its target
+//! as a new hidden `.part` file named after the transfer and only renamed once the size and checksum match.
+
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+use common::{api_bindings::StreamerStatsUpdate, config::FileTransferConfig};
+use log::{debug, warn};
+use thiserror::Error;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+};
+
+/// Chunks of an upload contain at most this many bytes
+pub const FILE_TRANSFER_CHUNK_SIZE: usize = 16 * 1024;
+/// Progress is reported every time this many bytes were written and once the file is complete
+const PROGRESS_INTERVAL: u64 = 1024 * 1024;
+/// Uploads which can run at the same time
+const MAX_ACTIVE_TRANSFERS: usize = 4;
+/// Most file systems allow 255 bytes, the name of the part file is up to 17 bytes longer
+const MAX_FILE_NAME_LEN: usize = 236;
+
+#[derive(Debug)]
+pub enum FileTransferRequest {
+    Start {
+        id: u32,
+        size: u64,
+        /// Empty = the first allowed directory
+        directory: String,
+        file_name: String,
+    },
+    Chunk {
+        id: u32,
+        data: Vec<u8>,
+    },
+    End {
+        id: u32,
+        /// Crc32 of the whole file
+        checksum: u32,
+    },
+    Cancel {
+        id: u32,
+    },
+}
+
+impl FileTransferRequest {
+    pub fn id(&self) -> u32 {
+        match self {
+            Self::Start { id, .. }
+            | Self::Chunk { id, .. }
+            | Self::End { id, .. }
+            | Self::Cancel { id } => *id,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FileTransferStatus {
+    Accepted,
+    Completed,
+    Failed(String),
+}
+
+#[derive(Debug, Error)]
+pub enum FileTransferError {
+    #[error("file transfers are disabled")]
+    Disabled,
+    #[error("the file is bigger than {0} bytes")]
+    TooLarge(u64),
+    #[error("the directory isn't allowed")]
+    DirectoryNotAllowed,
+    #[error("the file name is invalid")]
+    InvalidFileName,
+    #[error("the file already exists")]
+    AlreadyExists,
+    #[error("too many files are transferred at the same time")]
+    TooManyTransfers,
+    #[error("the transfer {0} is already running")]
+    DuplicateTransfer(u32),
+    #[error("the transfer {0} doesn't exist")]
+    UnknownTransfer(u32),
+    #[error("received more data than announced")]
+    SizeExceeded,
+    #[error("received {received} of {size} bytes")]
+    Incomplete { received: u64, size: u64 },
+    #[error("the checksum doesn't match")]
+    ChecksumMismatch,
+    #[error("io: {0}")]
+    Io(#[from] io::Error),
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+
+    let mut index = 0;
+    while index < table.len() {
+        let mut crc = index as u32;
+
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+
+        table[index] = crc;
+        index += 1;
+    }
+
+    table
+};
+
+/// Crc32 (IEEE) as used by zip and png
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32(u32);
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self(0xFFFF_FFFF)
+    }
+}
+
+impl Crc32 {
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.0 = CRC32_TABLE[((self.0 ^ *byte as u32) & 0xFF) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    pub fn finish(self) -> u32 {
+        !self.0
+    }
+}
+
+struct IncomingFile {
+    file_name: String,
+    path: PathBuf,
+    part_path: PathBuf,
+    file: File,
+    size: u64,
+    received: u64,
+    checksum: Crc32,
+    /// Set once the part file was renamed to the target
+    completed: bool,
+}
+
+impl Drop for IncomingFile {
+    fn drop(&mut self) {
+        // Cancelled, failed or the stream ended
+        if !self.completed
+            && let Err(err) = std::fs::remove_file(&self.part_path)
+        {
+            debug!(
+                "[FileTransfer]: failed to remove {}: {err}",
+                self.part_path.display()
+            );
+        }
+    }
+}
+
+pub struct FileTransfers {
+    config: FileTransferConfig,
+    incoming: HashMap<u32, IncomingFile>,
+}
+
+impl FileTransfers {
+    pub fn new(config: FileTransferConfig) -> Self {
+        Self {
+            config,
+            incoming: HashMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.is_enabled()
+    }
+
+    fn resolve_directory(&self, directory: &str) -> Result<PathBuf, FileTransferError> {
+        let allowed = if directory.is_empty() {
+            self.config.allowed_directories.first()
+        } else {
+            // Path comparison ignores trailing separators, but ".." components still won't match
+            self.config
+                .allowed_directories
+                .iter()
+                .find(|allowed| Path::new(allowed) == Path::new(directory))
+        };
+
+        allowed
+            .map(PathBuf::from)
+            .ok_or(FileTransferError::DirectoryNotAllowed)
+    }
+
+    pub fn validate_file_name(file_name: &str) -> Result<(), FileTransferError> {
+        if file_name.is_empty()
+            || file_name.len() > MAX_FILE_NAME_LEN
+            || file_name == "."
+            || file_name == ".."
+            || file_name.contains(['/', '\\', '\0'])
+        {
+            return Err(FileTransferError::InvalidFileName);
+        }
+
+        Ok(())
+    }
+
+    pub async fn start(
+        &mut self,
+        id: u32,
+        size: u64,
+        directory: &str,
+        file_name: String,
+    ) -> Result<(), FileTransferError> {
+        if !self.is_enabled() {
+            return Err(FileTransferError::Disabled);
+        }
+        if size > self.config.max_file_size {
+            return Err(FileTransferError::TooLarge(self.config.max_file_size));
+        }
+        if self.incoming.contains_key(&id) {
+            return Err(FileTransferError::DuplicateTransfer(id));
+        }
+        if self.incoming.len() >= MAX_ACTIVE_TRANSFERS {
+            return Err(FileTransferError::TooManyTransfers);
+        }
+        Self::validate_file_name(&file_name)?;
+
+        let directory = self.resolve_directory(directory)?;
+        let path = directory.join(&file_name);
+        if fs::try_exists(&path).await?
+            || self.incoming.values().any(|incoming| incoming.path == path)
+        {
+            return Err(FileTransferError::AlreadyExists);
+        }
+
+        // Never reuse an existing file, it could be a symlink or belong to someone else
+        let part_path = directory.join(format!(".{file_name}.{id}.part"));
+        let file = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&part_path)
+            .await
+        {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(FileTransferError::AlreadyExists);
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        self.incoming.insert(
+            id,
+            IncomingFile {
+                file_name,
+                path,
+                part_path,
+                file,
+                size,
+                received: 0,
+                checksum: Crc32::default(),
+                completed: false,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Returns the progress if it should be reported
+    pub async fn chunk(
+        &mut self,
+        id: u32,
+        data: &[u8],
+    ) -> Result<Option<StreamerStatsUpdate>, FileTransferError> {
+        let result = self.write_chunk(id, data).await;
+        if result.is_err() {
+            self.incoming.remove(&id);
+        }
+
+        result
+    }
+
+    async fn write_chunk(
+        &mut self,
+        id: u32,
+        data: &[u8],
+    ) -> Result<Option<StreamerStatsUpdate>, FileTransferError> {
+        let incoming = self
+            .incoming
+            .get_mut(&id)
+            .ok_or(FileTransferError::UnknownTransfer(id))?;
+
+        let received = incoming.received + data.len() as u64;
+        if received > incoming.size {
+            return Err(FileTransferError::SizeExceeded);
+        }
+
+        incoming.file.write_all(data).await?;
+        incoming.checksum.update(data);
+
+        let report = received / PROGRESS_INTERVAL != incoming.received / PROGRESS_INTERVAL
+            || received == incoming.size;
+        incoming.received = received;
+
+        Ok(report.then(|| StreamerStatsUpdate::FileTransfer {
+            id,
+            file_name: incoming.file_name.clone(),
+            transferred_bytes: incoming.received,
+            size: incoming.size,
+        }))
+    }
+
+    /// Returns the path the file was written to
+    pub async fn finish(&mut self, id: u32, checksum: u32) -> Result<PathBuf, FileTransferError> {
+        let mut incoming = self
+            .incoming
+            .remove(&id)
+            .ok_or(FileTransferError::UnknownTransfer(id))?;
+
+        if incoming.received != incoming.size {
+            return Err(FileTransferError::Incomplete {
+                received: incoming.received,
+                size: incoming.size,
+            });
+        }
+        if incoming.checksum.finish() != checksum {
+            return Err(FileTransferError::ChecksumMismatch);
+        }
+
+        incoming.file.flush().await?;
+        incoming.file.sync_all().await?;
+
+        // The file might have been created by someone else during the upload
+        if fs::try_exists(&incoming.path).await? {
+            return Err(FileTransferError::AlreadyExists);
+        }
+        fs::rename(&incoming.part_path, &incoming.path).await?;
+        incoming.completed = true;
+
+        Ok(incoming.path.clone())
+    }
+
+    pub fn cancel(&mut self, id: u32) {
+        if self.incoming.remove(&id).is_none() {
+            warn!("[FileTransfer]: tried to cancel the unknown transfer {id}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use common::config::FileTransferConfig;
+    use tokio::runtime::Builder;
+
+    use crate::file_transfer::{Crc32, FileTransferError, FileTransfers};
+
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = Crc32::default();
+        crc.update(data);
+        crc.finish()
+    }
+
+    fn temp_directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("streamer-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let mut crc = Crc32::default();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_file_name() {
+        for file_name in ["save.sav", ".hidden", "with space.txt"] {
+            assert!(FileTransfers::validate_file_name(file_name).is_ok());
+        }
+        for file_name in ["", ".", "..", "../escape", "dir/file", "dir\\file", "nul\0"] {
+            assert!(matches!(
+                FileTransfers::validate_file_name(file_name),
+                Err(FileTransferError::InvalidFileName)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_upload() {
+        let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+        let directory = temp_directory("upload");
+        let data = vec![7u8; 3000];
+
+        let mut transfers = FileTransfers::new(FileTransferConfig {
+            max_file_size: 4000,
+            allowed_directories: vec![directory.to_string_lossy().to_string()],
+        });
+
+        runtime.block_on(async {
+            assert!(matches!(
+                transfers.start(0, 5000, "", "big.bin".to_string()).await,
+                Err(FileTransferError::TooLarge(4000))
+            ));
+            assert!(matches!(
+                transfers.start(0, 10, "/", "file.bin".to_string()).await,
+                Err(FileTransferError::DirectoryNotAllowed)
+            ));
+
+            // Complete upload
+            transfers
+                .start(1, data.len() as u64, "", "file.bin".to_string())
+                .await
+                .unwrap();
+            assert!(transfers.chunk(1, &data[..1000]).await.unwrap().is_none());
+            assert!(transfers.chunk(1, &data[1000..]).await.unwrap().is_some());
+            let path = transfers.finish(1, crc32(&data)).await.unwrap();
+            assert_eq!(std::fs::read(path).unwrap(), data);
+
+            // The file now exists
+            assert!(matches!(
+                transfers.start(2, 10, "", "file.bin".to_string()).await,
+                Err(FileTransferError::AlreadyExists)
+            ));
+
+            // Wrong checksum
+            transfers
+                .start(3, data.len() as u64, "", "corrupt.bin".to_string())
+                .await
+                .unwrap();
+            transfers.chunk(3, &data).await.unwrap();
+            assert!(matches!(
+                transfers.finish(3, crc32(&data) ^ 1).await,
+                Err(FileTransferError::ChecksumMismatch)
+            ));
+
+            // More data than announced
+            transfers
+                .start(4, 10, "", "short.bin".to_string())
+                .await
+                .unwrap();
+            assert!(matches!(
+                transfers.chunk(4, &data).await,
+                Err(FileTransferError::SizeExceeded)
+            ));
+            assert!(matches!(
+                transfers.chunk(4, &data[..1]).await,
+                Err(FileTransferError::UnknownTransfer(4))
+            ));
+        });
+
+        // Only the complete upload is left, the part files are removed
+        let mut files = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, vec!["file.bin".to_string()]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_existing_part_file() {
+        let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+        let directory = temp_directory("part");
+
+        let mut transfers = FileTransfers::new(FileTransferConfig {
+            max_file_size: 4000,
+            allowed_directories: vec![directory.to_string_lossy().to_string()],
+        });
+
+        let part_path = directory.join(".file.bin.5.part");
+        std::fs::write(&part_path, b"not ours").unwrap();
+
+        runtime.block_on(async {
+            assert!(matches!(
+                transfers.start(5, 10, "", "file.bin".to_string()).await,
+                Err(FileTransferError::AlreadyExists)
+            ));
+
+            // Another transfer gets its own part file
+            transfers
+                .start(6, 10, "", "file.bin".to_string())
+                .await
+                .unwrap();
+            transfers.cancel(6);
+        });
+
+        // Neither truncated nor removed
+        assert_eq!(std::fs::read(&part_path).unwrap(), b"not ours");
+        assert!(!directory.join(".file.bin.6.part").exists());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
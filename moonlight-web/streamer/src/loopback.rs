@@ -0,0 +1,671 @@
+//! Loopback host used for integration tests and demos.
+//!
+//! Instead of connecting to a Sunshine host the streamer generates synthetic video and audio
+//! and accepts all input locally. The video frames are well formed H264 Annex B access units
+//! (SPS, PPS and slices) but don't contain decodable pictures: they exist to exercise the
+//! transport, session and ipc path end-to-end.
+//!
+//! The benchmark mode pads the frames to the bitrate of the stream, see [crate::benchmark].
+
+use std::{
+    sync::{Weak, atomic::Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{
+    api_bindings::{StreamCapabilities, StreamServerMessage},
+    ipc::StreamerIpcMessage,
+};
+use log::{debug, info, warn};
+use moonlight_common::stream::{
+    audio::AudioDecoder,
+    bindings::{
+        AudioConfig, BufferType, Colorspace, DecodeResult, FrameType, OpusMultistreamConfig,
+        SupportedVideoFormats, VideoDataBuffer, VideoDecodeUnit, VideoFormat,
+    },
+    video::{VideoDecoder, VideoSetup},
+};
+
+use crate::{
+    StreamAudioDecoder, StreamConnection,
+    benchmark::{BenchmarkStats, benchmark_frame_size},
+    transport::{OutboundPacket, TransportError},
+    video::StreamVideoDecoder,
+};
+
+/// Every n-th frame is an IDR frame
+const LOOPBACK_IDR_INTERVAL: i32 = 120;
+
+/// Payload of the slice of a frame which isn't padded
+const LOOPBACK_MIN_SLICE_SIZE: usize = 64;
+
+/// Duration of a single opus frame
+const LOOPBACK_AUDIO_FRAME_DURATION: Duration = Duration::from_millis(20);
+
+/// A valid opus packet containing 20ms of silence
+const OPUS_SILENCE_FRAME: &[u8] = &[0xF8, 0xFF, 0xFE];
+
+const H264_START_CODE: &[u8] = &[0, 0, 0, 1];
+// Constrained baseline, level 3.1
+const H264_SPS: &[u8] = &[0x67, 0x42, 0xE0, 0x1F, 0x8C, 0x8D, 0x40, 0x50, 0x1E, 0xD0];
+const H264_PPS: &[u8] = &[0x68, 0xCE, 0x3C, 0x80];
+const H264_IDR_SLICE_HEADER: u8 = 0x65;
+const H264_NON_IDR_SLICE_HEADER: u8 = 0x41;
+
+pub fn loopback_video_setup(width: u32, height: u32, fps: u32) -> VideoSetup {
+    VideoSetup {
+        format: VideoFormat::H264,
+        width,
+        height,
+        redraw_rate: fps,
+        flags: 0,
+    }
+}
+
+pub fn loopback_audio_config() -> OpusMultistreamConfig {
+    OpusMultistreamConfig {
+        sample_rate: 48000,
+        channel_count: 2,
+        streams: 1,
+        coupled_streams: 1,
+        samples_per_frame: 960,
+        mapping: std::array::from_fn(|i| i as u8),
+    }
+}
+
+pub struct LoopbackFrame {
+    pub frame_number: i32,
+    pub frame_type: FrameType,
+    pub buffers: Vec<(BufferType, Vec<u8>)>,
+}
+
+/// Generates synthetic H264 access units
+#[derive(Default)]
+pub struct LoopbackVideoSource {
+    frame_number: i32,
+    force_idr: bool,
+    /// The slice is padded so the whole frame has at least this many bytes
+    frame_size: usize,
+}
+
+impl LoopbackVideoSource {
+    pub fn with_frame_size(frame_size: usize) -> Self {
+        Self {
+            frame_size,
+            ..Default::default()
+        }
+    }
+
+    /// The next generated frame will be an IDR frame
+    pub fn request_idr(&mut self) {
+        self.force_idr = true;
+    }
+
+    pub fn next_frame(&mut self) -> LoopbackFrame {
+        let frame_number = self.frame_number;
+        self.frame_number = self.frame_number.wrapping_add(1);
+
+        let is_idr = self.force_idr || frame_number % LOOPBACK_IDR_INTERVAL == 0;
+        self.force_idr = false;
+
+        let mut buffers = Vec::new();
+        if is_idr {
+            buffers.push((BufferType::Sps, annex_b(H264_SPS)));
+            buffers.push((BufferType::Pps, annex_b(H264_PPS)));
+        }
+
+        let header_size =
+            buffers.iter().map(|(_, data)| data.len()).sum::<usize>() + H264_START_CODE.len() + 1;
+        let payload_size = self
+            .frame_size
+            .saturating_sub(header_size)
+            .max(LOOPBACK_MIN_SLICE_SIZE);
+
+        // The payload only contains non zero bytes so it never contains a start code
+        let mut slice = Vec::with_capacity(1 + payload_size);
+        slice.push(if is_idr {
+            H264_IDR_SLICE_HEADER
+        } else {
+            H264_NON_IDR_SLICE_HEADER
+        });
+        slice.extend(
+            (0..payload_size).map(|i| 0x80 | ((frame_number as u8).wrapping_add(i as u8) & 0x7F)),
+        );
+        buffers.push((BufferType::PicData, annex_b(&slice)));
+
+        LoopbackFrame {
+            frame_number,
+            frame_type: if is_idr {
+                FrameType::Idr
+            } else {
+                FrameType::PFrame
+            },
+            buffers,
+        }
+    }
+}
+
+fn annex_b(nal: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(H264_START_CODE.len() + nal.len());
+    data.extend_from_slice(H264_START_CODE);
+    data.extend_from_slice(nal);
+    data
+}
+
+/// Feeds synthetic frames into the transport until the stream terminates.
+/// With benchmark stats the frames are padded to its target bitrate and the results are reported.
+///
+/// The decoders block on the runtime so this runs on a dedicated thread.
+pub fn spawn_loopback_source(
+    stream: Weak<StreamConnection>,
+    setup: VideoSetup,
+    mut benchmark: Option<BenchmarkStats>,
+) {
+    thread::spawn(move || {
+        let mut video_decoder = StreamVideoDecoder {
+            stream: stream.clone(),
+            supported_formats: SupportedVideoFormats::H264,
+            stats: Default::default(),
+        };
+        let mut audio_decoder = StreamAudioDecoder {
+            stream: stream.clone(),
+        };
+
+        if video_decoder.setup(setup) != 0 {
+            warn!("[Loopback]: failed to setup video");
+            return;
+        }
+        if audio_decoder.setup(AudioConfig::STEREO, loopback_audio_config(), 0) != 0 {
+            warn!("[Loopback]: failed to setup audio");
+            return;
+        }
+
+        if let Some(stream) = stream.upgrade() {
+            let mut ipc_sender = stream.ipc_sender.clone();
+            ipc_sender.blocking_send(StreamerIpcMessage::WebSocket(
+                StreamServerMessage::ConnectionComplete {
+                    capabilities: StreamCapabilities {
+                        touch: false,
+                        file_transfer: stream.file_transfers.blocking_lock().is_enabled(),
+                    },
+                    format: setup.format as u32,
+                    width: setup.width,
+                    height: setup.height,
+                    fps: setup.redraw_rate,
+                },
+            ));
+        }
+
+        info!(
+            "[Loopback]: generating {}x{}x{} synthetic frames",
+            setup.width, setup.height, setup.redraw_rate
+        );
+        if let Some(benchmark) = &benchmark {
+            info!(
+                "[Benchmark]: padding the frames to {} kbps",
+                benchmark.target_kbps()
+            );
+        }
+
+        let frame_interval = Duration::from_secs_f64(1.0 / setup.redraw_rate.max(1) as f64);
+        let start = Instant::now();
+        let mut next_video = start;
+        let mut next_audio = start;
+
+        let mut source = match &benchmark {
+            Some(benchmark) => LoopbackVideoSource::with_frame_size(benchmark_frame_size(
+                benchmark.target_kbps(),
+                setup.redraw_rate,
+            )),
+            None => LoopbackVideoSource::default(),
+        };
+        loop {
+            match stream.upgrade() {
+                Some(stream) if !stream.is_terminating.load(Ordering::Acquire) => {}
+                _ => break,
+            }
+
+            let now = Instant::now();
+            if now >= next_video {
+                let frame = source.next_frame();
+                let buffers = frame
+                    .buffers
+                    .iter()
+                    .map(|(ty, data)| VideoDataBuffer { ty: *ty, data })
+                    .collect::<Vec<_>>();
+
+                let elapsed = now - start;
+                let enqueue_start = Instant::now();
+                let result = video_decoder.submit_decode_unit(VideoDecodeUnit {
+                    frame_number: frame.frame_number,
+                    frame_type: frame.frame_type,
+                    frame_processing_latency: None,
+                    receive_time: elapsed,
+                    enqueue_time: elapsed,
+                    presentation_time: elapsed,
+                    hdr_active: false,
+                    color_space: Colorspace::Rec709,
+                    buffers: &buffers,
+                });
+                if matches!(result, DecodeResult::NeedIdr) {
+                    source.request_idr();
+                }
+
+                if let Some(benchmark) = &mut benchmark {
+                    let frame_size = frame.buffers.iter().map(|(_, data)| data.len()).sum();
+                    benchmark.record_frame(frame_size, enqueue_start.elapsed());
+                }
+
+                next_video += frame_interval;
+            }
+            if now >= next_audio {
+                audio_decoder.decode_and_play_sample(OPUS_SILENCE_FRAME);
+
+                next_audio += LOOPBACK_AUDIO_FRAME_DURATION;
+            }
+            if let Some(benchmark) = &mut benchmark
+                && benchmark.is_report_due(now)
+            {
+                report_benchmark(&stream, benchmark, now);
+            }
+
+            let next = next_video.min(next_audio);
+            thread::sleep(next.saturating_duration_since(Instant::now()));
+        }
+
+        debug!("[Loopback]: stopped generating frames");
+    });
+}
+
+fn report_benchmark(stream: &Weak<StreamConnection>, benchmark: &mut BenchmarkStats, now: Instant) {
+    let Some(stream) = stream.upgrade() else {
+        return;
+    };
+
+    stream.runtime.clone().block_on(async {
+        let transport = stream.transport_sender.lock().await;
+
+        let queue_stats = transport.video_queue_stats().await;
+        let report = benchmark.report(now, queue_stats.frames_dropped);
+        info!("[Benchmark]: {report:?}");
+
+        match transport.send(OutboundPacket::Stats(report)).await {
+            Ok(_) => {}
+            Err(TransportError::ChannelClosed) => {
+                // ignore
+            }
+            Err(err) => {
+                warn!("Failed to send benchmark stats: {err:?}");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::ready, time::Duration};
+
+    use common::{
+        StreamSettings,
+        api_bindings::{
+            RtcIceCandidate, RtcSdpType, RtcSessionDescription, StreamClientMessage,
+            StreamServerMessage, StreamSignalingMessage,
+        },
+        config::WebRtcConfig,
+        ipc::{
+            IpcSender, ServerIpcMessage, StreamerConfig, StreamerIpcMessage, create_process_ipc,
+        },
+    };
+    use log::LevelFilter;
+    use moonlight_common::{
+        high::MoonlightHost,
+        stream::{
+            MoonlightInstance,
+            bindings::{BufferType, Colorspace, FrameType, SupportedVideoFormats},
+        },
+    };
+    use tokio::{
+        io::{duplex, split},
+        join, select, spawn,
+        sync::{Mutex, mpsc::channel},
+        time::timeout,
+    };
+    use webrtc::{
+        api::{APIBuilder, media_engine::MediaEngine, setting_engine::SettingEngine},
+        ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit},
+        peer_connection::{
+            RTCPeerConnection,
+            configuration::RTCConfiguration,
+            sdp::{sdp_type::RTCSdpType, session_description::RTCSessionDescription},
+        },
+        rtp_transceiver::rtp_codec::RTPCodecType,
+    };
+
+    use crate::{
+        StreamConnection, StreamInfo,
+        convert::from_webrtc_sdp,
+        loopback::{
+            H264_START_CODE, LOOPBACK_IDR_INTERVAL, LOOPBACK_MIN_SLICE_SIZE, LoopbackVideoSource,
+            loopback_audio_config,
+        },
+    };
+
+    fn nal_types(buffers: &[(BufferType, Vec<u8>)]) -> Vec<u8> {
+        buffers
+            .iter()
+            .map(|(_, data)| {
+                assert!(data.starts_with(H264_START_CODE));
+                assert!(
+                    !data[H264_START_CODE.len()..]
+                        .windows(3)
+                        .any(|w| w == [0, 0, 1])
+                );
+
+                data[H264_START_CODE.len()] & 0x1F
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_first_frame_is_idr() {
+        let mut source = LoopbackVideoSource::default();
+
+        let frame = source.next_frame();
+        assert!(matches!(frame.frame_type, FrameType::Idr));
+        // SPS, PPS, IDR slice
+        assert_eq!(nal_types(&frame.buffers), vec![7, 8, 5]);
+
+        let frame = source.next_frame();
+        assert!(matches!(frame.frame_type, FrameType::PFrame));
+        // Non IDR slice
+        assert_eq!(nal_types(&frame.buffers), vec![1]);
+    }
+
+    #[test]
+    fn test_idr_interval_and_request() {
+        let mut source = LoopbackVideoSource::default();
+
+        for _ in 0..LOOPBACK_IDR_INTERVAL {
+            source.next_frame();
+        }
+        assert!(matches!(source.next_frame().frame_type, FrameType::Idr));
+
+        source.request_idr();
+        assert!(matches!(source.next_frame().frame_type, FrameType::Idr));
+        assert!(matches!(source.next_frame().frame_type, FrameType::PFrame));
+    }
+
+    #[test]
+    fn test_padded_frame_size() {
+        let mut source = LoopbackVideoSource::with_frame_size(10_000);
+
+        let frame_size = |buffers: &[(BufferType, Vec<u8>)]| {
+            buffers.iter().map(|(_, data)| data.len()).sum::<usize>()
+        };
+
+        // The SPS and PPS count into the frame size
+        let frame = source.next_frame();
+        assert_eq!(frame_size(&frame.buffers), 10_000);
+        assert_eq!(nal_types(&frame.buffers), vec![7, 8, 5]);
+
+        let frame = source.next_frame();
+        assert_eq!(frame_size(&frame.buffers), 10_000);
+
+        // A frame isn't smaller than the unpadded frame
+        let mut source = LoopbackVideoSource::with_frame_size(1);
+        let frame = source.next_frame();
+        assert_eq!(
+            frame.buffers.last().unwrap().1.len(),
+            H264_START_CODE.len() + 1 + LOOPBACK_MIN_SLICE_SIZE
+        );
+    }
+
+    #[test]
+    fn test_audio_config() {
+        let config = loopback_audio_config();
+
+        assert_eq!(config.sample_rate, 48000);
+        assert_eq!(config.channel_count, 2);
+        assert_eq!(&config.mapping[0..2], &[0, 1]);
+    }
+
+    fn loopback_stream_settings() -> StreamSettings {
+        StreamSettings {
+            bitrate: 5000,
+            packet_size: 1024,
+            fps: 60,
+            width: 1280,
+            height: 720,
+            video_frame_queue_size: 3,
+            video_frame_queue_max_bytes: 0,
+            video_frame_pacing: false,
+            audio_sample_queue_size: 20,
+            audio_jitter_buffer_ms: 0,
+            play_audio_local: false,
+            video_supported_formats: SupportedVideoFormats::H264,
+            video_colorspace: Colorspace::Rec709,
+            video_color_range_full: false,
+            hybrid_mode: false,
+            allow_spectators: false,
+            force_relay: false,
+            hdr_enabled: false,
+            video_codec_preference: Vec::new(),
+        }
+    }
+
+    /// The peer of the browser, it only needs the loopback candidates to reach the streamer
+    async fn new_client_peer() -> RTCPeerConnection {
+        let mut media = MediaEngine::default();
+        media.register_default_codecs().unwrap();
+
+        let mut settings = SettingEngine::default();
+        settings.set_include_loopback_candidate(true);
+
+        APIBuilder::new()
+            .with_media_engine(media)
+            .with_setting_engine(settings)
+            .build()
+            .new_peer_connection(RTCConfiguration::default())
+            .await
+            .unwrap()
+    }
+
+    fn description_message(description: RTCSessionDescription) -> ServerIpcMessage {
+        ServerIpcMessage::WebSocket(StreamClientMessage::WebRtc(
+            StreamSignalingMessage::Description(RtcSessionDescription {
+                ty: from_webrtc_sdp(description.sdp_type),
+                sdp: description.sdp,
+            }),
+        ))
+    }
+
+    /// Answers the offers of the streamer and adds its candidates like the browser does
+    async fn on_streamer_signaling(
+        client: &RTCPeerConnection,
+        server_sender: &mut IpcSender<ServerIpcMessage>,
+        pending_candidates: &mut Vec<RTCIceCandidateInit>,
+        signaling: StreamSignalingMessage,
+    ) {
+        match signaling {
+            StreamSignalingMessage::Description(description) => {
+                let description = match description.ty {
+                    RtcSdpType::Offer => RTCSessionDescription::offer(description.sdp),
+                    _ => RTCSessionDescription::answer(description.sdp),
+                }
+                .unwrap();
+                let remote_ty = description.sdp_type;
+
+                client.set_remote_description(description).await.unwrap();
+                for candidate in pending_candidates.drain(..) {
+                    client.add_ice_candidate(candidate).await.unwrap();
+                }
+
+                // The streamer renegotiates when it adds the video and audio track
+                if remote_ty == RTCSdpType::Offer {
+                    let answer = client.create_answer(None).await.unwrap();
+                    client.set_local_description(answer.clone()).await.unwrap();
+                    server_sender.send(description_message(answer)).await;
+                }
+            }
+            StreamSignalingMessage::AddIceCandidate(candidate) => {
+                let candidate = RTCIceCandidateInit {
+                    candidate: candidate.candidate,
+                    sdp_mid: candidate.sdp_mid,
+                    sdp_mline_index: candidate.sdp_mline_index,
+                    username_fragment: candidate.username_fragment,
+                };
+
+                // Candidates can arrive before the answer
+                if client.remote_description().await.is_some() {
+                    client.add_ice_candidate(candidate).await.unwrap();
+                } else {
+                    pending_candidates.push(candidate);
+                }
+            }
+        }
+    }
+
+    /// Runs the streamer in loopback mode against a WebRTC peer which signals like the browser,
+    /// the ipc of the web server is an in memory pipe
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_loopback_session_receives_frames() {
+        let (streamer_pipe, server_pipe) = duplex(64 * 1024);
+        let (streamer_read, streamer_write) = split(streamer_pipe);
+        let (server_read, server_write) = split(server_pipe);
+        let (streamer_ipc, server_ipc) = join!(
+            create_process_ipc::<ServerIpcMessage, StreamerIpcMessage>(
+                streamer_read,
+                streamer_write
+            ),
+            create_process_ipc::<StreamerIpcMessage, ServerIpcMessage>(server_read, server_write),
+        );
+        let (streamer_sender, streamer_receiver) = streamer_ipc.unwrap();
+        let (mut server_sender, mut server_receiver) = server_ipc.unwrap();
+
+        let connection = StreamConnection::new(
+            MoonlightInstance::global().unwrap(),
+            StreamInfo {
+                // Never contacted in loopback mode
+                host: Mutex::new(MoonlightHost::new("127.0.0.1".to_string(), 47989, None).unwrap()),
+                app_id: 0,
+                loopback: true,
+                benchmark: false,
+                recorder: None,
+                launch_options: Default::default(),
+                launch_mode: None,
+            },
+            loopback_stream_settings(),
+            streamer_sender,
+            streamer_receiver,
+            StreamerConfig {
+                webrtc: WebRtcConfig {
+                    ice_servers: Vec::new(),
+                    include_loopback_candidates: true,
+                    ..Default::default()
+                },
+                log_level: LevelFilter::Off,
+                pidfile_dir: String::new(),
+                idle_timeout_secs: None,
+                file_transfer: Default::default(),
+                recording: Default::default(),
+                ipc_timeout_secs: None,
+            },
+            None,
+        )
+        .await
+        .unwrap();
+
+        let client = new_client_peer().await;
+        client.create_data_channel("stats", None).await.unwrap();
+
+        client.on_ice_candidate(Box::new({
+            let server_sender = server_sender.clone();
+
+            move |candidate: Option<RTCIceCandidate>| {
+                let mut server_sender = server_sender.clone();
+
+                Box::pin(async move {
+                    let Some(Ok(candidate)) = candidate.map(|candidate| candidate.to_json()) else {
+                        return;
+                    };
+
+                    server_sender
+                        .send(ServerIpcMessage::WebSocket(StreamClientMessage::WebRtc(
+                            StreamSignalingMessage::AddIceCandidate(RtcIceCandidate {
+                                candidate: candidate.candidate,
+                                sdp_mid: candidate.sdp_mid,
+                                sdp_mline_index: candidate.sdp_mline_index,
+                                username_fragment: candidate.username_fragment,
+                            }),
+                        )))
+                        .await;
+                })
+            }
+        }));
+
+        // Reports the kind of every track once it received its first packet
+        let (track_sender, mut track_receiver) = channel(2);
+        client.on_track(Box::new(move |track, _, _| {
+            let track_sender = track_sender.clone();
+            spawn(async move {
+                if track.read_rtp().await.is_ok() {
+                    let _ = track_sender.send(track.kind()).await;
+                }
+            });
+
+            Box::pin(ready(()))
+        }));
+
+        let offer = client.create_offer(None).await.unwrap();
+        client.set_local_description(offer.clone()).await.unwrap();
+        server_sender.send(description_message(offer)).await;
+
+        let mut pending_candidates = Vec::new();
+        let mut connection_complete = None;
+        let mut video_received = false;
+        let result = timeout(Duration::from_secs(30), async {
+            while connection_complete.is_none() || !video_received {
+                select! {
+                    message = server_receiver.recv() => {
+                        let Some(StreamerIpcMessage::WebSocket(message)) = message else {
+                            assert!(message.is_some(), "the streamer closed the ipc");
+                            continue;
+                        };
+
+                        match message {
+                            StreamServerMessage::WebRtc(signaling) => {
+                                on_streamer_signaling(
+                                    &client,
+                                    &mut server_sender,
+                                    &mut pending_candidates,
+                                    signaling,
+                                )
+                                .await;
+                            }
+                            StreamServerMessage::ConnectionComplete {
+                                width, height, fps, ..
+                            } => {
+                                connection_complete = Some((width, height, fps));
+                            }
+                            _ => {}
+                        }
+                    }
+                    Some(kind) = track_receiver.recv() => {
+                        video_received |= kind == RTPCodecType::Video;
+                    }
+                }
+            }
+        })
+        .await;
+
+        assert!(
+            result.is_ok(),
+            "the loopback stream didn't complete or no video frame arrived"
+        );
+        assert_eq!(connection_complete, Some((1280, 720, 60)));
+
+        connection.stop().await;
+        client.close().await.unwrap();
+    }
+}
@@ -2,27 +2,30 @@
 #![feature(async_fn_traits)]
 
 use std::{
-    panic,
-    process::exit,
+    fs, panic,
+    path::Path,
+    process::{self, exit},
     sync::{
         Arc, Weak,
         atomic::{AtomicBool, Ordering},
     },
+    time::{Duration, Instant},
 };
 
 use common::{
     StreamSettings,
-    api_bindings::GeneralServerMessage,
+    api_bindings::{GeneralServerMessage, LaunchOptions},
     ipc::{
         IpcReceiver, IpcSender, ServerIpcMessage, StreamerConfig, StreamerIpcMessage,
         create_process_ipc,
     },
+    pidfile::StreamerPidFile,
 };
 use log::{LevelFilter, debug, error, info, warn};
 use moonlight_common::{
     MoonlightError,
     high::{HostError, MoonlightHost},
-    network::backend::reqwest::ReqwestClient,
+    network::{backend::reqwest::ReqwestClient, launch::LaunchMode},
     pair::ClientAuth,
     stream::{
         MoonlightInstance, MoonlightStream,
@@ -42,22 +45,33 @@ use tokio::{
     runtime::Handle,
     spawn,
     sync::{Mutex, Notify, RwLock},
+    time::{self, sleep_until, timeout},
 };
 
 use common::api_bindings::{StreamCapabilities, StreamServerMessage};
 
 use crate::{
+    benchmark::{BenchmarkStats, is_benchmark_requested},
+    file_transfer::{FileTransferRequest, FileTransferStatus, FileTransfers},
+    loopback::{loopback_video_setup, spawn_loopback_source},
+    recording::StreamRecorder,
+    stats_timeline::StatsTimeline,
     transport::{
-        InboundPacket, OutboundPacket, TransportError, TransportEvent, TransportEvents,
-        TransportSender, webrtc,
+        ClipboardAssembler, InboundPacket, OutboundPacket, TransportError, TransportEvent,
+        TransportEvents, TransportSender, webrtc,
     },
     video::StreamVideoDecoder,
 };
 
 pub type RequestClient = ReqwestClient;
 
+mod benchmark;
 mod buffer;
 mod convert;
+mod file_transfer;
+mod loopback;
+mod recording;
+mod stats_timeline;
 mod transport;
 mod video;
 
@@ -71,7 +85,14 @@ async fn main() {
 
     // At this point we're authenticated
     let (mut ipc_sender, mut ipc_receiver) =
-        create_process_ipc::<ServerIpcMessage, StreamerIpcMessage>(stdin(), stdout()).await;
+        match create_process_ipc::<ServerIpcMessage, StreamerIpcMessage>(stdin(), stdout()).await {
+            Ok(ipc) => ipc,
+            Err(err) => {
+                // The logger is configured by the Init message, stderr is logged by the web server
+                eprintln!("Failed to set up the ipc with the web server: {err}");
+                return;
+            }
+        };
 
     // Send stage
     ipc_sender
@@ -93,6 +114,10 @@ async fn main() {
         server_certificate,
         app_id,
         session_token,
+        loopback,
+        session_id,
+        launch_options,
+        launch_mode,
     ) = loop {
         match ipc_receiver.recv().await {
             Some(ServerIpcMessage::Init {
@@ -106,6 +131,10 @@ async fn main() {
                 server_certificate,
                 app_id,
                 session_token,
+                loopback,
+                session_id,
+                launch_options,
+                launch_mode,
             }) => {
                 debug!(
                     "Client supported codecs: {:?}",
@@ -126,6 +155,10 @@ async fn main() {
                     server_certificate,
                     app_id,
                     session_token,
+                    loopback,
+                    session_id,
+                    launch_options.unwrap_or_default(),
+                    launch_mode,
                 );
             }
             _ => continue,
@@ -143,6 +176,19 @@ async fn main() {
     )
     .expect("failed to init logger");
 
+    let pidfile_path = match (StreamerPidFile {
+        pid: process::id(),
+        session_id: session_id.clone(),
+    })
+    .write(Path::new(&config.pidfile_dir))
+    {
+        Ok(path) => Some(path),
+        Err(err) => {
+            warn!("Failed to write pidfile: {err:?}");
+            None
+        }
+    };
+
     // Send stage
     ipc_sender
         .send(StreamerIpcMessage::WebSocket(
@@ -152,6 +198,16 @@ async fn main() {
         ))
         .await;
 
+    let benchmark = is_benchmark_requested();
+    if benchmark {
+        info!("Running the benchmark, no host will be contacted");
+    }
+
+    // Synthetic frames aren't worth recording
+    let recorder = (!loopback && !benchmark)
+        .then(|| StreamRecorder::new(&config.recording, &session_id))
+        .flatten();
+
     // -- Create the host and pair it
     let mut host = MoonlightHost::new(host_address, host_http_port, client_unique_id)
         .expect("failed to create host");
@@ -174,6 +230,12 @@ async fn main() {
         StreamInfo {
             host: Mutex::new(host),
             app_id,
+            // The benchmark streams the padded loopback frames
+            loopback: loopback || benchmark,
+            benchmark,
+            recorder,
+            launch_options,
+            launch_mode,
         },
         stream_settings,
         ipc_sender.clone(),
@@ -205,13 +267,88 @@ async fn main() {
     // Wait for termination
     connection.terminate.notified().await;
 
+    if let Some(pidfile_path) = pidfile_path
+        && let Err(err) = fs::remove_file(pidfile_path)
+    {
+        warn!("Failed to remove pidfile: {err:?}");
+    }
+
     // Exit streamer
     exit(0);
 }
 
+/// Tells the web server once the stream didn't receive input for the idle timeout
+async fn watch_idle(stream: Weak<StreamConnection>, idle_timeout: Duration) {
+    let Some((this, idle)) = wait_idle(&stream, |this| &this.last_input, idle_timeout).await else {
+        return;
+    };
+
+    info!(
+        "[Stream]: no input for {}s, telling the web server",
+        idle_timeout.as_secs()
+    );
+
+    let mut ipc_sender = this.ipc_sender.clone();
+    ipc_sender
+        .send(StreamerIpcMessage::Idle {
+            idle_secs: idle.as_secs(),
+        })
+        .await;
+}
+
+/// Waits until the last input is older than the idle timeout and returns how long it's been idle.
+/// Returns None once the stream is dropped.
+async fn wait_idle<T>(
+    stream: &Weak<T>,
+    last_input: impl Fn(&T) -> &Mutex<time::Instant>,
+    idle_timeout: Duration,
+) -> Option<(Arc<T>, Duration)> {
+    loop {
+        let this = stream.upgrade()?;
+
+        let last_input = *last_input(&this).lock().await;
+        let idle_until = last_input + idle_timeout;
+        if time::Instant::now() >= idle_until {
+            return Some((this, last_input.elapsed()));
+        }
+
+        drop(this);
+        sleep_until(idle_until).await;
+    }
+}
+
+/// Receives the next ipc message, None once the ipc is closed or nothing arrived within the timeout
+async fn recv_ipc<Message>(
+    recv: impl Future<Output = Option<Message>>,
+    ipc_timeout: Option<Duration>,
+) -> Option<Message> {
+    let Some(ipc_timeout) = ipc_timeout else {
+        return recv.await;
+    };
+
+    match timeout(ipc_timeout, recv).await {
+        Ok(message) => message,
+        Err(_) => {
+            warn!(
+                "[Ipc]: no message from the web server for {}s, stopping",
+                ipc_timeout.as_secs()
+            );
+            None
+        }
+    }
+}
+
 struct StreamInfo {
     host: Mutex<MoonlightHost<RequestClient>>,
     app_id: u32,
+    /// Generate synthetic frames instead of streaming from the host
+    loopback: bool,
+    /// Pad the synthetic frames to the bitrate and report the throughput
+    benchmark: bool,
+    /// Writes the encoded stream to disk, None if recording is disabled
+    recorder: Option<StreamRecorder>,
+    launch_options: LaunchOptions,
+    launch_mode: Option<LaunchMode>,
 }
 
 struct StreamConnection {
@@ -228,6 +365,11 @@ struct StreamConnection {
     pub transport_sender: Mutex<Box<dyn TransportSender + Send + Sync>>,
     pub terminate: Notify,
     is_terminating: AtomicBool,
+    // Spectators can't send input, so only the primary and the input client keep the stream active
+    last_input: Mutex<time::Instant>,
+    clipboard: Mutex<ClipboardAssembler>,
+    file_transfers: Mutex<FileTransfers>,
+    stats_timeline: Mutex<StatsTimeline>,
 }
 
 impl StreamConnection {
@@ -241,6 +383,8 @@ impl StreamConnection {
         session_token: Option<String>,
     ) -> Result<Arc<Self>, anyhow::Error> {
         let (sender, mut events) = webrtc::new(settings.clone(), &config.webrtc, session_token).await?;
+        let idle_timeout = config.idle_timeout();
+        let ipc_timeout = config.ipc_timeout();
 
         let this = Arc::new(Self {
             runtime: Handle::current(),
@@ -254,8 +398,16 @@ impl StreamConnection {
             transport_sender: Mutex::new(Box::new(sender)),
             terminate: Notify::default(),
             is_terminating: AtomicBool::new(false),
+            last_input: Mutex::new(time::Instant::now()),
+            clipboard: Mutex::new(ClipboardAssembler::default()),
+            file_transfers: Mutex::new(FileTransfers::new(config.file_transfer)),
+            stats_timeline: Mutex::new(StatsTimeline::new(Instant::now())),
         });
 
+        if let Some(idle_timeout) = idle_timeout {
+            spawn(watch_idle(Arc::downgrade(&this), idle_timeout));
+        }
+
         spawn({
             let mut ipc_sender = this.ipc_sender.clone();
             let this = Arc::downgrade(&this);
@@ -297,6 +449,16 @@ impl StreamConnection {
 
                             this.on_packet(packet).await;
                         }
+                        Ok(TransportEvent::RequestKeyframe) => {
+                            let Some(this) = this.upgrade() else {
+                                warn!(
+                                    "Failed to get stream connection, stopping listening to events"
+                                );
+                                return;
+                            };
+
+                            this.request_keyframe().await;
+                        }
                         Err(TransportError::Closed) | Ok(TransportEvent::Closed) => {
                             let Some(this) = this.upgrade() else {
                                 warn!(
@@ -334,14 +496,23 @@ impl StreamConnection {
             let this = Arc::downgrade(&this);
 
             async move {
-                while let Some(message) = ipc_receiver.recv().await {
+                loop {
+                    let message = recv_ipc(ipc_receiver.recv(), ipc_timeout).await;
+
                     let Some(this) = this.upgrade() else {
                         debug!("Received ipc message while the main type is already deallocated");
                         return;
                     };
 
+                    let Some(message) = message else {
+                        // The web server is gone or stuck, nobody would stop the stream otherwise
+                        this.stop().await;
+                        return;
+                    };
+
                     if let ServerIpcMessage::Stop = &message {
-                        this.on_ipc_message(ServerIpcMessage::Stop).await;
+                        // Sends the stats timeline and Stop back to the web server
+                        this.stop().await;
                         return;
                     }
 
@@ -353,7 +524,41 @@ impl StreamConnection {
         Ok(this)
     }
 
+    async fn request_keyframe(&self) {
+        if self.info.loopback {
+            debug!("[Loopback]: ignoring keyframe request");
+            return;
+        }
+
+        let stream = self.stream.read().await;
+        let Some(stream) = stream.as_ref() else {
+            debug!("[Stream]: ignoring keyframe request because the stream hasn't started yet");
+            return;
+        };
+
+        debug!("[Stream]: requesting keyframe from host");
+        stream.request_idr_frame();
+    }
+
     async fn on_packet(&self, packet: InboundPacket) {
+        if !matches!(packet, InboundPacket::General { .. }) {
+            *self.last_input.lock().await = time::Instant::now();
+        }
+
+        // Files are written by the streamer, so this doesn't need the stream
+        let packet = match packet {
+            InboundPacket::FileTransfer(request) => {
+                self.on_file_transfer(request).await;
+                return;
+            }
+            packet => packet,
+        };
+
+        if self.info.loopback {
+            debug!("[Loopback]: received input {packet:?}");
+            return;
+        }
+
         let stream = self.stream.read().await;
         let Some(stream) = stream.as_ref() else {
             warn!("Failed to send packet {packet:?} because of missing stream");
@@ -362,8 +567,8 @@ impl StreamConnection {
 
         let err = match packet {
             InboundPacket::General { message } => {
-                // TODO
-                todo!();
+                debug!("[Stream]: ignoring general message {message:?}");
+                return;
             }
             InboundPacket::MousePosition {
                 x,
@@ -408,6 +613,15 @@ impl StreamConnection {
                 .send_keyboard_event_non_standard(key as i16, action, modifiers, flags)
                 .err(),
             InboundPacket::Text { text } => stream.send_text(&text).err(),
+            InboundPacket::ClipboardChunk { last, data } => {
+                let Some(text) = self.clipboard.lock().await.push(last, &data) else {
+                    return;
+                };
+
+                // GameStream has no clipboard, so the text is typed on the host
+                stream.send_text(&text).err()
+            }
+            InboundPacket::FileTransfer(_) => return,
             InboundPacket::Touch {
                 pointer_id,
                 x,
@@ -522,6 +736,54 @@ impl StreamConnection {
         }
     }
 
+    async fn on_file_transfer(&self, request: FileTransferRequest) {
+        let id = request.id();
+        let status = |status| OutboundPacket::FileTransferStatus { id, status };
+
+        let mut file_transfers = self.file_transfers.lock().await;
+        let result = match request {
+            FileTransferRequest::Start {
+                id,
+                size,
+                directory,
+                file_name,
+            } => file_transfers
+                .start(id, size, &directory, file_name)
+                .await
+                .map(|_| Some(status(FileTransferStatus::Accepted))),
+            // Chunks aren't answered, only the progress is reported every now and then
+            FileTransferRequest::Chunk { id, data } => file_transfers
+                .chunk(id, &data)
+                .await
+                .map(|progress| progress.map(OutboundPacket::Stats)),
+            FileTransferRequest::End { id, checksum } => {
+                file_transfers.finish(id, checksum).await.map(|path| {
+                    info!("[FileTransfer]: received {}", path.display());
+                    Some(status(FileTransferStatus::Completed))
+                })
+            }
+            FileTransferRequest::Cancel { id } => {
+                file_transfers.cancel(id);
+                Ok(None)
+            }
+        };
+        drop(file_transfers);
+
+        let packet = match result {
+            Ok(Some(packet)) => packet,
+            Ok(None) => return,
+            Err(err) => {
+                warn!("[FileTransfer]: transfer {id} failed: {err}");
+                status(FileTransferStatus::Failed(err.to_string()))
+            }
+        };
+
+        let sender = self.transport_sender.lock().await;
+        if let Err(err) = sender.send(packet).await {
+            warn!("[FileTransfer]: failed to answer transfer {id}: {err:?}");
+        }
+    }
+
     async fn on_ipc_message(self: &Arc<Self>, message: ServerIpcMessage) {
         let this = self.clone();
 
@@ -544,6 +806,20 @@ impl StreamConnection {
             ))
             .await;
 
+        if self.info.loopback {
+            info!("[Stream]: starting loopback stream, no host will be contacted");
+
+            spawn_loopback_source(
+                Arc::downgrade(self),
+                loopback_video_setup(self.settings.width, self.settings.height, self.settings.fps),
+                self.info
+                    .benchmark
+                    .then(|| BenchmarkStats::new(self.settings.bitrate)),
+            );
+
+            return Ok(());
+        }
+
         let mut host = self.info.host.lock().await;
 
         let video_decoder = StreamVideoDecoder {
@@ -560,6 +836,7 @@ impl StreamConnection {
             stream: Arc::downgrade(self),
         };
 
+        let launch_options = &self.info.launch_options;
         let stream = match host
             .start_stream(
                 &self.moonlight,
@@ -567,11 +844,15 @@ impl StreamConnection {
                 self.settings.width,
                 self.settings.height,
                 self.settings.fps,
-                false,
-                true,
+                self.settings.hdr_enabled,
+                launch_options.sops.unwrap_or(true),
                 self.settings.play_audio_local,
                 ActiveGamepads::empty(),
-                false,
+                launch_options
+                    .gamepads_persist_after_disconnect
+                    .unwrap_or(false),
+                launch_options.display_mode.map(Into::into),
+                self.info.launch_mode,
                 self.settings.video_colorspace,
                 if self.settings.video_color_range_full {
                     ColorRange::Full
@@ -614,6 +895,7 @@ impl StreamConnection {
 
         let capabilities = StreamCapabilities {
             touch: host_features.contains(HostFeatures::PEN_TOUCH_EVENTS),
+            file_transfer: self.file_transfers.lock().await.is_enabled(),
         };
 
         let video_setup = {
@@ -668,7 +950,19 @@ impl StreamConnection {
         }
         drop(transport);
 
+        if let Some(recorder) = &self.info.recorder {
+            recorder.finish().await;
+        }
+
         let mut ipc_sender = self.ipc_sender.clone();
+
+        let samples = self.stats_timeline.lock().await.take_samples();
+        if !samples.is_empty() {
+            ipc_sender
+                .send(StreamerIpcMessage::StatsTimeline { samples })
+                .await;
+        }
+
         ipc_sender.send(StreamerIpcMessage::Stop).await;
 
         info!("Terminating Self");
@@ -869,6 +1163,10 @@ impl AudioDecoder for StreamAudioDecoder {
             return -1;
         };
 
+        if let Some(recorder) = &stream.info.recorder {
+            recorder.setup_audio(&stream_config);
+        }
+
         stream.runtime.clone().block_on(async move {
             let sender = stream.transport_sender.lock().await;
 
@@ -885,6 +1183,10 @@ impl AudioDecoder for StreamAudioDecoder {
             return;
         };
 
+        if let Some(recorder) = &stream.info.recorder {
+            recorder.record_audio(data);
+        }
+
         stream.runtime.clone().block_on(async move {
             let stream = stream.transport_sender.lock().await;
             if let Err(err) = stream.send_audio_sample(data).await {
@@ -901,3 +1203,115 @@ impl AudioDecoder for StreamAudioDecoder {
         Capabilities::empty()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::{
+        spawn,
+        sync::{Mutex, mpsc::channel},
+        time::{self, sleep},
+    };
+
+    use crate::{recv_ipc, wait_idle};
+
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+    const IPC_TIMEOUT: Duration = Duration::from_secs(30);
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wait_idle_without_input() {
+        let last_input = Arc::new(Mutex::new(time::Instant::now()));
+
+        let weak = Arc::downgrade(&last_input);
+        let task = spawn(async move {
+            wait_idle(&weak, |last_input| last_input, IDLE_TIMEOUT)
+                .await
+                .map(|(_, idle)| idle)
+        });
+
+        sleep(Duration::from_secs(29)).await;
+        assert!(!task.is_finished());
+
+        assert_eq!(task.await.unwrap(), Some(IDLE_TIMEOUT));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wait_idle_input_resets_timeout() {
+        let start = time::Instant::now();
+        let last_input = Arc::new(Mutex::new(start));
+
+        let weak = Arc::downgrade(&last_input);
+        let task = spawn(async move {
+            wait_idle(&weak, |last_input| last_input, IDLE_TIMEOUT)
+                .await
+                .map(|(_, idle)| idle)
+        });
+
+        sleep(Duration::from_secs(20)).await;
+        *last_input.lock().await = time::Instant::now();
+
+        // The first timeout passed, but the input moved it
+        sleep(Duration::from_secs(20)).await;
+        assert!(!task.is_finished());
+
+        assert_eq!(task.await.unwrap(), Some(IDLE_TIMEOUT));
+        assert_eq!(start.elapsed(), Duration::from_secs(50));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wait_idle_stream_dropped() {
+        let last_input = Arc::new(Mutex::new(time::Instant::now()));
+
+        let weak = Arc::downgrade(&last_input);
+        let task = spawn(async move {
+            wait_idle(&weak, |last_input| last_input, IDLE_TIMEOUT)
+                .await
+                .map(|(_, idle)| idle)
+        });
+
+        sleep(Duration::from_secs(10)).await;
+        drop(last_input);
+
+        assert_eq!(task.await.unwrap(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_recv_ipc_timeout() {
+        let (sender, mut receiver) = channel::<u32>(1);
+
+        // The heartbeat keeps the ipc alive
+        sender.send(1).await.unwrap();
+        assert_eq!(recv_ipc(receiver.recv(), Some(IPC_TIMEOUT)).await, Some(1));
+
+        // The web server went silent
+        let start = time::Instant::now();
+        assert_eq!(recv_ipc(receiver.recv(), Some(IPC_TIMEOUT)).await, None);
+        assert_eq!(start.elapsed(), IPC_TIMEOUT);
+
+        drop(sender);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_recv_ipc_without_timeout() {
+        let (sender, mut receiver) = channel::<u32>(1);
+
+        let task = spawn(async move { recv_ipc(receiver.recv(), None).await });
+
+        sleep(IPC_TIMEOUT * 10).await;
+        assert!(!task.is_finished());
+
+        sender.send(1).await.unwrap();
+        assert_eq!(task.await.unwrap(), Some(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_recv_ipc_closed() {
+        let (sender, mut receiver) = channel::<u32>(1);
+        drop(sender);
+
+        let start = time::Instant::now();
+        assert_eq!(recv_ipc(receiver.recv(), Some(IPC_TIMEOUT)).await, None);
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+}
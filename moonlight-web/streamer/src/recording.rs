@@ -0,0 +1,629 @@
+//! Recording of the encoded stream to disk.
+//!
+//! The decode units and audio samples are written as they arrive from the host, nothing is
+//! re-encoded. Video is written as an elementary stream (Annex B for H264 / H265, OBUs for AV1)
+//! and the Opus audio into an Ogg file, ffmpeg can copy both into a container.
+//! The files are written on their own thread: when the disk can't keep up or fails only the
+//! recording suffers, the live stream never waits for it.
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, SyncSender, TrySendError, sync_channel},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use common::config::RecordingConfig;
+use log::{debug, info, warn};
+use moonlight_common::stream::bindings::{
+    OpusMultistreamConfig, SupportedVideoFormats, VideoFormat,
+};
+use tokio::{sync::Mutex, task::spawn_blocking};
+use webrtc::{
+    media::io::{Writer, ogg_writer::OggWriter},
+    rtp::{header::Header, packet::Packet},
+};
+
+/// Messages which the disk didn't accept yet, above this the recording drops them
+const RECORDING_QUEUE_SIZE: usize = 256;
+
+/// The recording doesn't ask the host for keyframes more often than this
+const RECORDING_KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+enum RecordingMessage {
+    SetupVideo(VideoFormat),
+    SetupAudio {
+        sample_rate: u32,
+        channel_count: u8,
+        samples_per_frame: u32,
+    },
+    Video {
+        data: Vec<u8>,
+        keyframe: bool,
+    },
+    Audio(Vec<u8>),
+    Finish,
+}
+
+pub struct StreamRecorder {
+    sender: SyncSender<RecordingMessage>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+    /// The recording needs a keyframe to start the next segment
+    needs_keyframe: Arc<AtomicBool>,
+    last_keyframe_request: std::sync::Mutex<Option<Instant>>,
+    /// Set once a video frame was dropped, the frames until the next keyframe can't be decoded
+    video_dropped: AtomicBool,
+}
+
+impl StreamRecorder {
+    /// Returns None if recording is disabled or the directory can't be created
+    pub fn new(config: &RecordingConfig, session_id: &str) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+
+        let directory = PathBuf::from(&config.record_path);
+        if let Err(err) = fs::create_dir_all(&directory) {
+            warn!(
+                "[Recording]: Failed to create the directory {directory:?}, the stream won't be recorded: {err}"
+            );
+            return None;
+        }
+
+        let needs_keyframe = Arc::new(AtomicBool::new(false));
+        let mut writer = RecordingWriter::new(
+            directory,
+            session_id.to_string(),
+            config.max_segment_size,
+            config.max_segments as usize,
+            needs_keyframe.clone(),
+        );
+
+        let (sender, receiver) = sync_channel(RECORDING_QUEUE_SIZE);
+        let thread = match thread::Builder::new()
+            .name("recording".to_string())
+            .spawn(move || writer.run(receiver))
+        {
+            Ok(value) => value,
+            Err(err) => {
+                warn!("[Recording]: Failed to start the recording thread: {err}");
+                return None;
+            }
+        };
+
+        info!(
+            "[Recording]: Recording the stream into {:?}",
+            config.record_path
+        );
+
+        Some(Self {
+            sender,
+            thread: Mutex::new(Some(thread)),
+            needs_keyframe,
+            last_keyframe_request: std::sync::Mutex::new(None),
+            video_dropped: AtomicBool::new(false),
+        })
+    }
+
+    pub fn setup_video(&self, format: VideoFormat) {
+        self.send(RecordingMessage::SetupVideo(format));
+    }
+
+    pub fn setup_audio(&self, config: &OpusMultistreamConfig) {
+        self.send(RecordingMessage::SetupAudio {
+            sample_rate: config.sample_rate,
+            channel_count: config.channel_count as u8,
+            samples_per_frame: config.samples_per_frame,
+        });
+    }
+
+    pub fn record_video(&self, data: Vec<u8>, keyframe: bool) {
+        if !keyframe && self.video_dropped.load(Ordering::Acquire) {
+            return;
+        }
+
+        match self
+            .sender
+            .try_send(RecordingMessage::Video { data, keyframe })
+        {
+            Ok(()) => self.video_dropped.store(false, Ordering::Release),
+            Err(TrySendError::Full(_)) => {
+                debug!(
+                    "[Recording]: The disk can't keep up, dropping video until the next keyframe"
+                );
+                self.video_dropped.store(true, Ordering::Release);
+                // The host only sends keyframes on request, so the recording would stop otherwise.
+                // A slow disk doesn't ask for keyframes all the time, see take_keyframe_request.
+                self.needs_keyframe.store(true, Ordering::Release);
+            }
+            // The recording failed or finished
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+
+    pub fn record_audio(&self, data: &[u8]) {
+        self.send(RecordingMessage::Audio(data.to_vec()));
+    }
+
+    /// The video decoder asks the host for a keyframe if this returns true.
+    /// The host only sends keyframes on request, so a new segment would never start otherwise.
+    /// A request within [RECORDING_KEYFRAME_REQUEST_INTERVAL] of the last one is held back.
+    pub fn take_keyframe_request(&self, now: Instant) -> bool {
+        if !self.needs_keyframe.load(Ordering::Acquire) {
+            return false;
+        }
+
+        let Ok(mut last_request) = self.last_keyframe_request.lock() else {
+            return false;
+        };
+        if last_request.is_some_and(|last| now < last + RECORDING_KEYFRAME_REQUEST_INTERVAL) {
+            return false;
+        }
+
+        *last_request = Some(now);
+        self.needs_keyframe.store(false, Ordering::Release);
+        true
+    }
+
+    /// Writes the rest of the queue and closes the files
+    pub async fn finish(&self) {
+        let Some(thread) = self.thread.lock().await.take() else {
+            return;
+        };
+
+        let sender = self.sender.clone();
+        let result = spawn_blocking(move || {
+            // The thread already stopped if this fails
+            let _ = sender.send(RecordingMessage::Finish);
+            thread.join()
+        })
+        .await;
+
+        if !matches!(result, Ok(Ok(()))) {
+            warn!("[Recording]: The recording thread didn't stop cleanly");
+        }
+    }
+
+    fn send(&self, message: RecordingMessage) {
+        match self.sender.try_send(message) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                debug!("[Recording]: The disk can't keep up, dropping a message");
+            }
+            // The recording failed or finished
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct AudioSetup {
+    sample_rate: u32,
+    channel_count: u8,
+    samples_per_frame: u32,
+}
+
+struct Segment {
+    files: Vec<PathBuf>,
+    video: BufWriter<File>,
+    video_size: u64,
+    /// Only asks once for the keyframe which starts the next segment
+    keyframe_requested: bool,
+    audio: Option<OggWriter<BufWriter<File>>>,
+    audio_timestamp: u32,
+}
+
+struct RecordingWriter {
+    directory: PathBuf,
+    session_id: String,
+    max_segment_size: u64,
+    /// 0 = keep all
+    max_segments: usize,
+    needs_keyframe: Arc<AtomicBool>,
+    video_format: Option<VideoFormat>,
+    audio_setup: Option<AudioSetup>,
+    segment: Option<Segment>,
+    next_segment_index: u32,
+    /// Files of the closed segments, oldest first
+    closed_segments: VecDeque<Vec<PathBuf>>,
+}
+
+impl RecordingWriter {
+    fn new(
+        directory: PathBuf,
+        session_id: String,
+        max_segment_size: u64,
+        max_segments: usize,
+        needs_keyframe: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            directory,
+            session_id,
+            max_segment_size,
+            max_segments,
+            needs_keyframe,
+            video_format: None,
+            audio_setup: None,
+            segment: None,
+            next_segment_index: 0,
+            closed_segments: VecDeque::new(),
+        }
+    }
+
+    fn run(&mut self, receiver: Receiver<RecordingMessage>) {
+        while let Ok(message) = receiver.recv() {
+            if let RecordingMessage::Finish = message {
+                break;
+            }
+
+            if let Err(err) = self.handle(message) {
+                warn!("[Recording]: Failed to write the recording, stopping it: {err}");
+                break;
+            }
+        }
+
+        if let Err(err) = self.close_segment() {
+            warn!("[Recording]: Failed to close the recording: {err}");
+        }
+        debug!("[Recording]: Stopped");
+    }
+
+    fn handle(&mut self, message: RecordingMessage) -> io::Result<()> {
+        match message {
+            RecordingMessage::SetupVideo(format) => {
+                // A segment only contains one codec
+                self.close_segment()?;
+                self.video_format = Some(format);
+            }
+            RecordingMessage::SetupAudio {
+                sample_rate,
+                channel_count,
+                samples_per_frame,
+            } => {
+                let setup = AudioSetup {
+                    sample_rate,
+                    channel_count,
+                    samples_per_frame,
+                };
+                self.audio_setup = Some(setup);
+
+                if let Some(segment) = &mut self.segment
+                    && segment.audio.is_none()
+                {
+                    let path = segment_path(
+                        &self.directory,
+                        &self.session_id,
+                        self.next_segment_index - 1,
+                        "audio.ogg",
+                    );
+                    segment.audio = Some(create_audio_writer(&path, setup)?);
+                    segment.files.push(path);
+                }
+            }
+            RecordingMessage::Video { data, keyframe } => {
+                let segment_full = self
+                    .segment
+                    .as_ref()
+                    .is_some_and(|segment| segment.video_size >= self.max_segment_size);
+
+                if keyframe && (self.segment.is_none() || segment_full) {
+                    self.open_segment()?;
+                } else if segment_full
+                    && let Some(segment) = &mut self.segment
+                    && !segment.keyframe_requested
+                {
+                    segment.keyframe_requested = true;
+                    self.needs_keyframe.store(true, Ordering::Release);
+                }
+
+                // Frames before the first keyframe can't be decoded
+                let Some(segment) = &mut self.segment else {
+                    return Ok(());
+                };
+
+                segment.video.write_all(&data)?;
+                segment.video_size += data.len() as u64;
+            }
+            RecordingMessage::Audio(data) => {
+                let (Some(segment), Some(setup)) = (&mut self.segment, self.audio_setup) else {
+                    return Ok(());
+                };
+                let Some(audio) = &mut segment.audio else {
+                    return Ok(());
+                };
+
+                // The granule position of Ogg is derived from the timestamp
+                audio
+                    .write_rtp(&Packet {
+                        header: Header {
+                            timestamp: segment.audio_timestamp,
+                            ..Default::default()
+                        },
+                        payload: Bytes::from(data),
+                    })
+                    .map_err(io::Error::other)?;
+                segment.audio_timestamp = segment
+                    .audio_timestamp
+                    .wrapping_add(setup.samples_per_frame);
+            }
+            RecordingMessage::Finish => {}
+        }
+
+        Ok(())
+    }
+
+    fn open_segment(&mut self) -> io::Result<()> {
+        self.close_segment()?;
+
+        let Some(format) = self.video_format else {
+            return Ok(());
+        };
+
+        let index = self.next_segment_index;
+        self.next_segment_index += 1;
+
+        let video_path = segment_path(
+            &self.directory,
+            &self.session_id,
+            index,
+            video_file_name(format),
+        );
+        let video = BufWriter::new(File::create_new(&video_path)?);
+        let mut files = vec![video_path];
+
+        let audio = match self.audio_setup {
+            Some(setup) => {
+                let path = segment_path(&self.directory, &self.session_id, index, "audio.ogg");
+                let writer = create_audio_writer(&path, setup)?;
+                files.push(path);
+                Some(writer)
+            }
+            None => None,
+        };
+
+        debug!("[Recording]: Started segment {index}");
+
+        self.segment = Some(Segment {
+            files,
+            video,
+            video_size: 0,
+            keyframe_requested: false,
+            audio,
+            audio_timestamp: 0,
+        });
+
+        self.remove_old_segments();
+
+        Ok(())
+    }
+
+    fn close_segment(&mut self) -> io::Result<()> {
+        let Some(mut segment) = self.segment.take() else {
+            return Ok(());
+        };
+        self.closed_segments.push_back(segment.files);
+
+        segment.video.flush()?;
+        if let Some(audio) = &mut segment.audio {
+            audio.close().map_err(io::Error::other)?;
+        }
+
+        Ok(())
+    }
+
+    fn remove_old_segments(&mut self) {
+        if self.max_segments == 0 {
+            return;
+        }
+
+        let open_segments = usize::from(self.segment.is_some());
+        while self.closed_segments.len() + open_segments > self.max_segments {
+            let Some(files) = self.closed_segments.pop_front() else {
+                break;
+            };
+
+            for file in files {
+                if let Err(err) = fs::remove_file(&file) {
+                    warn!("[Recording]: Failed to remove the old segment {file:?}: {err}");
+                }
+            }
+        }
+    }
+}
+
+fn video_file_name(format: VideoFormat) -> &'static str {
+    if format.contained_in(SupportedVideoFormats::MASK_H264) {
+        "video.h264"
+    } else if format.contained_in(SupportedVideoFormats::MASK_H265) {
+        "video.h265"
+    } else {
+        "video.obu"
+    }
+}
+
+fn segment_path(directory: &Path, session_id: &str, index: u32, name: &str) -> PathBuf {
+    directory.join(format!("{session_id}-{index:03}-{name}"))
+}
+
+fn create_audio_writer(path: &Path, setup: AudioSetup) -> io::Result<OggWriter<BufWriter<File>>> {
+    OggWriter::new(
+        BufWriter::new(File::create_new(path)?),
+        setup.sample_rate,
+        setup.channel_count,
+    )
+    .map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+            mpsc::sync_channel,
+        },
+        time::{Duration, Instant},
+    };
+
+    use common::config::RecordingConfig;
+    use moonlight_common::stream::bindings::VideoFormat;
+    use tokio::sync::Mutex;
+
+    use crate::recording::{
+        RECORDING_KEYFRAME_REQUEST_INTERVAL, RecordingMessage, RecordingWriter, StreamRecorder,
+    };
+
+    fn temp_directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("streamer-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn file_names(directory: &PathBuf) -> Vec<String> {
+        let mut names = std::fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    fn video(size: usize, keyframe: bool) -> RecordingMessage {
+        RecordingMessage::Video {
+            data: vec![0; size],
+            keyframe,
+        }
+    }
+
+    #[test]
+    fn test_segments() {
+        let directory = temp_directory("recording-segments");
+        let needs_keyframe = Arc::new(AtomicBool::new(false));
+        let mut writer = RecordingWriter::new(
+            directory.clone(),
+            "session".to_string(),
+            100,
+            2,
+            needs_keyframe.clone(),
+        );
+
+        writer
+            .handle(RecordingMessage::SetupVideo(VideoFormat::H264))
+            .unwrap();
+        // Nothing is written before the first keyframe
+        writer.handle(video(10, false)).unwrap();
+        assert!(file_names(&directory).is_empty());
+
+        writer.handle(video(60, true)).unwrap();
+        writer.handle(video(60, false)).unwrap();
+        assert!(!needs_keyframe.load(Ordering::Acquire));
+
+        // The segment is full but continues until the next keyframe
+        writer.handle(video(10, false)).unwrap();
+        assert!(needs_keyframe.swap(false, Ordering::AcqRel));
+        writer.handle(video(10, false)).unwrap();
+        assert!(!needs_keyframe.load(Ordering::Acquire));
+        assert_eq!(file_names(&directory), vec!["session-000-video.h264"]);
+
+        writer.handle(video(150, true)).unwrap();
+        writer.handle(video(10, true)).unwrap();
+        writer.close_segment().unwrap();
+
+        // Only the newest two segments are kept
+        assert_eq!(
+            file_names(&directory),
+            vec!["session-001-video.h264", "session-002-video.h264"]
+        );
+        assert_eq!(
+            std::fs::metadata(directory.join("session-001-video.h264"))
+                .unwrap()
+                .len(),
+            150
+        );
+
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[tokio::test]
+    async fn test_keyframe_requests_are_rate_limited() {
+        let directory = temp_directory("recording-keyframes");
+        let recorder = StreamRecorder::new(
+            &RecordingConfig {
+                enabled: true,
+                record_path: directory.to_string_lossy().to_string(),
+                ..Default::default()
+            },
+            "session",
+        )
+        .unwrap();
+
+        let start = Instant::now();
+        assert!(!recorder.take_keyframe_request(start));
+
+        recorder.needs_keyframe.store(true, Ordering::Release);
+        assert!(recorder.take_keyframe_request(start));
+        assert!(!recorder.take_keyframe_request(start));
+
+        // The next segment is full too early, the request waits for the interval
+        recorder.needs_keyframe.store(true, Ordering::Release);
+        assert!(!recorder.take_keyframe_request(start + Duration::from_secs(1)));
+        assert!(recorder.take_keyframe_request(start + RECORDING_KEYFRAME_REQUEST_INTERVAL));
+
+        recorder.finish().await;
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_dropped_video_requests_keyframe() {
+        // The queue holds a single message and nobody writes it to disk
+        let (sender, receiver) = sync_channel(1);
+        let recorder = StreamRecorder {
+            sender,
+            thread: Mutex::new(None),
+            needs_keyframe: Arc::new(AtomicBool::new(false)),
+            last_keyframe_request: std::sync::Mutex::new(None),
+            video_dropped: AtomicBool::new(false),
+        };
+        let now = Instant::now();
+
+        recorder.record_video(vec![1], true);
+        recorder.record_video(vec![2], false);
+        assert!(recorder.take_keyframe_request(now));
+
+        // The frames until the next keyframe can't be decoded
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(RecordingMessage::Video { keyframe: true, .. })
+        ));
+        recorder.record_video(vec![3], false);
+        assert!(receiver.try_recv().is_err());
+
+        // The requested keyframe resumes the recording
+        recorder.record_video(vec![4], true);
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(RecordingMessage::Video { keyframe: true, .. })
+        ));
+        recorder.record_video(vec![5], false);
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(RecordingMessage::Video {
+                keyframe: false,
+                ..
+            })
+        ));
+        assert!(!recorder.take_keyframe_request(now + RECORDING_KEYFRAME_REQUEST_INTERVAL));
+    }
+}
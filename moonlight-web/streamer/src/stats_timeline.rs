@@ -0,0 +1,111 @@
+//! Stats samples of the whole stream, they're sent to the web server once the stream stops
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use common::api_bindings::StatsSample;
+
+/// The stats are sent every second, only every few of them are kept
+const STATS_SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+/// 12 hours of samples, the oldest are dropped above this
+const MAX_STATS_SAMPLES: usize = 8640;
+
+pub struct StatsTimeline {
+    started_at: Instant,
+    last_sample: Option<Instant>,
+    samples: VecDeque<StatsSample>,
+}
+
+impl StatsTimeline {
+    pub fn new(started_at: Instant) -> Self {
+        Self {
+            started_at,
+            last_sample: None,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Records a sample if the last one is older than the sample interval,
+    /// the sample is created with the milliseconds since the stream started
+    pub fn record(&mut self, now: Instant, sample: impl FnOnce(u64) -> StatsSample) {
+        if self
+            .last_sample
+            .is_some_and(|last_sample| now.duration_since(last_sample) < STATS_SAMPLE_INTERVAL)
+        {
+            return;
+        }
+        self.last_sample = Some(now);
+
+        if self.samples.len() >= MAX_STATS_SAMPLES {
+            self.samples.pop_front();
+        }
+
+        let elapsed_ms = now.duration_since(self.started_at).as_millis() as u64;
+        self.samples.push_back(sample(elapsed_ms));
+    }
+
+    /// Oldest first
+    pub fn take_samples(&mut self) -> Vec<StatsSample> {
+        self.samples.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use common::api_bindings::StatsSample;
+
+    use crate::stats_timeline::{MAX_STATS_SAMPLES, STATS_SAMPLE_INTERVAL, StatsTimeline};
+
+    fn sample(elapsed_ms: u64) -> StatsSample {
+        StatsSample {
+            elapsed_ms,
+            bitrate_kbps: 10000,
+            packet_loss_percent: None,
+            rtt_ms: Some(5.0),
+            queued_frames: 0,
+            queue_delay_ms: 0.0,
+            frames_dropped: 0,
+        }
+    }
+
+    #[test]
+    fn test_stats_timeline_interval() {
+        let start = Instant::now();
+        let mut timeline = StatsTimeline::new(start);
+
+        timeline.record(start, sample);
+        timeline.record(start + Duration::from_secs(1), sample);
+        timeline.record(start + STATS_SAMPLE_INTERVAL, sample);
+
+        let samples = timeline.take_samples();
+        assert_eq!(
+            samples
+                .iter()
+                .map(|sample| sample.elapsed_ms)
+                .collect::<Vec<_>>(),
+            vec![0, STATS_SAMPLE_INTERVAL.as_millis() as u64]
+        );
+        assert!(timeline.take_samples().is_empty());
+    }
+
+    #[test]
+    fn test_stats_timeline_bounded() {
+        let start = Instant::now();
+        let mut timeline = StatsTimeline::new(start);
+
+        for index in 0..MAX_STATS_SAMPLES as u32 + 5 {
+            timeline.record(start + STATS_SAMPLE_INTERVAL * index, sample);
+        }
+
+        let samples = timeline.take_samples();
+        assert_eq!(samples.len(), MAX_STATS_SAMPLES);
+        assert_eq!(
+            samples[0].elapsed_ms,
+            (STATS_SAMPLE_INTERVAL * 5).as_millis() as u64
+        );
+    }
+}
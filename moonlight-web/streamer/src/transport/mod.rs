@@ -1,4 +1,4 @@
-use std::ops::Range;
+use std::{ops::Range, time::Duration};
 
 use async_trait::async_trait;
 use common::{
@@ -20,14 +20,23 @@ use moonlight_common::stream::{
 use num::FromPrimitive;
 use thiserror::Error;
 
-use crate::buffer::ByteBuffer;
+use crate::{
+    buffer::ByteBuffer,
+    file_transfer::{FILE_TRANSFER_CHUNK_SIZE, FileTransferRequest, FileTransferStatus},
+};
 
+mod rate_limit;
 pub mod webrtc;
 
 /// Look at TransportChannelId
 #[derive(Debug, Clone, Copy)]
 pub struct TransportChannel(pub u8);
 
+/// Clipboard texts are split into chunks of at most this many bytes
+pub const CLIPBOARD_CHUNK_SIZE: usize = 16 * 1024;
+/// Clipboard texts which are longer than this are dropped
+pub const CLIPBOARD_MAX_SIZE: usize = 1024 * 1024;
+
 #[derive(Debug, Error)]
 pub enum TransportError {
     #[error("the channel was closed")]
@@ -103,6 +112,12 @@ pub enum InboundPacket {
         rotation: Option<u16>,
         event_type: TouchEventType,
     },
+    /// Part of a clipboard text, see ClipboardAssembler
+    ClipboardChunk {
+        last: bool,
+        data: Vec<u8>,
+    },
+    FileTransfer(FileTransferRequest),
 }
 
 impl InboundPacket {
@@ -128,6 +143,22 @@ impl InboundPacket {
         TransportChannelId::CONTROLLER15,
     ];
 
+    /// If the packet releases a key, mouse button or touch, which is checked without parsing it.
+    /// Dropping these would leave the input pressed on the host.
+    pub fn is_release(channel: TransportChannel, bytes: &[u8]) -> bool {
+        match channel.0 {
+            // Key with action up
+            TransportChannelId::KEYBOARD => matches!(bytes, [0, 0, ..]),
+            // Button with action release
+            TransportChannelId::MOUSE_ABSOLUTE
+            | TransportChannelId::MOUSE_RELIABLE
+            | TransportChannelId::MOUSE_RELATIVE => matches!(bytes, [2, 0, ..]),
+            // Touch up
+            TransportChannelId::TOUCH => matches!(bytes, [2, ..]),
+            _ => false,
+        }
+    }
+
     pub fn deserialize(channel: TransportChannel, bytes: &[u8]) -> Option<Self> {
         let mut buffer = ByteBuffer::new(bytes);
 
@@ -363,11 +394,137 @@ impl InboundPacket {
                     None
                 }
             }
+            TransportChannel(TransportChannelId::CLIPBOARD) => {
+                // A clipboard text is sent as one or more chunks, the format is the same in both directions:
+                // u8 flags, bit 0 is set on the last chunk of the text
+                // u16 length of the data, at most CLIPBOARD_CHUNK_SIZE
+                // [u8] data, the data of all chunks joined together is the utf8 text
+                // A chunk can end in the middle of a character, so the text is only decoded once it's complete
+                if bytes.len() < 3 {
+                    warn!("[InboundPacket]: received clipboard chunk without header");
+                    return None;
+                }
+                let flags = buffer.get_u8();
+                let len = buffer.get_u16() as usize;
+                if len > CLIPBOARD_CHUNK_SIZE || len > bytes.len() - 3 {
+                    warn!("[InboundPacket]: received clipboard chunk with invalid length {len}");
+                    return None;
+                }
+
+                let mut data = vec![0; len];
+                buffer.get_u8_array(&mut data);
+
+                Some(InboundPacket::ClipboardChunk {
+                    last: flags & 1 != 0,
+                    data,
+                })
+            }
+            TransportChannel(TransportChannelId::FILE_TRANSFER) => {
+                // Uploads of the client, every message starts with a header:
+                // u8 type, u32 id of the transfer which is picked by the client
+                // The rest depends on the type:
+                // 0 = Start: u64 size, u16 length + utf8 directory (empty = default), u16 length + utf8 file name
+                // 1 = Chunk: u16 length + data, at most FILE_TRANSFER_CHUNK_SIZE
+                // 2 = End: u32 crc32 of the whole file
+                // 3 = Cancel
+                // The streamer answers with OutboundPacket::FileTransferStatus
+                if bytes.len() < 5 {
+                    warn!("[InboundPacket]: received file transfer message without header");
+                    return None;
+                }
+                let ty = buffer.get_u8();
+                let id = buffer.get_u32();
+
+                fn get_data(buffer: &mut ByteBuffer<&[u8]>, max_len: usize) -> Option<Vec<u8>> {
+                    if buffer.remaining() < 2 {
+                        return None;
+                    }
+                    let len = buffer.get_u16() as usize;
+                    if len > max_len || len > buffer.remaining() {
+                        return None;
+                    }
+
+                    let mut data = vec![0; len];
+                    buffer.get_u8_array(&mut data);
+                    Some(data)
+                }
+                fn get_text(buffer: &mut ByteBuffer<&[u8]>) -> Option<String> {
+                    String::from_utf8(get_data(buffer, u16::MAX as usize)?).ok()
+                }
+
+                let request = match ty {
+                    0 if buffer.remaining() >= 8 => {
+                        let size = buffer.get_u64();
+                        let directory = get_text(&mut buffer);
+                        let file_name = get_text(&mut buffer);
+
+                        directory.zip(file_name).map(|(directory, file_name)| {
+                            FileTransferRequest::Start {
+                                id,
+                                size,
+                                directory,
+                                file_name,
+                            }
+                        })
+                    }
+                    1 => get_data(&mut buffer, FILE_TRANSFER_CHUNK_SIZE)
+                        .map(|data| FileTransferRequest::Chunk { id, data }),
+                    2 if buffer.remaining() >= 4 => Some(FileTransferRequest::End {
+                        id,
+                        checksum: buffer.get_u32(),
+                    }),
+                    3 => Some(FileTransferRequest::Cancel { id }),
+                    _ => None,
+                };
+
+                if request.is_none() {
+                    warn!("[InboundPacket]: received invalid file transfer message with type {ty}");
+                }
+                request.map(InboundPacket::FileTransfer)
+            }
             _ => None,
         }
     }
 }
 
+/// Joins the clipboard chunks of the client back into the text
+#[derive(Debug, Default)]
+pub struct ClipboardAssembler {
+    data: Vec<u8>,
+    too_long: bool,
+}
+
+impl ClipboardAssembler {
+    /// Returns the text once its last chunk arrived
+    pub fn push(&mut self, last: bool, data: &[u8]) -> Option<String> {
+        if self.data.len() + data.len() > CLIPBOARD_MAX_SIZE {
+            self.too_long = true;
+            self.data.clear();
+        }
+        if !self.too_long {
+            self.data.extend_from_slice(data);
+        }
+
+        if !last {
+            return None;
+        }
+
+        let data = std::mem::take(&mut self.data);
+        if std::mem::take(&mut self.too_long) {
+            warn!("[Clipboard]: dropping a text which is longer than {CLIPBOARD_MAX_SIZE} bytes");
+            return None;
+        }
+
+        match String::from_utf8(data) {
+            Ok(text) => Some(text),
+            Err(err) => {
+                warn!("[Clipboard]: received a text which isn't utf8: {err}");
+                None
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum OutboundPacket {
     General {
@@ -384,9 +541,38 @@ pub enum OutboundPacket {
         left_trigger_motor: u16,
         right_trigger_motor: u16,
     },
+    ClipboardChunk {
+        last: bool,
+        data: Vec<u8>,
+    },
+    FileTransferStatus {
+        id: u32,
+        status: FileTransferStatus,
+    },
 }
 
 impl OutboundPacket {
+    /// Splits the text into chunks of CLIPBOARD_CHUNK_SIZE, an empty text is still sent as one chunk
+    pub fn clipboard_chunks(text: &str) -> Vec<Self> {
+        let bytes = text.as_bytes();
+        if bytes.is_empty() {
+            return vec![Self::ClipboardChunk {
+                last: true,
+                data: Vec::new(),
+            }];
+        }
+
+        let count = bytes.len().div_ceil(CLIPBOARD_CHUNK_SIZE);
+        bytes
+            .chunks(CLIPBOARD_CHUNK_SIZE)
+            .enumerate()
+            .map(|(index, chunk)| Self::ClipboardChunk {
+                last: index + 1 == count,
+                data: chunk.to_vec(),
+            })
+            .collect()
+    }
+
     pub fn serialize(&self, raw_buffer: &mut Vec<u8>) -> Option<(TransportChannel, Range<usize>)> {
         match self {
             Self::General { message } => {
@@ -461,14 +647,97 @@ impl OutboundPacket {
                     buffer.into_raw().1,
                 ))
             }
+            Self::ClipboardChunk { last, data } => {
+                // The format is described in InboundPacket::deserialize
+                raw_buffer.resize(data.len() + 3, 0u8);
+                let mut buffer = ByteBuffer::new(raw_buffer as &mut [u8]);
+
+                buffer.put_u8(*last as u8);
+                buffer.put_u16(data.len() as u16);
+                buffer.put_u8_array(data);
+
+                buffer.flip();
+                Some((
+                    TransportChannel(TransportChannelId::CLIPBOARD),
+                    buffer.into_raw().1,
+                ))
+            }
+            Self::FileTransferStatus { id, status } => {
+                // u8 status, u32 id of the transfer
+                // 0 = Accepted, 1 = Completed, 2 = Failed: u16 length + utf8 reason
+                let reason = match status {
+                    FileTransferStatus::Failed(reason) => Some(reason.as_str()),
+                    _ => None,
+                };
+                raw_buffer.resize(5 + reason.map(|reason| reason.len() + 2).unwrap_or(0), 0u8);
+                let mut buffer = ByteBuffer::new(raw_buffer as &mut [u8]);
+
+                buffer.put_u8(match status {
+                    FileTransferStatus::Accepted => 0,
+                    FileTransferStatus::Completed => 1,
+                    FileTransferStatus::Failed(_) => 2,
+                });
+                buffer.put_u32(*id);
+                if let Some(reason) = reason {
+                    buffer.put_u16(reason.len() as u16);
+                    buffer.put_utf8_raw(reason);
+                }
+
+                buffer.flip();
+                Some((
+                    TransportChannel(TransportChannelId::FILE_TRANSFER),
+                    buffer.into_raw().1,
+                ))
+            }
         }
     }
 }
 
+/// Counters of the outgoing video queue since the stream started
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VideoQueueStats {
+    /// Frames dropped because the network couldn't keep up or a keyframe superseded them
+    pub frames_dropped: u32,
+    /// Keyframes requested from the host
+    pub idrs_forced: u32,
+    /// Size of the frames which are currently queued
+    pub queued_bytes: usize,
+    pub queued_frames: usize,
+    /// How long the last sent frame waited in the queue, includes the frame pacing
+    pub queue_delay: Duration,
+}
+
+/// How the client receives one of the streams, taken from its last receiver report
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StreamReception {
+    pub ssrc: u32,
+    /// Share of the packets lost since the previous report, between 0 and 1
+    pub fraction_lost: f64,
+    /// Packets lost since the stream started
+    pub cumulative_lost: u32,
+    /// Interarrival jitter
+    pub jitter: Duration,
+}
+
+/// One entry for every peer which receives the stream, the primary peer is first
+#[derive(Debug, Clone, Default)]
+pub struct ReceptionStats {
+    pub video: Vec<StreamReception>,
+    pub audio: Vec<StreamReception>,
+}
+
 pub enum TransportEvent {
-    StartStream { settings: StreamSettings },
+    StartStream {
+        settings: StreamSettings,
+    },
     RecvPacket(InboundPacket),
     SendIpc(StreamerIpcMessage),
+    /// The client requested a keyframe, e.g. because it lost packets or its decoder reset.
+    ///
+    /// The request still needs to travel to the host and the keyframe has to be encoded,
+    /// so the client should expect it roughly one host rtt plus one frame time later.
+    /// Repeated requests in that window won't make it arrive any faster.
+    RequestKeyframe,
     // TODO: use the error and not this event here
     Closed,
 }
@@ -483,6 +752,8 @@ pub trait TransportSender {
         &'a self,
         unit: &'a VideoDecodeUnit<'a>,
     ) -> Result<DecodeResult, TransportError>;
+    async fn video_queue_stats(&self) -> VideoQueueStats;
+    async fn reception_stats(&self) -> ReceptionStats;
 
     async fn setup_audio(
         &self,
@@ -497,3 +768,125 @@ pub trait TransportSender {
 
     async fn close(&self) -> Result<(), TransportError>;
 }
+
+#[cfg(test)]
+mod tests {
+    use common::api_bindings::TransportChannelId;
+
+    use crate::{
+        file_transfer::{FileTransferRequest, FileTransferStatus},
+        transport::{
+            CLIPBOARD_CHUNK_SIZE, CLIPBOARD_MAX_SIZE, ClipboardAssembler, InboundPacket,
+            OutboundPacket, TransportChannel,
+        },
+    };
+
+    fn roundtrip(text: &str) -> Option<String> {
+        let mut assembler = ClipboardAssembler::default();
+        let mut result = None;
+
+        for packet in OutboundPacket::clipboard_chunks(text) {
+            let mut raw_buffer = Vec::new();
+            let (channel, range) = packet.serialize(&mut raw_buffer).unwrap();
+
+            let Some(InboundPacket::ClipboardChunk { last, data }) =
+                InboundPacket::deserialize(channel, &raw_buffer[range])
+            else {
+                panic!("expected a clipboard chunk");
+            };
+
+            assert!(result.is_none());
+            result = assembler.push(last, &data);
+        }
+
+        result
+    }
+
+    #[test]
+    fn test_clipboard_roundtrip() {
+        assert_eq!(roundtrip("").as_deref(), Some(""));
+        assert_eq!(roundtrip("hello").as_deref(), Some("hello"));
+
+        // The chunk border is in the middle of a three byte character
+        let long = "€".repeat(CLIPBOARD_CHUNK_SIZE / 2);
+        assert_eq!(OutboundPacket::clipboard_chunks(&long).len(), 2);
+        assert_eq!(roundtrip(&long), Some(long));
+    }
+
+    #[test]
+    fn test_clipboard_too_long() {
+        let mut assembler = ClipboardAssembler::default();
+
+        let chunk = vec![b'a'; CLIPBOARD_CHUNK_SIZE];
+        for _ in 0..CLIPBOARD_MAX_SIZE / CLIPBOARD_CHUNK_SIZE + 1 {
+            assert_eq!(assembler.push(false, &chunk), None);
+        }
+        assert_eq!(assembler.push(true, b"a"), None);
+
+        // The next text is received again
+        assert_eq!(assembler.push(true, b"a").as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn test_file_transfer_start() {
+        let mut bytes = vec![0, 0, 0, 0, 9];
+        bytes.extend_from_slice(&1234u64.to_be_bytes());
+        bytes.extend_from_slice(&[0, 0]);
+        bytes.extend_from_slice(&[0, 8]);
+        bytes.extend_from_slice(b"save.sav");
+
+        let channel = TransportChannel(TransportChannelId::FILE_TRANSFER);
+        let Some(InboundPacket::FileTransfer(FileTransferRequest::Start {
+            id,
+            size,
+            directory,
+            file_name,
+        })) = InboundPacket::deserialize(channel, &bytes)
+        else {
+            panic!("expected a file transfer start");
+        };
+        assert_eq!((id, size), (9, 1234));
+        assert_eq!(directory, "");
+        assert_eq!(file_name, "save.sav");
+
+        // The file name is longer than the message
+        bytes.truncate(bytes.len() - 1);
+        assert!(InboundPacket::deserialize(channel, &bytes).is_none());
+    }
+
+    #[test]
+    fn test_is_release() {
+        let keyboard = TransportChannel(TransportChannelId::KEYBOARD);
+        assert!(InboundPacket::is_release(keyboard, &[0, 0, 0, 0, 65]));
+        assert!(!InboundPacket::is_release(keyboard, &[0, 1, 0, 0, 65]));
+        // Text
+        assert!(!InboundPacket::is_release(keyboard, &[1, 0]));
+
+        let mouse = TransportChannel(TransportChannelId::MOUSE_RELIABLE);
+        assert!(InboundPacket::is_release(mouse, &[2, 0, 1]));
+        assert!(!InboundPacket::is_release(mouse, &[2, 1, 1]));
+        // Move
+        assert!(!InboundPacket::is_release(mouse, &[0, 0, 0, 0, 0]));
+
+        let touch = TransportChannel(TransportChannelId::TOUCH);
+        assert!(InboundPacket::is_release(touch, &[2]));
+        assert!(!InboundPacket::is_release(touch, &[1]));
+
+        assert!(!InboundPacket::is_release(
+            TransportChannel(TransportChannelId::CLIPBOARD),
+            &[2, 0]
+        ));
+    }
+
+    #[test]
+    fn test_file_transfer_status() {
+        let packet = OutboundPacket::FileTransferStatus {
+            id: 3,
+            status: FileTransferStatus::Failed("no".to_string()),
+        };
+
+        let mut raw_buffer = Vec::new();
+        let (_, range) = packet.serialize(&mut raw_buffer).unwrap();
+        assert_eq!(&raw_buffer[range], &[2, 0, 0, 0, 3, 0, 2, b'n', b'o']);
+    }
+}
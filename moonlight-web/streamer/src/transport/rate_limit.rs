@@ -0,0 +1,133 @@
+//! Limits of the packets a client sends over one channel, see InboundLimitsConfig.
+
+use std::time::{Duration, Instant};
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InboundVerdict {
+    Accept,
+    Drop {
+        /// Only the first dropped packet of a window is logged
+        first_in_window: bool,
+    },
+    /// The channel sent a multiple of its limit, reported once
+    Terminate,
+}
+
+/// Counts the packets of one channel in fixed windows of a second
+#[derive(Debug)]
+pub struct InboundRateLimiter {
+    /// 0 = unlimited
+    max_packets_per_sec: u32,
+    /// 0 = never terminate
+    terminate_factor: u32,
+    window_start: Instant,
+    window_packets: u32,
+    terminated: bool,
+}
+
+impl InboundRateLimiter {
+    pub fn new(max_packets_per_sec: u32, terminate_factor: u32, now: Instant) -> Self {
+        Self {
+            max_packets_per_sec,
+            terminate_factor,
+            window_start: now,
+            window_packets: 0,
+            terminated: false,
+        }
+    }
+
+    pub fn check(&mut self, now: Instant) -> InboundVerdict {
+        if self.max_packets_per_sec == 0 {
+            return InboundVerdict::Accept;
+        }
+
+        if now.duration_since(self.window_start) >= RATE_LIMIT_WINDOW {
+            self.window_start = now;
+            self.window_packets = 0;
+        }
+        self.window_packets = self.window_packets.saturating_add(1);
+
+        if self.window_packets <= self.max_packets_per_sec {
+            return InboundVerdict::Accept;
+        }
+
+        if self.terminate_factor > 0
+            && !self.terminated
+            && self.window_packets
+                > self
+                    .max_packets_per_sec
+                    .saturating_mul(self.terminate_factor)
+        {
+            self.terminated = true;
+            return InboundVerdict::Terminate;
+        }
+
+        InboundVerdict::Drop {
+            first_in_window: self.window_packets == self.max_packets_per_sec + 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::transport::rate_limit::{InboundRateLimiter, InboundVerdict};
+
+    #[test]
+    fn test_rate_limit() {
+        let start = Instant::now();
+        let mut limiter = InboundRateLimiter::new(2, 0, start);
+
+        assert_eq!(limiter.check(start), InboundVerdict::Accept);
+        assert_eq!(limiter.check(start), InboundVerdict::Accept);
+        assert_eq!(
+            limiter.check(start),
+            InboundVerdict::Drop {
+                first_in_window: true
+            }
+        );
+        assert_eq!(
+            limiter.check(start + Duration::from_millis(500)),
+            InboundVerdict::Drop {
+                first_in_window: false
+            }
+        );
+
+        // A new window starts after a second
+        assert_eq!(
+            limiter.check(start + Duration::from_secs(1)),
+            InboundVerdict::Accept
+        );
+    }
+
+    #[test]
+    fn test_rate_limit_terminate() {
+        let start = Instant::now();
+        let mut limiter = InboundRateLimiter::new(2, 3, start);
+
+        for _ in 0..6 {
+            assert_ne!(limiter.check(start), InboundVerdict::Terminate);
+        }
+        assert_eq!(limiter.check(start), InboundVerdict::Terminate);
+        // Only reported once
+        assert_eq!(
+            limiter.check(start),
+            InboundVerdict::Drop {
+                first_in_window: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_rate_limit_unlimited() {
+        let start = Instant::now();
+        let mut limiter = InboundRateLimiter::new(0, 1, start);
+
+        for _ in 0..1000 {
+            assert_eq!(limiter.check(start), InboundVerdict::Accept);
+        }
+    }
+}
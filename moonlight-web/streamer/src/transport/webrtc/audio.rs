@@ -1,50 +1,143 @@
-use std::{sync::Weak, time::Duration};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
 
 use bytes::Bytes;
+use common::media_api::OPUS_CLOCK_RATE;
 use log::{error, warn};
 use moonlight_common::stream::bindings::{AudioConfig, OpusMultistreamConfig};
-use tokio::runtime::Handle;
+use tokio::{runtime::Handle, sync::Notify, time::sleep_until};
 use webrtc::{
-    api::media_engine::{MIME_TYPE_OPUS, MediaEngine},
-    media::Sample,
-    peer_connection::RTCPeerConnection,
-    rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType},
+    api::media_engine::MIME_TYPE_OPUS, media::Sample, peer_connection::RTCPeerConnection,
+    rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
     track::track_local::track_local_static_sample::TrackLocalStaticSample,
 };
 
-use crate::transport::webrtc::{WebRtcInner, sender::TrackLocalSender};
-
-pub fn register_audio_codecs(media_engine: &mut MediaEngine) -> Result<(), webrtc::Error> {
-    media_engine.register_codec(
-        RTCRtpCodecParameters {
-            capability: RTCRtpCodecCapability {
-                mime_type: MIME_TYPE_OPUS.to_owned(),
-                clock_rate: 48000,
-                channels: 2,
-                sdp_fmtp_line: "minptime=10;useinbandfec=1".to_owned(),
-                rtcp_feedback: vec![],
-            },
-            payload_type: 111,
-            ..Default::default()
-        },
-        RTPCodecType::Audio,
-    )?;
+use crate::transport::{
+    StreamReception,
+    webrtc::{WebRtcInner, sender::TrackLocalSender},
+};
+
+/// Samples above this depth are released early, e.g. after the host stalled and then sent a burst
+const MAX_JITTER_BUFFER_DEPTH: usize = 100;
+
+/// Holds every sample back for a fixed delay so irregular delivery from the host
+/// doesn't turn into gaps in the playback
+struct AudioJitterBuffer {
+    delay: Duration,
+    samples: VecDeque<(Instant, Sample)>,
+    overflowing: bool,
+}
+
+impl AudioJitterBuffer {
+    fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            samples: VecDeque::new(),
+            overflowing: false,
+        }
+    }
+
+    /// Holds the sample back and returns the samples which have to be released early because
+    /// the buffer is full, oldest first
+    fn push(&mut self, now: Instant, sample: Sample) -> Vec<Sample> {
+        self.samples.push_back((now, sample));
+
+        let overflow = self.samples.len().saturating_sub(MAX_JITTER_BUFFER_DEPTH);
+        if overflow > 0 && !self.overflowing {
+            warn!(
+                "[Stream]: Audio jitter buffer is full with {MAX_JITTER_BUFFER_DEPTH} samples, releasing them early"
+            );
+        }
+        self.overflowing = overflow > 0;
+
+        self.samples
+            .drain(..overflow)
+            .map(|(_, sample)| sample)
+            .collect()
+    }
 
-    Ok(())
+    /// Returns the samples which were held back long enough, oldest first
+    fn release(&mut self, now: Instant) -> Vec<Sample> {
+        let mut ready = Vec::new();
+        while self
+            .samples
+            .front()
+            .is_some_and(|(received, _)| now.duration_since(*received) >= self.delay)
+        {
+            if let Some((_, sample)) = self.samples.pop_front() {
+                ready.push(sample);
+            }
+        }
+
+        ready
+    }
+
+    /// When the oldest sample was held back long enough
+    fn next_release(&self) -> Option<Instant> {
+        self.samples
+            .front()
+            .map(|(received, _)| *received + self.delay)
+    }
+}
+
+/// Releases the samples of the jitter buffer on time, even if the host doesn't send new ones
+pub async fn release_audio_jitter_buffer(inner: Weak<WebRtcInner>, new_samples: Arc<Notify>) {
+    loop {
+        let next_release = {
+            let Some(inner) = inner.upgrade() else {
+                return;
+            };
+
+            inner.audio.lock().await.release_jitter_buffer().await
+        };
+
+        match next_release {
+            Some(next_release) => sleep_until(next_release.into()).await,
+            None => new_samples.notified().await,
+        }
+    }
 }
 
 pub struct WebRtcAudio {
     sender: TrackLocalSender<TrackLocalStaticSample>,
     config: Option<OpusMultistreamConfig>,
+    jitter_buffer: Option<AudioJitterBuffer>,
+    /// Wakes the release task when a sample was held back
+    jitter_buffer_notify: Arc<Notify>,
 }
 
 impl WebRtcAudio {
-    pub fn new(runtime: Handle, peer: Weak<RTCPeerConnection>, channel_queue_size: usize) -> Self {
+    pub fn new(
+        runtime: Handle,
+        peer: Weak<RTCPeerConnection>,
+        channel_queue_size: usize,
+        jitter_buffer_delay: Duration,
+    ) -> Self {
         Self {
-            sender: TrackLocalSender::new(runtime, peer, channel_queue_size),
+            sender: TrackLocalSender::new(runtime, peer, channel_queue_size, 0),
             config: None,
+            jitter_buffer: (!jitter_buffer_delay.is_zero())
+                .then(|| AudioJitterBuffer::new(jitter_buffer_delay)),
+            jitter_buffer_notify: Default::default(),
         }
     }
+
+    /// Set if the samples are held back, the release task must wait for it
+    pub fn jitter_buffer_notify(&self) -> Option<Arc<Notify>> {
+        self.jitter_buffer
+            .as_ref()
+            .map(|_| self.jitter_buffer_notify.clone())
+    }
+}
+
+impl Drop for WebRtcAudio {
+    fn drop(&mut self) {
+        // Lets the release task see that the stream is gone
+        self.jitter_buffer_notify.notify_one();
+    }
 }
 
 impl WebRtcAudio {
@@ -114,10 +207,103 @@ impl WebRtcAudio {
             ..Default::default()
         };
 
-        self.sender.send_samples(vec![sample], false).await;
+        let samples = match &mut self.jitter_buffer {
+            Some(jitter_buffer) => {
+                let samples = jitter_buffer.push(Instant::now(), sample);
+                self.jitter_buffer_notify.notify_one();
+                samples
+            }
+            None => vec![sample],
+        };
+
+        if !samples.is_empty() {
+            self.sender.send_samples(samples, false).await;
+        }
+    }
+
+    /// Sends the samples which were held back long enough and returns when the next one is due
+    pub async fn release_jitter_buffer(&mut self) -> Option<Instant> {
+        let jitter_buffer = self.jitter_buffer.as_mut()?;
+
+        let samples = jitter_buffer.release(Instant::now());
+        let next_release = jitter_buffer.next_release();
+
+        if !samples.is_empty() {
+            self.sender.send_samples(samples, false).await;
+        }
+
+        next_release
+    }
+
+    pub async fn add_spectator(&self, peer: &RTCPeerConnection) -> Result<(), anyhow::Error> {
+        self.sender.add_to_peer(peer, |_| {}).await
+    }
+
+    pub async fn reception_stats(&self) -> Vec<StreamReception> {
+        self.sender.reception_stats(OPUS_CLOCK_RATE).await
     }
 
     fn config(&self) -> AudioConfig {
         AudioConfig::STEREO
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use bytes::Bytes;
+    use webrtc::media::Sample;
+
+    use crate::transport::webrtc::audio::{AudioJitterBuffer, MAX_JITTER_BUFFER_DEPTH};
+
+    fn sample(id: u8) -> Sample {
+        Sample {
+            data: Bytes::from(vec![id]),
+            duration: Duration::from_millis(10),
+            ..Default::default()
+        }
+    }
+
+    fn ids(samples: &[Sample]) -> Vec<u8> {
+        samples.iter().map(|sample| sample.data[0]).collect()
+    }
+
+    #[test]
+    fn test_jitter_buffer_holds_samples() {
+        let mut buffer = AudioJitterBuffer::new(Duration::from_millis(30));
+        let start = Instant::now();
+
+        assert!(buffer.push(start, sample(0)).is_empty());
+        assert!(
+            buffer
+                .push(start + Duration::from_millis(10), sample(1))
+                .is_empty()
+        );
+        assert_eq!(
+            buffer.next_release(),
+            Some(start + Duration::from_millis(30))
+        );
+        assert!(buffer.release(start + Duration::from_millis(20)).is_empty());
+
+        // Released on time without a new sample
+        assert_eq!(
+            ids(&buffer.release(start + Duration::from_millis(40))),
+            vec![0, 1]
+        );
+        assert_eq!(buffer.next_release(), None);
+    }
+
+    #[test]
+    fn test_jitter_buffer_overflow() {
+        let mut buffer = AudioJitterBuffer::new(Duration::from_secs(60));
+        let start = Instant::now();
+
+        for id in 0..MAX_JITTER_BUFFER_DEPTH {
+            assert!(buffer.push(start, sample(id as u8)).is_empty());
+        }
+
+        assert_eq!(ids(&buffer.push(start, sample(255))), vec![0]);
+        assert_eq!(buffer.samples.len(), MAX_JITTER_BUFFER_DEPTH);
+    }
+}
@@ -0,0 +1,103 @@
+use std::{
+    collections::VecDeque,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use common::api_bindings::ConnectionLogEntry;
+use webrtc::{
+    ice_transport::ice_connection_state::RTCIceConnectionState,
+    peer_connection::peer_connection_state::RTCPeerConnectionState,
+};
+
+/// Only the newest transitions are kept, a flapping connection could log forever
+const MAX_CONNECTION_LOG_ENTRIES: usize = 64;
+
+/// The last state transitions and signaling events of the primary peer.
+/// They're sent to the web server once the peer fails
+pub struct ConnectionLog {
+    ice_state: RTCIceConnectionState,
+    peer_state: RTCPeerConnectionState,
+    entries: VecDeque<ConnectionLogEntry>,
+}
+
+impl Default for ConnectionLog {
+    fn default() -> Self {
+        Self {
+            ice_state: RTCIceConnectionState::New,
+            peer_state: RTCPeerConnectionState::New,
+            entries: VecDeque::new(),
+        }
+    }
+}
+
+impl ConnectionLog {
+    pub fn ice_state_changed(&mut self, state: RTCIceConnectionState) {
+        self.ice_state = state;
+        self.record(format!("ice connection state changed to {state}"));
+    }
+
+    pub fn peer_state_changed(&mut self, state: RTCPeerConnectionState) {
+        self.peer_state = state;
+        self.record(format!("peer connection state changed to {state}"));
+    }
+
+    pub fn record(&mut self, event: impl Into<String>) {
+        if self.entries.len() >= MAX_CONNECTION_LOG_ENTRIES {
+            self.entries.pop_front();
+        }
+
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or(0);
+
+        self.entries.push_back(ConnectionLogEntry {
+            timestamp_ms,
+            ice_state: self.ice_state.to_string(),
+            peer_state: self.peer_state.to_string(),
+            event: event.into(),
+        });
+    }
+
+    /// Oldest first
+    pub fn entries(&self) -> Vec<ConnectionLogEntry> {
+        self.entries.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webrtc::{
+        ice_transport::ice_connection_state::RTCIceConnectionState,
+        peer_connection::peer_connection_state::RTCPeerConnectionState,
+    };
+
+    use crate::transport::webrtc::connection_log::{ConnectionLog, MAX_CONNECTION_LOG_ENTRIES};
+
+    #[test]
+    fn test_connection_log() {
+        let mut log = ConnectionLog::default();
+
+        log.ice_state_changed(RTCIceConnectionState::Checking);
+        log.peer_state_changed(RTCPeerConnectionState::Connecting);
+
+        let entries = log.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].ice_state, "checking");
+        assert_eq!(entries[0].peer_state, "new");
+        assert_eq!(entries[1].peer_state, "connecting");
+    }
+
+    #[test]
+    fn test_connection_log_bounded() {
+        let mut log = ConnectionLog::default();
+
+        for index in 0..MAX_CONNECTION_LOG_ENTRIES + 5 {
+            log.record(format!("event {index}"));
+        }
+
+        let entries = log.entries();
+        assert_eq!(entries.len(), MAX_CONNECTION_LOG_ENTRIES);
+        assert_eq!(entries[0].event, "event 5");
+    }
+}
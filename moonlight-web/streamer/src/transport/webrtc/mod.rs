@@ -1,7 +1,14 @@
 use std::{
+    collections::HashMap,
     future::ready,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     pin::Pin,
-    sync::{Arc, Weak},
+    ptr,
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicU64, Ordering},
+    },
     time::{Duration, Instant},
 };
 
@@ -10,18 +17,21 @@ use bytes::Bytes;
 use common::{
     StreamSettings,
     api_bindings::{
-        RtcIceCandidate, RtcSdpType, RtcSessionDescription, StreamClientMessage,
-        StreamServerMessage, StreamSignalingMessage, TransportChannelId,
+        GeneralClientMessage, RtcIceCandidate, RtcSdpType, RtcSessionDescription,
+        StreamClientMessage, StreamServerMessage, StreamSignalingMessage, TransportChannelId,
     },
-    config::{PortRange, WebRtcConfig},
+    config::{PortRange, WebRtcConfig, WebRtcNetworkType},
     ipc::{ServerIpcMessage, StreamerIpcMessage},
+    media_api::{create_media_api, create_setting_engine, into_webrtc_ice},
 };
 use log::{debug, error, info, warn};
 use moonlight_common::stream::{
     bindings::{AudioConfig, DecodeResult, OpusMultistreamConfig, VideoDecodeUnit},
     video::VideoSetup,
 };
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::{
+    net::UdpSocket,
     runtime::Handle,
     spawn,
     sync::{
@@ -31,35 +41,32 @@ use tokio::{
     time::sleep,
 };
 use webrtc::{
-    api::{
-        APIBuilder, interceptor_registry::register_default_interceptors, media_engine::MediaEngine,
-        setting_engine::SettingEngine,
-    },
+    api::APIBuilder,
     data_channel::{RTCDataChannel, data_channel_init::RTCDataChannelInit, data_channel_message::DataChannelMessage},
-    ice::udp_network::{EphemeralUDP, UDPNetwork},
+    ice::udp_mux::{UDPMuxDefault, UDPMuxParams},
     ice_transport::{
         ice_candidate::{RTCIceCandidate, RTCIceCandidateInit},
         ice_connection_state::RTCIceConnectionState,
     },
-    interceptor::registry::Registry,
     peer_connection::{
         RTCPeerConnection,
         configuration::RTCConfiguration,
         peer_connection_state::RTCPeerConnectionState,
+        policy::ice_transport_policy::RTCIceTransportPolicy,
         sdp::{sdp_type::RTCSdpType, session_description::RTCSessionDescription},
     },
 };
 
 use crate::{
-    convert::{
-        from_webrtc_sdp, into_webrtc_ice, into_webrtc_ice_candidate, into_webrtc_network_type,
-    },
+    convert::from_webrtc_sdp,
     transport::{
-        InboundPacket, OutboundPacket, TransportChannel, TransportError, TransportEvent,
-        TransportEvents, TransportSender,
+        InboundPacket, OutboundPacket, ReceptionStats, TransportChannel, TransportError,
+        TransportEvent, TransportEvents, TransportSender, VideoQueueStats,
+        rate_limit::{InboundRateLimiter, InboundVerdict},
         webrtc::{
-            audio::{WebRtcAudio, register_audio_codecs},
-            video::{WebRtcVideo, register_video_codecs},
+            audio::{WebRtcAudio, release_audio_jitter_buffer},
+            connection_log::ConnectionLog,
+            video::{WebRtcVideo, negotiated_video_codec},
         },
     },
 };
@@ -67,6 +74,7 @@ use crate::{
 pub const TIMEOUT_DURATION: Duration = Duration::from_secs(10);
 
 mod audio;
+mod connection_log;
 mod sender;
 mod video;
 
@@ -76,6 +84,8 @@ struct WebRtcInner {
     event_sender: Sender<TransportEvent>,
     general_channel: Arc<RTCDataChannel>,
     stats_channel: Mutex<Option<Arc<RTCDataChannel>>>,
+    // Open controller{N} channels by controller index
+    controller_channels: Mutex<HashMap<usize, Arc<RTCDataChannel>>>,
     // TODO: use negotiated channels -> no rwlock required
     video: Mutex<WebRtcVideo>,
     audio: Mutex<WebRtcAudio>,
@@ -85,8 +95,21 @@ struct WebRtcInner {
     input_peer: Mutex<Option<Arc<RTCPeerConnection>>>,
     // Stats channel on input peer (preferred in hybrid mode)
     input_stats_channel: Mutex<Option<Arc<RTCDataChannel>>>,
+    // Clipboard channel of the primary or input peer, the newest one wins
+    clipboard_channel: Mutex<Option<Arc<RTCDataChannel>>>,
+    // File transfer channel of the primary or input peer, the newest one wins
+    file_transfer_channel: Mutex<Option<Arc<RTCDataChannel>>>,
+    // Watch-only peer connections by spectator id
+    spectators: Mutex<HashMap<u32, Arc<RTCPeerConnection>>>,
     // Store config for creating input peer
     rtc_config: RTCConfiguration,
+    webrtc_config: WebRtcConfig,
+    // The DSCP marked socket shared by all peers, if configured
+    udp_mux: Option<Arc<UDPMuxDefault>>,
+    // Payload of video, audio and data channel messages, reported with every pong
+    bytes_sent: AtomicU64,
+    // Last transitions of the primary peer, sent to the web server if it fails
+    connection_log: Mutex<ConnectionLog>,
 }
 
 pub async fn new(
@@ -102,57 +125,33 @@ pub async fn new(
             .into_iter()
             .map(into_webrtc_ice)
             .collect(),
+        ice_transport_policy: if stream_settings.force_relay {
+            RTCIceTransportPolicy::Relay
+        } else {
+            RTCIceTransportPolicy::All
+        },
         ..Default::default()
     };
-    let mut api_settings = SettingEngine::default();
-
-    if let Some(PortRange { min, max }) = config.port_range {
-        match EphemeralUDP::new(min, max) {
-            Ok(udp) => {
-                api_settings.set_udp_network(UDPNetwork::Ephemeral(udp));
-            }
-            Err(err) => {
-                warn!("[Stream]: Invalid port range in config: {err:?}");
-            }
+    if stream_settings.force_relay {
+        info!("[Stream]: Only using relay candidates");
+
+        if !config.has_relay_server() {
+            warn!("[Stream]: Relay candidates are forced but no TURN server is configured");
+        } else if !config.has_streamer_relay_server() {
+            warn!(
+                "[Stream]: Relay candidates are forced but the streamer can only gather them from turn: urls over udp, it won't have any candidates"
+            );
         }
     }
-    if let Some(mapping) = config.nat_1to1.as_ref() {
-        api_settings.set_nat_1to1_ips(
-            mapping.ips.clone(),
-            into_webrtc_ice_candidate(mapping.ice_candidate_type),
-        );
-    }
-    api_settings.set_network_types(
-        config
-            .network_types
-            .iter()
-            .copied()
-            .map(into_webrtc_network_type)
-            .collect(),
+    let udp_mux = bind_dscp_udp_mux(config);
+    let api = create_media_api(
+        config,
+        udp_mux.clone(),
+        stream_settings.video_supported_formats,
+        stream_settings.hdr_enabled,
+        &stream_settings.video_codec_preference,
     );
 
-    api_settings.set_include_loopback_candidate(config.include_loopback_candidates);
-
-    // -- Register media codecs
-    // TODO: register them based on the sdp
-    let mut api_media = MediaEngine::default();
-    register_audio_codecs(&mut api_media).expect("failed to register audio codecs");
-    register_video_codecs(&mut api_media, stream_settings.video_supported_formats)
-        .expect("failed to register video codecs");
-
-    // -- Build Api
-    let mut api_registry = Registry::new();
-
-    // Use the default set of Interceptors
-    api_registry = register_default_interceptors(api_registry, &mut api_media)
-        .expect("failed to register webrtc default interceptors");
-
-    let api = APIBuilder::new()
-        .with_setting_engine(api_settings)
-        .with_media_engine(api_media)
-        .with_interceptor_registry(api_registry)
-        .build();
-
     let (event_sender, event_receiver) = channel::<TransportEvent>(20);
 
     // Send WebRTC Info
@@ -161,6 +160,7 @@ pub async fn new(
             StreamServerMessage::Setup {
                 ice_servers: config.ice_servers.clone(),
                 session_token,
+                force_relay: stream_settings.force_relay,
             },
         )))
         .await
@@ -184,25 +184,42 @@ pub async fn new(
         event_sender,
         general_channel,
         stats_channel: Mutex::new(None),
+        controller_channels: Mutex::new(HashMap::new()),
         video: Mutex::new(WebRtcVideo::new(
             runtime.clone(),
             Arc::downgrade(&peer),
             stream_settings.video_supported_formats,
             stream_settings.video_frame_queue_size as usize,
+            stream_settings.video_frame_queue_max_bytes as usize,
+            stream_settings
+                .video_frame_pacing
+                .then(|| Duration::from_secs_f64(1.0 / stream_settings.fps.max(1) as f64)),
         )),
         audio: Mutex::new(WebRtcAudio::new(
             runtime,
             Arc::downgrade(&peer),
             stream_settings.audio_sample_queue_size as usize,
+            Duration::from_millis(stream_settings.audio_jitter_buffer_ms as u64),
         )),
         timeout_terminate_request: Mutex::new(None),
         input_peer: Mutex::new(None),
         input_stats_channel: Mutex::new(None),
+        clipboard_channel: Mutex::new(None),
+        file_transfer_channel: Mutex::new(None),
+        spectators: Mutex::new(HashMap::new()),
         rtc_config: rtc_config_clone,
+        webrtc_config: config.clone(),
+        udp_mux,
+        bytes_sent: AtomicU64::new(0),
+        connection_log: Mutex::new(ConnectionLog::default()),
     });
 
     let this = Arc::downgrade(&this_owned);
 
+    if let Some(new_samples) = this_owned.audio.lock().await.jitter_buffer_notify() {
+        spawn(release_audio_jitter_buffer(this.clone(), new_samples));
+    }
+
     // -- Connection state
     peer.on_ice_connection_state_change(create_event_handler(
         this.clone(),
@@ -243,6 +260,86 @@ pub async fn new(
     ))
 }
 
+/// The DSCP is the upper 6 bits of the IPv4 TOS byte and of the IPv6 traffic class
+fn bind_dscp_socket(ip: IpAddr, port: u16, dscp: u8) -> io::Result<UdpSocket> {
+    let address = SocketAddr::new(ip, port);
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    match ip {
+        IpAddr::V4(_) => socket.set_tos_v4((dscp as u32) << 2)?,
+        IpAddr::V6(_) => {
+            socket.set_only_v6(true)?;
+            set_tclass_v6(&socket, (dscp as u32) << 2)?;
+        }
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(not(windows))]
+fn set_tclass_v6(socket: &Socket, tclass: u32) -> io::Result<()> {
+    socket.set_tclass_v6(tclass)
+}
+
+#[cfg(windows)]
+fn set_tclass_v6(_socket: &Socket, _tclass: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the IPv6 traffic class can't be set on windows",
+    ))
+}
+
+/// webrtc-rs binds its ephemeral sockets internally, so they can't be marked.
+/// Instead all peers share one marked socket, the first free port of the port range.
+fn bind_dscp_udp_mux(config: &WebRtcConfig) -> Option<Arc<UDPMuxDefault>> {
+    let dscp = config.dscp?;
+
+    // The shared socket can only have one ip version, the other one would be unreachable
+    let udp4 = config
+        .network_types
+        .iter()
+        .any(|network_type| matches!(network_type, WebRtcNetworkType::Udp4));
+    let udp6 = config
+        .network_types
+        .iter()
+        .any(|network_type| matches!(network_type, WebRtcNetworkType::Udp6));
+    let ip = match (udp4, udp6) {
+        (true, false) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        (false, true) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        _ => {
+            warn!(
+                "[Stream]: DSCP marking needs the network types to contain either udp4 or udp6, WebRTC packets won't be marked"
+            );
+            return None;
+        }
+    };
+
+    let ports = match config.port_range {
+        Some(PortRange { min, max }) => min..=max,
+        None => 0..=0,
+    };
+    for port in ports {
+        match bind_dscp_socket(ip, port, dscp) {
+            Ok(socket) => {
+                info!(
+                    "[Stream]: Marking WebRTC packets with DSCP {dscp} on {:?}",
+                    socket.local_addr()
+                );
+                return Some(UDPMuxDefault::new(UDPMuxParams::new(socket)));
+            }
+            Err(err) => debug!("[Stream]: Failed to bind the DSCP socket on port {port}: {err}"),
+        }
+    }
+
+    warn!("[Stream]: Failed to bind a DSCP marked socket, WebRTC packets won't be marked");
+    None
+}
+
 // It compiling...
 #[allow(clippy::complexity)]
 fn create_event_handler<F, Args>(
@@ -275,6 +372,17 @@ where
                 + 'static,
         >
 }
+
+/// Drops the handlers so they don't keep any state alive after the channel is gone
+fn clear_channel_handlers(channel: &RTCDataChannel) {
+    channel.on_message(Box::new(|_| {
+        Box::pin(ready(())) as Pin<Box<dyn Future<Output = ()> + Send + 'static>>
+    }));
+    channel.on_close(Box::new(|| {
+        Box::pin(ready(())) as Pin<Box<dyn Future<Output = ()> + Send + 'static>>
+    }));
+}
+
 #[allow(clippy::complexity)]
 fn create_channel_message_handler(
     inner: Weak<WebRtcInner>,
@@ -285,25 +393,88 @@ fn create_channel_message_handler(
         + Sync
         + 'static,
 > {
+    // Every channel is limited on its own, the handler is created once per channel
+    let limiter = inner.upgrade().map(|inner| {
+        let limits = &inner.webrtc_config.inbound_limits;
+
+        Arc::new(std::sync::Mutex::new(InboundRateLimiter::new(
+            limits.max_packets_per_sec(channel.0),
+            limits.terminate_factor,
+            Instant::now(),
+        )))
+    });
+
     create_event_handler(inner, async move |inner, message: DataChannelMessage| {
+        let max_packet_size = inner.webrtc_config.inbound_limits.max_packet_size;
+        if message.data.len() > max_packet_size {
+            warn!(
+                "[Inbound]: Dropping a packet of {} bytes on channel {}, the limit is {max_packet_size} bytes",
+                message.data.len(),
+                channel.0
+            );
+            return;
+        }
+
+        let verdict = limiter
+            .as_ref()
+            .and_then(|limiter| {
+                limiter
+                    .lock()
+                    .ok()
+                    .map(|mut limiter| limiter.check(Instant::now()))
+            })
+            .unwrap_or(InboundVerdict::Accept);
+        match verdict {
+            InboundVerdict::Accept => {}
+            InboundVerdict::Drop { first_in_window } => {
+                if first_in_window {
+                    warn!(
+                        "[Inbound]: Channel {} exceeds its packet limit, dropping packets for the rest of the second",
+                        channel.0
+                    );
+                }
+                // Releases still count towards the limit, but they're never dropped
+                if !InboundPacket::is_release(channel, &message.data) {
+                    return;
+                }
+            }
+            InboundVerdict::Terminate => {
+                warn!(
+                    "[Inbound]: Channel {} grossly exceeds its packet limit, ending the stream",
+                    channel.0
+                );
+                if let Err(err) = inner.event_sender.send(TransportEvent::Closed).await {
+                    warn!("Failed to send that the peer should close: {err:?}");
+                }
+                return;
+            }
+        }
+
         let Some(packet) = InboundPacket::deserialize(channel, &message.data) else {
             return;
         };
 
-        if let Err(err) = inner
-            .event_sender
-            .send(TransportEvent::RecvPacket(packet))
-            .await
-        {
-            warn!("Failed to dispatch RecvPacket event: {err:?}");
+        let event = match packet {
+            InboundPacket::General {
+                message: GeneralClientMessage::RequestKeyframe,
+            } => TransportEvent::RequestKeyframe,
+            packet => TransportEvent::RecvPacket(packet),
+        };
+
+        if let Err(err) = inner.event_sender.send(event).await {
+            warn!("Failed to dispatch inbound packet event: {err:?}");
         };
     })
 }
 
 impl WebRtcInner {
     // -- Handle Connection State
-    async fn on_ice_connection_state_change(self: &Arc<Self>, _state: RTCIceConnectionState) {}
+    async fn on_ice_connection_state_change(self: &Arc<Self>, state: RTCIceConnectionState) {
+        self.connection_log.lock().await.ice_state_changed(state);
+    }
     async fn on_peer_connection_state_change(self: Arc<Self>, state: RTCPeerConnectionState) {
+        self.connection_log.lock().await.peer_state_changed(state);
+
         #[allow(clippy::collapsible_if)]
         if matches!(state, RTCPeerConnectionState::Connected) {
             if let Err(err) = self
@@ -320,10 +491,31 @@ impl WebRtcInner {
                 warn!("Failed to send peer closed event to stream: {err:?}");
                 self.request_terminate().await;
             };
-        } else if matches!(
-            state,
-            RTCPeerConnectionState::Failed | RTCPeerConnectionState::Disconnected
-        ) {
+        } else if matches!(state, RTCPeerConnectionState::Failed) {
+            let entries = self.connection_log.lock().await.entries();
+            if let Err(err) = self
+                .event_sender
+                .send(TransportEvent::SendIpc(StreamerIpcMessage::ConnectionLog {
+                    entries,
+                }))
+                .await
+            {
+                warn!("Failed to send the connection log of the failed peer: {err:?}");
+            }
+
+            // Tell the client before terminating so it knows a retry might work
+            if let Err(err) = self
+                .event_sender
+                .send(TransportEvent::SendIpc(StreamerIpcMessage::WebSocket(
+                    StreamServerMessage::PeerFailed,
+                )))
+                .await
+            {
+                warn!("Failed to send peer failed event to stream: {err:?}");
+            }
+
+            self.request_terminate().await;
+        } else if matches!(state, RTCPeerConnectionState::Disconnected) {
             self.request_terminate().await;
         } else {
             self.clear_terminate_request().await;
@@ -353,6 +545,7 @@ impl WebRtcInner {
             "[Signaling] Sending Local Description as Answer: {:?}",
             local_description.sdp
         );
+        self.connection_log.lock().await.record("sent answer");
 
         if let Err(err) = self
             .event_sender
@@ -393,6 +586,7 @@ impl WebRtcInner {
             "[Signaling] Sending Local Description as Offer: {:?}",
             local_description.sdp
         );
+        self.connection_log.lock().await.record("sent offer");
 
         if let Err(err) = self
             .event_sender
@@ -412,6 +606,31 @@ impl WebRtcInner {
         true
     }
 
+    async fn send_negotiated_format(&self) {
+        // The answer contains the codec which was selected
+        let answer = match self.peer.current_local_description().await {
+            Some(local) if local.sdp_type == RTCSdpType::Answer => Some(local),
+            _ => self.peer.current_remote_description().await,
+        };
+
+        let Some((codec, profile)) = answer.and_then(|answer| negotiated_video_codec(&answer.sdp))
+        else {
+            return;
+        };
+
+        info!("[Signaling]: Negotiated video codec {codec} with profile {profile:?}");
+
+        if let Err(err) = self
+            .event_sender
+            .send(TransportEvent::SendIpc(StreamerIpcMessage::WebSocket(
+                StreamServerMessage::NegotiatedFormat { codec, profile },
+            )))
+            .await
+        {
+            warn!("Failed to send negotiated format via web socket from peer: {err:?}");
+        }
+    }
+
     async fn on_ws_message(&self, message: StreamClientMessage) {
         match message {
             StreamClientMessage::WebRtc(StreamSignalingMessage::Description(description)) => {
@@ -438,13 +657,23 @@ impl WebRtcInner {
                 let remote_ty = description.sdp_type;
                 if let Err(err) = self.peer.set_remote_description(description).await {
                     warn!("[Signaling]: failed to set remote description: {err:?}");
+                    self.connection_log
+                        .lock()
+                        .await
+                        .record(format!("failed to set remote {remote_ty}: {err}"));
                     return;
                 }
+                self.connection_log
+                    .lock()
+                    .await
+                    .record(format!("received {remote_ty}"));
 
                 // Send an answer (local description) if we got an offer
-                if remote_ty == RTCSdpType::Offer {
-                    self.send_answer().await;
+                if remote_ty == RTCSdpType::Offer && !self.send_answer().await {
+                    return;
                 }
+
+                self.send_negotiated_format().await;
             }
             StreamClientMessage::WebRtc(StreamSignalingMessage::AddIceCandidate(description)) => {
                 debug!("[Signaling] Received Ice Candidate");
@@ -464,13 +693,39 @@ impl WebRtcInner {
             }
             // This should already be done
             StreamClientMessage::Init { .. } => {}
+            // Handled by the web server
+            StreamClientMessage::Reconnect { .. }
+            | StreamClientMessage::Spectate { .. }
+            | StreamClientMessage::ConfirmCancel { .. } => {}
         }
     }
 
+    /// Candidates in an excluded ip range aren't sent, excluded interface names are already
+    /// filtered while gathering
+    fn is_excluded_candidate(&self, candidate: &RTCIceCandidate) -> bool {
+        // mDNS candidates don't have an ip
+        let Ok(ip) = candidate.address.parse::<IpAddr>() else {
+            return false;
+        };
+
+        let excluded = self.webrtc_config.excludes_ip(ip);
+        if excluded {
+            debug!(
+                "[Signaling] Dropping {} ICE candidate in an excluded range: {ip}",
+                candidate.typ
+            );
+        }
+
+        excluded
+    }
+
     async fn on_ice_candidate(&self, candidate: Option<RTCIceCandidate>) {
         let Some(candidate) = candidate else {
             return;
         };
+        if self.is_excluded_candidate(&candidate) {
+            return;
+        }
 
         let Ok(candidate_json) = candidate.to_json() else {
             return;
@@ -529,6 +784,12 @@ impl WebRtcInner {
 
                 *stats = Some(channel);
             }
+            "general" => {
+                channel.on_message(create_channel_message_handler(
+                    inner,
+                    TransportChannel(TransportChannelId::GENERAL),
+                ));
+            }
             "mouse_reliable" | "mouse_absolute" | "mouse_relative" => {
                 channel.on_message(create_channel_message_handler(
                     inner,
@@ -557,10 +818,15 @@ impl WebRtcInner {
                 && let Ok(id) = number.parse::<usize>()
                 && id < InboundPacket::CONTROLLER_CHANNELS.len() =>
             {
-                channel.on_message(create_channel_message_handler(
-                    inner,
-                    TransportChannel(InboundPacket::CONTROLLER_CHANNELS[id]),
-                ));
+                self.add_controller_channel(id, channel).await;
+            }
+            "clipboard" => {
+                self.set_reliable_channel(TransportChannelId::CLIPBOARD, channel)
+                    .await;
+            }
+            "file_transfer" => {
+                self.set_reliable_channel(TransportChannelId::FILE_TRANSFER, channel)
+                    .await;
             }
             _ => {}
         };
@@ -572,12 +838,127 @@ impl WebRtcInner {
         *stats = None;
     }
 
+    /// The stored channel for clipboard and file transfer messages
+    fn reliable_channel(&self, channel_id: u8) -> Option<&Mutex<Option<Arc<RTCDataChannel>>>> {
+        match channel_id {
+            TransportChannelId::CLIPBOARD => Some(&self.clipboard_channel),
+            TransportChannelId::FILE_TRANSFER => Some(&self.file_transfer_channel),
+            _ => None,
+        }
+    }
+
+    async fn set_reliable_channel(self: &Arc<Self>, channel_id: u8, channel: Arc<RTCDataChannel>) {
+        let Some(slot) = self.reliable_channel(channel_id) else {
+            return;
+        };
+
+        // Clipboard texts and files are split into chunks which must all arrive in order
+        if !channel.ordered()
+            || channel.max_retransmits().is_some()
+            || channel.max_packet_lifetime().is_some()
+        {
+            warn!(
+                "Ignoring data channel \"{}\" because it isn't ordered and reliable",
+                channel.label()
+            );
+            return;
+        }
+
+        channel.on_message(create_channel_message_handler(
+            Arc::downgrade(self),
+            TransportChannel(channel_id),
+        ));
+        channel.on_close({
+            let this = Arc::downgrade(self);
+
+            Box::new(move || {
+                let this = this.clone();
+
+                Box::pin(async move {
+                    let Some(this) = this.upgrade() else {
+                        return;
+                    };
+
+                    if let Some(slot) = this.reliable_channel(channel_id) {
+                        *slot.lock().await = None;
+                    }
+                })
+            })
+        });
+
+        // The handlers of a replaced channel are cleared so its close can't remove the new one
+        if let Some(old_channel) = slot.lock().await.replace(channel) {
+            debug!("Replacing data channel \"{}\"", old_channel.label());
+            clear_channel_handlers(&old_channel);
+        }
+    }
+
+    async fn add_controller_channel(self: &Arc<Self>, id: usize, channel: Arc<RTCDataChannel>) {
+        let mut controller_channels = self.controller_channels.lock().await;
+
+        // The newest channel wins, e.g. the client reopened it after a renegotiation
+        if let Some(old_channel) = controller_channels.remove(&id) {
+            warn!("Received duplicate data channel for controller {id}, replacing the old channel");
+
+            clear_channel_handlers(&old_channel);
+            if let Err(err) = old_channel.close().await {
+                debug!("Failed to close duplicate controller channel {id}: {err:?}");
+            }
+        }
+
+        channel.on_message(create_channel_message_handler(
+            Arc::downgrade(self),
+            TransportChannel(InboundPacket::CONTROLLER_CHANNELS[id]),
+        ));
+
+        channel.on_close({
+            let this = Arc::downgrade(self);
+            let channel = Arc::downgrade(&channel);
+
+            Box::new(move || {
+                let this = this.clone();
+                let channel = channel.clone();
+
+                Box::pin(async move {
+                    let Some(this) = this.upgrade() else {
+                        warn!("Failed to close controller channel because the main type is already deallocated");
+                        return;
+                    };
+
+                    this.close_controller_channel(id, &channel).await;
+                })
+            })
+        });
+
+        controller_channels.insert(id, channel);
+    }
+
+    async fn close_controller_channel(&self, id: usize, channel: &Weak<RTCDataChannel>) {
+        let mut controller_channels = self.controller_channels.lock().await;
+
+        // The channel might already be replaced by a newer one
+        if let Some(current) = controller_channels.get(&id)
+            && ptr::eq(Arc::as_ptr(current), channel.as_ptr())
+        {
+            if let Some(channel) = controller_channels.remove(&id) {
+                clear_channel_handlers(&channel);
+            }
+
+            debug!("Controller channel {id} closed");
+        }
+    }
+
     // -- Input Peer (Hybrid Mode)
     async fn create_input_peer(self: &Arc<Self>) {
         info!("[InputPeer]: Creating input-only peer connection");
 
         // Create a new peer connection for input only (no media)
-        let api = APIBuilder::new().build();
+        let api = APIBuilder::new()
+            .with_setting_engine(create_setting_engine(
+                &self.webrtc_config,
+                self.udp_mux.clone(),
+            ))
+            .build();
 
         let input_peer = match api.new_peer_connection(self.rtc_config.clone()).await {
             Ok(peer) => Arc::new(peer),
@@ -711,6 +1092,20 @@ impl WebRtcInner {
             ).await;
         }
 
+        // Clipboard and file transfer channels (ordered and reliable because the data is chunked)
+        for (name, channel_id) in [
+            ("clipboard", TransportChannelId::CLIPBOARD),
+            ("file_transfer", TransportChannelId::FILE_TRANSFER),
+        ] {
+            match input_peer
+                .create_data_channel(name, Some(ordered_config.clone()))
+                .await
+            {
+                Ok(channel) => self.set_reliable_channel(channel_id, channel).await,
+                Err(err) => error!("[InputPeer]: Failed to create {name} channel: {err:?}"),
+            }
+        }
+
         // Stats channel for latency info (ordered) - store reference for sending stats
         if let Ok(stats_channel) = input_peer.create_data_channel("stats", Some(ordered_config.clone())).await {
             stats_channel.on_close({
@@ -825,6 +1220,9 @@ impl WebRtcInner {
         let Some(candidate) = candidate else {
             return;
         };
+        if self.is_excluded_candidate(&candidate) {
+            return;
+        }
 
         let Ok(candidate_json) = candidate.to_json() else {
             return;
@@ -910,10 +1308,15 @@ impl WebRtcInner {
                 && let Ok(id) = number.parse::<usize>()
                 && id < InboundPacket::CONTROLLER_CHANNELS.len() =>
             {
-                channel.on_message(create_channel_message_handler(
-                    inner,
-                    TransportChannel(InboundPacket::CONTROLLER_CHANNELS[id]),
-                ));
+                self.add_controller_channel(id, channel).await;
+            }
+            "clipboard" => {
+                self.set_reliable_channel(TransportChannelId::CLIPBOARD, channel)
+                    .await;
+            }
+            "file_transfer" => {
+                self.set_reliable_channel(TransportChannelId::FILE_TRANSFER, channel)
+                    .await;
             }
             _ => {
                 debug!("[InputPeer]: Unknown data channel: {label}");
@@ -933,9 +1336,21 @@ impl WebRtcInner {
             {
                 warn!("[InputPeer]: Failed to send InputReady: {err:?}");
             }
+        } else if matches!(state, RTCPeerConnectionState::Failed) {
+            warn!("[InputPeer]: Input peer ICE failed");
+            if let Err(err) = self
+                .event_sender
+                .send(TransportEvent::SendIpc(StreamerIpcMessage::InputFailed))
+                .await
+            {
+                warn!("[InputPeer]: Failed to send InputFailed: {err:?}");
+            }
+
+            let mut input_peer_guard = self.input_peer.lock().await;
+            *input_peer_guard = None;
         } else if matches!(
             state,
-            RTCPeerConnectionState::Failed | RTCPeerConnectionState::Disconnected | RTCPeerConnectionState::Closed
+            RTCPeerConnectionState::Disconnected | RTCPeerConnectionState::Closed
         ) {
             info!("[InputPeer]: Input peer disconnected");
             // Clean up input peer
@@ -960,6 +1375,246 @@ impl WebRtcInner {
         info!("[InputPeer]: Input peer closed");
     }
 
+    // -- Spectators
+    async fn add_spectator(self: &Arc<Self>, spectator_id: u32) {
+        info!("[Spectator]: Creating peer for spectator {spectator_id}");
+
+        let api = create_media_api(
+            &self.webrtc_config,
+            self.udp_mux.clone(),
+            self.stream_settings.video_supported_formats,
+            self.stream_settings.hdr_enabled,
+            &self.stream_settings.video_codec_preference,
+        );
+
+        let peer = match api.new_peer_connection(self.rtc_config.clone()).await {
+            Ok(peer) => Arc::new(peer),
+            Err(err) => {
+                error!("[Spectator]: Failed to create peer for spectator {spectator_id}: {err:?}");
+                return;
+            }
+        };
+
+        // The spectator gets bound to the tracks of the primary peer, nothing is encoded twice
+        let video_result = self.video.lock().await.add_spectator(&peer).await;
+        let audio_result = self.audio.lock().await.add_spectator(&peer).await;
+        if let Err(err) = video_result.and(audio_result) {
+            warn!("[Spectator]: Failed to add tracks for spectator {spectator_id}: {err:?}");
+
+            let _ = peer.close().await;
+            return;
+        }
+
+        let inner = Arc::downgrade(self);
+
+        peer.on_ice_candidate(create_event_handler(
+            inner.clone(),
+            async move |this, candidate| {
+                this.on_spectator_ice_candidate(spectator_id, candidate)
+                    .await;
+            },
+        ));
+        peer.on_peer_connection_state_change(create_event_handler(
+            inner,
+            async move |this, state| {
+                this.on_spectator_state_change(spectator_id, state).await;
+            },
+        ));
+
+        // Spectators can't send input
+        peer.on_data_channel(Box::new(move |channel: Arc<RTCDataChannel>| {
+            Box::pin(async move {
+                warn!(
+                    "[Spectator]: Refusing data channel \"{}\" of spectator {spectator_id}",
+                    channel.label()
+                );
+
+                if let Err(err) = channel.close().await {
+                    debug!("[Spectator]: Failed to close refused data channel: {err:?}");
+                }
+            })
+        }));
+
+        {
+            let mut spectators = self.spectators.lock().await;
+            if let Some(old_peer) = spectators.insert(spectator_id, peer.clone()) {
+                warn!("[Spectator]: Replacing existing peer of spectator {spectator_id}");
+                let _ = old_peer.close().await;
+            }
+        }
+
+        // The streamer initiates the negotiation, the spectator only has to answer
+        let offer = match peer.create_offer(None).await {
+            Ok(offer) => offer,
+            Err(err) => {
+                error!("[Spectator]: Failed to create offer for spectator {spectator_id}: {err:?}");
+                return;
+            }
+        };
+        if let Err(err) = peer.set_local_description(offer.clone()).await {
+            error!("[Spectator]: Failed to set local description: {err:?}");
+            return;
+        }
+
+        if let Err(err) = self
+            .event_sender
+            .send(TransportEvent::SendIpc(
+                StreamerIpcMessage::SpectatorSignaling {
+                    spectator_id,
+                    signaling: StreamSignalingMessage::Description(RtcSessionDescription {
+                        ty: from_webrtc_sdp(offer.sdp_type),
+                        sdp: offer.sdp,
+                    }),
+                },
+            ))
+            .await
+        {
+            error!("[Spectator]: Failed to send offer: {err:?}");
+        }
+    }
+
+    async fn on_spectator_signaling(&self, spectator_id: u32, signaling: StreamSignalingMessage) {
+        let peer = {
+            let spectators = self.spectators.lock().await;
+            spectators.get(&spectator_id).cloned()
+        };
+        let Some(peer) = peer else {
+            warn!("[Spectator]: Received signaling for unknown spectator {spectator_id}");
+            return;
+        };
+
+        match signaling {
+            StreamSignalingMessage::Description(description) => {
+                let description = match &description.ty {
+                    RtcSdpType::Answer => RTCSessionDescription::answer(description.sdp),
+                    RtcSdpType::Pranswer => RTCSessionDescription::pranswer(description.sdp),
+                    _ => {
+                        warn!(
+                            "[Spectator]: Unsupported SDP type from spectator {spectator_id}: {:?}",
+                            description.ty
+                        );
+                        return;
+                    }
+                };
+
+                let Ok(description) = description else {
+                    warn!("[Spectator]: Invalid RTCSessionDescription");
+                    return;
+                };
+
+                if let Err(err) = peer.set_remote_description(description).await {
+                    warn!("[Spectator]: Failed to set remote description: {err:?}");
+                }
+            }
+            StreamSignalingMessage::AddIceCandidate(candidate) => {
+                if let Err(err) = peer
+                    .add_ice_candidate(RTCIceCandidateInit {
+                        candidate: candidate.candidate,
+                        sdp_mid: candidate.sdp_mid,
+                        sdp_mline_index: candidate.sdp_mline_index,
+                        username_fragment: candidate.username_fragment,
+                    })
+                    .await
+                {
+                    warn!("[Spectator]: Failed to add ICE candidate: {err:?}");
+                }
+            }
+        }
+    }
+
+    async fn on_spectator_ice_candidate(
+        &self,
+        spectator_id: u32,
+        candidate: Option<RTCIceCandidate>,
+    ) {
+        let Some(candidate) = candidate else {
+            return;
+        };
+        if self.is_excluded_candidate(&candidate) {
+            return;
+        }
+
+        let Ok(candidate_json) = candidate.to_json() else {
+            return;
+        };
+
+        if let Err(err) = self
+            .event_sender
+            .send(TransportEvent::SendIpc(
+                StreamerIpcMessage::SpectatorSignaling {
+                    spectator_id,
+                    signaling: StreamSignalingMessage::AddIceCandidate(RtcIceCandidate {
+                        candidate: candidate_json.candidate,
+                        sdp_mid: candidate_json.sdp_mid,
+                        sdp_mline_index: candidate_json.sdp_mline_index,
+                        username_fragment: candidate_json.username_fragment,
+                    }),
+                },
+            ))
+            .await
+        {
+            error!("[Spectator]: Failed to send ICE candidate: {err:?}");
+        }
+    }
+
+    async fn on_spectator_state_change(&self, spectator_id: u32, state: RTCPeerConnectionState) {
+        debug!("[Spectator]: Connection state of spectator {spectator_id} changed: {state:?}");
+
+        if matches!(
+            state,
+            RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed
+        ) {
+            self.remove_spectator(spectator_id).await;
+        }
+    }
+
+    async fn remove_spectator(&self, spectator_id: u32) {
+        let peer = {
+            let mut spectators = self.spectators.lock().await;
+            spectators.remove(&spectator_id)
+        };
+
+        if let Some(peer) = peer {
+            // Closing the peer unbinds it from the shared tracks
+            if let Err(err) = peer.close().await {
+                warn!("[Spectator]: Failed to close peer of spectator {spectator_id}: {err:?}");
+            }
+            info!("[Spectator]: Spectator {spectator_id} removed");
+        }
+    }
+
+    async fn close_spectators(&self) {
+        let peers = {
+            let mut spectators = self.spectators.lock().await;
+            spectators.drain().collect::<Vec<_>>()
+        };
+
+        for (spectator_id, peer) in peers {
+            if let Err(err) = peer.close().await {
+                warn!("[Spectator]: Failed to close peer of spectator {spectator_id}: {err:?}");
+            }
+        }
+    }
+
+    // -- Heartbeat
+    async fn on_ping(&self, sequence: u32) {
+        // A stream stuck on one of these locks must miss its pongs
+        drop(self.video.lock().await);
+        drop(self.audio.lock().await);
+
+        // The pong goes through the event loop so that gets checked too
+        if let Err(err) = self
+            .event_sender
+            .send(TransportEvent::SendIpc(StreamerIpcMessage::Pong {
+                sequence,
+                bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            }))
+            .await
+        {
+            warn!("Failed to send pong: {err:?}");
+        }
+    }
+
     // -- Termination
     async fn request_terminate(self: &Arc<Self>) {
         let this = self.clone();
@@ -1022,9 +1677,24 @@ impl TransportSender for WebRTCTransportSender {
         &'a self,
         unit: &'a VideoDecodeUnit<'a>,
     ) -> Result<DecodeResult, TransportError> {
+        let size = unit.buffers.iter().map(|buffer| buffer.data.len()).sum::<usize>();
+        self.inner
+            .bytes_sent
+            .fetch_add(size as u64, Ordering::Relaxed);
+
         let mut video = self.inner.video.lock().await;
         Ok(video.send_decode_unit(unit).await)
     }
+    async fn video_queue_stats(&self) -> VideoQueueStats {
+        let video = self.inner.video.lock().await;
+        video.queue_stats().await
+    }
+    async fn reception_stats(&self) -> ReceptionStats {
+        let video = self.inner.video.lock().await.reception_stats().await;
+        let audio = self.inner.audio.lock().await.reception_stats().await;
+
+        ReceptionStats { video, audio }
+    }
 
     async fn setup_audio(
         &self,
@@ -1036,6 +1706,10 @@ impl TransportSender for WebRTCTransportSender {
         audio.setup(&self.inner, audio_config, stream_config).await
     }
     async fn send_audio_sample(&self, data: &[u8]) -> Result<(), TransportError> {
+        self.inner
+            .bytes_sent
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+
         let mut audio = self.inner.audio.lock().await;
 
         audio.send_audio_sample(data).await;
@@ -1054,6 +1728,10 @@ impl TransportSender for WebRTCTransportSender {
         let bytes = Bytes::from(buffer);
         let bytes = bytes.slice(range);
 
+        self.inner
+            .bytes_sent
+            .fetch_add(bytes.len() as u64, Ordering::Relaxed);
+
         match channel.0 {
             TransportChannelId::GENERAL => match self.inner.general_channel.send(&bytes).await {
                 Ok(_) => {}
@@ -1064,32 +1742,27 @@ impl TransportSender for WebRTCTransportSender {
             },
             TransportChannelId::STATS => {
                 // In hybrid mode, prefer the input stats channel (native client)
-                // Fall back to primary stats channel if input not available
-                let input_stats = self.inner.input_stats_channel.lock().await;
-                if let Some(input_stats) = input_stats.as_ref() {
-                    match input_stats.send(&bytes).await {
-                        Ok(_) => {}
-                        Err(webrtc::Error::ErrDataChannelNotOpen) => {
-                            return Err(TransportError::ChannelClosed);
-                        }
-                        _ => {}
-                    }
-                } else {
-                    // Fall back to primary stats channel
-                    drop(input_stats);
-                    let stats = self.inner.stats_channel.lock().await;
-                    if let Some(stats) = stats.as_ref() {
-                        match stats.send(&bytes).await {
-                            Ok(_) => {}
-                            Err(webrtc::Error::ErrDataChannelNotOpen) => {
-                                return Err(TransportError::ChannelClosed);
-                            }
-                            _ => {}
-                        }
-                    } else {
-                        return Err(TransportError::ChannelClosed);
-                    }
-                }
+                // Fall back to primary stats channel if input not available or closed
+                let input_stats = self.inner.input_stats_channel.lock().await.clone();
+                let stats = self.inner.stats_channel.lock().await.clone();
+
+                return send_with_fallback(input_stats.into_iter().chain(stats), |channel| {
+                    let bytes = bytes.clone();
+                    async move { channel.send(&bytes).await }
+                })
+                .await;
+            }
+            TransportChannelId::CLIPBOARD | TransportChannelId::FILE_TRANSFER => {
+                let reliable_channel = match self.inner.reliable_channel(channel.0) {
+                    Some(slot) => slot.lock().await.clone(),
+                    None => None,
+                };
+
+                return send_with_fallback(reliable_channel, |channel| {
+                    let bytes = bytes.clone();
+                    async move { channel.send(&bytes).await }
+                })
+                .await;
             }
             _ => {
                 warn!("Cannot send data on channel {channel:?}");
@@ -1116,6 +1789,35 @@ impl TransportSender for WebRTCTransportSender {
                 info!("[WebRTC]: Input connection disconnected");
                 self.inner.close_input_peer().await;
             }
+            ServerIpcMessage::SpectatorJoined { spectator_id } => {
+                if !self.inner.stream_settings.allow_spectators {
+                    warn!(
+                        "[WebRTC]: Spectator {spectator_id} joined but spectators aren't allowed"
+                    );
+                    return Ok(());
+                }
+
+                self.inner.add_spectator(spectator_id).await;
+            }
+            ServerIpcMessage::SpectatorSignaling {
+                spectator_id,
+                signaling,
+            } => {
+                self.inner
+                    .on_spectator_signaling(spectator_id, signaling)
+                    .await;
+            }
+            ServerIpcMessage::SpectatorLeft { spectator_id } => {
+                self.inner.remove_spectator(spectator_id).await;
+            }
+            ServerIpcMessage::Ping { sequence } => {
+                self.inner.on_ping(sequence).await;
+            }
+            ServerIpcMessage::Clipboard { text } => {
+                for packet in OutboundPacket::clipboard_chunks(&text) {
+                    self.send(packet).await?;
+                }
+            }
             ServerIpcMessage::Init { .. } | ServerIpcMessage::Stop => {
                 // These are handled elsewhere
             }
@@ -1124,6 +1826,8 @@ impl TransportSender for WebRTCTransportSender {
     }
 
     async fn close(&self) -> Result<(), TransportError> {
+        self.inner.close_spectators().await;
+
         self.inner
             .peer
             .close()
@@ -1133,3 +1837,79 @@ impl TransportSender for WebRTCTransportSender {
         Ok(())
     }
 }
+
+/// Sends on the first channel which is open
+async fn send_with_fallback<C, F, Fut>(
+    channels: impl IntoIterator<Item = C>,
+    mut send: F,
+) -> Result<(), TransportError>
+where
+    F: FnMut(C) -> Fut,
+    Fut: Future<Output = Result<usize, webrtc::Error>>,
+{
+    for channel in channels {
+        match send(channel).await {
+            Err(webrtc::Error::ErrDataChannelNotOpen) => continue,
+            _ => return Ok(()),
+        }
+    }
+
+    Err(TransportError::ChannelClosed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tokio::runtime::Builder;
+
+    use crate::transport::{TransportError, webrtc::send_with_fallback};
+
+    /// Sends to the named channels and returns the result and which channels were tried
+    fn send(channels: &[(&'static str, bool)]) -> (Result<(), TransportError>, Vec<&'static str>) {
+        let tried = Mutex::new(Vec::new());
+
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let result = runtime.block_on(send_with_fallback(
+            channels.iter().copied(),
+            |(name, open)| {
+                tried.lock().unwrap().push(name);
+                async move {
+                    if open {
+                        Ok(1)
+                    } else {
+                        Err(webrtc::Error::ErrDataChannelNotOpen)
+                    }
+                }
+            },
+        ));
+
+        (result, tried.into_inner().unwrap())
+    }
+
+    #[test]
+    fn test_stats_prefer_input() {
+        let (result, tried) = send(&[("input", true), ("primary", true)]);
+
+        assert!(result.is_ok());
+        assert_eq!(tried, vec!["input"]);
+    }
+
+    #[test]
+    fn test_stats_fall_back_to_primary_when_input_closed() {
+        let (result, tried) = send(&[("input", false), ("primary", true)]);
+
+        assert!(result.is_ok());
+        assert_eq!(tried, vec!["input", "primary"]);
+    }
+
+    #[test]
+    fn test_stats_all_closed() {
+        let (result, tried) = send(&[("input", false), ("primary", false)]);
+
+        assert!(matches!(result, Err(TransportError::ChannelClosed)));
+        assert_eq!(tried, vec!["input", "primary"]);
+
+        assert!(matches!(send(&[]).0, Err(TransportError::ChannelClosed)));
+    }
+}
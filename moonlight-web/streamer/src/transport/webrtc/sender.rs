@@ -1,7 +1,10 @@
 use std::{
-    collections::VecDeque,
-    sync::{Arc, Weak},
-    time::{SystemTime, UNIX_EPOCH},
+    collections::{BTreeMap, VecDeque},
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::anyhow;
@@ -9,11 +12,13 @@ use log::{debug, warn};
 use tokio::{
     runtime::Handle,
     sync::{Mutex, Notify},
+    task::JoinHandle,
+    time::sleep_until,
 };
 use webrtc::{
     media::Sample,
     peer_connection::RTCPeerConnection,
-    rtcp::packet::Packet,
+    rtcp::{packet::Packet, receiver_report::ReceiverReport, reception_report::ReceptionReport},
     rtp::{
         self,
         extension::{
@@ -21,12 +26,15 @@ use webrtc::{
             playout_delay_extension::PlayoutDelayExtension,
         },
     },
+    rtp_transceiver::rtp_sender::RTCRtpSender,
     track::track_local::{
         TrackLocal, track_local_static_rtp::TrackLocalStaticRTP,
         track_local_static_sample::TrackLocalStaticSample,
     },
 };
 
+use crate::transport::StreamReception;
+
 pub struct TrackLocalSender<Track>
 where
     Track: TrackLike,
@@ -34,8 +42,23 @@ where
     runtime: Handle,
     peer: Weak<RTCPeerConnection>,
     channel_queue_size: usize,
+    /// 0 = unlimited
+    channel_queue_max_bytes: usize,
     new_samples_notify: Arc<Notify>,
     queue: Arc<Mutex<VecDeque<FrameSamples<Track>>>>,
+    track: Option<Arc<dyn TrackLocal + Send + Sync + 'static>>,
+    /// Writes the queued frames into the track
+    sample_sender_task: Option<JoinHandle<()>>,
+    /// The senders of the track on every peer which is still open, used to replace the track
+    rtp_senders: Arc<Mutex<Vec<Arc<RTCRtpSender>>>>,
+    /// The last receiver report of every peer by the slot of the peer
+    reception_reports: Arc<Mutex<BTreeMap<usize, ReceptionReport>>>,
+    /// Every peer gets the next slot when it's added, so the primary peer has the first one
+    next_peer_slot: AtomicUsize,
+    /// None = frames are sent as soon as they're queued
+    frame_interval: Option<Duration>,
+    /// How long the last sent frame waited in the queue
+    queue_delay_micros: Arc<AtomicU64>,
 }
 
 struct FrameSamples<Track>
@@ -43,28 +66,98 @@ where
     Track: TrackLike,
 {
     important: bool,
+    size: usize,
+    queued_at: Instant,
     samples: Vec<Track::Sample>,
 }
 
+/// Frames queued above this are sent without waiting, so a host which runs slightly faster
+/// than the requested fps can't build up latency
+const MAX_PACED_FRAMES: usize = 4;
+
+/// Releases frames at a steady cadence instead of the irregular arrival from the host.
+///
+/// The first frame (and the first frame after the queue ran empty) is held back for one interval,
+/// which absorbs the arrival jitter of the following frames.
+struct FramePacer {
+    interval: Duration,
+    max_frames: usize,
+    next_release: Option<Instant>,
+}
+
+impl FramePacer {
+    /// Catching up starts before the queue is full, because dropped frames would force a keyframe
+    fn new(interval: Duration, queue_size: usize) -> Self {
+        Self {
+            interval,
+            max_frames: queue_size.saturating_sub(1).clamp(1, MAX_PACED_FRAMES),
+            next_release: None,
+        }
+    }
+
+    /// When the oldest queued frame should be sent
+    fn release_at(&self, now: Instant, queued_frames: usize, oldest_queued_at: Instant) -> Instant {
+        if queued_frames > self.max_frames {
+            return now;
+        }
+
+        self.next_release
+            .unwrap_or(oldest_queued_at + self.interval)
+    }
+
+    fn released(&mut self, now: Instant) {
+        let scheduled = self.next_release.unwrap_or(now);
+
+        // Keep the cadence unless we fell behind by a whole frame
+        self.next_release = Some(if now >= scheduled + self.interval {
+            now + self.interval
+        } else {
+            scheduled + self.interval
+        });
+    }
+
+    fn ran_empty(&mut self) {
+        self.next_release = None;
+    }
+}
+
 impl<Track> TrackLocalSender<Track>
 where
     Track: TrackLike,
 {
-    pub fn new(runtime: Handle, peer: Weak<RTCPeerConnection>, channel_queue_size: usize) -> Self {
+    pub fn new(
+        runtime: Handle,
+        peer: Weak<RTCPeerConnection>,
+        channel_queue_size: usize,
+        channel_queue_max_bytes: usize,
+    ) -> Self {
         Self {
             runtime,
             peer,
             channel_queue_size,
+            channel_queue_max_bytes,
             new_samples_notify: Default::default(),
             queue: Default::default(),
+            track: None,
+            sample_sender_task: None,
+            rtp_senders: Default::default(),
+            reception_reports: Default::default(),
+            next_peer_slot: Default::default(),
+            frame_interval: None,
+            queue_delay_micros: Default::default(),
         }
     }
 
+    /// Sends one frame per interval, must be set before the track is created
+    pub fn set_frame_pacing(&mut self, frame_interval: Option<Duration>) {
+        self.frame_interval = frame_interval;
+    }
+
     // TODO: make the blocking calls use runtime.block_on
     pub async fn create_track(
         &mut self,
         track: Track,
-        mut on_packet: impl FnMut(Box<dyn Packet + Send + Sync>) + Send + 'static,
+        on_packet: impl FnMut(Box<dyn Packet + Send + Sync>) + Send + 'static,
     ) -> Result<(), anyhow::Error> {
         let Some(peer) = self.peer.upgrade() else {
             return Err(anyhow!(
@@ -73,17 +166,92 @@ where
         };
 
         let track = Arc::new(track);
+        self.spawn_sample_sender(track.clone());
+
+        self.track = Some(track.track());
+
+        self.add_to_peer(&peer, on_packet).await
+    }
+
+    /// Swaps the track on every peer, e.g. because the host changed the codec.
+    ///
+    /// The queued frames belong to the old track and are dropped.
+    /// The peers have to be renegotiated afterwards if the codec isn't part of their current answer.
+    pub async fn replace_track(&mut self, track: Track) -> Result<(), anyhow::Error> {
+        if self.track.is_none() {
+            return Err(anyhow!(
+                "Failed to replace track because it wasn't created yet"
+            ));
+        }
+
+        if let Some(task) = self.sample_sender_task.take() {
+            task.abort();
+        }
+        self.queue.lock().await.clear();
+
+        let track = Arc::new(track);
+        self.spawn_sample_sender(track.clone());
+
+        let track = track.track();
+        self.track = Some(track.clone());
+
+        // One failing peer mustn't keep the others on the old track
+        for rtp_sender in self.rtp_senders.lock().await.iter() {
+            if let Err(err) = rtp_sender.replace_track(Some(track.clone())).await {
+                warn!("Failed to replace the track of a peer: {err:?}");
+            }
+        }
 
+        Ok(())
+    }
+
+    fn spawn_sample_sender(&mut self, track: Arc<Track>) {
         let new_samples_notify = self.new_samples_notify.clone();
         let queue = Arc::downgrade(&self.queue);
-        self.runtime.spawn({
-            let track = track.clone();
-            async move {
-                sample_sender(track, &new_samples_notify, queue).await;
-            }
-        });
+        let pacer = self
+            .frame_interval
+            .map(|interval| FramePacer::new(interval, self.channel_queue_size));
+        let queue_delay_micros = self.queue_delay_micros.clone();
+        self.sample_sender_task = Some(self.runtime.spawn(async move {
+            sample_sender(
+                track,
+                &new_samples_notify,
+                queue,
+                pacer,
+                &queue_delay_micros,
+            )
+            .await;
+        }));
+    }
 
-        let track_sender = peer.add_track(track.track()).await?;
+    /// Adds the already created track to another peer, e.g. a spectator.
+    ///
+    /// The track keeps one binding per peer and writes every packet to all of them,
+    /// so the samples are only queued and packetized once no matter how many peers watch.
+    pub async fn add_to_peer(
+        &self,
+        peer: &RTCPeerConnection,
+        mut on_packet: impl FnMut(Box<dyn Packet + Send + Sync>) + Send + 'static,
+    ) -> Result<(), anyhow::Error> {
+        let Some(track) = self.track.clone() else {
+            return Err(anyhow!(
+                "Failed to add track to peer because it wasn't created yet"
+            ));
+        };
+
+        let track_sender = peer.add_track(track).await?;
+        self.rtp_senders.lock().await.push(track_sender.clone());
+
+        // A receiver report can also contain the reports of the other tracks of the peer
+        let ssrc = track_sender
+            .get_parameters()
+            .await
+            .encodings
+            .first()
+            .map(|encoding| encoding.ssrc);
+        let slot = self.next_peer_slot.fetch_add(1, Ordering::Relaxed);
+        let reception_reports = self.reception_reports.clone();
+        let rtp_senders = self.rtp_senders.clone();
 
         // Read incoming RTCP packets
         // Before these packets are returned they are processed by interceptors. For things
@@ -92,51 +260,175 @@ where
             let mut rtcp_buf = vec![0u8; 1500];
             while let Ok((packets, _)) = track_sender.read(&mut rtcp_buf).await {
                 for packet in packets {
+                    if let Some(ssrc) = ssrc
+                        && let Some(receiver_report) =
+                            packet.as_any().downcast_ref::<ReceiverReport>()
+                        && let Some(report) = receiver_report
+                            .reports
+                            .iter()
+                            .find(|report| report.ssrc == ssrc)
+                    {
+                        reception_reports.lock().await.insert(slot, report.clone());
+                    }
+
                     on_packet(packet);
                 }
             }
+
+            // The peer is gone
+            rtp_senders
+                .lock()
+                .await
+                .retain(|rtp_sender| !Arc::ptr_eq(rtp_sender, &track_sender));
+            reception_reports.lock().await.remove(&slot);
         });
 
         Ok(())
     }
 
-    /// Returns if the frame will be delivered
-    pub async fn send_samples(&self, samples: Vec<Track::Sample>, important: bool) -> bool {
+    /// Queues the frame and returns how many queued frames had to be dropped for it.
+    ///
+    /// An important frame (keyframe) supersedes everything that is still queued.
+    /// Otherwise the oldest frames are dropped once the queue is full or would exceed the max bytes.
+    pub async fn send_samples(&self, samples: Vec<Track::Sample>, important: bool) -> usize {
+        let size = samples.iter().map(Track::sample_size).sum::<usize>();
+
         let mut queue = self.queue.lock().await;
 
-        let result = if important {
-            queue.push_front(FrameSamples { important, samples });
-            true
+        let dropped = if important {
+            let dropped = queue.len();
+            queue.clear();
+            dropped
         } else {
-            if queue.len() > self.channel_queue_size {
-                return false;
+            let mut dropped =
+                drop_oldest_frames(&mut queue, self.channel_queue_size, |frame| frame.important);
+            if self.channel_queue_max_bytes > 0 {
+                dropped += drop_oldest_frames_over_size(
+                    &mut queue,
+                    self.channel_queue_max_bytes.saturating_sub(size),
+                    |frame| frame.size,
+                    |frame| frame.important,
+                );
             }
-
-            queue.push_front(FrameSamples { important, samples });
-            true
+            dropped
         };
 
+        queue.push_front(FrameSamples {
+            important,
+            size,
+            queued_at: Instant::now(),
+            samples,
+        });
+
         self.new_samples_notify.notify_waiters();
 
-        result
+        dropped
     }
 
-    /// Returns if the frame will be delivered
-    pub async fn clear_queue(&self, clear_important: bool) {
-        let mut queue = self.queue.lock().await;
+    /// The size of all frames which weren't sent yet
+    pub async fn queued_bytes(&self) -> usize {
+        let queue = self.queue.lock().await;
+        queue.iter().map(|frame| frame.size).sum()
+    }
 
-        if clear_important {
-            queue.clear();
+    /// The number of frames which weren't sent yet
+    pub async fn queued_frames(&self) -> usize {
+        self.queue.lock().await.len()
+    }
+
+    /// How long the last sent frame waited in the queue
+    pub fn queue_delay(&self) -> Duration {
+        Duration::from_micros(self.queue_delay_micros.load(Ordering::Relaxed))
+    }
+
+    /// The last receiver report of every peer, the primary peer is first
+    pub async fn reception_stats(&self, clock_rate: u32) -> Vec<StreamReception> {
+        let reports = self.reception_reports.lock().await;
+
+        reports
+            .values()
+            .map(|report| stream_reception(report, clock_rate))
+            .collect()
+    }
+}
+
+/// The jitter is reported in rtp timestamp units
+fn stream_reception(report: &ReceptionReport, clock_rate: u32) -> StreamReception {
+    StreamReception {
+        ssrc: report.ssrc,
+        fraction_lost: report.fraction_lost as f64 / 256.0,
+        cumulative_lost: report.total_lost,
+        jitter: if clock_rate > 0 {
+            Duration::from_secs_f64(report.jitter as f64 / clock_rate as f64)
         } else {
-            queue.retain(|frame| frame.important);
+            Duration::ZERO
+        },
+    }
+}
+
+/// Drops frames, oldest first, until there's room for one more frame.
+/// The most recent important frame is always kept.
+///
+/// New frames are pushed to the front of the queue.
+fn drop_oldest_frames<T>(
+    queue: &mut VecDeque<T>,
+    max_len: usize,
+    is_important: impl Fn(&T) -> bool,
+) -> usize {
+    let max_len = max_len.max(1);
+    let newest_important = queue.iter().position(is_important);
+
+    let mut dropped = 0;
+    let mut index = queue.len();
+    while queue.len() >= max_len && index > 0 {
+        index -= 1;
+
+        if Some(index) == newest_important {
+            continue;
         }
+
+        queue.remove(index);
+        dropped += 1;
     }
+
+    dropped
+}
+
+/// Drops frames, oldest first, until the queued frames fit into max bytes.
+/// Like [drop_oldest_frames] the most recent important frame is kept, even if it's larger on its own.
+fn drop_oldest_frames_over_size<T>(
+    queue: &mut VecDeque<T>,
+    max_bytes: usize,
+    size: impl Fn(&T) -> usize,
+    is_important: impl Fn(&T) -> bool,
+) -> usize {
+    let newest_important = queue.iter().position(is_important);
+    let mut queued_bytes = queue.iter().map(&size).sum::<usize>();
+
+    let mut dropped = 0;
+    let mut index = queue.len();
+    while queued_bytes > max_bytes && index > 0 {
+        index -= 1;
+
+        if Some(index) == newest_important {
+            continue;
+        }
+
+        if let Some(frame) = queue.remove(index) {
+            queued_bytes -= size(&frame);
+            dropped += 1;
+        }
+    }
+
+    dropped
 }
 
 async fn sample_sender<Track>(
     track: Arc<Track>,
     new_samples_notify: &Notify,
     queue: Weak<Mutex<VecDeque<FrameSamples<Track>>>>,
+    mut pacer: Option<FramePacer>,
+    queue_delay_micros: &AtomicU64,
 ) where
     Track: TrackLike,
 {
@@ -148,16 +440,43 @@ async fn sample_sender<Track>(
             };
 
             let mut queue = queue.lock().await;
-            let Some(new_frame) = queue.pop_back() else {
+            let Some(oldest_queued_at) = queue.back().map(|frame| frame.queued_at) else {
                 drop(queue); // Important: drop the mutex
 
+                if let Some(pacer) = &mut pacer {
+                    pacer.ran_empty();
+                }
+
                 new_samples_notify.notified().await;
                 continue;
             };
 
+            if let Some(pacer) = &mut pacer {
+                let now = Instant::now();
+                let release_at = pacer.release_at(now, queue.len(), oldest_queued_at);
+                if release_at > now {
+                    drop(queue);
+
+                    sleep_until(release_at.into()).await;
+                    continue;
+                }
+
+                pacer.released(now);
+            }
+
+            let Some(new_frame) = queue.pop_back() else {
+                continue;
+            };
+
             new_frame
         };
 
+        let queue_delay = frame.queued_at.elapsed();
+        queue_delay_micros.store(
+            queue_delay.as_micros().try_into().unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("clock went backwards");
@@ -186,6 +505,9 @@ async fn sample_sender<Track>(
 pub trait TrackLike: Send + Sync + 'static {
     type Sample: Send + 'static;
 
+    /// Used for the max bytes of the queue
+    fn sample_size(sample: &Self::Sample) -> usize;
+
     fn write_with_extensions(
         &self,
         sample: Self::Sample,
@@ -198,6 +520,10 @@ pub trait TrackLike: Send + Sync + 'static {
 impl TrackLike for TrackLocalStaticSample {
     type Sample = Sample;
 
+    fn sample_size(sample: &Self::Sample) -> usize {
+        sample.data.len()
+    }
+
     async fn write_with_extensions(
         &self,
         sample: Self::Sample,
@@ -230,6 +556,10 @@ impl From<TrackLocalStaticRTP> for SequencedTrackLocalStaticRTP {
 impl TrackLike for SequencedTrackLocalStaticRTP {
     type Sample = rtp::packet::Packet;
 
+    fn sample_size(sample: &Self::Sample) -> usize {
+        sample.payload.len()
+    }
+
     async fn write_with_extensions(
         &self,
         mut sample: Self::Sample,
@@ -263,3 +593,282 @@ impl TrackLike for SequencedTrackLocalStaticRTP {
         self.track.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use tokio::{
+        runtime::Handle,
+        time::{sleep, timeout},
+    };
+    use webrtc::{
+        api::{
+            APIBuilder,
+            media_engine::{MIME_TYPE_H264, MIME_TYPE_OPUS, MediaEngine},
+        },
+        peer_connection::{RTCPeerConnection, configuration::RTCConfiguration},
+        rtcp::reception_report::ReceptionReport,
+        rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTPCodecType},
+        track::track_local::{TrackLocal, track_local_static_sample::TrackLocalStaticSample},
+    };
+
+    use crate::transport::webrtc::sender::{
+        FramePacer, TrackLocalSender, drop_oldest_frames, drop_oldest_frames_over_size,
+        stream_reception,
+    };
+
+    // (frame number, important), newest at the front
+    fn queue(frames: &[(u32, bool)]) -> VecDeque<(u32, bool)> {
+        frames.iter().rev().copied().collect()
+    }
+
+    fn frame_numbers(queue: &VecDeque<(u32, bool)>) -> Vec<u32> {
+        queue.iter().rev().map(|(number, _)| *number).collect()
+    }
+
+    #[test]
+    fn test_no_drop_with_room() {
+        let mut frames = queue(&[(0, true), (1, false)]);
+
+        assert_eq!(drop_oldest_frames(&mut frames, 3, |frame| frame.1), 0);
+        assert_eq!(frame_numbers(&frames), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_drops_oldest_first() {
+        let mut frames = queue(&[(0, false), (1, false), (2, false)]);
+
+        assert_eq!(drop_oldest_frames(&mut frames, 3, |frame| frame.1), 1);
+        assert_eq!(frame_numbers(&frames), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_keeps_newest_idr() {
+        let mut frames = queue(&[(0, true), (1, false), (2, true), (3, false), (4, false)]);
+
+        assert_eq!(drop_oldest_frames(&mut frames, 2, |frame| frame.1), 4);
+        assert_eq!(frame_numbers(&frames), vec![2]);
+    }
+
+    #[test]
+    fn test_drops_oldest_over_size() {
+        // The frame number doubles as the size
+        let mut frames = queue(&[(100, false), (20, false), (30, false)]);
+
+        assert_eq!(
+            drop_oldest_frames_over_size(
+                &mut frames,
+                60,
+                |frame| frame.0 as usize,
+                |frame| frame.1
+            ),
+            1
+        );
+        assert_eq!(frame_numbers(&frames), vec![20, 30]);
+    }
+
+    #[test]
+    fn test_keeps_newest_idr_over_size() {
+        let mut frames = queue(&[(100, true), (20, false)]);
+
+        assert_eq!(
+            drop_oldest_frames_over_size(
+                &mut frames,
+                50,
+                |frame| frame.0 as usize,
+                |frame| frame.1
+            ),
+            1
+        );
+        assert_eq!(frame_numbers(&frames), vec![100]);
+    }
+
+    #[test]
+    fn test_zero_queue_size() {
+        let mut frames = queue(&[(0, false)]);
+
+        assert_eq!(drop_oldest_frames(&mut frames, 0, |frame| frame.1), 1);
+        assert!(frames.is_empty());
+    }
+
+    #[test]
+    fn test_pacer_steady_cadence() {
+        let interval = Duration::from_millis(10);
+        let mut pacer = FramePacer::new(interval, 3);
+        let start = Instant::now();
+
+        // The first frame is held back for one interval
+        assert_eq!(pacer.release_at(start, 1, start), start + interval);
+        pacer.released(start + interval);
+
+        // A frame that arrived early waits for its slot
+        let early = start + Duration::from_millis(12);
+        assert_eq!(pacer.release_at(early, 1, early), start + interval * 2);
+        pacer.released(start + interval * 2 + Duration::from_millis(1));
+
+        // A late wake up doesn't shift the cadence
+        assert_eq!(pacer.release_at(early, 1, early), start + interval * 3);
+    }
+
+    #[test]
+    fn test_pacer_catches_up() {
+        let interval = Duration::from_millis(10);
+        let mut pacer = FramePacer::new(interval, 3);
+        let start = Instant::now();
+
+        pacer.released(start);
+        assert_eq!(pacer.release_at(start, 2, start), start + interval);
+        // Sending before the queue is full avoids dropped frames
+        assert_eq!(pacer.release_at(start, 3, start), start);
+
+        // Fell behind by more than a frame: the cadence restarts from now
+        let late = start + interval * 5;
+        pacer.released(late);
+        assert_eq!(pacer.release_at(late, 1, late), late + interval);
+
+        pacer.ran_empty();
+        assert_eq!(pacer.release_at(late, 1, late), late + interval);
+    }
+
+    #[test]
+    fn test_stream_reception() {
+        let report = ReceptionReport {
+            ssrc: 1234,
+            fraction_lost: 64,
+            total_lost: 10,
+            jitter: 900,
+            ..Default::default()
+        };
+
+        let reception = stream_reception(&report, 90000);
+        assert_eq!(reception.ssrc, 1234);
+        assert_eq!(reception.fraction_lost, 0.25);
+        assert_eq!(reception.cumulative_lost, 10);
+        assert_eq!(reception.jitter, Duration::from_millis(10));
+
+        assert_eq!(stream_reception(&report, 0).jitter, Duration::ZERO);
+    }
+
+    async fn new_peer() -> Arc<RTCPeerConnection> {
+        let mut media = MediaEngine::default();
+        media.register_default_codecs().unwrap();
+
+        Arc::new(
+            APIBuilder::new()
+                .with_media_engine(media)
+                .build()
+                .new_peer_connection(RTCConfiguration::default())
+                .await
+                .unwrap(),
+        )
+    }
+
+    fn new_track(mime_type: &str) -> TrackLocalStaticSample {
+        TrackLocalStaticSample::new(
+            RTCRtpCodecCapability {
+                mime_type: mime_type.to_owned(),
+                ..Default::default()
+            },
+            "track".to_owned(),
+            "stream".to_owned(),
+        )
+    }
+
+    async fn new_sender(
+        primary: &Arc<RTCPeerConnection>,
+    ) -> TrackLocalSender<TrackLocalStaticSample> {
+        let mut sender = TrackLocalSender::new(Handle::current(), Arc::downgrade(primary), 3, 0);
+        sender
+            .create_track(new_track(MIME_TYPE_H264), |_| {})
+            .await
+            .unwrap();
+        sender
+    }
+
+    #[tokio::test]
+    async fn test_reception_stats_in_peer_order() {
+        let primary = new_peer().await;
+        let sender = new_sender(&primary).await;
+
+        // The spectator reports before the primary peer
+        {
+            let mut reports = sender.reception_reports.lock().await;
+            reports.insert(
+                1,
+                ReceptionReport {
+                    ssrc: 2,
+                    ..Default::default()
+                },
+            );
+            reports.insert(
+                0,
+                ReceptionReport {
+                    ssrc: 1,
+                    ..Default::default()
+                },
+            );
+        }
+
+        let stats = sender.reception_stats(90000).await;
+        assert_eq!(
+            stats
+                .iter()
+                .map(|reception| reception.ssrc)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+
+        primary.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_closed_peer_is_pruned() {
+        let primary = new_peer().await;
+        let spectator = new_peer().await;
+
+        let sender = new_sender(&primary).await;
+        sender.add_to_peer(&spectator, |_| {}).await.unwrap();
+        assert_eq!(sender.rtp_senders.lock().await.len(), 2);
+
+        spectator.close().await.unwrap();
+
+        timeout(Duration::from_secs(5), async {
+            while sender.rtp_senders.lock().await.len() != 1 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the sender of the closed peer wasn't removed");
+
+        primary.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replace_track_continues_after_failed_peer() {
+        let primary = new_peer().await;
+        let spectator = new_peer().await;
+
+        let mut sender = new_sender(&primary).await;
+        sender.add_to_peer(&spectator, |_| {}).await.unwrap();
+
+        // Every peer rejects a track of another kind, but the replacement still goes through
+        sender
+            .replace_track(new_track(MIME_TYPE_OPUS))
+            .await
+            .unwrap();
+        assert_eq!(sender.rtp_senders.lock().await.len(), 2);
+
+        assert_eq!(
+            sender.track.as_ref().map(|track| track.kind()),
+            Some(RTPCodecType::Audio)
+        );
+
+        primary.close().await.unwrap();
+        spectator.close().await.unwrap();
+    }
+}
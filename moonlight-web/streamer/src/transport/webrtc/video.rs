@@ -5,22 +5,27 @@ use std::{
         Arc, Weak,
         atomic::{AtomicBool, Ordering},
     },
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use bytes::{Bytes, BytesMut};
-use log::{debug, error, info, trace, warn};
+use common::{
+    api_bindings::StreamServerMessage, ipc::StreamerIpcMessage, media_api::video_format_to_codec,
+};
+use log::{error, info, trace, warn};
 use moonlight_common::stream::{
     bindings::{DecodeResult, FrameType, SupportedVideoFormats, VideoDecodeUnit, VideoFormat},
     video::VideoSetup,
 };
 use tokio::runtime::Handle;
 use webrtc::{
-    api::media_engine::{MIME_TYPE_AV1, MIME_TYPE_H264, MIME_TYPE_HEVC, MediaEngine},
     peer_connection::RTCPeerConnection,
-    rtcp::payload_feedbacks::{
-        picture_loss_indication::PictureLossIndication,
-        receiver_estimated_maximum_bitrate::ReceiverEstimatedMaximumBitrate,
+    rtcp::{
+        packet::Packet as RtcpPacket,
+        payload_feedbacks::{
+            picture_loss_indication::PictureLossIndication,
+            receiver_estimated_maximum_bitrate::ReceiverEstimatedMaximumBitrate,
+        },
     },
     rtp::{
         codecs::{
@@ -32,17 +37,16 @@ use webrtc::{
         packet::Packet,
         packetizer::Payloader,
     },
-    rtp_transceiver::{
-        RTCPFeedback,
-        rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType},
-    },
     track::track_local::track_local_static_rtp::TrackLocalStaticRTP,
 };
 
-use crate::transport::webrtc::{
-    WebRtcInner,
-    sender::{SequencedTrackLocalStaticRTP, TrackLocalSender},
-    video::{annexb::AnnexBSplitter, h264::reader::H264Reader, h265::reader::H265Reader},
+use crate::transport::{
+    StreamReception, TransportEvent, VideoQueueStats,
+    webrtc::{
+        WebRtcInner,
+        sender::{SequencedTrackLocalStaticRTP, TrackLocalSender},
+        video::{annexb::AnnexBSplitter, h264::reader::H264Reader, h265::reader::H265Reader},
+    },
 };
 
 mod annexb;
@@ -71,6 +75,9 @@ pub struct WebRtcVideo {
     clock_rate: u32,
     codec: Option<VideoCodec>,
     samples: Vec<BytesMut>,
+    queue_stats: VideoQueueStats,
+    /// The last setup of the host, None until the track was created
+    setup: Option<VideoSetup>,
     /// Track the start time for local timestamp generation.
     /// Using a local clock prevents jitter buffer growth from clock drift
     /// between the host (game server) and the receiver.
@@ -83,18 +90,46 @@ impl WebRtcVideo {
         peer: Weak<RTCPeerConnection>,
         supported_video_formats: SupportedVideoFormats,
         frame_queue_size: usize,
+        frame_queue_max_bytes: usize,
+        frame_interval: Option<Duration>,
     ) -> Self {
+        let mut sender =
+            TrackLocalSender::new(runtime, peer, frame_queue_size, frame_queue_max_bytes);
+        sender.set_frame_pacing(frame_interval);
+
         Self {
             clock_rate: 0,
             needs_idr: Default::default(),
-            sender: TrackLocalSender::new(runtime, peer, frame_queue_size),
+            sender,
             codec: None,
             supported_video_formats,
             samples: Default::default(),
+            queue_stats: Default::default(),
+            setup: None,
             stream_start_time: None,
         }
     }
 
+    pub async fn queue_stats(&self) -> VideoQueueStats {
+        VideoQueueStats {
+            queued_bytes: self.sender.queued_bytes().await,
+            queued_frames: self.sender.queued_frames().await,
+            queue_delay: self.sender.queue_delay(),
+            ..self.queue_stats
+        }
+    }
+
+    pub async fn reception_stats(&self) -> Vec<StreamReception> {
+        self.sender.reception_stats(self.clock_rate).await
+    }
+
+    /// A picture loss reported by the spectator also forces a keyframe for everyone else
+    pub async fn add_spectator(&self, peer: &RTCPeerConnection) -> Result<(), anyhow::Error> {
+        self.sender
+            .add_to_peer(peer, create_rtcp_handler(self.needs_idr.clone()))
+            .await
+    }
+
     pub async fn setup(
         &mut self,
         inner: &Arc<WebRtcInner>,
@@ -103,7 +138,7 @@ impl WebRtcVideo {
             width,
             height,
             redraw_rate,
-            flags: _,
+            flags,
         }: VideoSetup,
     ) -> bool {
         info!("[Stream] Stream setup: {width}x{height}x{redraw_rate} and {format:?}");
@@ -123,46 +158,71 @@ impl WebRtcVideo {
             return false;
         };
 
-        let needs_idr = self.needs_idr.clone();
-        if let Err(err) = self
-            .sender
-            .create_track(
-                TrackLocalStaticRTP::new(
-                    codec.capability.clone(),
-                    "video".to_string(),
-                    "moonlight".to_string(),
-                )
-                .into(),
+        let track = TrackLocalStaticRTP::new(
+            codec.capability.clone(),
+            "video".to_string(),
+            "moonlight".to_string(),
+        );
+
+        // The host sets up the video again if it changed mid-stream, e.g. the game switched the resolution
+        let previous_setup = self.setup;
+        let renegotiate = match previous_setup {
+            None => {
+                if let Err(err) = self
+                    .sender
+                    .create_track(track.into(), create_rtcp_handler(self.needs_idr.clone()))
+                    .await
                 {
-                    let needs_idr = needs_idr.clone();
-
-                    move |packet| {
-                        let packet = packet.as_any();
-
-                        if packet.is::<PictureLossIndication>() {
-                            needs_idr.store(true, Ordering::Release);
-                        }
-                        if let Some(_max_bitrate) =
-                            packet.downcast_ref::<ReceiverEstimatedMaximumBitrate>()
-                        {
-                            // Moonlight doesn't support dynamic bitrate changing :(
-                        }
-                    }
-                },
-            )
-            .await
-        {
-            error!(
-                "Failed to create video track with format {format:?} and codec \"{codec:?}\": {err:?}"
-            );
-            return false;
-        }
+                    error!(
+                        "Failed to create video track with format {format:?} and codec \"{codec:?}\": {err:?}"
+                    );
+                    return false;
+                }
+
+                true
+            }
+            // The resolution is part of the bitstream, the track and sdp stay valid
+            Some(previous)
+                if video_format_to_codec(previous.format)
+                    .is_some_and(|previous| previous.capability == codec.capability) =>
+            {
+                info!(
+                    "[Stream] The host changed the video from {}x{}x{} to {width}x{height}x{redraw_rate}",
+                    previous.width, previous.height, previous.redraw_rate
+                );
+
+                false
+            }
+            Some(previous) => {
+                info!(
+                    "[Stream] The host changed the video format from {:?} to {format:?}, replacing the track",
+                    previous.format
+                );
+
+                if let Err(err) = self.sender.replace_track(track.into()).await {
+                    error!(
+                        "Failed to replace video track with format {format:?} and codec \"{codec:?}\": {err:?}"
+                    );
+                    return false;
+                }
+
+                true
+            }
+        };
+        self.setup = Some(VideoSetup {
+            format,
+            width,
+            height,
+            redraw_rate,
+            flags,
+        });
 
         self.clock_rate = codec.capability.clock_rate;
 
         // Initialize stream start time for local timestamp generation
         // This prevents jitter buffer growth from clock drift between host and receiver
-        self.stream_start_time = Some(Instant::now());
+        // A changed video keeps the clock, the timestamps of the track must not jump back
+        self.stream_start_time.get_or_insert_with(Instant::now);
 
         self.codec = match format {
             // -- H264
@@ -188,11 +248,31 @@ impl WebRtcVideo {
             }),
         };
 
-        // Renegotiate
-        if !inner.send_offer().await {
+        // Renegotiate, the answer is reported to the client with NegotiatedFormat
+        if renegotiate && !inner.send_offer().await {
             warn!("Failed to renegotiate. Video was added!");
         }
 
+        if previous_setup.is_some() {
+            // The old frames are gone, the decoder of the client needs a new keyframe
+            self.needs_idr.store(true, Ordering::Release);
+
+            if let Err(err) = inner
+                .event_sender
+                .send(TransportEvent::SendIpc(StreamerIpcMessage::WebSocket(
+                    StreamServerMessage::VideoChanged {
+                        format: format as u32,
+                        width,
+                        height,
+                        fps: redraw_rate,
+                    },
+                )))
+                .await
+            {
+                warn!("Failed to send the changed video via web socket from peer: {err:?}");
+            }
+        }
+
         true
     }
 
@@ -214,6 +294,7 @@ impl WebRtcVideo {
         }
 
         let important = matches!(unit.frame_type, FrameType::Idr);
+        let dropped;
 
         match &mut self.codec {
             // -- H264
@@ -239,7 +320,7 @@ impl WebRtcVideo {
                     self.samples.push(data);
                 }
 
-                send_single_frame(
+                dropped = send_single_frame(
                     &mut self.samples,
                     &mut self.sender,
                     payloader,
@@ -272,7 +353,7 @@ impl WebRtcVideo {
                     self.samples.push(data);
                 }
 
-                send_single_frame(
+                dropped = send_single_frame(
                     &mut self.samples,
                     &mut self.sender,
                     payloader,
@@ -293,7 +374,7 @@ impl WebRtcVideo {
                     self.samples.push(data);
                 }
 
-                send_single_frame(
+                dropped = send_single_frame(
                     &mut self.samples,
                     &mut self.sender,
                     payloader,
@@ -305,14 +386,22 @@ impl WebRtcVideo {
             }
             None => {
                 warn!("Failed to send decode unit because of missing codec!");
+                dropped = 0;
             }
         }
 
+        self.queue_stats.frames_dropped = self
+            .queue_stats
+            .frames_dropped
+            .saturating_add(dropped as u32);
+
         if self
             .needs_idr
             .compare_exchange_weak(true, false, Ordering::SeqCst, Ordering::Relaxed)
             .is_ok()
         {
+            self.queue_stats.idrs_forced = self.queue_stats.idrs_forced.saturating_add(1);
+
             return DecodeResult::NeedIdr;
         }
 
@@ -320,29 +409,7 @@ impl WebRtcVideo {
     }
 }
 
-pub fn register_video_codecs(
-    media_engine: &mut MediaEngine,
-    supported_video_formats: SupportedVideoFormats,
-) -> Result<(), webrtc::Error> {
-    for format in VideoFormat::all() {
-        if !format.contained_in(supported_video_formats) {
-            continue;
-        }
-
-        let Some(codec) = video_format_to_codec(format) else {
-            continue;
-        };
-        debug!(
-            "Registering Video Format {format:?}, Codec: {:?}",
-            codec.capability
-        );
-
-        media_engine.register_codec(codec, RTPCodecType::Video)?;
-    }
-
-    Ok(())
-}
-
+/// Returns how many queued frames were dropped to make room for this one
 async fn send_single_frame(
     samples: &mut Vec<BytesMut>,
     sender: &mut TrackLocalSender<SequencedTrackLocalStaticRTP>,
@@ -350,11 +417,7 @@ async fn send_single_frame(
     timestamp: u32,
     important: bool,
     needs_idr: &AtomicBool,
-) {
-    if important {
-        sender.clear_queue(false).await;
-    }
-
+) -> usize {
     let mut peekable = samples.drain(..).peekable();
 
     let mut frame_samples = Vec::new();
@@ -377,12 +440,29 @@ async fn send_single_frame(
         frame_samples.extend(packets);
     }
 
-    if !sender.send_samples(frame_samples, important).await {
-        sender.clear_queue(true).await;
+    let dropped = sender.send_samples(frame_samples, important).await;
 
-        // We've dropped a frame (likely due to buffering)
+    // Frames superseded by a keyframe don't matter, otherwise the decoder is missing references
+    if dropped > 0 && !important {
         needs_idr.store(true, Ordering::Release);
     }
+
+    dropped
+}
+
+fn create_rtcp_handler(
+    needs_idr: Arc<AtomicBool>,
+) -> impl FnMut(Box<dyn RtcpPacket + Send + Sync>) + Send + 'static {
+    move |packet| {
+        let packet = packet.as_any();
+
+        if packet.is::<PictureLossIndication>() {
+            needs_idr.store(true, Ordering::Release);
+        }
+        if let Some(_max_bitrate) = packet.downcast_ref::<ReceiverEstimatedMaximumBitrate>() {
+            // Moonlight doesn't support dynamic bitrate changing :(
+        }
+    }
 }
 
 fn packetize(
@@ -416,130 +496,6 @@ fn packetize(
     Ok(packets)
 }
 
-fn video_format_to_codec(format: VideoFormat) -> Option<RTCRtpCodecParameters> {
-    let rtcp_feedback = vec![
-        RTCPFeedback {
-            typ: "nack".to_string(),
-            parameter: "".to_string(),
-        },
-        RTCPFeedback {
-            typ: "nack".to_string(),
-            parameter: "pli".to_string(),
-        },
-        RTCPFeedback {
-            typ: "goog-remb".to_string(),
-            parameter: "".to_string(),
-        },
-    ];
-
-    match format {
-        // -- H264 Constrained Baseline Profile
-        VideoFormat::H264 => Some(RTCRtpCodecParameters {
-            capability: RTCRtpCodecCapability {
-                mime_type: MIME_TYPE_H264.to_owned(),
-                clock_rate: 90000,
-                channels: 0,
-                sdp_fmtp_line:
-                    "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f"
-                        .to_owned(),
-                rtcp_feedback: rtcp_feedback.clone(),
-            },
-            payload_type: 96,
-            ..Default::default()
-        }),
-        // -- H264 High Profile
-        VideoFormat::H264High8_444 => Some(RTCRtpCodecParameters {
-            capability: RTCRtpCodecCapability {
-                mime_type: MIME_TYPE_H264.to_owned(),
-                clock_rate: 90000,
-                channels: 0,
-                sdp_fmtp_line:
-                    "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=640032"
-                        .to_owned(),
-                rtcp_feedback: rtcp_feedback.clone(),
-            },
-            payload_type: 97,
-            ..Default::default()
-        }),
-
-        // TODO: h265 requires resolution in the level-id field, set it based on resolution and fps
-        // -- H265 Main Profile
-        VideoFormat::H265 => Some(RTCRtpCodecParameters {
-            capability: RTCRtpCodecCapability {
-                mime_type: MIME_TYPE_HEVC.to_owned(),
-                clock_rate: 90000,
-                channels: 0,
-                // They're the same
-                // sdp_fmtp_line: "profile-id=1;level-id=93;tier-flag=0;tx-mode=1".to_owned(),
-                sdp_fmtp_line: "".to_owned(),
-                rtcp_feedback: rtcp_feedback.clone(),
-            },
-            payload_type: 98,
-            ..Default::default()
-        }),
-        // -- H265 Main10 Profile
-        VideoFormat::H265Main10 => Some(RTCRtpCodecParameters {
-            capability: RTCRtpCodecCapability {
-                mime_type: MIME_TYPE_HEVC.to_owned(),
-                clock_rate: 90000,
-                channels: 0,
-                sdp_fmtp_line: "profile-id=2;tier-flag=0;level-id=93;tx-mode=SRST".to_owned(),
-                rtcp_feedback: rtcp_feedback.clone(),
-            },
-            payload_type: 99,
-            ..Default::default()
-        }),
-        // -- H265 RExt 4:4:4 8-bit
-        VideoFormat::H265Rext8_444 => Some(RTCRtpCodecParameters {
-            capability: RTCRtpCodecCapability {
-                mime_type: MIME_TYPE_HEVC.to_owned(),
-                clock_rate: 90000,
-                channels: 0,
-                sdp_fmtp_line: "profile-id=4;tier-flag=0;level-id=120;tx-mode=SRST".to_owned(),
-                rtcp_feedback: rtcp_feedback.clone(),
-            },
-            payload_type: 100,
-            ..Default::default()
-        }),
-        // -- H265 RExt 4:4:4 10-bit
-        VideoFormat::H265Rext10_444 => Some(RTCRtpCodecParameters {
-            capability: RTCRtpCodecCapability {
-                mime_type: MIME_TYPE_HEVC.to_owned(),
-                clock_rate: 90000,
-                channels: 0,
-                sdp_fmtp_line: "profile-id=5;tier-flag=0;level-id=93;tx-mode=SRST".to_owned(),
-                rtcp_feedback: rtcp_feedback.clone(),
-            },
-            payload_type: 101,
-            ..Default::default()
-        }),
-
-        // -- Av1
-        VideoFormat::Av1Main8 | VideoFormat::Av1Main10 => Some(RTCRtpCodecParameters {
-            capability: RTCRtpCodecCapability {
-                mime_type: MIME_TYPE_AV1.to_owned(),
-                clock_rate: 90000,
-                channels: 0,
-                sdp_fmtp_line: "profile=0".to_owned(),
-                rtcp_feedback: rtcp_feedback.clone(),
-            },
-            payload_type: 102,
-            ..Default::default()
-        }),
-        VideoFormat::Av1High8_444 | VideoFormat::Av1High10_444 => Some(RTCRtpCodecParameters {
-            capability: RTCRtpCodecCapability {
-                mime_type: MIME_TYPE_AV1.to_owned(),
-                clock_rate: 90000,
-                channels: 0,
-                sdp_fmtp_line: "profile=1".to_owned(),
-                rtcp_feedback: rtcp_feedback.clone(),
-            },
-            payload_type: 103,
-            ..Default::default()
-        }),
-    }
-}
-
 fn trim_bytes_to_range(mut buf: BytesMut, range: Range<usize>) -> BytesMut {
     if range.start > 0 {
         let _ = buf.split_to(range.start);
@@ -551,3 +507,65 @@ fn trim_bytes_to_range(mut buf: BytesMut, range: Range<usize>) -> BytesMut {
 
     buf
 }
+
+/// Returns the codec name and fmtp line of the video codec which was selected in the answer,
+/// which is always the first payload type of the video media section
+pub fn negotiated_video_codec(answer_sdp: &str) -> Option<(String, Option<String>)> {
+    let mut lines = answer_sdp.lines().map(str::trim);
+
+    let payload_type = lines.find_map(|line| {
+        let mut parts = line.strip_prefix("m=video ")?.split_whitespace();
+        // port and protocol
+        parts.nth(2)
+    })?;
+
+    let mut codec = None;
+    let mut profile = None;
+    for line in lines.take_while(|line| !line.starts_with("m=")) {
+        if let Some(rtpmap) = line.strip_prefix("a=rtpmap:")
+            && let Some((pt, value)) = rtpmap.split_once(' ')
+            && pt == payload_type
+        {
+            codec = value.split('/').next().map(str::to_owned);
+        } else if let Some(fmtp) = line.strip_prefix("a=fmtp:")
+            && let Some((pt, value)) = fmtp.split_once(' ')
+            && pt == payload_type
+        {
+            profile = Some(value.to_owned());
+        }
+    }
+
+    codec.map(|codec| (codec, profile))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transport::webrtc::video::negotiated_video_codec;
+
+    #[test]
+    fn test_negotiated_video_codec() {
+        let sdp = "v=0\r\n\
+            m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n\
+            a=rtpmap:111 opus/48000/2\r\n\
+            a=fmtp:111 minptime=10;useinbandfec=1\r\n\
+            m=video 9 UDP/TLS/RTP/SAVPF 99 98\r\n\
+            a=rtpmap:98 H265/90000\r\n\
+            a=rtpmap:99 H265/90000\r\n\
+            a=fmtp:99 profile-id=2;tier-flag=0;level-id=93;tx-mode=SRST\r\n";
+
+        assert_eq!(
+            negotiated_video_codec(sdp),
+            Some((
+                "H265".to_owned(),
+                Some("profile-id=2;tier-flag=0;level-id=93;tx-mode=SRST".to_owned())
+            ))
+        );
+    }
+
+    #[test]
+    fn test_negotiated_video_codec_without_video() {
+        let sdp = "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=rtpmap:111 opus/48000/2\r\n";
+
+        assert_eq!(negotiated_video_codec(sdp), None);
+    }
+}
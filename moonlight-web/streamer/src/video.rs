@@ -3,18 +3,21 @@ use std::{
     time::{Duration, Instant},
 };
 
-use common::api_bindings::{StatsHostProcessingLatency, StreamerStatsUpdate};
+use common::api_bindings::{
+    StatsHostProcessingLatency, StatsReception, StatsSample, StreamerStatsUpdate,
+};
 use log::warn;
 use moonlight_common::stream::{
     bindings::{
-        Capabilities, DecodeResult, EstimatedRttInfo, SupportedVideoFormats, VideoDecodeUnit,
+        Capabilities, DecodeResult, EstimatedRttInfo, FrameType, SupportedVideoFormats,
+        VideoDecodeUnit,
     },
     video::{VideoDecoder, VideoSetup},
 };
 
 use crate::{
     StreamConnection,
-    transport::{OutboundPacket, TransportError},
+    transport::{OutboundPacket, StreamReception, TransportError},
 };
 
 pub(crate) struct StreamVideoDecoder {
@@ -35,6 +38,10 @@ impl VideoDecoder for StreamVideoDecoder {
             *stream_info = Some(setup);
         }
 
+        if let Some(recorder) = &stream.info.recorder {
+            recorder.setup_video(setup.format);
+        }
+
         {
             stream.runtime.clone().block_on(async move {
                 let sender = stream.transport_sender.lock().await;
@@ -69,6 +76,18 @@ impl VideoDecoder for StreamVideoDecoder {
             let frame_processing_time = Instant::now() - start;
             self.stats.analyze(&stream, &unit, frame_processing_time);
 
+            if let Some(recorder) = &stream.info.recorder {
+                let mut data = Vec::new();
+                for buffer in unit.buffers {
+                    data.extend_from_slice(buffer.data);
+                }
+                recorder.record_video(data, matches!(unit.frame_type, FrameType::Idr));
+
+                if recorder.take_keyframe_request(Instant::now()) {
+                    return DecodeResult::NeedIdr;
+                }
+            }
+
             result
         })
     }
@@ -93,6 +112,7 @@ pub(crate) struct VideoStats {
     max_streamer_processing_time: Duration,
     total_streamer_processing_time: Duration,
     streamer_processing_time_frame_count: usize,
+    video_bytes: u64,
 }
 
 impl VideoStats {
@@ -119,11 +139,17 @@ impl VideoStats {
             self.max_streamer_processing_time.max(frame_processing_time);
         self.total_streamer_processing_time += frame_processing_time;
         self.streamer_processing_time_frame_count += 1;
+        self.video_bytes += unit
+            .buffers
+            .iter()
+            .map(|buffer| buffer.data.len() as u64)
+            .sum::<u64>();
 
         // Send in 1 sec intervall
+        let now = Instant::now();
         if self
             .last_send
-            .map(|last_send| last_send + Duration::from_secs(1) < Instant::now())
+            .map(|last_send| last_send + Duration::from_secs(1) < now)
             .unwrap_or(true)
         {
             // Collect data
@@ -142,6 +168,13 @@ impl VideoStats {
                 .checked_div(self.streamer_processing_time_frame_count as u32)
                 .unwrap_or(Duration::ZERO);
 
+            // Unknown for the first frame
+            let video_bytes = self.video_bytes;
+            let bitrate_kbps = self.last_send.map(|last_send| {
+                let secs = now.duration_since(last_send).as_secs_f64();
+                (video_bytes as f64 * 8.0 / 1000.0 / secs) as u32
+            });
+
             // Send data
             let runtime = stream.runtime.clone();
 
@@ -182,9 +215,49 @@ impl VideoStats {
                         warn!("Failed to send stats: {err:?}");
                     }
                 };
+
+                // Send Video queue info
+                let queue_stats = transport.video_queue_stats().await;
+                match transport
+                    .send(OutboundPacket::Stats(StreamerStatsUpdate::VideoQueue {
+                        frames_dropped: queue_stats.frames_dropped,
+                        idrs_forced: queue_stats.idrs_forced,
+                        queued_bytes: queue_stats.queued_bytes.try_into().unwrap_or(u32::MAX),
+                        queued_frames: queue_stats.queued_frames.try_into().unwrap_or(u32::MAX),
+                        queue_delay_ms: queue_stats.queue_delay.as_secs_f64() * 1000.0,
+                    }))
+                    .await
+                {
+                    Ok(_) => {}
+                    Err(TransportError::ChannelClosed) => {
+                        // ignore
+                    }
+                    Err(err) => {
+                        warn!("Failed to send stats: {err:?}");
+                    }
+                };
+
+                // Send what the client reported back
+                let reception_stats = transport.reception_stats().await;
+                match transport
+                    .send(OutboundPacket::Stats(StreamerStatsUpdate::Reception {
+                        video: reception_stats.video.iter().map(stats_reception).collect(),
+                        audio: reception_stats.audio.iter().map(stats_reception).collect(),
+                    }))
+                    .await
+                {
+                    Ok(_) => {}
+                    Err(TransportError::ChannelClosed) => {
+                        // ignore
+                    }
+                    Err(err) => {
+                        warn!("Failed to send stats: {err:?}");
+                    }
+                };
                 drop(transport);
 
                 // Send RTT info
+                let mut rtt_ms = None;
                 let ml_stream_lock = stream.stream.read().await;
                 if let Some(ml_stream) = ml_stream_lock.as_ref() {
                     let rtt = ml_stream.estimated_rtt_info();
@@ -194,6 +267,8 @@ impl VideoStats {
 
                     match rtt {
                         Ok(EstimatedRttInfo { rtt, rtt_variance }) => {
+                            rtt_ms = Some(rtt.as_secs_f64() * 1000.0);
+
                             match transport
                                 .send(OutboundPacket::Stats(StreamerStatsUpdate::Rtt {
                                     rtt_ms: rtt.as_secs_f64() * 1000.0,
@@ -215,6 +290,28 @@ impl VideoStats {
                         }
                     };
                 }
+
+                // Record the sample for the timeline of the whole stream
+                if let Some(bitrate_kbps) = bitrate_kbps {
+                    let packet_loss_percent = reception_stats
+                        .video
+                        .first()
+                        .map(|reception| reception.fraction_lost * 100.0);
+
+                    stream
+                        .stats_timeline
+                        .lock()
+                        .await
+                        .record(Instant::now(), |elapsed_ms| StatsSample {
+                            elapsed_ms,
+                            bitrate_kbps,
+                            packet_loss_percent,
+                            rtt_ms,
+                            queued_frames: queue_stats.queued_frames.try_into().unwrap_or(u32::MAX),
+                            queue_delay_ms: queue_stats.queue_delay.as_secs_f64() * 1000.0,
+                            frames_dropped: queue_stats.frames_dropped,
+                        });
+                }
             });
 
             // Clear data
@@ -226,8 +323,18 @@ impl VideoStats {
             self.max_streamer_processing_time = Duration::ZERO;
             self.total_streamer_processing_time = Duration::ZERO;
             self.streamer_processing_time_frame_count = 0;
+            self.video_bytes = 0;
 
-            self.last_send = Some(Instant::now());
+            self.last_send = Some(now);
         }
     }
 }
+
+fn stats_reception(reception: &StreamReception) -> StatsReception {
+    StatsReception {
+        ssrc: reception.ssrc,
+        fraction_lost_percent: reception.fraction_lost * 100.0,
+        cumulative_lost: reception.cumulative_lost,
+        jitter_ms: reception.jitter.as_secs_f64() * 1000.0,
+    }
+}
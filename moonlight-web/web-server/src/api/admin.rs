@@ -1,17 +1,22 @@
+use std::time::Instant;
+
 use actix_web::{
     HttpResponse, delete, get, patch, post,
-    web::{Data, Json},
+    web::{Data, Json, Query},
 };
 use common::api_bindings::{
-    DeleteUserRequest, DetailedUser, GetUsersResponse, PatchUserRequest, PostUserRequest,
+    AuditLogEntry, DeleteUserRequest, DetailedUser, GetAuditLogQuery, GetAuditLogResponse,
+    GetStreamersResponse, GetUsersQuery, GetUsersResponse, PatchUserRequest, PostUserRequest,
 };
 use futures::future::join_all;
 use log::warn;
 
 use crate::app::{
     App, AppError,
+    audit::{ADD_USER_ACTION, DELETE_USER_ACTION, PATCH_USER_ACTION},
+    auth::normalize_fingerprint,
     password::StoragePassword,
-    storage::{StorageUserAdd, StorageUserModify},
+    storage::{StorageQueryUsers, StorageUserAdd, StorageUserModify},
     user::{Admin, AuthenticatedUser, Role, UserId},
 };
 
@@ -21,21 +26,30 @@ pub async fn add_user(
     admin: Admin,
     Json(request): Json<PostUserRequest>,
 ) -> Result<Json<DetailedUser>, AppError> {
-    let mut user = app
-        .add_user(
-            &admin,
-            StorageUserAdd {
-                name: request.name.clone(),
-                password: Some(StoragePassword::new(&request.password)?),
-                role: request.role.into(),
-                client_unique_id: request.client_unique_id,
-            },
-        )
-        .await?;
+    let target = format!("user_name:{}", request.name);
+
+    let result = async {
+        let mut user = app
+            .add_user(
+                &admin,
+                StorageUserAdd {
+                    name: request.name.clone(),
+                    password: Some(StoragePassword::new(&request.password)?),
+                    role: request.role.into(),
+                    client_unique_id: request.client_unique_id,
+                },
+            )
+            .await?;
 
-    let detailed_user = user.detailed_user().await?;
+        user.detailed_user().await
+    }
+    .await;
 
-    Ok(Json(detailed_user))
+    app.audit_log()
+        .record(admin.id(), ADD_USER_ACTION, target, &result)
+        .await;
+
+    Ok(Json(result?))
 }
 
 #[patch("/user")]
@@ -48,24 +62,49 @@ pub async fn patch_user(
 
     match Admin::try_from(user).await? {
         Ok(admin) => {
-            let mut target_user = app.user_by_id(target_user_id).await?;
-
-            let new_password = if let Some(new_password) = request.password {
-                Some(StoragePassword::new(&new_password)?)
-            } else {
-                None
-            };
-
-            target_user
-                .modify(
-                    &admin,
-                    StorageUserModify {
-                        password: Some(new_password),
-                        role: request.role.map(Role::from),
-                        client_unique_id: request.client_unique_id,
-                    },
+            let result = async {
+                let mut target_user = app.user_by_id(target_user_id).await?;
+
+                let new_password = if let Some(new_password) = request.password {
+                    Some(StoragePassword::new(&new_password)?)
+                } else {
+                    None
+                };
+
+                let fingerprints = match &request.client_certificate_fingerprints {
+                    Some(fingerprints) => Some(
+                        client_certificate_fingerprints(&app, target_user_id, fingerprints).await?,
+                    ),
+                    None => None,
+                };
+
+                target_user
+                    .modify(
+                        &admin,
+                        StorageUserModify {
+                            password: Some(new_password),
+                            role: request.role.map(Role::from),
+                            client_unique_id: request.client_unique_id,
+                            pair_device_name: request
+                                .pair_device_name
+                                .map(|name| Some(name).filter(|name| !name.is_empty())),
+                            client_certificate_fingerprints: fingerprints,
+                        },
+                    )
+                    .await
+            }
+            .await;
+
+            app.audit_log()
+                .record(
+                    admin.id(),
+                    PATCH_USER_ACTION,
+                    format!("user_id:{}", target_user_id.0),
+                    &result,
                 )
-                .await?;
+                .await;
+
+            result?;
         }
         Err(mut user) => {
             if user.id() != target_user_id {
@@ -78,8 +117,14 @@ pub async fn patch_user(
                 password: _,
                 role,
                 client_unique_id,
+                pair_device_name,
+                client_certificate_fingerprints,
             } = &request;
-            if role.is_some() || client_unique_id.is_some() {
+            if role.is_some()
+                || client_unique_id.is_some()
+                || pair_device_name.is_some()
+                || client_certificate_fingerprints.is_some()
+            {
                 return Err(AppError::Forbidden);
             }
 
@@ -93,6 +138,31 @@ pub async fn patch_user(
     Ok(HttpResponse::Ok().finish())
 }
 
+/// Normalizes the fingerprints, a client certificate can only belong to one user
+async fn client_certificate_fingerprints(
+    app: &App,
+    user_id: UserId,
+    fingerprints: &[String],
+) -> Result<Vec<String>, AppError> {
+    let mut normalized = Vec::with_capacity(fingerprints.len());
+
+    for fingerprint in fingerprints {
+        let fingerprint = normalize_fingerprint(fingerprint).ok_or(AppError::BadRequest)?;
+
+        match app.user_by_client_certificate(&fingerprint).await {
+            Ok(user) if user.id() != user_id => return Err(AppError::ClientCertificateInUse),
+            Ok(_) | Err(AppError::UserNotFound) => {}
+            Err(err) => return Err(err),
+        }
+
+        if !normalized.contains(&fingerprint) {
+            normalized.push(fingerprint);
+        }
+    }
+
+    Ok(normalized)
+}
+
 #[delete("/user")]
 pub async fn delete_user(
     app: Data<App>,
@@ -101,16 +171,44 @@ pub async fn delete_user(
 ) -> Result<HttpResponse, AppError> {
     let user_id = UserId(request.id);
 
-    let user = app.user_by_id(user_id).await?;
+    let result = async {
+        let user = app.user_by_id(user_id).await?;
 
-    user.delete(&admin).await?;
+        user.delete(&admin).await
+    }
+    .await;
+
+    app.audit_log()
+        .record(
+            admin.id(),
+            DELETE_USER_ACTION,
+            format!("user_id:{}", user_id.0),
+            &result,
+        )
+        .await;
+
+    result?;
 
     Ok(HttpResponse::Ok().finish())
 }
 
 #[get("/users")]
-pub async fn list_users(app: Data<App>, admin: Admin) -> Result<Json<GetUsersResponse>, AppError> {
-    let mut users = app.all_users(admin).await?;
+pub async fn list_users(
+    app: Data<App>,
+    admin: Admin,
+    Query(query): Query<GetUsersQuery>,
+) -> Result<Json<GetUsersResponse>, AppError> {
+    let (mut users, total) = app
+        .users(
+            admin,
+            StorageQueryUsers {
+                offset: query.offset.unwrap_or(0) as usize,
+                limit: query.limit.map(|limit| limit as usize),
+                role: query.role.map(Role::from),
+                name_contains: query.name.filter(|name| !name.is_empty()),
+            },
+        )
+        .await?;
 
     let user_results = join_all(users.iter_mut().map(|user| user.detailed_user_no_auth())).await;
 
@@ -126,5 +224,61 @@ pub async fn list_users(app: Data<App>, admin: Admin) -> Result<Json<GetUsersRes
         }
     }
 
-    Ok(Json(GetUsersResponse { users: out_users }))
+    Ok(Json(GetUsersResponse {
+        users: out_users,
+        total: total as u32,
+    }))
+}
+
+#[get("/streamers")]
+pub async fn list_streamers(
+    app: Data<App>,
+    _admin: Admin,
+) -> Result<Json<GetStreamersResponse>, AppError> {
+    let streamer_manager = app.streamer_manager();
+
+    let now = Instant::now();
+    let streamers = streamer_manager.detailed_streamers(now).await;
+    let hybrid_sessions = app.session_manager().session_statuses(now).await;
+
+    Ok(Json(GetStreamersResponse {
+        process_count: streamers.len() as u32,
+        streamers,
+        hybrid_sessions,
+    }))
+}
+
+const AUDIT_LOG_DEFAULT_LIMIT: u32 = 50;
+const AUDIT_LOG_MAX_LIMIT: u32 = 200;
+
+#[get("/admin/audit")]
+pub async fn get_audit_log(
+    app: Data<App>,
+    _admin: Admin,
+    Query(query): Query<GetAuditLogQuery>,
+) -> Result<Json<GetAuditLogResponse>, AppError> {
+    let offset = query.offset.unwrap_or(0);
+    let limit = query
+        .limit
+        .unwrap_or(AUDIT_LOG_DEFAULT_LIMIT)
+        .min(AUDIT_LOG_MAX_LIMIT);
+
+    let (entries, total) = app
+        .audit_log()
+        .list(offset as usize, limit as usize)
+        .await?;
+
+    Ok(Json(GetAuditLogResponse {
+        entries: entries
+            .into_iter()
+            .map(|entry| AuditLogEntry {
+                timestamp: entry.timestamp,
+                actor_user_id: entry.actor_user_id,
+                action: entry.action,
+                target: entry.target,
+                error: entry.error,
+            })
+            .collect(),
+        total: total as u32,
+    }))
 }
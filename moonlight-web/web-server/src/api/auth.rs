@@ -10,12 +10,13 @@ use actix_web::{
 };
 use common::api_bindings::{PostLoginRequest, PostLoginResponse};
 use futures::future::{Ready, ready};
+use log::warn;
 use std::{pin::Pin, time::Duration};
 
 use crate::{
     app::{
         App, AppError,
-        auth::{SessionToken, UserAuth},
+        auth::{ClientCertificate, SessionToken, UserAuth},
         user::{Admin, AuthenticatedUser},
     },
     remote_access::RemoteAccessProvider,
@@ -67,6 +68,9 @@ fn extract_user_auth(req: &HttpRequest) -> Result<UserAuth, AppError> {
         let token = SessionToken::decode(cookie.value())?;
 
         Ok(UserAuth::Session(token))
+    } else if let Some(certificate) = req.conn_data::<ClientCertificate>() {
+        // Only set if client_certificate_auth is configured, sessions still take precedence
+        Ok(UserAuth::ClientCertificate(certificate.clone()))
     } else {
         Ok(UserAuth::None)
     }
@@ -116,29 +120,60 @@ impl FromRequest for Admin {
 async fn login(
     app: Data<App>,
     remote_provider: Data<RemoteAccessProvider>,
+    req: HttpRequest,
     Json(request): Json<PostLoginRequest>,
 ) -> Result<HttpResponse, Error> {
+    // The peer address is used instead of forwarded headers because those can be spoofed
+    let ip = req.peer_addr().map(|address| address.ip());
+    let username = request.name.clone();
+
+    if let Err(retry_after) = app.login_limiter().check(ip, &username).await {
+        if let Some(ip) = ip {
+            warn!(
+                "[Login]: refused login of \"{username}\" from {}, too many failed attempts",
+                app.config().log.ip(ip)
+            );
+        }
+
+        // Round up so the client doesn't retry while still locked
+        let retry_after_secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+
+        return Err(AppError::TooManyLoginAttempts(retry_after_secs).into());
+    }
+
     let user = if app.config().web_server.first_login_create_admin {
         match app
             .try_add_first_login(request.name.clone(), request.password.clone())
             .await
         {
-            Ok(user) => user,
+            Ok(user) => Ok(user),
             Err(AppError::FirstUserAlreadyExists) => {
                 app.user_by_auth(UserAuth::UserPassword {
                     username: request.name,
                     password: request.password,
                 })
-                .await?
+                .await
             }
-            Err(err) => return Err(err.into()),
+            Err(err) => Err(err),
         }
     } else {
         app.user_by_auth(UserAuth::UserPassword {
             username: request.name,
             password: request.password,
         })
-        .await?
+        .await
+    };
+
+    let user = match user {
+        Ok(user) => {
+            app.login_limiter().record_success(ip, &username).await;
+            user
+        }
+        Err(err @ (AppError::CredentialsWrong | AppError::UserNotFound)) => {
+            app.login_limiter().record_failure(ip, &username).await;
+            return Err(err.into());
+        }
+        Err(err) => return Err(err.into()),
     };
 
     let session_expiration = app.config().web_server.session_cookie_expiration;
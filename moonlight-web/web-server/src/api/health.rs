@@ -0,0 +1,107 @@
+//! Health and readiness probes for container orchestration.
+//!
+//! These endpoints don't require authentication, so they must never contain host or user information.
+
+use std::time::Instant;
+
+use actix_web::{HttpResponse, get, web::Data};
+use serde::Serialize;
+
+use crate::app::App;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthResponse {
+    pub status: &'static str,
+    pub active_streams: u32,
+    pub uptime_secs: u64,
+    pub version: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReadyResponse {
+    pub ready: bool,
+}
+
+#[get("/health")]
+pub async fn health(app: Data<App>) -> HttpResponse {
+    let active_streams = app.streamer_manager().process_count().await;
+
+    HttpResponse::Ok().json(HealthResponse {
+        status: "ok",
+        active_streams: active_streams as u32,
+        uptime_secs: Instant::now().duration_since(app.started_at()).as_secs(),
+        version: env!("CARGO_PKG_VERSION"),
+    })
+}
+
+#[get("/ready")]
+pub async fn ready(app: Data<App>) -> HttpResponse {
+    let ready = app.is_ready();
+
+    if ready {
+        HttpResponse::Ok().json(ReadyResponse { ready })
+    } else {
+        HttpResponse::ServiceUnavailable().json(ReadyResponse { ready })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, time::Duration};
+
+    use actix_web::{
+        App as ActixApp,
+        http::StatusCode,
+        test::{TestRequest, call_service, init_service, read_body_json},
+        web::Data,
+    };
+    use common::config::{Config, StorageConfig};
+    use serde_json::Value;
+
+    use crate::{
+        api::health::{health, ready},
+        app::App,
+    };
+
+    async fn test_app(name: &str) -> Data<App> {
+        let directory = env::temp_dir().join(format!("moonlight-web-test-{name}"));
+
+        let mut config = Config::default();
+        config.data_storage = StorageConfig::Json {
+            path: directory.join("data.json").to_string_lossy().to_string(),
+            session_expiration_check_interval: Duration::from_mins(5),
+        };
+        config.streamer_pidfile_dir = directory.join("streamers").to_string_lossy().to_string();
+        config.audit_log.path = None;
+
+        Data::new(App::new(config).await.unwrap())
+    }
+
+    #[actix_web::test]
+    async fn test_ready_after_upnp() {
+        let app = test_app("ready").await;
+        let service = init_service(ActixApp::new().app_data(app.clone()).service(ready)).await;
+
+        let response = call_service(&service, TestRequest::get().uri("/ready").to_request()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        app.set_upnp_ready();
+
+        let response = call_service(&service, TestRequest::get().uri("/ready").to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = read_body_json(response).await;
+        assert_eq!(body["ready"], true);
+    }
+
+    #[actix_web::test]
+    async fn test_health() {
+        let app = test_app("health").await;
+        let service = init_service(ActixApp::new().app_data(app).service(health)).await;
+
+        let response = call_service(&service, TestRequest::get().uri("/health").to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = read_body_json(response).await;
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["active_streams"], 0);
+    }
+}
@@ -3,20 +3,79 @@
 //! This endpoint handles the native input connection that joins an existing
 //! hybrid streaming session using a session token.
 
-use actix_web::{Error, HttpRequest, HttpResponse, get, rt as actix_rt, web::{Data, Payload}};
+use std::time::Instant;
+
+use actix_web::{
+    Error, HttpRequest, HttpResponse, get, post, rt as actix_rt,
+    web::{Data, Json, Path, Payload},
+};
 use actix_ws::{Closed, Message, Session};
 use common::{
-    api_bindings::{InputClientMessage, InputErrorCode, InputServerMessage},
+    api_bindings::{
+        HybridSessionStatus, InputClientMessage, InputErrorCode, InputServerMessage,
+        PostInputHandoffRequest, PostInputHandoffResponse,
+    },
     serialize_json,
 };
 use log::{debug, error, info, warn};
 use tokio::sync::mpsc::{Receiver, Sender};
 
 use crate::app::{
-    App,
-    session::{InputToStreamerMessage, SessionError, StreamerToInputMessage},
+    App, AppError,
+    session::{InputId, InputToStreamerMessage, SessionError, StreamerToInputMessage},
+    user::{AuthenticatedUser, Role},
 };
 
+/// Status of a hybrid session, only visible to the user who started the stream and admins
+#[get("/session/hybrid/{session_id}")]
+pub async fn get_hybrid_session(
+    web_app: Data<App>,
+    mut user: AuthenticatedUser,
+    session_id: Path<String>,
+) -> Result<Json<HybridSessionStatus>, AppError> {
+    let (owner, status) = web_app
+        .session_manager()
+        .session_status(&session_id, Instant::now())
+        .await
+        .ok_or(AppError::HybridSessionNotFound)?;
+
+    if owner != user.id() && user.role().await? != Role::Admin {
+        return Err(AppError::Forbidden);
+    }
+
+    Ok(Json(status))
+}
+
+/// Moves the input of a hybrid session to another device.
+/// The current input connection is closed once the new one joined with the returned token
+#[post("/session/input/handoff")]
+pub async fn post_input_handoff(
+    web_app: Data<App>,
+    mut user: AuthenticatedUser,
+    Json(request): Json<PostInputHandoffRequest>,
+) -> Result<Json<PostInputHandoffResponse>, AppError> {
+    let session_manager = web_app.session_manager();
+
+    let (owner, _) = session_manager
+        .session_status(&request.session_id, Instant::now())
+        .await
+        .ok_or(AppError::HybridSessionNotFound)?;
+
+    if owner != user.id() && user.role().await? != Role::Admin {
+        return Err(AppError::Forbidden);
+    }
+
+    let (session_token, token_expiration) = session_manager
+        .start_input_handoff(&request.session_id)
+        .await
+        .ok_or(AppError::HybridSessionNotFound)?;
+
+    Ok(Json(PostInputHandoffResponse {
+        session_token,
+        token_expires_in_secs: token_expiration.as_secs().try_into().unwrap_or(u32::MAX),
+    }))
+}
+
 /// WebSocket endpoint for input-only connections in hybrid mode
 #[get("/host/input")]
 pub async fn input_connect(
@@ -87,7 +146,7 @@ pub async fn input_connect(
         info!("[Input]: Received join request with token: {}", session_token);
 
         // -- Validate token and claim session
-        let (session_id, input_to_streamer_tx, streamer_to_input_rx) = match web_app
+        let (session_id, input_id, input_to_streamer_tx, streamer_to_input_rx) = match web_app
             .session_manager()
             .claim_session(&session_token)
             .await
@@ -106,7 +165,7 @@ pub async fn input_connect(
                     }
                     SessionError::InputAlreadyConnected => (
                         InputErrorCode::InputAlreadyConnected,
-                        "Input connection already established",
+                        "Another input connection is still active for this session, close it or use a handoff token",
                     ),
                     SessionError::PrimaryDisconnected => (
                         InputErrorCode::SessionNotFound,
@@ -130,7 +189,7 @@ pub async fn input_connect(
         );
 
         // -- Send Accepted with ICE servers
-        let ice_servers = web_app.config().webrtc.ice_servers.clone();
+        let ice_servers = web_app.ice_servers().await.ice_servers;
         if let Err(Closed) = send_message(
             &mut session,
             InputServerMessage::Accepted { ice_servers },
@@ -154,6 +213,7 @@ pub async fn input_connect(
         // -- Handle bidirectional message flow
         handle_input_session(
             session_id.clone(),
+            input_id,
             session,
             stream,
             input_to_streamer_tx,
@@ -171,6 +231,7 @@ pub async fn input_connect(
 /// Handle the input session after successful join
 async fn handle_input_session(
     session_id: String,
+    input_id: InputId,
     mut ws_session: Session,
     mut ws_stream: actix_ws::MessageStream,
     input_to_streamer_tx: Sender<InputToStreamerMessage>,
@@ -196,6 +257,16 @@ async fn handle_input_session(
                                 }
                                 info!("[Input]: >>> Signaling sent successfully to channel");
                             }
+                            Ok(InputClientMessage::Clipboard { text }) => {
+                                debug!("[Input]: Clipboard text with {} bytes", text.len());
+                                if let Err(err) = input_to_streamer_tx
+                                    .send(InputToStreamerMessage::Clipboard(text))
+                                    .await
+                                {
+                                    warn!("[Input]: Failed to forward clipboard to streamer: {err:?}");
+                                    break;
+                                }
+                            }
                             Ok(InputClientMessage::Join { .. }) => {
                                 warn!("[Input]: Received unexpected Join message after session established");
                             }
@@ -246,6 +317,26 @@ async fn handle_input_session(
                         debug!("[Input]: Streamer signaled input peer ready");
                         // Could send a status update to the client if needed
                     }
+                    Some(StreamerToInputMessage::Failed) => {
+                        info!("[Input]: Input peer ICE failed, asking the client to retry");
+                        if let Err(Closed) = send_message(
+                            &mut ws_session,
+                            InputServerMessage::Error {
+                                code: InputErrorCode::IceFailed,
+                                message: "The input connection failed, please reconnect".to_string(),
+                            },
+                        ).await {
+                            break;
+                        }
+                    }
+                    Some(StreamerToInputMessage::HandedOff) => {
+                        info!("[Input]: Another input connection took over session {session_id}");
+                        let _ = send_message(
+                            &mut ws_session,
+                            InputServerMessage::HandedOff,
+                        ).await;
+                        break;
+                    }
                     None => {
                         // Streamer channel closed (primary disconnected)
                         info!("[Input]: Streamer channel closed, notifying client");
@@ -260,15 +351,10 @@ async fn handle_input_session(
         }
     }
 
-    // Notify streamer that input disconnected
-    let _ = input_to_streamer_tx
-        .send(InputToStreamerMessage::Disconnected)
-        .await;
-
-    // Clean up session
+    // Clean up session, this also notifies the streamer unless the session was handed over
     web_app
         .session_manager()
-        .input_disconnected(&session_id)
+        .input_disconnected(&session_id, input_id)
         .await;
 
     // Close WebSocket
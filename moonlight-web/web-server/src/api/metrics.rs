@@ -0,0 +1,215 @@
+//! Prometheus metrics for operators, enabled with `metrics.enabled`.
+//!
+//! Like the health probes these don't require authentication, so only counts are exported.
+
+use std::fmt::Write;
+
+use actix_web::{HttpResponse, get, web::Data};
+
+use crate::{
+    app::{App, streamer_manager::StreamerMetrics},
+    stun::binding_request_counts,
+    upnp::{UpnpManager, UpnpStatus},
+};
+
+/// Every stream uses this transport, its bytes are counted by the streamers
+const TRANSPORT: &str = "webrtc";
+
+#[get("/metrics")]
+pub async fn metrics(app: Data<App>, upnp_manager: Option<Data<UpnpManager>>) -> HttpResponse {
+    let upnp_status = match upnp_manager {
+        Some(upnp_manager) => Some(upnp_manager.status().await),
+        None => None,
+    };
+
+    let snapshot = MetricsSnapshot {
+        streamers: app.streamer_manager().metrics().await,
+        hybrid_sessions: app.session_manager().session_count().await,
+        app_image_fetch_failures: app.app_image_cache().total_fetch_failures(),
+        upnp_status,
+        stun_binding_requests: binding_request_counts(),
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(snapshot.render())
+}
+
+struct MetricsSnapshot {
+    streamers: StreamerMetrics,
+    hybrid_sessions: usize,
+    app_image_fetch_failures: u64,
+    /// None if UPnP is disabled
+    upnp_status: Option<UpnpStatus>,
+    /// Sent and failed requests
+    stun_binding_requests: (u64, u64),
+}
+
+impl MetricsSnapshot {
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        write_metric(
+            &mut out,
+            "moonlight_web_active_streams",
+            "gauge",
+            "Streams with a running streamer",
+            &[("", self.streamers.active as u64)],
+        );
+        write_metric(
+            &mut out,
+            "moonlight_web_streams_started_total",
+            "counter",
+            "Streamers started since the web server started",
+            &[("", self.streamers.started)],
+        );
+        write_metric(
+            &mut out,
+            "moonlight_web_streamer_spawn_failures_total",
+            "counter",
+            "Streamer processes which couldn't be started",
+            &[("", self.streamers.spawn_failures)],
+        );
+        write_metric(
+            &mut out,
+            "moonlight_web_hybrid_sessions",
+            "gauge",
+            "Hybrid sessions waiting for or using an input connection",
+            &[("", self.hybrid_sessions as u64)],
+        );
+        write_metric(
+            &mut out,
+            "moonlight_web_app_image_fetch_failures_total",
+            "counter",
+            "Box art requests to hosts which failed, the cached image or the placeholder was served",
+            &[("", self.app_image_fetch_failures)],
+        );
+
+        if let Some(upnp_status) = &self.upnp_status {
+            let mapped = upnp_status
+                .port_mappings
+                .iter()
+                .filter(|mapping| mapping.success)
+                .count();
+
+            write_metric(
+                &mut out,
+                "moonlight_web_upnp_available",
+                "gauge",
+                "1 if a UPnP gateway was found",
+                &[("", upnp_status.available as u64)],
+            );
+            write_metric(
+                &mut out,
+                "moonlight_web_upnp_port_mappings",
+                "gauge",
+                "Port mappings by result",
+                &[
+                    ("result=\"success\"", mapped as u64),
+                    (
+                        "result=\"failure\"",
+                        (upnp_status.port_mappings.len() - mapped) as u64,
+                    ),
+                ],
+            );
+        }
+
+        let (sent, failed) = self.stun_binding_requests;
+        write_metric(
+            &mut out,
+            "moonlight_web_stun_binding_requests_total",
+            "counter",
+            "STUN binding requests of the NAT detection by result",
+            &[
+                ("result=\"success\"", sent.saturating_sub(failed)),
+                ("result=\"failure\"", failed),
+            ],
+        );
+
+        write_metric(
+            &mut out,
+            "moonlight_web_transport_bytes_sent_total",
+            "counter",
+            "Video, audio and data channel payload sent to clients",
+            &[(
+                &format!("transport=\"{TRANSPORT}\""),
+                self.streamers.bytes_sent,
+            )],
+        );
+
+        out
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, ty: &str, help: &str, samples: &[(&str, u64)]) {
+    // Writing into a String can't fail
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {ty}");
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{name} {value}");
+        } else {
+            let _ = writeln!(out, "{name}{{{labels}}} {value}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::metrics::MetricsSnapshot, app::streamer_manager::StreamerMetrics, upnp::UpnpStatus,
+    };
+
+    #[test]
+    fn test_render_metrics() {
+        let snapshot = MetricsSnapshot {
+            streamers: StreamerMetrics {
+                active: 2,
+                started: 5,
+                spawn_failures: 1,
+                bytes_sent: 4096,
+            },
+            hybrid_sessions: 1,
+            app_image_fetch_failures: 3,
+            upnp_status: Some(UpnpStatus {
+                available: true,
+                ..Default::default()
+            }),
+            stun_binding_requests: (4, 1),
+        };
+
+        let rendered = snapshot.render();
+        assert!(rendered.contains(
+            "# TYPE moonlight_web_active_streams gauge\nmoonlight_web_active_streams 2\n"
+        ));
+        assert!(rendered.contains("moonlight_web_streams_started_total 5\n"));
+        assert!(rendered.contains("moonlight_web_app_image_fetch_failures_total 3\n"));
+        assert!(rendered.contains("moonlight_web_upnp_available 1\n"));
+        assert!(rendered.contains("moonlight_web_upnp_port_mappings{result=\"failure\"} 0\n"));
+        assert!(
+            rendered.contains("moonlight_web_stun_binding_requests_total{result=\"success\"} 3\n")
+        );
+        assert!(
+            rendered
+                .contains("moonlight_web_transport_bytes_sent_total{transport=\"webrtc\"} 4096\n")
+        );
+    }
+
+    #[test]
+    fn test_render_metrics_without_upnp() {
+        let snapshot = MetricsSnapshot {
+            streamers: StreamerMetrics {
+                active: 0,
+                started: 0,
+                spawn_failures: 0,
+                bytes_sent: 0,
+            },
+            hybrid_sessions: 0,
+            app_image_fetch_failures: 0,
+            upnp_status: None,
+            stun_binding_requests: (0, 0),
+        };
+
+        assert!(!snapshot.render().contains("moonlight_web_upnp"));
+    }
+}
@@ -4,37 +4,46 @@ use actix_web::{
     get,
     middleware::from_fn,
     patch, post, services,
-    web::{self, Bytes, Data, Json, Query},
+    web::{self, Bytes, Data, Json, Path, Query},
 };
 use futures::future::try_join_all;
 use log::warn;
 use moonlight_common::PairPin;
-use tokio::spawn;
+use std::{net::Ipv4Addr, time::Instant};
+use tokio::{spawn, time::timeout};
 
 use crate::{
     api::{
-        admin::{add_user, delete_user, list_users, patch_user},
+        admin::{add_user, delete_user, get_audit_log, list_streamers, list_users, patch_user},
         auth::auth_middleware,
         response_streaming::StreamedResponse,
     },
     app::{
         App, AppError,
-        host::{AppId, HostId},
+        audit::CHANGE_HOST_OWNER_ACTION,
+        host::{AppId, HostId, is_valid_host_address},
+        stats_timelines::stats_timeline_csv,
         storage::StorageHostModify,
         user::{AuthenticatedUser, Role, UserId},
     },
     remote_access::RemoteAccessProvider,
 };
 use common::api_bindings::{
-    self, DeleteHostQuery, DetailedHost, DetailedUser, GetAppImageQuery, GetAppsQuery, GetAppsResponse,
-    GetHostQuery, GetHostResponse, GetHostsResponse, GetUserQuery, PatchHostRequest,
-    PostHostRequest, PostHostResponse, PostPairRequest, PostPairResponse1, PostPairResponse2,
-    PostWakeUpRequest, UndetailedHost,
+    self, DeleteHostQuery, DeletePairQuery, DetailedHost, DetailedUser, GetAppImageQuery,
+    GetAppQuery, GetAppResponse, GetAppsQuery, GetAppsResponse, GetConnectionLogsResponse,
+    GetDiscoveredHostsResponse, GetHostLogsQuery, GetHostLogsResponse, GetHostQuery,
+    GetHostResponse, GetHostsResponse, GetStatsTimelineQuery, GetStatsTimelineResponse,
+    GetStreamHistoryQuery, GetStreamHistoryResponse, GetUserQuery, HostTestResponse,
+    PatchHostRequest, PostHostRequest, PostHostResponse, PostHostTestRequest, PostPairRequest,
+    PostPairResponse1, PostPairResponse2, PostWakeUpRequest, PostWakeUpResponse,
+    StatsTimelineFormat, StreamHistoryEntry, UndetailedHost,
 };
 
 pub mod admin;
 pub mod auth;
+pub mod health;
 pub mod input;
+pub mod metrics;
 pub mod network;
 pub mod stream;
 
@@ -74,6 +83,7 @@ async fn get_user(
 
 #[get("/hosts")]
 async fn list_hosts(
+    app: Data<App>,
     mut user: AuthenticatedUser,
 ) -> Result<StreamedResponse<GetHostsResponse, UndetailedHost>, AppError> {
     let (mut stream_response, stream_sender) =
@@ -81,6 +91,9 @@ async fn list_hosts(
 
     let hosts = user.hosts().await?;
 
+    // A hanging host would otherwise keep the response open forever
+    let host_info_timeout = app.config().moonlight.host_info_timeout();
+
     // Try join all because storage should always work, the actual host info will be send using response streaming
     let undetailed_hosts = try_join_all(hosts.into_iter().map(move |mut host| {
         let mut user = user.clone();
@@ -94,13 +107,29 @@ async fn list_hosts(
             let mut user = user.clone();
 
             spawn(async move {
-                let undetailed = match host.undetailed_host(&mut user).await {
-                    Ok(value) => value,
-                    Err(err) => {
-                        warn!("Failed to get undetailed host of {host:?}: {err:?}");
-                        return;
-                    }
-                };
+                let undetailed =
+                    match timeout(host_info_timeout, host.undetailed_host(&mut user)).await {
+                        Ok(Ok(value)) => value,
+                        Ok(Err(err)) => {
+                            warn!("Failed to get undetailed host of {host:?}: {err:?}");
+                            return;
+                        }
+                        Err(_) => {
+                            warn!(
+                                "Host {host:?} didn't answer within {host_info_timeout:?}, using the cached data"
+                            );
+
+                            match host.undetailed_host_cached(&mut user).await {
+                                Ok(value) => value,
+                                Err(err) => {
+                                    warn!(
+                                        "Failed to get cached undetailed host of {host:?}: {err:?}"
+                                    );
+                                    return;
+                                }
+                            }
+                        }
+                    };
 
                 if let Err(err) = stream_sender.send(undetailed).await {
                     warn!(
@@ -136,6 +165,36 @@ fn attach_remote_access(
     host
 }
 
+/// Hosts on the local network which advertise themselves with mDNS and aren't added yet
+#[get("/hosts/discover")]
+async fn discover_hosts(
+    app: Data<App>,
+    mut user: AuthenticatedUser,
+) -> Result<Json<GetDiscoveredHostsResponse>, AppError> {
+    let discovered = app.host_discovery().discover().await;
+
+    let mut known = Vec::new();
+    for host in user.hosts().await? {
+        known.push(host.address_port(&mut user).await?);
+    }
+
+    let hosts = discovered
+        .into_iter()
+        .filter(|discovered| {
+            let hostname = discovered.hostname.trim_end_matches(".local");
+
+            !known.iter().any(|(address, port)| {
+                *port == discovered.http_port
+                    && (*address == discovered.address
+                        || address.eq_ignore_ascii_case(&discovered.hostname)
+                        || address.eq_ignore_ascii_case(hostname))
+            })
+        })
+        .collect();
+
+    Ok(Json(GetDiscoveredHostsResponse { hosts }))
+}
+
 #[get("/host")]
 async fn get_host(
     mut user: AuthenticatedUser,
@@ -152,6 +211,24 @@ async fn get_host(
     Ok(Json(GetHostResponse { host: detailed }))
 }
 
+/// The recent log of the host to find out why a stream didn't start, only owners and admins can see it
+#[get("/host/logs")]
+async fn get_host_logs(
+    mut user: AuthenticatedUser,
+    Query(query): Query<GetHostLogsQuery>,
+) -> Result<Json<GetHostLogsResponse>, AppError> {
+    let host_id = HostId(query.host_id);
+
+    let mut host = user.host(host_id).await?;
+
+    let response = match host.logs(&mut user).await? {
+        Some((text, truncated)) => GetHostLogsResponse::Logs { text, truncated },
+        None => GetHostLogsResponse::NotAvailable,
+    };
+
+    Ok(Json(response))
+}
+
 #[post("/host")]
 async fn post_host(
     app: Data<App>,
@@ -176,6 +253,7 @@ async fn post_host(
 
 #[patch("/host")]
 async fn patch_host(
+    app: Data<App>,
     mut user: AuthenticatedUser,
     Json(request): Json<PatchHostRequest>,
 ) -> Result<HttpResponse, AppError> {
@@ -197,7 +275,60 @@ async fn patch_host(
         }
     }
 
-    host.modify(&mut user, modify).await?;
+    // Only changing the owner is an admin action
+    let audit_target = request.change_owner.then(|| {
+        let new_owner = match request.owner {
+            Some(owner) => format!("user_id:{owner}"),
+            None => "none".to_string(),
+        };
+
+        format!("host_id:{} new_owner:{new_owner}", host_id.0)
+    });
+
+    let result = async {
+        if request.change_wake_broadcast_address {
+            let address = request
+                .wake_broadcast_address
+                .map(|address| address.parse::<Ipv4Addr>())
+                .transpose()
+                .map_err(|_| AppError::BadRequest)?;
+
+            modify.wake_broadcast_address = Some(address);
+        }
+        if request.change_default_stream_settings {
+            modify.default_stream_settings = Some(request.default_stream_settings);
+        }
+        if request.address.is_some() || request.http_port.is_some() {
+            let (address, http_port) = host.address_port(&mut user).await?;
+            let address = request
+                .address
+                .map(|address| address.trim().to_string())
+                .unwrap_or(address);
+            let http_port = request.http_port.unwrap_or(http_port);
+
+            if !is_valid_host_address(&address) || http_port == 0 {
+                return Err(AppError::BadRequest);
+            }
+            let address = user.check_host_address(&address, http_port).await?;
+            if request.probe_address {
+                user.probe_host(&address, http_port).await?;
+            }
+
+            modify.address = Some(address);
+            modify.http_port = Some(http_port);
+        }
+
+        host.modify(&mut user, modify).await
+    }
+    .await;
+
+    if let Some(target) = audit_target {
+        app.audit_log()
+            .record(user.id(), CHANGE_HOST_OWNER_ACTION, target, &result)
+            .await;
+    }
+
+    result?;
 
     Ok(HttpResponse::Ok().finish())
 }
@@ -270,6 +401,21 @@ async fn pair_host(
             Ok(stream_response)
         }
         HostType::Standard => {
+            // Don't waste a PIN on a host which can't finish pairing
+            let unreachable = match host.check_pair_reachability(&mut user).await {
+                Ok(()) => None,
+                Err(AppError::HostOffline) => Some(PostPairResponse1::HostOffline),
+                Err(AppError::HostHttpsUnreachable(https_port)) => {
+                    warn!("Host {host_id:?} isn't reachable on its https port {https_port}");
+                    Some(PostPairResponse1::HttpsUnreachable)
+                }
+                Err(err) => return Err(err),
+            };
+            if let Some(response) = unreachable {
+                let (stream_response, _) = StreamedResponse::new(response);
+                return Ok(stream_response);
+            }
+
             // Standard Sunshine: use PIN-based pairing
             let pin = PairPin::generate()?;
 
@@ -310,18 +456,58 @@ async fn pair_host(
     }
 }
 
+#[delete("/pair")]
+async fn unpair_host(
+    mut user: AuthenticatedUser,
+    Query(query): Query<DeletePairQuery>,
+) -> Result<HttpResponse, AppError> {
+    let host_id = HostId(query.host_id);
+
+    let host = user.host(host_id).await?;
+
+    host.unpair(&mut user).await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
 #[post("/host/wake")]
 async fn wake_host(
     mut user: AuthenticatedUser,
     Json(request): Json<PostWakeUpRequest>,
-) -> Result<HttpResponse, AppError> {
+) -> Result<Json<PostWakeUpResponse>, AppError> {
     let host_id = HostId(request.host_id);
 
     let host = user.host(host_id).await?;
 
-    host.wake(&mut user).await?;
+    let packets_sent = host.wake(&mut user).await?;
 
-    Ok(HttpResponse::Ok().finish())
+    Ok(Json(PostWakeUpResponse { packets_sent }))
+}
+
+#[post("/host/test")]
+async fn test_host(
+    mut user: AuthenticatedUser,
+    Json(request): Json<PostHostTestRequest>,
+) -> Result<Json<HostTestResponse>, AppError> {
+    let host_id = HostId(request.host_id);
+
+    let mut host = user.host(host_id).await?;
+
+    // The host is freshly loaded, so this always queries the host info
+    let start = Instant::now();
+    let (detailed, online) = host.probe_detailed_host(&mut user).await?;
+    let latency = start.elapsed();
+
+    Ok(Json(HostTestResponse {
+        host_id: detailed.host_id,
+        online,
+        paired: detailed.paired,
+        server_state: detailed.server_state,
+        current_game: detailed.current_game,
+        max_luma_pixels_hevc: detailed.max_luma_pixels_hevc,
+        server_codec_mode_support: detailed.server_codec_mode_support,
+        latency_ms: online.then(|| latency.as_millis() as u32),
+    }))
 }
 
 #[get("/apps")]
@@ -347,6 +533,34 @@ async fn get_apps(
     }))
 }
 
+#[get("/app")]
+async fn get_app(
+    app: Data<App>,
+    mut user: AuthenticatedUser,
+    Query(query): Query<GetAppQuery>,
+) -> Result<Json<GetAppResponse>, AppError> {
+    let host_id = HostId(query.host_id);
+    let app_id = AppId(query.app_id);
+
+    let mut host = user.host(host_id).await?;
+
+    let host_app = host.app(&mut user, app_id).await?;
+
+    Ok(Json(GetAppResponse {
+        app: api_bindings::App {
+            app_id: host_app.id.0,
+            title: host_app.title,
+            is_hdr_supported: host_app.is_hdr_supported,
+        },
+        box_art_url: format!(
+            "{}/api/app/image?host_id={}&app_id={}",
+            app.config().web_server.url_path_prefix,
+            host_id.0,
+            app_id.0
+        ),
+    }))
+}
+
 #[get("/app/image")]
 async fn get_app_image(
     mut user: AuthenticatedUser,
@@ -364,44 +578,139 @@ async fn get_app_image(
     Ok(image)
 }
 
+const STREAM_HISTORY_DEFAULT_LIMIT: u32 = 50;
+const STREAM_HISTORY_MAX_LIMIT: u32 = 200;
+
+#[get("/sessions/history")]
+async fn get_stream_history(
+    app: Data<App>,
+    mut user: AuthenticatedUser,
+    Query(query): Query<GetStreamHistoryQuery>,
+) -> Result<Json<GetStreamHistoryResponse>, AppError> {
+    let offset = query.offset.unwrap_or(0);
+    let limit = query
+        .limit
+        .unwrap_or(STREAM_HISTORY_DEFAULT_LIMIT)
+        .min(STREAM_HISTORY_MAX_LIMIT);
+
+    let (entries, total) = app
+        .stream_history(&mut user, offset as usize, limit as usize)
+        .await?;
+
+    Ok(Json(GetStreamHistoryResponse {
+        sessions: entries
+            .into_iter()
+            .map(|entry| StreamHistoryEntry {
+                user_id: entry.user_id.0,
+                host_id: entry.host_id.0,
+                host_name: entry.host_name,
+                app_title: entry.app_title,
+                started_at: entry.started_at,
+                duration_secs: entry.ended_at.saturating_sub(entry.started_at),
+                end_reason: entry.end_reason,
+                session_id: entry.session_id,
+            })
+            .collect(),
+        total: total as u32,
+    }))
+}
+
+/// Why the peer connection of the latest failed streams failed
+#[get("/sessions/connection-logs")]
+async fn get_connection_logs(
+    app: Data<App>,
+    mut user: AuthenticatedUser,
+) -> Result<Json<GetConnectionLogsResponse>, AppError> {
+    let sessions = app.failed_connection_logs(&mut user).await?;
+
+    Ok(Json(GetConnectionLogsResponse { sessions }))
+}
+
+/// The stats samples of an ended stream, the session id is in the stream history
+#[get("/sessions/{session_id}/stats")]
+async fn get_stats_timeline(
+    app: Data<App>,
+    mut user: AuthenticatedUser,
+    session_id: Path<String>,
+    Query(query): Query<GetStatsTimelineQuery>,
+) -> Result<HttpResponse, AppError> {
+    let samples = app.stats_timeline(&mut user, &session_id).await?;
+
+    Ok(match query.format {
+        StatsTimelineFormat::Json => HttpResponse::Ok().json(GetStatsTimelineResponse {
+            session_id: session_id.into_inner(),
+            samples,
+        }),
+        StatsTimelineFormat::Csv => HttpResponse::Ok()
+            .content_type("text/csv")
+            .body(stats_timeline_csv(&samples)),
+    })
+}
+
 pub fn api_service() -> impl HttpServiceFactory {
     web::scope("/api")
-        .wrap(from_fn(auth_middleware))
         .service(services![
-            // -- Auth
-            auth::login,
-            auth::logout,
-            auth::authenticate
-        ])
-        .service(services![
-            // -- Host
-            get_user,
-            list_hosts,
-            get_host,
-            post_host,
-            patch_host,
-            wake_host,
-            delete_host,
-            pair_host,
-            get_apps,
-            get_app_image,
-        ])
-        .service(services![
-            // -- Stream
-            stream::start_host,
-            stream::cancel_host,
-            // -- Input (hybrid mode)
-            input::input_connect,
-        ])
-        .service(services![
-            // -- Admin
-            add_user,
-            patch_user,
-            delete_user,
-            list_users
-        ])
-        .service(services![
-            // -- Network
-            network::get_network_status,
+            // -- Health, reachable without authentication
+            health::health,
+            health::ready,
         ])
+        .service(
+            web::scope("")
+                .wrap(from_fn(auth_middleware))
+                .service(services![
+                    // -- Auth
+                    auth::login,
+                    auth::logout,
+                    auth::authenticate
+                ])
+                .service(services![
+                    // -- Host
+                    get_user,
+                    list_hosts,
+                    discover_hosts,
+                    get_host,
+                    post_host,
+                    patch_host,
+                    wake_host,
+                    test_host,
+                    delete_host,
+                    pair_host,
+                    unpair_host,
+                    get_apps,
+                    get_app,
+                    get_app_image,
+                ])
+                .service(services![
+                    // -- Host diagnostics
+                    get_host_logs,
+                ])
+                .service(services![
+                    // -- Stream
+                    stream::start_host,
+                    stream::cancel_host,
+                    stream::probe_stream,
+                    get_stream_history,
+                    get_connection_logs,
+                    get_stats_timeline,
+                    // -- Input (hybrid mode)
+                    input::input_connect,
+                    input::get_hybrid_session,
+                    input::post_input_handoff,
+                ])
+                .service(services![
+                    // -- Admin
+                    add_user,
+                    patch_user,
+                    delete_user,
+                    list_users,
+                    list_streamers,
+                    get_audit_log
+                ])
+                .service(services![
+                    // -- Network
+                    network::get_network_status,
+                    network::network_diagnostics_ws,
+                    network::get_ice_servers,
+                ]),
+        )
 }
@@ -1,11 +1,31 @@
 //! Network status API endpoints for remote streaming diagnostics.
 
-use actix_web::{get, web::Data, HttpResponse};
-use log::info;
+use std::net::IpAddr;
+
+use actix_web::{
+    HttpRequest, HttpResponse, get, rt as actix_rt,
+    web::{Data, Json, Payload},
+};
+use common::{
+    api_bindings::{GetIceServersResponse, RtcIceServer},
+    config::IpPreference,
+    serialize_json,
+};
+use futures::{StreamExt, stream::FuturesUnordered};
+use log::{info, warn};
 use serde::Serialize;
+use tokio::{
+    join,
+    sync::{
+        mpsc::{UnboundedSender, unbounded_channel},
+        oneshot,
+    },
+    task::spawn_blocking,
+};
 
 use crate::{
-    stun::{NatType, StunClient},
+    app::{App, ice_servers::IceServerList, user::AuthenticatedUser},
+    stun::{NatDetectionResult, NatType, StunClient, check_port_accessible, classify_nat},
     upnp::UpnpManager,
 };
 
@@ -18,8 +38,12 @@ pub struct NetworkStatusResponse {
     pub nat: NatStatusResponse,
     /// Server's local IP addresses
     pub local_addresses: Vec<String>,
+    /// Interfaces WebRTC is restricted to, empty if all interfaces are used
+    pub bind_interfaces: Vec<String>,
     /// Whether the server is likely accessible remotely
     pub remote_accessible: bool,
+    /// Whether the web server port was verified to be reachable through the external ip
+    pub port_forward_verified: bool,
     /// Whether direct P2P connections are likely to work
     pub direct_connection_possible: bool,
     /// Whether TURN relay may be needed
@@ -73,6 +97,28 @@ pub struct MappedPortResponse {
     pub success: bool,
 }
 
+/// Result of a single STUN server
+#[derive(Debug, Clone, Serialize)]
+pub struct StunServerResponse {
+    pub server: String,
+    pub external_ip: Option<String>,
+    pub external_port: Option<u16>,
+    pub error: Option<String>,
+}
+
+/// Sent by the diagnostics socket as soon as each probe finished
+#[derive(Debug, Clone, Serialize)]
+pub enum NetworkDiagnosticEvent {
+    Upnp(UpnpStatusResponse),
+    StunServer(StunServerResponse),
+    Nat(NatStatusResponse),
+    PortForward {
+        verified: bool,
+    },
+    /// The same response as the status endpoint, always the last event
+    Complete(NetworkStatusResponse),
+}
+
 /// Network issues that may affect remote streaming
 #[derive(Debug, Clone, Serialize)]
 pub struct NetworkIssue {
@@ -84,25 +130,122 @@ pub struct NetworkIssue {
     pub message: String,
 }
 
+/// The ice servers with TURN credentials for clients which build their own peer connection,
+/// e.g. the hybrid input client
+#[get("/ice-servers")]
+pub async fn get_ice_servers(
+    app: Data<App>,
+    _user: AuthenticatedUser,
+) -> Json<GetIceServersResponse> {
+    let IceServerList {
+        ice_servers,
+        expires_in,
+    } = app.ice_servers().await;
+
+    Json(GetIceServersResponse {
+        ice_servers,
+        expires_in_secs: expires_in
+            .map(|expires_in| expires_in.as_secs().try_into().unwrap_or(u32::MAX)),
+    })
+}
+
 /// Get network status for remote streaming diagnostics
 #[get("/network/status")]
-pub async fn get_network_status(upnp_manager: Option<Data<UpnpManager>>) -> HttpResponse {
+pub async fn get_network_status(
+    app: Data<App>,
+    upnp_manager: Option<Data<UpnpManager>>,
+) -> HttpResponse {
+    let response = network_status(&app, upnp_manager, None).await;
+
+    HttpResponse::Ok().json(response)
+}
+
+/// Runs the same probes as the status endpoint, but sends every result as soon as it's known
+#[get("/network/diagnostics/ws")]
+pub async fn network_diagnostics_ws(
+    app: Data<App>,
+    upnp_manager: Option<Data<UpnpManager>>,
+    request: HttpRequest,
+    payload: Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (response, mut session, _stream) = actix_ws::handle(&request, payload)?;
+
+    actix_rt::spawn(async move {
+        let (events, mut event_receiver) = unbounded_channel();
+        actix_rt::spawn(async move {
+            let status = network_status(&app, upnp_manager, Some(events.clone())).await;
+            let _ = events.send(NetworkDiagnosticEvent::Complete(status));
+        });
+
+        while let Some(event) = event_receiver.recv().await {
+            let Some(json) = serialize_json(&event) else {
+                continue;
+            };
+            if session.text(json).await.is_err() {
+                // The client left, the probes finish on their own
+                return;
+            }
+        }
+
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
+}
+
+fn emit(events: &Option<UnboundedSender<NetworkDiagnosticEvent>>, event: NetworkDiagnosticEvent) {
+    if let Some(events) = events {
+        let _ = events.send(event);
+    }
+}
+
+async fn network_status(
+    app: &App,
+    upnp_manager: Option<Data<UpnpManager>>,
+    events: Option<UnboundedSender<NetworkDiagnosticEvent>>,
+) -> NetworkStatusResponse {
     let mut recommendations = Vec::new();
     let mut issues = Vec::new();
-    let mut remote_accessible = false;
+    let mut remote_accessible = None;
     let mut direct_connection_possible = false;
     let mut turn_recommended = false;
 
-    // Collect external IPs for comparison
-    let mut upnp_external_ip: Option<String> = None;
-
     // === UPnP Status ===
-    let upnp_status = if let Some(manager) = upnp_manager {
+    let (upnp_ip_tx, upnp_ip_rx) = oneshot::channel();
+    let upnp_probe = async {
+        let mut issues = Vec::new();
+        let mut recommendations = Vec::new();
+        let mut remote_accessible = false;
+
+        let Some(manager) = upnp_manager else {
+            let _ = upnp_ip_tx.send(None);
+
+            recommendations.push(
+                "UPnP is disabled. Enable it in config for automatic port forwarding, \
+                or manually forward ports for remote streaming."
+                    .to_string(),
+            );
+
+            let upnp_status = UpnpStatusResponse {
+                enabled: false,
+                available: false,
+                external_ip: None,
+                gateway: None,
+                mapped_ports: Vec::new(),
+                last_error: None,
+            };
+            emit(&events, NetworkDiagnosticEvent::Upnp(upnp_status.clone()));
+
+            return (upnp_status, issues, recommendations, remote_accessible);
+        };
+
+        // Ask the gateway again, the address might have changed since the mappings were created
+        manager.refresh_external_ip().await;
         let status = manager.status().await;
+        let _ = upnp_ip_tx.send(status.external_ip);
 
         if status.available {
-            if let Some(ip) = status.external_ip {
-                upnp_external_ip = Some(ip.to_string());
+            if status.external_ip.is_some() {
                 remote_accessible = true;
             }
 
@@ -140,7 +283,7 @@ pub async fn get_network_status(upnp_manager: Option<Data<UpnpManager>>) -> Http
             );
         }
 
-        UpnpStatusResponse {
+        let upnp_status = UpnpStatusResponse {
             enabled: true,
             available: status.available,
             external_ip: status.external_ip.map(|ip| ip.to_string()),
@@ -159,30 +302,27 @@ pub async fn get_network_status(upnp_manager: Option<Data<UpnpManager>>) -> Http
                 })
                 .collect(),
             last_error: status.last_error,
-        }
-    } else {
-        recommendations.push(
-            "UPnP is disabled. Enable it in config for automatic port forwarding, \
-            or manually forward ports for remote streaming."
-                .to_string(),
-        );
+        };
+        emit(&events, NetworkDiagnosticEvent::Upnp(upnp_status.clone()));
 
-        UpnpStatusResponse {
-            enabled: false,
-            available: false,
-            external_ip: None,
-            gateway: None,
-            mapped_ports: Vec::new(),
-            last_error: None,
-        }
+        (upnp_status, issues, recommendations, remote_accessible)
     };
 
-    // === NAT Type Detection via STUN ===
-    let nat_status = {
-        let client = StunClient::new();
-        let result = client.detect_nat_type();
+    // The port check only needs an external ip. It's taken from UPnP and only waits for the STUN
+    // servers if UPnP didn't find one, all three probes run at the same time.
+    let (stun_ip_tx, stun_ip_rx) = oneshot::channel();
 
-        if result.success {
+    // === NAT Type Detection via STUN ===
+    let nat_detection = async {
+        let result = detect_nat_type(
+            &app.config().webrtc.ice_servers,
+            app.config().ip_preference,
+            &events,
+        )
+        .await;
+        let _ = stun_ip_tx.send(result.external_ip);
+
+        let nat_status = if result.success {
             let nat_type = result.nat_type;
             direct_connection_possible = nat_type.supports_direct_connection();
             turn_recommended = !direct_connection_possible;
@@ -208,7 +348,7 @@ pub async fn get_network_status(upnp_manager: Option<Data<UpnpManager>>) -> Http
                     recommendations.push(
                         "Your ISP uses Carrier-Grade NAT (CGNAT). Direct connections are not possible. Use Tailscale VPN or contact your ISP for a public IP.".to_string()
                     );
-                    remote_accessible = false;
+                    remote_accessible = Some(false);
                 }
                 NatType::DoubleNat => {
                     issues.push(NetworkIssue {
@@ -230,27 +370,11 @@ pub async fn get_network_status(upnp_manager: Option<Data<UpnpManager>>) -> Http
                 _ => {
                     // Good NAT types
                     if result.external_ip.is_some() {
-                        remote_accessible = true;
+                        remote_accessible = Some(true);
                     }
                 }
             }
 
-            // Compare STUN and UPnP external IPs
-            if let (Some(stun_ip), Some(upnp_ip)) =
-                (result.external_ip.map(|ip| ip.to_string()), &upnp_external_ip)
-            {
-                if stun_ip != *upnp_ip {
-                    issues.push(NetworkIssue {
-                        severity: "warning".to_string(),
-                        code: "ip_mismatch".to_string(),
-                        message: format!(
-                            "External IP mismatch: UPnP reports {} but STUN reports {}. This may indicate complex NAT or load balancing.",
-                            upnp_ip, stun_ip
-                        ),
-                    });
-                }
-            }
-
             NatStatusResponse {
                 nat_type: nat_type.as_str().to_string(),
                 description: nat_type.description().to_string(),
@@ -274,9 +398,84 @@ pub async fn get_network_status(upnp_manager: Option<Data<UpnpManager>>) -> Http
                 detection_successful: false,
                 error: result.error,
             }
+        };
+        emit(&events, NetworkDiagnosticEvent::Nat(nat_status.clone()));
+
+        nat_status
+    };
+
+    // === Port Forwarding Verification ===
+    let port_check = async {
+        let external_ip = match upnp_ip_rx.await.ok().flatten() {
+            Some(ip) => Some(ip),
+            None => stun_ip_rx.await.ok().flatten(),
         }
+        .map(IpAddr::V4);
+
+        let (verified, issue) = match external_ip {
+            Some(external_ip) => {
+                let config = app.config();
+                let port = config
+                    .remote
+                    .port
+                    .unwrap_or(config.web_server.bind_address.port());
+
+                match check_port_accessible(
+                    external_ip,
+                    port,
+                    config.remote.port_check_url.as_deref(),
+                )
+                .await
+                {
+                    Ok(true) => (true, None),
+                    Ok(false) => (
+                        false,
+                        Some(NetworkIssue {
+                            severity: "warning".to_string(),
+                            code: "port_not_verified".to_string(),
+                            message: format!(
+                                "Port {port} could not be reached through the external IP {external_ip}. Check your port forwarding."
+                            ),
+                        }),
+                    ),
+                    Err(err) => {
+                        warn!("[Network] Port accessibility check failed: {err}");
+                        (false, None)
+                    }
+                }
+            }
+            None => (false, None),
+        };
+        emit(&events, NetworkDiagnosticEvent::PortForward { verified });
+
+        (verified, issue)
     };
 
+    let (
+        (upnp_status, upnp_issues, upnp_recommendations, upnp_remote_accessible),
+        nat_status,
+        (port_forward_verified, port_issue),
+    ) = join!(upnp_probe, nat_detection, port_check);
+
+    issues.splice(0..0, upnp_issues);
+    recommendations.splice(0..0, upnp_recommendations);
+    let remote_accessible = remote_accessible.unwrap_or(upnp_remote_accessible);
+
+    // Compare STUN and UPnP external IPs
+    if let (Some(stun_ip), Some(upnp_ip)) = (&nat_status.external_ip_stun, &upnp_status.external_ip)
+        && stun_ip != upnp_ip
+    {
+        issues.push(NetworkIssue {
+            severity: "warning".to_string(),
+            code: "ip_mismatch".to_string(),
+            message: format!(
+                "External IP mismatch: UPnP reports {} but STUN reports {}. This may indicate complex NAT or load balancing.",
+                upnp_ip, stun_ip
+            ),
+        });
+    }
+    issues.extend(port_issue);
+
     // Get local addresses
     let local_addresses = get_local_addresses();
 
@@ -294,18 +493,79 @@ pub async fn get_network_status(upnp_manager: Option<Data<UpnpManager>>) -> Http
         );
     }
 
-    let response = NetworkStatusResponse {
+    NetworkStatusResponse {
         upnp: upnp_status,
         nat: nat_status,
         local_addresses,
+        bind_interfaces: app.config().webrtc.bind_interfaces.clone(),
         remote_accessible,
+        port_forward_verified,
         direct_connection_possible,
         turn_recommended,
         issues,
         recommendations,
+    }
+}
+
+/// Queries all STUN servers at once and reports every result as soon as it arrived
+async fn detect_nat_type(
+    ice_servers: &[RtcIceServer],
+    ip_preference: IpPreference,
+    events: &Option<UnboundedSender<NetworkDiagnosticEvent>>,
+) -> NatDetectionResult {
+    let client = StunClient::from_ice_servers(ice_servers).with_ip_preference(ip_preference);
+
+    let mut probes = client
+        .servers()
+        .iter()
+        .cloned()
+        .enumerate()
+        .map(|(index, server)| {
+            let client = client.clone();
+            async move {
+                let result = spawn_blocking({
+                    let server = server.clone();
+                    move || client.binding_request(&server)
+                })
+                .await
+                .unwrap_or_else(|err| Err(format!("STUN probe failed: {err}")));
+
+                (index, server, result)
+            }
+        })
+        .collect::<FuturesUnordered<_>>();
+
+    let mut results = vec![None; client.servers().len()];
+    while let Some((index, server, result)) = probes.next().await {
+        emit(
+            events,
+            NetworkDiagnosticEvent::StunServer(StunServerResponse {
+                server,
+                external_ip: result
+                    .as_ref()
+                    .ok()
+                    .map(|result| result.external_ip.to_string()),
+                external_port: result.as_ref().ok().map(|result| result.external_port),
+                error: result.as_ref().err().cloned(),
+            }),
+        );
+        results[index] = result.ok();
+    }
+
+    // The servers are compared in their configured order, not in the order they answered
+    let Some(first_result) = results.iter().flatten().next() else {
+        warn!("[STUN] Failed to get external address from any STUN server");
+        return NatDetectionResult {
+            nat_type: NatType::Unknown,
+            external_ip: None,
+            external_port: None,
+            success: false,
+            error: Some("Failed to contact any STUN server".to_string()),
+        };
     };
+    let second_result = results.get(1).and_then(Option::as_ref);
 
-    HttpResponse::Ok().json(response)
+    classify_nat(first_result, second_result)
 }
 
 /// Get local IP addresses
@@ -118,3 +118,45 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{future::pending, time::Duration};
+
+    use futures::StreamExt;
+    use tokio::{spawn, time::timeout};
+
+    use crate::api::response_streaming::{StreamedResponse, StreamedResponseReceiver};
+
+    #[tokio::test]
+    async fn test_closes_after_all_senders_finished() {
+        let (response, sender) = StreamedResponse::<u32, u32>::new(0);
+
+        let answering = sender.clone();
+        spawn(async move {
+            let _ = answering.send(1).await;
+        });
+
+        let hanging = sender.clone();
+        spawn(async move {
+            let _ = timeout(Duration::from_millis(10), pending::<()>()).await;
+            drop(hanging);
+        });
+
+        drop(sender);
+
+        let stream = StreamedResponseReceiver {
+            initial: Some(response.initial),
+            receiver: response.receiver,
+        };
+        let lines = timeout(Duration::from_secs(5), stream.collect::<Vec<_>>())
+            .await
+            .expect("streamed response didn't close");
+
+        let lines = lines
+            .into_iter()
+            .map(|line| line.expect("failed to serialize"))
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec!["0\n", "1\n"]);
+    }
+}
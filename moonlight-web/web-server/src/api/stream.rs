@@ -1,36 +1,150 @@
-use std::process::Stdio;
+use std::{
+    collections::HashMap,
+    process::Stdio,
+    time::{Duration, Instant, SystemTime},
+};
 
 use actix_web::{
     Error, HttpRequest, HttpResponse, get, post, rt as actix_rt,
     web::{Data, Json, Payload},
 };
-use actix_ws::{Closed, Message, Session};
+use actix_ws::{Closed, Message, MessageStream, ProtocolError, Session};
 use common::{
     StreamSettings,
     api_bindings::{
-        PostCancelRequest, PostCancelResponse, StreamClientMessage, StreamServerMessage,
+        self, DetailedHost, DisconnectReason, HostStreamSettings, PostCancelRequest,
+        PostCancelResponse, PostStreamProbeRequest, PostStreamProbeResponse, RemoteAccessInfo,
+        RtcIceServer, StreamCapabilities, StreamClientMessage, StreamCodec, StreamServerMessage,
+    },
+    config::WebRtcConfig,
+    ipc::{
+        IpcSender, STREAMER_BENCHMARK_ARG, ServerIpcMessage, StreamerConfig, StreamerIpcMessage,
+        create_child_ipc,
     },
-    ipc::{ServerIpcMessage, StreamerConfig, StreamerIpcMessage, create_child_ipc},
+    media_api::{create_media_api, into_webrtc_ice},
     serialize_json,
 };
 use log::{debug, error, info, warn};
-use moonlight_common::stream::bindings::SupportedVideoFormats;
-use tokio::{process::Command, spawn};
-
-use crate::app::{
-    App, AppError,
-    host::{AppId, HostId},
-    session::{InputToStreamerMessage, SessionEvent},
-    user::AuthenticatedUser,
+use moonlight_common::{
+    network::launch::LaunchMode,
+    pair::generate_new_client,
+    stream::bindings::{
+        Colorspace, ServerCodeModeSupport, SupportedVideoFormats, TerminationError,
+    },
+};
+use tokio::{
+    process::Command,
+    spawn,
+    sync::mpsc::{Receiver, Sender, channel, error::TrySendError},
+    time::{sleep_until, timeout},
+};
+use webrtc::{
+    peer_connection::{
+        configuration::RTCConfiguration, policy::ice_transport_policy::RTCIceTransportPolicy,
+    },
+    rtp_transceiver::{
+        RTCRtpTransceiverInit, rtp_codec::RTPCodecType,
+        rtp_transceiver_direction::RTCRtpTransceiverDirection,
+    },
+};
+
+use crate::{
+    app::{
+        App, AppError,
+        host::{AppId, HostId},
+        ice_servers::IceServerList,
+        session::{
+            DeadlineEvent, InputToStreamerMessage, MAX_DURATION_REASON, MAX_SPECTATORS_PER_STREAM,
+            PrimaryReattach, SessionDeadline, SessionEvent, SpectatorJoin,
+        },
+        storage::StorageHostPairInfo,
+        streamer_manager::{
+            HeartbeatTick, SERVER_SHUTDOWN_REASON, STREAMER_UNRESPONSIVE_REASON, StreamerHeartbeat,
+        },
+        user::AuthenticatedUser,
+    },
+    remote_access::RemoteAccessProvider,
 };
 
+/// Relay is forced if configured, or if the NAT detection says direct connections won't work
+/// and there's a TURN server the streamer can use
+fn default_force_relay(config: &WebRtcConfig, remote_info: Option<RemoteAccessInfo>) -> bool {
+    match config.force_relay {
+        Some(force_relay) => force_relay,
+        None => remote_info.is_some_and(|info| info.turn_recommended) && config.has_relay_server(),
+    }
+}
+
+/// HDR needs a 10 bit codec which both the host and the client support
+fn host_supports_hdr(host: &DetailedHost, settings: &StreamSettings) -> bool {
+    let server_support = ServerCodeModeSupport::from_bits_truncate(host.server_codec_mode_support);
+    let client_support = settings.video_supported_formats;
+
+    let pixels = settings.width as u64 * settings.height as u64;
+    let hevc = server_support.contains(ServerCodeModeSupport::HEVC_MAIN10)
+        && client_support.contains(SupportedVideoFormats::H265_MAIN10)
+        && pixels <= host.max_luma_pixels_hevc as u64;
+    let av1 = server_support.contains(ServerCodeModeSupport::AV1_MAIN10)
+        && client_support.contains(SupportedVideoFormats::AV1_MAIN10);
+
+    hevc || av1
+}
+
+// Why a stream ended, stored in the stream history
+const CLIENT_DISCONNECTED_REASON: &str = "client_disconnected";
+const RECONNECT_TIMEOUT_REASON: &str = "reconnect_timeout";
+const CONNECTION_TERMINATED_REASON: &str = "connection_terminated";
+const STREAMER_STOPPED_REASON: &str = "streamer_stopped";
+/// The ipc closed without the streamer stopping, it probably crashed
+const STREAMER_CRASHED_REASON: &str = "streamer_crashed";
+const IDLE_REASON: &str = "idle";
+
+/// Maps the reason stored in the history to the one sent to the client.
+/// The error code is only used if the host terminated the connection.
+fn disconnect_reason(end_reason: &str, terminated_error_code: Option<i32>) -> DisconnectReason {
+    match end_reason {
+        CLIENT_DISCONNECTED_REASON | STREAMER_STOPPED_REASON => DisconnectReason::UserRequested,
+        RECONNECT_TIMEOUT_REASON | IDLE_REASON | MAX_DURATION_REASON => DisconnectReason::Timeout,
+        CONNECTION_TERMINATED_REASON => match terminated_error_code {
+            Some(code) if code == TerminationError::Graceful as i32 => DisconnectReason::GameEnded,
+            Some(code)
+                if code == TerminationError::NoVideoTraffic as i32
+                    || code == TerminationError::NoVideoFrame as i32 =>
+            {
+                DisconnectReason::HostOffline
+            }
+            _ => DisconnectReason::Error,
+        },
+        _ => DisconnectReason::Error,
+    }
+}
+
+/// Host id which selects the synthetic loopback host instead of a real one.
+///
+/// Only available if `web_server.enable_loopback_host` is set.
+pub const LOOPBACK_HOST_ID: u32 = u32::MAX;
+/// App id of the loopback host which runs the streamer benchmark
+pub const LOOPBACK_BENCHMARK_APP_ID: u32 = u32::MAX;
+
+/// How long the client has to answer GameRunningConflict before the stream is aborted
+const CONFIRM_CANCEL_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a freshly spawned streamer has to send its ipc version
+const IPC_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the streamer has to send its last messages after it was asked to stop
+const STREAMER_STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
 #[get("/host/stream")]
 pub async fn start_host(
     web_app: Data<App>,
+    remote_provider: Data<RemoteAccessProvider>,
     mut user: AuthenticatedUser,
     request: HttpRequest,
     payload: Payload,
 ) -> Result<HttpResponse, Error> {
+    if web_app.streamer_manager().is_shutting_down() {
+        return Ok(HttpResponse::ServiceUnavailable().finish());
+    }
+
     let (response, mut session, mut stream) = actix_ws::handle(&request, payload)?;
 
     let client_unique_id = user.host_unique_id().await?;
@@ -63,6 +177,44 @@ pub async fn start_host(
             }
         };
 
+        if let StreamClientMessage::Reconnect { reconnection_token } = message {
+            let reattach = PrimaryReattach {
+                session: session.clone(),
+                stream,
+            };
+
+            if let Err(err) = web_app
+                .session_manager()
+                .reattach_primary(&reconnection_token, user.id(), reattach)
+                .await
+            {
+                info!("[Stream]: failed to reconnect to stream: {err}");
+
+                let _ = send_ws_message(&mut session, StreamServerMessage::ReconnectFailed).await;
+                let _ = session.close(None).await;
+            }
+            return;
+        }
+
+        if let StreamClientMessage::Spectate { spectator_token } = message {
+            let join = SpectatorJoin {
+                session: session.clone(),
+                stream,
+            };
+
+            if let Err(err) = web_app
+                .session_manager()
+                .join_spectator(&spectator_token, join)
+                .await
+            {
+                info!("[Stream]: failed to join stream as spectator: {err}");
+
+                let _ = send_ws_message(&mut session, StreamServerMessage::SpectateFailed).await;
+                let _ = session.close(None).await;
+            }
+            return;
+        }
+
         let StreamClientMessage::Init {
             host_id,
             app_id,
@@ -72,12 +224,20 @@ pub async fn start_host(
             width,
             height,
             video_frame_queue_size,
+            video_frame_pacing,
             play_audio_local,
             audio_sample_queue_size,
+            audio_jitter_buffer_ms,
             video_supported_formats,
             video_colorspace,
             video_color_range_full,
             hybrid_mode,
+            allow_spectators,
+            force_relay,
+            hdr_enabled,
+            video_codec_preference,
+            mut launch_options,
+            cancel_running_app,
         } = message
         else {
             let _ = session.close(None).await;
@@ -94,7 +254,7 @@ pub async fn start_host(
             let token = uuid::Uuid::new_v4().to_string();
             let (session_id, _, input_rx) = web_app
                 .session_manager()
-                .register_session(token.clone())
+                .register_session(token.clone(), user.id())
                 .await;
             info!(
                 "[Stream]: Hybrid mode enabled, session_id: {}, token: {}",
@@ -105,7 +265,27 @@ pub async fn start_host(
             (None, None, None)
         };
 
-        let stream_settings = StreamSettings {
+        let force_relay = force_relay.unwrap_or_else(|| {
+            default_force_relay(&web_app.config().webrtc, remote_provider.get_info())
+        });
+
+        // Settings which the client didn't send fall back to the host defaults, then to the config
+        let host_defaults = if host_id.0 == LOOPBACK_HOST_ID {
+            None
+        } else {
+            match user.host(host_id).await {
+                Ok(host) => host
+                    .default_stream_settings(&mut user)
+                    .await
+                    .unwrap_or_else(|err| {
+                        warn!("[Stream]: failed to get the default stream settings of host {host_id:?}: {err:?}");
+                        None
+                    }),
+                // Reported once the host data is collected
+                Err(_) => None,
+            }
+        };
+        let requested = HostStreamSettings {
             bitrate,
             packet_size,
             fps,
@@ -114,6 +294,28 @@ pub async fn start_host(
             video_frame_queue_size,
             audio_sample_queue_size,
             play_audio_local,
+        }
+        .or(host_defaults.unwrap_or_default());
+        let defaults = &web_app.config().default_stream_settings;
+
+        let mut stream_settings = StreamSettings {
+            bitrate: requested.bitrate.unwrap_or(defaults.bitrate),
+            packet_size: requested.packet_size.unwrap_or(defaults.packet_size),
+            fps: requested.fps.unwrap_or(defaults.fps),
+            width: requested.width.unwrap_or(defaults.width),
+            height: requested.height.unwrap_or(defaults.height),
+            video_frame_queue_size: requested
+                .video_frame_queue_size
+                .unwrap_or(defaults.video_frame_queue_size),
+            video_frame_queue_max_bytes: defaults.video_frame_queue_max_bytes,
+            video_frame_pacing,
+            audio_sample_queue_size: requested
+                .audio_sample_queue_size
+                .unwrap_or(defaults.audio_sample_queue_size),
+            audio_jitter_buffer_ms,
+            play_audio_local: requested
+                .play_audio_local
+                .unwrap_or(defaults.play_audio_local),
             video_supported_formats: SupportedVideoFormats::from_bits(video_supported_formats)
                 .unwrap_or_else(|| {
                     warn!("[Stream]: Received invalid supported video formats");
@@ -122,18 +324,17 @@ pub async fn start_host(
             video_colorspace: video_colorspace.into(),
             video_color_range_full,
             hybrid_mode,
+            allow_spectators,
+            force_relay,
+            hdr_enabled,
+            video_codec_preference: video_codec_preference
+                .unwrap_or_else(|| defaults.video_codec_preference.clone()),
         };
 
-        // -- Collect host data
-        let mut host = match user.host(host_id).await {
-            Ok(host) => host,
-            Err(AppError::HostNotFound) => {
-                let _ = send_ws_message(&mut session, StreamServerMessage::HostNotFound).await;
-                let _ = session.close(None).await;
-                return;
-            }
+        let role = match user.role().await {
+            Ok(role) => role,
             Err(err) => {
-                warn!("failed to start stream for host {host_id:?} (at host): {err:?}");
+                warn!("failed to start stream for host {host_id:?} (at user role): {err:?}");
 
                 let _ =
                     send_ws_message(&mut session, StreamServerMessage::InternalServerError).await;
@@ -141,55 +342,235 @@ pub async fn start_host(
                 return;
             }
         };
+        let stream_limits = web_app
+            .config()
+            .stream_limits
+            .restrict_to(web_app.config().limits.for_role(role.into()));
 
-        let apps = match host.list_apps(&mut user).await {
-            Ok(apps) => apps,
-            Err(err) => {
-                warn!("failed to start stream for host {host_id:?} (at list_apps): {err:?}");
+        let mut settings_adjusted = stream_settings.clamp_to_limits(&stream_limits);
+        if let Some(launch_options) = &mut launch_options {
+            // The stream itself isn't changed by this, so the client isn't told about it
+            launch_options.clamp_to_limits(&stream_limits);
+        }
 
-                let _ =
-                    send_ws_message(&mut session, StreamServerMessage::InternalServerError).await;
+        // -- Collect host data
+        let loopback = host_id.0 == LOOPBACK_HOST_ID;
+        let (app, address, http_port, pair_info, host_hdr_supported, launch_mode) = if loopback {
+            if !web_app.config().web_server.enable_loopback_host {
+                let _ = send_ws_message(&mut session, StreamServerMessage::HostNotFound).await;
                 let _ = session.close(None).await;
                 return;
             }
-        };
 
-        let Some(app) = apps.into_iter().find(|app| app.id == app_id) else {
-            warn!("failed to start stream for host {host_id:?} because the app couldn't be found!");
+            match loopback_host_data(app_id) {
+                Ok((app, address, http_port, pair_info)) => {
+                    (app, address, http_port, pair_info, false, None)
+                }
+                Err(err) => {
+                    warn!("failed to start loopback stream: {err:?}");
 
-            let _ = send_ws_message(&mut session, StreamServerMessage::AppNotFound).await;
-            let _ = session.close(None).await;
-            return;
-        };
+                    let _ = send_ws_message(&mut session, StreamServerMessage::InternalServerError)
+                        .await;
+                    let _ = session.close(None).await;
+                    return;
+                }
+            }
+        } else {
+            let mut host = match user.host(host_id).await {
+                Ok(host) => host,
+                Err(AppError::HostNotFound) => {
+                    let _ = send_ws_message(&mut session, StreamServerMessage::HostNotFound).await;
+                    let _ = session.close(None).await;
+                    return;
+                }
+                Err(err) => {
+                    warn!("failed to start stream for host {host_id:?} (at host): {err:?}");
 
-        let (address, http_port) = match host.address_port(&mut user).await {
-            Ok(address_port) => address_port,
-            Err(err) => {
-                warn!("failed to start stream for host {host_id:?} (at get address_port): {err:?}");
+                    let _ =
+                        send_ws_message(&mut session, StreamServerMessage::InternalServerError).await;
+                    let _ = session.close(None).await;
+                    return;
+                }
+            };
 
-                let _ =
-                    send_ws_message(&mut session, StreamServerMessage::InternalServerError).await;
+            let apps = match host.list_apps(&mut user).await {
+                Ok(apps) => apps,
+                Err(err) => {
+                    warn!("failed to start stream for host {host_id:?} (at list_apps): {err:?}");
+
+                    let _ =
+                        send_ws_message(&mut session, StreamServerMessage::InternalServerError).await;
+                    let _ = session.close(None).await;
+                    return;
+                }
+            };
+
+            // Without the details the hdr support is unknown and the streamer decides between launch and resume
+            let detailed_host = match host.detailed_host(&mut user).await {
+                Ok(detailed_host) => Some(detailed_host),
+                Err(err) => {
+                    warn!("failed to get the details of host {host_id:?}: {err:?}");
+                    None
+                }
+            };
+            let current_game = detailed_host
+                .as_ref()
+                .map(|detailed_host| detailed_host.current_game);
+            let current_title = current_game.and_then(|current_game| {
+                apps.iter()
+                    .find(|app| app.id.0 == current_game)
+                    .map(|app| app.title.clone())
+            });
+
+            let Some(app) = apps.into_iter().find(|app| app.id == app_id) else {
+                warn!("failed to start stream for host {host_id:?} because the app couldn't be found!");
+
+                let _ = send_ws_message(&mut session, StreamServerMessage::AppNotFound).await;
                 let _ = session.close(None).await;
                 return;
-            }
+            };
+
+            let (address, http_port) = match host.address_port(&mut user).await {
+                Ok(address_port) => address_port,
+                Err(err) => {
+                    warn!("failed to start stream for host {host_id:?} (at get address_port): {err:?}");
+
+                    let _ =
+                        send_ws_message(&mut session, StreamServerMessage::InternalServerError).await;
+                    let _ = session.close(None).await;
+                    return;
+                }
+            };
+
+            let pair_info = match host.pair_info(&mut user).await {
+                Ok(pair_info) => pair_info,
+                Err(err) => {
+                    warn!("failed to start stream for host {host_id:?} (at get pair_info): {err:?}");
+
+                    let _ =
+                        send_ws_message(&mut session, StreamServerMessage::InternalServerError).await;
+                    let _ = session.close(None).await;
+                    return;
+                }
+            };
+
+            let host_hdr_supported = stream_settings.hdr_enabled
+                && detailed_host.as_ref().is_some_and(|detailed_host| {
+                    host_supports_hdr(detailed_host, &stream_settings)
+                });
+
+            // A resume continues whichever game is running, even if it isn't the requested app
+            let launch_mode = match current_game {
+                None => None,
+                Some(0) => Some(LaunchMode::Launch),
+                Some(current_game) if current_game == app_id.0 => Some(LaunchMode::Resume),
+                Some(current_game) => {
+                    if !cancel_running_app {
+                        info!(
+                            "[Stream]: app {current_game} is running on host {host_id:?}, asking the client before quitting it"
+                        );
+
+                        if let Err(Closed) = send_ws_message(
+                            &mut session,
+                            StreamServerMessage::GameRunningConflict { current_title },
+                        )
+                        .await
+                        {
+                            return;
+                        }
+
+                        if !recv_cancel_confirmation(&mut stream).await {
+                            info!(
+                                "[Stream]: the client didn't confirm quitting app {current_game} on host {host_id:?}"
+                            );
+
+                            let _ = session.close(None).await;
+                            return;
+                        }
+                    }
+
+                    match host.cancel_app(&mut user).await {
+                        Ok(true) => {
+                            info!(
+                                "[Stream]: quit app {current_game} on host {host_id:?} to launch {app_id:?}"
+                            );
+                            Some(LaunchMode::Launch)
+                        }
+                        Ok(false) => {
+                            warn!(
+                                "failed to start stream for host {host_id:?} because the running app {current_game} couldn't be quit"
+                            );
+
+                            let _ = send_ws_message(
+                                &mut session,
+                                StreamServerMessage::AlreadyStreaming,
+                            )
+                            .await;
+                            let _ = session.close(None).await;
+                            return;
+                        }
+                        Err(err) => {
+                            warn!(
+                                "failed to start stream for host {host_id:?} (at cancel_app): {err:?}"
+                            );
+
+                            let _ = send_ws_message(
+                                &mut session,
+                                StreamServerMessage::InternalServerError,
+                            )
+                            .await;
+                            let _ = session.close(None).await;
+                            return;
+                        }
+                    }
+                }
+            };
+
+            (
+                app.into(),
+                address,
+                http_port,
+                pair_info,
+                host_hdr_supported,
+                launch_mode,
+            )
         };
 
-        let pair_info = match host.pair_info(&mut user).await {
-            Ok(pair_info) => pair_info,
-            Err(err) => {
-                warn!("failed to start stream for host {host_id:?} (at get pair_info): {err:?}");
+        if stream_settings.hdr_enabled {
+            if host_hdr_supported && app.is_hdr_supported {
+                stream_settings.video_colorspace = Colorspace::Rec2020;
+            } else {
+                warn!(
+                    "[Stream]: HDR was requested but the host or app doesn't support it, streaming in SDR"
+                );
 
-                let _ =
-                    send_ws_message(&mut session, StreamServerMessage::InternalServerError).await;
-                let _ = session.close(None).await;
-                return;
+                stream_settings.hdr_enabled = false;
+                settings_adjusted = true;
             }
-        };
+        }
+
+        if settings_adjusted
+            && let Err(Closed) = send_ws_message(
+                &mut session,
+                StreamServerMessage::SettingsAdjusted {
+                    bitrate: stream_settings.bitrate,
+                    packet_size: stream_settings.packet_size,
+                    fps: stream_settings.fps,
+                    width: stream_settings.width,
+                    height: stream_settings.height,
+                    hdr_enabled: stream_settings.hdr_enabled,
+                },
+            )
+            .await
+        {
+            return;
+        }
 
         // -- Send App info
+        let app_title = app.title.clone();
         let _ = send_ws_message(
             &mut session,
-            StreamServerMessage::UpdateApp { app: app.into() },
+            StreamServerMessage::UpdateApp { app },
         )
         .await;
 
@@ -203,7 +584,11 @@ pub async fn start_host(
         .await;
 
         // Spawn child
-        let (mut child, stdin, stdout) = match Command::new(&web_app.config().streamer_path)
+        let mut command = Command::new(&web_app.config().streamer_path);
+        if loopback && app_id.0 == LOOPBACK_BENCHMARK_APP_ID {
+            command.arg(STREAMER_BENCHMARK_ARG);
+        }
+        let (mut child, stdin, stdout) = match command
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
@@ -217,6 +602,7 @@ pub async fn start_host(
                     (child, stdin, stdout)
                 } else {
                     error!("[Stream]: streamer process didn't include a stdin or stdout");
+                    web_app.streamer_manager().record_spawn_failure();
 
                     let _ = send_ws_message(&mut session, StreamServerMessage::InternalServerError)
                         .await;
@@ -231,6 +617,7 @@ pub async fn start_host(
             }
             Err(err) => {
                 error!("[Stream]: failed to spawn streamer process: {err:?}");
+                web_app.streamer_manager().record_spawn_failure();
 
                 let _ =
                     send_ws_message(&mut session, StreamServerMessage::InternalServerError).await;
@@ -240,13 +627,48 @@ pub async fn start_host(
         };
 
         // Create ipc
-        let (mut ipc_sender, mut ipc_receiver) = create_child_ipc::<
-            ServerIpcMessage,
-            StreamerIpcMessage,
-        >(
-            "Streamer", stdin, stdout, child.stderr.take()
+        let ipc = timeout(
+            IPC_HANDSHAKE_TIMEOUT,
+            create_child_ipc::<ServerIpcMessage, StreamerIpcMessage>(
+                "Streamer",
+                stdin,
+                stdout,
+                child.stderr.take(),
+            ),
         )
         .await;
+        let (mut ipc_sender, mut ipc_receiver) = match ipc {
+            Ok(Ok(ipc)) => ipc,
+            result => {
+                if let Ok(Err(err)) = result {
+                    error!("[Stream]: failed to set up the ipc with the streamer: {err}");
+                } else {
+                    error!("[Stream]: the streamer didn't send its ipc version in time");
+                }
+                web_app.streamer_manager().record_spawn_failure();
+
+                let _ =
+                    send_ws_message(&mut session, StreamServerMessage::InternalServerError).await;
+                let _ = session.close(None).await;
+
+                if let Err(err) = child.kill().await {
+                    warn!("[Stream]: failed to kill child: {err:?}");
+                }
+
+                return;
+            }
+        };
+
+        let session_id = uuid::Uuid::new_v4().to_string();
+        let streamer_id = web_app
+            .streamer_manager()
+            .register(child.id(), session_id.clone())
+            .await;
+
+        let heartbeat_interval = web_app.config().streamer_heartbeat.interval();
+        let mut heartbeat =
+            StreamerHeartbeat::new(web_app.config().streamer_heartbeat.max_missed_pongs);
+        let mut next_heartbeat = heartbeat_interval.map(|interval| Instant::now() + interval);
 
         // Clone web_app for use in spawned task (for session cleanup)
         let web_app_cleanup = web_app.clone();
@@ -273,6 +695,11 @@ pub async fn start_host(
                                 .send(ServerIpcMessage::InputWebSocket(signaling))
                                 .await;
                         }
+                        InputToStreamerMessage::Clipboard(text) => {
+                            ipc_sender_for_input
+                                .send(ServerIpcMessage::Clipboard { text })
+                                .await;
+                        }
                         InputToStreamerMessage::Disconnected => {
                             info!("[Stream]: >>> Input connection disconnected, notifying streamer");
                             ipc_sender_for_input
@@ -303,155 +730,928 @@ pub async fn start_host(
                 .await;
         }
 
-        // Redirect ipc message into ws, also handle session events
-        spawn(async move {
-            loop {
-                tokio::select! {
-                    ipc_msg = ipc_receiver.recv() => {
-                        match ipc_msg {
-                            Some(StreamerIpcMessage::WebSocket(message)) => {
-                                if let Err(Closed) = send_ws_message(&mut session, message).await {
-                                    warn!(
-                                        "[Ipc]: Tried to send a ws message but the socket is already closed"
-                                    );
-                                    break;
-                                }
-                            }
-                            Some(StreamerIpcMessage::InputSignaling(signaling)) => {
-                                // Forward input signaling to input client via session manager
-                                if let Some(ref session_id) = hybrid_session_id_for_input {
-                                    debug!("[Ipc]: Forwarding input signaling from streamer to input client");
-                                    web_app_for_input
-                                        .session_manager()
-                                        .send_to_input(
-                                            session_id,
-                                            crate::app::session::StreamerToInputMessage::Signaling(signaling),
-                                        )
-                                        .await;
-                                }
-                            }
-                            Some(StreamerIpcMessage::InputReady) => {
-                                if let Some(ref session_id) = hybrid_session_id_for_input {
-                                    debug!("[Ipc]: Input peer ready, notifying input client");
-                                    web_app_for_input
-                                        .session_manager()
-                                        .send_to_input(
-                                            session_id,
-                                            crate::app::session::StreamerToInputMessage::Ready,
-                                        )
-                                        .await;
-                                }
+        // Enforce the maximum session duration if configured
+        let session_limits = &web_app.config().session_limits;
+        let mut deadline = session_limits.max_duration().map(|max_duration| {
+            SessionDeadline::new(
+                Instant::now(),
+                max_duration,
+                session_limits.max_duration_warning(),
+            )
+        });
+
+        // Allow the client to take over this stream with a new web socket if the connection is lost
+        let reconnect_grace = session_limits.reconnect_grace();
+        let (reconnection_token, mut reattach_rx) = if reconnect_grace.is_some() {
+            let (token, reattach_rx) = web_app
+                .session_manager()
+                .register_primary_reconnect(user.id())
+                .await;
+
+            (Some(token), Some(reattach_rx))
+        } else {
+            (None, None)
+        };
+
+        let mut primary = PrimaryConnection {
+            session,
+            stream: Some(stream),
+            reconnect_grace,
+            detached_until: None,
+            last_pong: Instant::now(),
+        };
+
+        let ws_ping_interval = web_app.config().web_server.ws_ping_interval();
+        let ws_pong_timeout = web_app.config().web_server.ws_pong_timeout();
+        let mut next_ws_ping = ws_ping_interval.map(|interval| Instant::now() + interval);
+
+        // Spectators can only join once the stream is connected, see ConnectionComplete
+        let mut spectator_token = None;
+        let mut spectator_rx = None;
+        let mut spectators = Spectators::new(ws_ping_interval, ws_pong_timeout);
+
+        if let Some(reconnection_token) = reconnection_token.clone() {
+            let _ = primary
+                .send(StreamServerMessage::StreamReconnectionToken { reconnection_token })
+                .await;
+        }
+
+        let started_at = SystemTime::now();
+        // Set once we know why the stream ends, a closed web socket is the default
+        let mut end_reason = None;
+        // Set once the streamer stopped on its own or its ipc closed
+        let mut streamer_stopped = false;
+
+        // Send init into ipc
+        ipc_sender
+            .send(ServerIpcMessage::Init {
+                config: StreamerConfig {
+                    // The streamer sends these ice servers to the client in the setup message
+                    webrtc: WebRtcConfig {
+                        ice_servers: web_app.ice_servers().await.ice_servers,
+                        ..web_app.config().webrtc.clone()
+                    },
+                    log_level: web_app.config().log.level_filter,
+                    pidfile_dir: web_app.config().streamer_pidfile_dir.clone(),
+                    idle_timeout_secs: session_limits.idle_timeout_secs,
+                    file_transfer: web_app.config().file_transfer.clone(),
+                    recording: web_app.config().recording.clone(),
+                    ipc_timeout_secs: web_app.config().streamer_heartbeat.streamer_timeout_secs(),
+                },
+                stream_settings,
+                host_address: address,
+                host_http_port: http_port,
+                client_unique_id: Some(client_unique_id),
+                client_private_key: pair_info.client_private_key,
+                client_certificate: pair_info.client_certificate,
+                server_certificate: pair_info.server_certificate,
+                app_id: app_id.0,
+                session_token,
+                loopback,
+                session_id: session_id.clone(),
+                launch_options,
+                launch_mode,
+            })
+            .await;
+
+        let mut shutdown_requested = false;
+        let mut terminated_error_code = None;
+        // The channel closes once the hybrid session is removed
+        let mut session_events_open = true;
+
+        // Redirect ws messages into ipc and ipc messages into ws, also handle session events
+        loop {
+            let next_deadline = deadline.as_ref().and_then(SessionDeadline::next_deadline);
+
+            tokio::select! {
+                _ = web_app.streamer_manager().wait_for_shutdown(), if !shutdown_requested => {
+                    info!("[Stream]: Server is shutting down, stopping the stream");
+                    shutdown_requested = true;
+                    end_reason = Some(SERVER_SHUTDOWN_REASON);
+                    let _ = primary.send(
+                        StreamServerMessage::Ended {
+                            reason: SERVER_SHUTDOWN_REASON.to_string(),
+                        },
+                    ).await;
+
+                    // The streamer answers with Stop once it's done
+                    ipc_sender.send(ServerIpcMessage::Stop).await;
+                }
+                _ = wait_for_deadline(next_deadline) => {
+                    let Some(deadline) = deadline.as_mut() else {
+                        continue;
+                    };
+
+                    match deadline.poll(Instant::now()) {
+                        Some(DeadlineEvent::Warning { remaining }) => {
+                            info!("[Stream]: Session ends in {}s because of the maximum session duration", remaining.as_secs());
+                            if let Err(Closed) = primary.send(
+                                StreamServerMessage::SessionEnding {
+                                    reason: MAX_DURATION_REASON.to_string(),
+                                    remaining_secs: remaining.as_secs(),
+                                },
+                            ).await {
+                                warn!("[Stream]: Failed to send SessionEnding to client");
+                                break;
                             }
-                            Some(StreamerIpcMessage::Stop) => {
-                                debug!("[Ipc]: ipc receiver stopped by streamer");
+                        }
+                        Some(DeadlineEvent::Expired) => {
+                            info!("[Stream]: Session reached the maximum session duration, stopping");
+                            end_reason = Some(MAX_DURATION_REASON);
+                            let _ = primary.send(
+                                StreamServerMessage::Ended {
+                                    reason: MAX_DURATION_REASON.to_string(),
+                                },
+                            ).await;
+
+                            // The streamer answers with Stop once it's done
+                            ipc_sender.send(ServerIpcMessage::Stop).await;
+                        }
+                        None => {}
+                    }
+                }
+                _ = wait_for_deadline(next_heartbeat) => {
+                    next_heartbeat = heartbeat_interval.map(|interval| Instant::now() + interval);
+
+                    match heartbeat.tick() {
+                        HeartbeatTick::Ping { sequence } => {
+                            ipc_sender.send(ServerIpcMessage::Ping { sequence }).await;
+                        }
+                        HeartbeatTick::Unresponsive { missed } => {
+                            error!("[Stream]: Streamer didn't answer {missed} pings, stopping the stream");
+                            end_reason = Some(STREAMER_UNRESPONSIVE_REASON);
+                            let _ = primary.send(
+                                StreamServerMessage::Ended {
+                                    reason: STREAMER_UNRESPONSIVE_REASON.to_string(),
+                                },
+                            ).await;
+
+                            // A hung streamer won't answer Stop, the child is killed below
+                            break;
+                        }
+                    }
+                }
+                _ = wait_for_deadline(next_ws_ping) => {
+                    next_ws_ping = ws_ping_interval.map(|interval| Instant::now() + interval);
+
+                    if let Err(Closed) = primary.ping(ws_pong_timeout).await {
+                        break;
+                    }
+                }
+                _ = wait_for_deadline(primary.detached_until) => {
+                    info!("[Stream]: Client didn't reconnect in time, stopping");
+                    primary.give_up();
+                    end_reason = Some(RECONNECT_TIMEOUT_REASON);
+
+                    // The streamer answers with Stop once it's done
+                    ipc_sender.send(ServerIpcMessage::Stop).await;
+                }
+                reattach = recv_reattach(&mut reattach_rx) => {
+                    let Some(reattach) = reattach else {
+                        reattach_rx = None;
+                        continue;
+                    };
+
+                    info!("[Stream]: Client reconnected to the stream");
+                    primary.attach(reattach).await;
+
+                    if let Err(Closed) = primary.send(StreamServerMessage::Reconnected).await {
+                        warn!("[Stream]: Failed to send Reconnected to client");
+                        break;
+                    }
+                }
+                join = recv_spectator(&mut spectator_rx) => {
+                    let Some(join) = join else {
+                        spectator_rx = None;
+                        continue;
+                    };
+
+                    spectators.join(
+                        join,
+                        web_app.ice_servers().await.ice_servers,
+                        force_relay,
+                        ipc_sender.clone(),
+                    );
+                }
+                ws_msg = primary.recv() => {
+                    match ws_msg {
+                        Some(Ok(Message::Text(text))) => {
+                            let Ok(message) = serde_json::from_str::<StreamClientMessage>(&text) else {
+                                warn!("[Stream]: failed to deserialize from json");
+                                continue;
+                            };
+
+                            ipc_sender.send(ServerIpcMessage::WebSocket(message)).await;
+                        }
+                        Some(Ok(Message::Ping(bytes))) => {
+                            if let Err(Closed) = primary.pong(&bytes).await {
                                 break;
                             }
-                            None => {
-                                debug!("[Ipc]: ipc receiver channel closed");
+                        }
+                        Some(Ok(Message::Pong(_))) => {
+                            primary.last_pong = Instant::now();
+                        }
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                            if let Err(Closed) = primary.detach() {
                                 break;
                             }
                         }
+                        Some(Ok(_)) => {}
                     }
-                    session_event = session_event_rx.recv() => {
-                        match session_event {
-                            Some(SessionEvent::InputJoined) => {
-                                debug!("[Stream]: Input connection joined");
-                                if let Err(Closed) = send_ws_message(
-                                    &mut session,
-                                    StreamServerMessage::InputJoined,
-                                ).await {
-                                    warn!("[Stream]: Failed to send InputJoined to client");
-                                    break;
+                }
+                ipc_msg = ipc_receiver.recv() => {
+                    match ipc_msg {
+                        Some(StreamerIpcMessage::WebSocket(message)) => {
+                            let connection_complete =
+                                matches!(message, StreamServerMessage::ConnectionComplete { .. });
+                            match message {
+                                StreamServerMessage::ConnectionComplete { format, width, height, fps, .. }
+                                | StreamServerMessage::VideoChanged { format, width, height, fps } => {
+                                    spectators.set_video(SpectatorVideo { format, width, height, fps });
                                 }
+                                _ => {}
                             }
-                            Some(SessionEvent::InputDisconnected) => {
-                                debug!("[Stream]: Input connection disconnected");
-                                if let Err(Closed) = send_ws_message(
-                                    &mut session,
-                                    StreamServerMessage::InputDisconnected,
-                                ).await {
-                                    warn!("[Stream]: Failed to send InputDisconnected to client");
-                                    break;
-                                }
+                            if let StreamServerMessage::ConnectionTerminated { error_code } = message {
+                                end_reason.get_or_insert(CONNECTION_TERMINATED_REASON);
+                                terminated_error_code.get_or_insert(error_code);
+                            }
+
+                            if let Err(Closed) = primary.send(message).await {
+                                warn!(
+                                    "[Ipc]: Tried to send a ws message but the socket is already closed"
+                                );
+                                break;
                             }
-                            Some(SessionEvent::ReconnectionTokenAvailable(token)) => {
-                                debug!("[Stream]: Reconnection token available: {}", token);
-                                if let Err(Closed) = send_ws_message(
-                                    &mut session,
-                                    StreamServerMessage::ReconnectionTokenAvailable {
-                                        session_token: token,
-                                    },
-                                ).await {
-                                    warn!("[Stream]: Failed to send ReconnectionTokenAvailable to client");
-                                    break;
+
+                            if connection_complete && allow_spectators && spectator_token.is_none() {
+                                let (token, join_rx) =
+                                    web_app.session_manager().register_spectators().await;
+
+                                if let Err(Closed) = primary
+                                    .send(StreamServerMessage::SpectatorToken {
+                                        spectator_token: token.clone(),
+                                    })
+                                    .await
+                                {
+                                    warn!("[Stream]: Failed to send SpectatorToken to client");
                                 }
+
+                                spectator_token = Some(token);
+                                spectator_rx = Some(join_rx);
                             }
-                            Some(SessionEvent::PrimaryDisconnected) => {
-                                // This shouldn't happen as we ARE the primary
-                                warn!("[Stream]: Received unexpected PrimaryDisconnected event");
+                        }
+                        Some(StreamerIpcMessage::SpectatorSignaling { spectator_id, signaling }) => {
+                            spectators.send(spectator_id, StreamServerMessage::WebRtc(signaling));
+                        }
+                        Some(StreamerIpcMessage::InputSignaling(signaling)) => {
+                            // Forward input signaling to input client via session manager
+                            if let Some(ref session_id) = hybrid_session_id_for_input {
+                                debug!("[Ipc]: Forwarding input signaling from streamer to input client");
+                                web_app_for_input
+                                    .session_manager()
+                                    .send_to_input(
+                                        session_id,
+                                        crate::app::session::StreamerToInputMessage::Signaling(signaling),
+                                    )
+                                    .await;
                             }
-                            None => {
-                                // Session event channel closed, continue with IPC only
-                                debug!("[Stream]: Session event channel closed");
+                        }
+                        Some(StreamerIpcMessage::InputReady) => {
+                            if let Some(ref session_id) = hybrid_session_id_for_input {
+                                debug!("[Ipc]: Input peer ready, notifying input client");
+                                web_app_for_input
+                                    .session_manager()
+                                    .send_to_input(
+                                        session_id,
+                                        crate::app::session::StreamerToInputMessage::Ready,
+                                    )
+                                    .await;
+                            }
+                        }
+                        Some(StreamerIpcMessage::InputFailed) => {
+                            if let Some(ref session_id) = hybrid_session_id_for_input {
+                                debug!("[Ipc]: Input peer failed, notifying input client");
+                                web_app_for_input
+                                    .session_manager()
+                                    .send_to_input(
+                                        session_id,
+                                        crate::app::session::StreamerToInputMessage::Failed,
+                                    )
+                                    .await;
+                            }
+
+                            if let Err(Closed) = primary.send(StreamServerMessage::InputFailed).await {
+                                warn!("[Stream]: Failed to send InputFailed to client");
+                                break;
+                            }
+                        }
+                        Some(StreamerIpcMessage::Pong {
+                            sequence,
+                            bytes_sent,
+                        }) => {
+                            if heartbeat.pong(sequence) {
+                                web_app
+                                    .streamer_manager()
+                                    .record_pong(streamer_id, Instant::now(), bytes_sent)
+                                    .await;
+                            } else {
+                                debug!("[Ipc]: Received unexpected pong {sequence}");
                             }
                         }
+                        Some(StreamerIpcMessage::Idle { idle_secs }) => {
+                            info!("[Stream]: Stopping the stream after {idle_secs}s without input");
+
+                            end_reason = Some(IDLE_REASON);
+                            let _ = primary
+                                .send(StreamServerMessage::Ended {
+                                    reason: IDLE_REASON.to_string(),
+                                })
+                                .await;
+
+                            // Stopping doesn't quit the app, so it can be resumed later
+                            ipc_sender.send(ServerIpcMessage::Stop).await;
+                        }
+                        Some(StreamerIpcMessage::ConnectionLog { entries }) => {
+                            info!("[Stream]: The peer connection failed, keeping its connection log");
+
+                            web_app
+                                .record_failed_connection(
+                                    user.id(),
+                                    host_id,
+                                    app_title.clone(),
+                                    entries,
+                                )
+                                .await;
+                        }
+                        Some(StreamerIpcMessage::StatsTimeline { samples }) => {
+                            debug!("[Stream]: Received a stats timeline with {} samples", samples.len());
+
+                            web_app
+                                .record_stats_timeline(session_id.clone(), user.id(), samples)
+                                .await;
+                        }
+                        Some(StreamerIpcMessage::Stop) => {
+                            debug!("[Ipc]: ipc receiver stopped by streamer");
+                            end_reason.get_or_insert(STREAMER_STOPPED_REASON);
+                            streamer_stopped = true;
+                            break;
+                        }
+                        None => {
+                            debug!("[Ipc]: ipc receiver channel closed");
+                            end_reason.get_or_insert(STREAMER_CRASHED_REASON);
+                            streamer_stopped = true;
+                            break;
+                        }
+                    }
+                }
+                session_event = session_event_rx.recv(), if session_events_open => {
+                    match session_event {
+                        Some(SessionEvent::InputJoined) => {
+                            debug!("[Stream]: Input connection joined");
+                            if let Err(Closed) = primary.send(
+                                StreamServerMessage::InputJoined,
+                            ).await {
+                                warn!("[Stream]: Failed to send InputJoined to client");
+                                break;
+                            }
+                        }
+                        Some(SessionEvent::InputDisconnected) => {
+                            debug!("[Stream]: Input connection disconnected");
+                            if let Err(Closed) = primary.send(
+                                StreamServerMessage::InputDisconnected,
+                            ).await {
+                                warn!("[Stream]: Failed to send InputDisconnected to client");
+                                break;
+                            }
+                        }
+                        Some(SessionEvent::ReconnectionTokenAvailable(token)) => {
+                            debug!("[Stream]: Reconnection token available: {}", token);
+                            if let Err(Closed) = primary.send(
+                                StreamServerMessage::ReconnectionTokenAvailable {
+                                    session_token: token,
+                                },
+                            ).await {
+                                warn!("[Stream]: Failed to send ReconnectionTokenAvailable to client");
+                                break;
+                            }
+                        }
+                        Some(SessionEvent::PrimaryDisconnected) => {
+                            // This shouldn't happen as we ARE the primary
+                            warn!("[Stream]: Received unexpected PrimaryDisconnected event");
+                        }
+                        Some(SessionEvent::InputReconnectLimitReached) => {
+                            warn!("[Stream]: Input connection reconnected too often, it can't join again");
+                            if let Err(Closed) = primary.send(StreamServerMessage::InputFailed).await {
+                                warn!("[Stream]: Failed to send InputFailed to client");
+                                break;
+                            }
+                        }
+                        None => {
+                            // Session event channel closed, continue with IPC only
+                            debug!("[Stream]: Session event channel closed");
+                            session_events_open = false;
+                        }
                     }
                 }
             }
-            info!("[Ipc]: ipc receiver loop ended");
-
-            // Clean up hybrid session if applicable
-            if let Some(session_id) = hybrid_session_id_cleanup {
-                web_app_cleanup
-                    .session_manager()
-                    .primary_disconnected(&session_id)
-                    .await;
-            }
+        }
+        info!("[Ipc]: ipc receiver loop ended");
 
-            // close the websocket when the streamer crashed / disconnected / whatever
-            if let Err(err) = session.close(None).await {
-                warn!("failed to close streamer web socket: {err}");
-            }
+        // The streamer sends its stats timeline when it stops, so it's asked to stop before it's killed
+        if !streamer_stopped {
+            ipc_sender.send(ServerIpcMessage::Stop).await;
 
-            // kill the streamer
-            if let Err(err) = child.kill().await {
-                warn!("failed to kill streamer child: {err}");
+            let stopped = timeout(STREAMER_STOP_TIMEOUT, async {
+                while let Some(message) = ipc_receiver.recv().await {
+                    match message {
+                        StreamerIpcMessage::StatsTimeline { samples } => {
+                            web_app
+                                .record_stats_timeline(session_id.clone(), user.id(), samples)
+                                .await;
+                        }
+                        StreamerIpcMessage::Stop => break,
+                        _ => {}
+                    }
+                }
+            })
+            .await;
+            if stopped.is_err() {
+                debug!("[Ipc]: the streamer didn't stop in time, killing it");
             }
-        });
+        }
 
-        // Send init into ipc
-        ipc_sender
-            .send(ServerIpcMessage::Init {
-                config: StreamerConfig {
-                    webrtc: web_app.config().webrtc.clone(),
-                    log_level: web_app.config().log.level_filter,
-                },
-                stream_settings,
-                host_address: address,
-                host_http_port: http_port,
-                client_unique_id: Some(client_unique_id),
-                client_private_key: pair_info.client_private_key,
-                client_certificate: pair_info.client_certificate,
-                server_certificate: pair_info.server_certificate,
-                app_id: app_id.0,
-                session_token,
+        if let Some(spectator_token) = spectator_token {
+            web_app
+                .session_manager()
+                .remove_spectators(&spectator_token)
+                .await;
+        }
+        // Dropping the senders closes the spectator web sockets
+        drop(spectators);
+
+        if let Some(reconnection_token) = reconnection_token {
+            web_app
+                .session_manager()
+                .remove_primary_reconnect(&reconnection_token)
+                .await;
+        }
+
+        // Clean up hybrid session if applicable
+        if let Some(session_id) = hybrid_session_id_cleanup {
+            web_app_cleanup
+                .session_manager()
+                .primary_disconnected(&session_id)
+                .await;
+        }
+
+        let end_reason = end_reason.unwrap_or(CLIENT_DISCONNECTED_REASON);
+
+        // close the websocket when the streamer crashed / disconnected / whatever
+        let _ = primary
+            .send(StreamServerMessage::Disconnected {
+                reason: disconnect_reason(end_reason, terminated_error_code),
             })
             .await;
+        if let Err(err) = primary.session.close(None).await {
+            warn!("failed to close streamer web socket: {err}");
+        }
 
-        // Redirect ws message into ipc
-        while let Some(Ok(Message::Text(text))) = stream.recv().await {
-            let Ok(message) = serde_json::from_str::<StreamClientMessage>(&text) else {
-                warn!("[Stream]: failed to deserialize from json");
-                return;
-            };
+        // kill the streamer
+        if let Err(err) = child.kill().await {
+            warn!("failed to kill streamer child: {err}");
+        }
+        web_app.streamer_manager().remove(streamer_id).await;
 
-            ipc_sender.send(ServerIpcMessage::WebSocket(message)).await;
+        if let Err(err) = web_app
+            .record_stream_history(
+                user.id(),
+                host_id,
+                app_title,
+                session_id,
+                started_at,
+                end_reason,
+            )
+            .await
+        {
+            warn!("[Stream]: failed to record the stream in the history: {err:?}");
         }
     });
 
     Ok(response)
 }
 
+/// The primary web socket of a stream, it can be replaced if the client reconnects
+struct PrimaryConnection {
+    session: Session,
+    stream: Option<MessageStream>,
+    reconnect_grace: Option<Duration>,
+    /// Set while no web socket is attached, the stream is stopped at this instant
+    detached_until: Option<Instant>,
+    last_pong: Instant,
+}
+
+impl PrimaryConnection {
+    /// Messages are dropped while detached.
+    /// Returns Err if the web socket is closed and the client can't reconnect.
+    async fn send(&mut self, message: StreamServerMessage) -> Result<(), Closed> {
+        if self.detached_until.is_some() {
+            debug!("[Stream]: dropping message for detached client: {message:?}");
+            return Ok(());
+        }
+
+        if let Err(Closed) = send_ws_message(&mut self.session, message).await {
+            return self.detach();
+        }
+
+        Ok(())
+    }
+
+    /// Sends a keepalive ping.
+    /// A socket which didn't answer the previous pings is handled like a closed one.
+    async fn ping(&mut self, pong_timeout: Duration) -> Result<(), Closed> {
+        if self.detached_until.is_some() {
+            return Ok(());
+        }
+
+        if self.last_pong.elapsed() > pong_timeout {
+            info!(
+                "[Stream]: Web socket didn't answer pings for {}s",
+                pong_timeout.as_secs()
+            );
+            return self.detach();
+        }
+
+        if let Err(Closed) = self.session.ping(b"").await {
+            return self.detach();
+        }
+
+        Ok(())
+    }
+
+    async fn pong(&mut self, bytes: &[u8]) -> Result<(), Closed> {
+        if let Err(Closed) = self.session.pong(bytes).await {
+            return self.detach();
+        }
+
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Option<Result<Message, ProtocolError>> {
+        match &mut self.stream {
+            Some(stream) => stream.recv().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    fn detach(&mut self) -> Result<(), Closed> {
+        self.stream = None;
+
+        let Some(reconnect_grace) = self.reconnect_grace else {
+            return Err(Closed);
+        };
+
+        if self.detached_until.is_none() {
+            info!(
+                "[Stream]: Web socket lost, waiting {}s for the client to reconnect",
+                reconnect_grace.as_secs()
+            );
+            self.detached_until = Some(Instant::now() + reconnect_grace);
+        }
+
+        Ok(())
+    }
+
+    async fn attach(&mut self, reattach: PrimaryReattach) {
+        let mut old_session = std::mem::replace(&mut self.session, reattach.session);
+        // The old web socket might still be open if the client reconnected from somewhere else
+        let _ = send_ws_message(
+            &mut old_session,
+            StreamServerMessage::Disconnected {
+                reason: DisconnectReason::ReplacedByNewSession,
+            },
+        )
+        .await;
+        let _ = old_session.close(None).await;
+
+        self.stream = Some(reattach.stream);
+        self.detached_until = None;
+        self.last_pong = Instant::now();
+    }
+
+    /// The client won't be able to reconnect anymore
+    fn give_up(&mut self) {
+        self.reconnect_grace = None;
+        self.detached_until = None;
+    }
+}
+
+/// The video which is currently streamed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SpectatorVideo {
+    format: u32,
+    width: u32,
+    height: u32,
+    fps: u32,
+}
+
+/// Messages which a spectator didn't write to its web socket yet, above this it's removed
+const SPECTATOR_QUEUE_SIZE: usize = 32;
+
+/// The web sockets of everyone watching a stream.
+/// Nothing in here waits for a spectator, a slow one must never hold up the primary stream.
+struct Spectators {
+    next_id: u32,
+    senders: HashMap<u32, Sender<StreamServerMessage>>,
+    /// Spectators join after the connection completed, they set up their video with this
+    video: Option<SpectatorVideo>,
+    ws_ping_interval: Option<Duration>,
+    ws_pong_timeout: Duration,
+}
+
+impl Spectators {
+    fn new(ws_ping_interval: Option<Duration>, ws_pong_timeout: Duration) -> Self {
+        Self {
+            next_id: 0,
+            senders: HashMap::new(),
+            video: None,
+            ws_ping_interval,
+            ws_pong_timeout,
+        }
+    }
+
+    /// Remembers the video for spectators which join later,
+    /// the ones already watching are told if it changed
+    fn set_video(&mut self, video: SpectatorVideo) {
+        if self.video.replace(video).is_none() {
+            return;
+        }
+
+        let spectator_ids = self.senders.keys().copied().collect::<Vec<_>>();
+        for spectator_id in spectator_ids {
+            self.send(
+                spectator_id,
+                StreamServerMessage::VideoChanged {
+                    format: video.format,
+                    width: video.width,
+                    height: video.height,
+                    fps: video.fps,
+                },
+            );
+        }
+    }
+
+    fn join(
+        &mut self,
+        SpectatorJoin {
+            mut session,
+            stream,
+        }: SpectatorJoin,
+        ice_servers: Vec<RtcIceServer>,
+        force_relay: bool,
+        ipc_sender: IpcSender<ServerIpcMessage>,
+    ) {
+        self.senders.retain(|_, sender| !sender.is_closed());
+
+        if self.senders.len() >= MAX_SPECTATORS_PER_STREAM {
+            info!(
+                "[Stream]: Refusing spectator because the stream already has {MAX_SPECTATORS_PER_STREAM} spectators"
+            );
+
+            spawn(async move {
+                let _ = send_ws_message(&mut session, StreamServerMessage::SpectateFailed).await;
+                let _ = session.close(None).await;
+            });
+            return;
+        }
+
+        let spectator_id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let mut initial_messages = vec![StreamServerMessage::Setup {
+            ice_servers,
+            session_token: None,
+            force_relay,
+        }];
+        // Spectators can't send input, so none of the capabilities apply to them
+        if let Some(video) = self.video {
+            initial_messages.push(StreamServerMessage::ConnectionComplete {
+                capabilities: StreamCapabilities {
+                    touch: false,
+                    file_transfer: false,
+                },
+                format: video.format,
+                width: video.width,
+                height: video.height,
+                fps: video.fps,
+            });
+        }
+
+        let (sender, receiver) = channel(SPECTATOR_QUEUE_SIZE);
+        self.senders.insert(spectator_id, sender);
+
+        spawn(run_spectator(
+            spectator_id,
+            session,
+            stream,
+            initial_messages,
+            receiver,
+            ipc_sender,
+            self.ws_ping_interval,
+            self.ws_pong_timeout,
+        ));
+    }
+
+    /// A spectator which can't keep up is removed, which closes its web socket
+    fn send(&mut self, spectator_id: u32, message: StreamServerMessage) {
+        let Some(sender) = self.senders.get(&spectator_id) else {
+            debug!("[Stream]: dropping message for unknown spectator {spectator_id}");
+            return;
+        };
+
+        match sender.try_send(message) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                info!("[Stream]: Spectator {spectator_id} can't keep up, removing it");
+                self.senders.remove(&spectator_id);
+            }
+            Err(TrySendError::Closed(_)) => {
+                self.senders.remove(&spectator_id);
+            }
+        }
+    }
+}
+
+/// Forwards signaling between a spectator web socket and the streamer.
+/// Everything else the spectator sends is ignored, input only flows over the primary peer.
+/// A spectator which doesn't answer the keepalive pings is removed like the primary would be detached.
+async fn run_spectator(
+    spectator_id: u32,
+    mut session: Session,
+    mut stream: MessageStream,
+    initial_messages: Vec<StreamServerMessage>,
+    mut receiver: Receiver<StreamServerMessage>,
+    mut ipc_sender: IpcSender<ServerIpcMessage>,
+    ws_ping_interval: Option<Duration>,
+    ws_pong_timeout: Duration,
+) {
+    for message in initial_messages {
+        if let Err(Closed) = send_ws_message(&mut session, message).await {
+            return;
+        }
+    }
+
+    info!("[Stream]: Spectator {spectator_id} joined");
+
+    ipc_sender
+        .send(ServerIpcMessage::SpectatorJoined { spectator_id })
+        .await;
+
+    let mut next_ws_ping = ws_ping_interval.map(|interval| Instant::now() + interval);
+    let mut last_pong = Instant::now();
+
+    loop {
+        tokio::select! {
+            _ = wait_for_deadline(next_ws_ping) => {
+                next_ws_ping = ws_ping_interval.map(|interval| Instant::now() + interval);
+
+                if last_pong.elapsed() > ws_pong_timeout {
+                    info!(
+                        "[Stream]: Spectator {spectator_id} didn't answer pings for {}s",
+                        ws_pong_timeout.as_secs()
+                    );
+                    break;
+                }
+
+                if let Err(Closed) = session.ping(b"").await {
+                    break;
+                }
+            }
+            message = receiver.recv() => {
+                let Some(message) = message else {
+                    break;
+                };
+
+                if let Err(Closed) = send_ws_message(&mut session, message).await {
+                    break;
+                }
+            }
+            ws_msg = stream.recv() => {
+                match ws_msg {
+                    Some(Ok(Message::Text(text))) => {
+                        match serde_json::from_str::<StreamClientMessage>(&text) {
+                            Ok(StreamClientMessage::WebRtc(signaling)) => {
+                                ipc_sender
+                                    .send(ServerIpcMessage::SpectatorSignaling {
+                                        spectator_id,
+                                        signaling,
+                                    })
+                                    .await;
+                            }
+                            Ok(message) => {
+                                debug!("[Stream]: ignoring message of spectator {spectator_id}: {message:?}");
+                            }
+                            Err(_) => {
+                                warn!("[Stream]: failed to deserialize spectator message from json");
+                            }
+                        }
+                    }
+                    Some(Ok(Message::Ping(bytes))) => {
+                        if let Err(Closed) = session.pong(&bytes).await {
+                            break;
+                        }
+                    }
+                    Some(Ok(Message::Pong(_))) => {
+                        last_pong = Instant::now();
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                }
+            }
+        }
+    }
+
+    info!("[Stream]: Spectator {spectator_id} left");
+
+    ipc_sender
+        .send(ServerIpcMessage::SpectatorLeft { spectator_id })
+        .await;
+
+    let _ = session.close(None).await;
+}
+
+async fn recv_spectator(
+    spectator_rx: &mut Option<Receiver<SpectatorJoin>>,
+) -> Option<SpectatorJoin> {
+    match spectator_rx {
+        Some(spectator_rx) => spectator_rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn recv_reattach(
+    reattach_rx: &mut Option<Receiver<PrimaryReattach>>,
+) -> Option<PrimaryReattach> {
+    match reattach_rx {
+        Some(reattach_rx) => reattach_rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn wait_for_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The loopback host doesn't exist so the streamer only gets throwaway credentials
+fn loopback_host_data(
+    app_id: AppId,
+) -> Result<(api_bindings::App, String, u16, StorageHostPairInfo), AppError> {
+    let client = generate_new_client()?;
+
+    Ok((
+        api_bindings::App {
+            app_id: app_id.0,
+            title: if app_id.0 == LOOPBACK_BENCHMARK_APP_ID {
+                "Benchmark".to_string()
+            } else {
+                "Loopback".to_string()
+            },
+            is_hdr_supported: false,
+        },
+        "127.0.0.1".to_string(),
+        0,
+        StorageHostPairInfo {
+            client_private_key: client.private_key,
+            server_certificate: client.certificate.clone(),
+            client_certificate: client.certificate,
+        },
+    ))
+}
+
+/// Anything other than a confirmation, including a timeout, declines quitting the running app
+async fn recv_cancel_confirmation(stream: &mut MessageStream) -> bool {
+    let answer = async {
+        loop {
+            match stream.recv().await {
+                Some(Ok(Message::Text(text))) => {
+                    return matches!(
+                        serde_json::from_str::<StreamClientMessage>(&text),
+                        Ok(StreamClientMessage::ConfirmCancel { confirm: true })
+                    );
+                }
+                Some(Ok(Message::Binary(_))) | Some(Err(_)) | None => return false,
+                Some(Ok(_)) => continue,
+            }
+        }
+    };
+
+    timeout(CONFIRM_CANCEL_TIMEOUT, answer)
+        .await
+        .unwrap_or(false)
+}
+
 async fn send_ws_message(sender: &mut Session, message: StreamServerMessage) -> Result<(), Closed> {
     let Some(json) = serialize_json(&message) else {
         return Ok(());
@@ -473,3 +1673,264 @@ pub async fn cancel_host(
 
     Ok(Json(PostCancelResponse { success: true }))
 }
+
+/// Creates a peer with the same api as the streamer and returns its offer.
+/// Unlike the streamer it offers video and audio right away, so the codecs are part of the offer.
+async fn probe_offer(
+    config: &WebRtcConfig,
+    ice_servers: Vec<RtcIceServer>,
+    force_relay: bool,
+    video_supported_formats: SupportedVideoFormats,
+    video_codec_preference: &[StreamCodec],
+) -> Result<String, webrtc::Error> {
+    let api = create_media_api(
+        config,
+        None,
+        video_supported_formats,
+        false,
+        video_codec_preference,
+    );
+    let peer = api
+        .new_peer_connection(RTCConfiguration {
+            ice_servers: ice_servers.into_iter().map(into_webrtc_ice).collect(),
+            ice_transport_policy: if force_relay {
+                RTCIceTransportPolicy::Relay
+            } else {
+                RTCIceTransportPolicy::All
+            },
+            ..Default::default()
+        })
+        .await?;
+
+    let offer = async {
+        peer.create_data_channel("general", None).await?;
+        for kind in [RTPCodecType::Video, RTPCodecType::Audio] {
+            peer.add_transceiver_from_kind(
+                kind,
+                Some(RTCRtpTransceiverInit {
+                    direction: RTCRtpTransceiverDirection::Sendonly,
+                    send_encodings: Vec::new(),
+                }),
+            )
+            .await?;
+        }
+
+        peer.create_offer(None).await
+    }
+    .await;
+
+    if let Err(err) = peer.close().await {
+        debug!("[Probe]: Failed to close the peer: {err:?}");
+    }
+
+    Ok(offer?.sdp)
+}
+
+/// The ice servers, codecs and offer a stream with the host would be set up with,
+/// no streamer is spawned and no app is launched
+#[post("/host/stream/probe")]
+pub async fn probe_stream(
+    web_app: Data<App>,
+    remote_provider: Data<RemoteAccessProvider>,
+    mut user: AuthenticatedUser,
+    Json(request): Json<PostStreamProbeRequest>,
+) -> Result<Json<PostStreamProbeResponse>, AppError> {
+    let host_id = HostId(request.host_id);
+
+    let mut host = user.host(host_id).await?;
+    let detailed_host = host.detailed_host(&mut user).await?;
+    if detailed_host.server_state.is_none() {
+        return Err(AppError::HostOffline);
+    }
+
+    let config = web_app.config();
+
+    let server_support =
+        ServerCodeModeSupport::from_bits_truncate(detailed_host.server_codec_mode_support);
+    let video_codecs = StreamCodec::ordered(&config.default_stream_settings.video_codec_preference)
+        .into_iter()
+        .filter(|codec| codec.host_supports(server_support))
+        .collect::<Vec<_>>();
+    let hdr_supported = server_support
+        .intersects(ServerCodeModeSupport::HEVC_MAIN10 | ServerCodeModeSupport::AV1_MAIN10);
+
+    let IceServerList {
+        ice_servers,
+        expires_in,
+    } = web_app.ice_servers().await;
+    let force_relay = default_force_relay(&config.webrtc, remote_provider.get_info());
+
+    // The client's formats aren't known yet, so every profile of the host's codecs is offered
+    let video_supported_formats = video_codecs
+        .iter()
+        .fold(SupportedVideoFormats::empty(), |formats, codec| {
+            formats | codec.formats()
+        });
+    let offer = probe_offer(
+        &config.webrtc,
+        ice_servers.clone(),
+        force_relay,
+        video_supported_formats,
+        &config.default_stream_settings.video_codec_preference,
+    )
+    .await?;
+
+    Ok(Json(PostStreamProbeResponse {
+        ice_servers,
+        expires_in_secs: expires_in
+            .map(|expires_in| expires_in.as_secs().try_into().unwrap_or(u32::MAX)),
+        force_relay,
+        video_codecs,
+        hdr_supported,
+        offer,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use common::{
+        api_bindings::{DisconnectReason, StreamCodec, StreamServerMessage},
+        config::WebRtcConfig,
+    };
+    use moonlight_common::stream::bindings::TerminationError;
+    use tokio::sync::mpsc::channel;
+
+    use crate::{
+        api::stream::{
+            CLIENT_DISCONNECTED_REASON, CONNECTION_TERMINATED_REASON, IDLE_REASON,
+            STREAMER_CRASHED_REASON, STREAMER_STOPPED_REASON, SpectatorVideo, Spectators,
+            disconnect_reason, probe_offer,
+        },
+        app::{session::MAX_DURATION_REASON, streamer_manager::STREAMER_UNRESPONSIVE_REASON},
+    };
+
+    #[test]
+    fn test_disconnect_reason() {
+        assert_eq!(
+            disconnect_reason(CLIENT_DISCONNECTED_REASON, None),
+            DisconnectReason::UserRequested
+        );
+        assert_eq!(
+            disconnect_reason(STREAMER_STOPPED_REASON, None),
+            DisconnectReason::UserRequested
+        );
+        assert_eq!(
+            disconnect_reason(IDLE_REASON, None),
+            DisconnectReason::Timeout
+        );
+        assert_eq!(
+            disconnect_reason(MAX_DURATION_REASON, None),
+            DisconnectReason::Timeout
+        );
+
+        // A crashed or stuck streamer isn't something the user requested
+        assert_eq!(
+            disconnect_reason(STREAMER_CRASHED_REASON, None),
+            DisconnectReason::Error
+        );
+        assert_eq!(
+            disconnect_reason(STREAMER_UNRESPONSIVE_REASON, None),
+            DisconnectReason::Error
+        );
+
+        assert_eq!(
+            disconnect_reason(
+                CONNECTION_TERMINATED_REASON,
+                Some(TerminationError::Graceful as i32)
+            ),
+            DisconnectReason::GameEnded
+        );
+        assert_eq!(
+            disconnect_reason(
+                CONNECTION_TERMINATED_REASON,
+                Some(TerminationError::NoVideoTraffic as i32)
+            ),
+            DisconnectReason::HostOffline
+        );
+        assert_eq!(
+            disconnect_reason(CONNECTION_TERMINATED_REASON, None),
+            DisconnectReason::Error
+        );
+    }
+    #[test]
+    fn test_spectators_video_changed() {
+        let mut spectators = Spectators::new(None, Duration::from_secs(30));
+
+        let (watching, mut watching_rx) = channel(4);
+        let (left, left_rx) = channel(4);
+        spectators.senders.insert(0, watching);
+        spectators.senders.insert(1, left);
+        drop(left_rx);
+
+        // The first video is only remembered for spectators which join later
+        let video = SpectatorVideo {
+            format: 1,
+            width: 1920,
+            height: 1080,
+            fps: 60,
+        };
+        spectators.set_video(video);
+        assert_eq!(spectators.video, Some(video));
+        assert!(watching_rx.try_recv().is_err());
+
+        spectators.set_video(SpectatorVideo {
+            width: 1280,
+            height: 720,
+            ..video
+        });
+        assert!(matches!(
+            watching_rx.try_recv(),
+            Ok(StreamServerMessage::VideoChanged {
+                width: 1280,
+                height: 720,
+                ..
+            })
+        ));
+
+        // Spectators whose web socket is gone are removed
+        assert_eq!(spectators.senders.len(), 1);
+    }
+
+    #[test]
+    fn test_spectators_slow_spectator_removed() {
+        let mut spectators = Spectators::new(None, Duration::from_secs(30));
+
+        let (slow, mut slow_rx) = channel(1);
+        spectators.senders.insert(0, slow);
+
+        spectators.send(0, StreamServerMessage::SpectateFailed);
+        assert_eq!(spectators.senders.len(), 1);
+
+        // The queue is full, the spectator is dropped instead of waiting for it
+        spectators.send(0, StreamServerMessage::SpectateFailed);
+        assert!(spectators.senders.is_empty());
+
+        assert!(matches!(
+            slow_rx.try_recv(),
+            Ok(StreamServerMessage::SpectateFailed)
+        ));
+        assert!(slow_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_probe_offer() {
+        let offer = probe_offer(
+            &WebRtcConfig::default(),
+            Vec::new(),
+            false,
+            StreamCodec::H264.formats(),
+            &[],
+        )
+        .await
+        .unwrap();
+
+        assert!(offer.contains("m=application"));
+        assert!(offer.contains("a=rtpmap:96 H264/90000"));
+        assert!(!offer.contains("H265"));
+        assert!(offer.contains("a=rtpmap:111 opus/48000/2"));
+        // The peer is closed before it gathers candidates
+        assert!(!offer.contains("a=candidate:"));
+    }
+}
@@ -0,0 +1,205 @@
+//! Append-only log of admin actions
+//!
+//! Every action is written as one json line, including the error if the action failed.
+//! Existing lines are never rewritten, so the file can be shipped to other log tooling.
+
+use std::{io, path::PathBuf, time::SystemTime};
+
+use common::config::AuditLogConfig;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+};
+
+use crate::app::{AppError, unix_secs, user::UserId};
+
+pub const ADD_USER_ACTION: &str = "add_user";
+pub const DELETE_USER_ACTION: &str = "delete_user";
+pub const PATCH_USER_ACTION: &str = "patch_user";
+pub const CHANGE_HOST_OWNER_ACTION: &str = "change_host_owner";
+
+/// One line of the audit log, the timestamp is a unix timestamp in seconds
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: u64,
+    pub actor_user_id: u32,
+    pub action: String,
+    pub target: String,
+    /// None if the action succeeded
+    pub error: Option<String>,
+}
+
+pub struct AuditLog {
+    path: Option<PathBuf>,
+    // Also held while reading so no half written line is read
+    file: Mutex<Option<File>>,
+}
+
+impl AuditLog {
+    pub async fn open(config: &AuditLogConfig) -> Result<Self, io::Error> {
+        let Some(path) = config.path.as_ref().map(PathBuf::from) else {
+            return Ok(Self {
+                path: None,
+                file: Mutex::new(None),
+            });
+        };
+
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            fs::create_dir_all(parent).await?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+
+        Ok(Self {
+            path: Some(path),
+            file: Mutex::new(Some(file)),
+        })
+    }
+
+    /// Records the action with the error of the result, failing to write is only logged
+    /// because the action already happened
+    pub async fn record<T>(
+        &self,
+        actor: UserId,
+        action: &str,
+        target: String,
+        result: &Result<T, AppError>,
+    ) {
+        let entry = AuditEntry {
+            timestamp: unix_secs(SystemTime::now()),
+            actor_user_id: actor.0,
+            action: action.to_string(),
+            target,
+            error: result.as_ref().err().map(|err| err.to_string()),
+        };
+
+        if let Err(err) = self.append(&entry).await {
+            warn!("[Audit]: failed to record {entry:?}: {err}");
+        }
+    }
+
+    async fn append(&self, entry: &AuditEntry) -> Result<(), io::Error> {
+        let mut file = self.file.lock().await;
+        let Some(file) = file.as_mut() else {
+            return Ok(());
+        };
+
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await
+    }
+
+    /// Returns the entries newest first and the amount of entries in the log
+    pub async fn list(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<AuditEntry>, usize), AppError> {
+        let file = self.file.lock().await;
+        let Some(path) = &self.path else {
+            return Ok((Vec::new(), 0));
+        };
+
+        let text = fs::read_to_string(path).await?;
+        drop(file);
+
+        Ok(parse_page(&text, offset, limit))
+    }
+}
+
+fn parse_page(text: &str, offset: usize, limit: usize) -> (Vec<AuditEntry>, usize) {
+    let entries = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str::<AuditEntry>(line) {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                warn!("[Audit]: skipping invalid line in the audit log: {err}");
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let total = entries.len();
+    let page = entries.into_iter().rev().skip(offset).take(limit).collect();
+
+    (page, total)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use common::config::AuditLogConfig;
+
+    use crate::app::{
+        AppError,
+        audit::{ADD_USER_ACTION, AuditLog, DELETE_USER_ACTION, parse_page},
+        user::UserId,
+    };
+
+    #[tokio::test]
+    async fn test_record_and_list() {
+        let path = env::temp_dir().join("moonlight-web-test-audit.jsonl");
+        let _ = std::fs::remove_file(&path);
+
+        let audit_log = AuditLog::open(&AuditLogConfig {
+            path: Some(path.to_string_lossy().to_string()),
+        })
+        .await
+        .unwrap();
+
+        audit_log
+            .record(
+                UserId(1),
+                ADD_USER_ACTION,
+                "user_name:guest".to_string(),
+                &Ok(()),
+            )
+            .await;
+        audit_log
+            .record::<()>(
+                UserId(1),
+                DELETE_USER_ACTION,
+                "user_id:7".to_string(),
+                &Err(AppError::UserNotFound),
+            )
+            .await;
+
+        let (entries, total) = audit_log.list(0, 10).await.unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(entries[0].action, DELETE_USER_ACTION);
+        assert_eq!(entries[0].error.as_deref(), Some("the user was not found"));
+        assert_eq!(entries[1].action, ADD_USER_ACTION);
+        assert_eq!(entries[1].error, None);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_parse_page_skips_invalid_lines() {
+        let text = concat!(
+            r#"{"timestamp":1,"actor_user_id":1,"action":"add_user","target":"user_name:a","error":null}"#,
+            "\n",
+            "not json\n",
+            r#"{"timestamp":2,"actor_user_id":1,"action":"delete_user","target":"user_id:2","error":null}"#,
+            "\n",
+        );
+
+        let (entries, total) = parse_page(text, 1, 10);
+        assert_eq!(total, 2);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].timestamp, 1);
+    }
+}
@@ -1,7 +1,7 @@
 use std::fmt;
 
 use hex::FromHexError;
-use openssl::rand::rand_bytes;
+use openssl::{error::ErrorStack, hash::MessageDigest, nid::Nid, rand::rand_bytes, x509::X509Ref};
 use serde::{
     Deserialize, Deserializer, Serialize, Serializer,
     de::{self, Visitor},
@@ -14,6 +14,40 @@ pub enum UserAuth {
     UserPassword { username: String, password: String },
     Session(SessionToken),
     ForwardedHeaders { username: String },
+    ClientCertificate(ClientCertificate),
+}
+
+/// The verified client certificate of an https connection, see the client_certificate_auth config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCertificate {
+    /// SHA-256 of the DER encoded certificate, lowercase hex without colons
+    pub fingerprint: String,
+    pub common_name: Option<String>,
+}
+
+impl ClientCertificate {
+    pub fn from_x509(certificate: &X509Ref) -> Result<Self, ErrorStack> {
+        let fingerprint = hex::encode(certificate.digest(MessageDigest::sha256())?);
+        let common_name = certificate
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .and_then(|entry| entry.data().as_utf8().ok())
+            .map(|common_name| common_name.to_string());
+
+        Ok(Self {
+            fingerprint,
+            common_name,
+        })
+    }
+}
+
+/// Brings a SHA-256 fingerprint into the stored format, tools often print them with colons and in upper case
+pub fn normalize_fingerprint(fingerprint: &str) -> Option<String> {
+    let fingerprint = fingerprint.trim().replace(':', "").to_ascii_lowercase();
+
+    (fingerprint.len() == 64 && fingerprint.chars().all(|c| c.is_ascii_hexdigit()))
+        .then_some(fingerprint)
 }
 
 const SESSION_TOKEN_SIZE: usize = 32;
@@ -77,3 +111,61 @@ impl<'de> Deserialize<'de> for SessionToken {
         deserializer.deserialize_str(SessionTokenVisitor)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use openssl::{
+        asn1::Asn1Time,
+        hash::MessageDigest,
+        pkey::PKey,
+        rsa::Rsa,
+        x509::{X509, X509NameBuilder},
+    };
+
+    use crate::app::auth::{ClientCertificate, normalize_fingerprint};
+
+    #[test]
+    fn test_normalize_fingerprint() {
+        let fingerprint = "ab".repeat(32);
+
+        assert_eq!(
+            normalize_fingerprint(&format!(" {} ", "AB:".repeat(31) + "AB")),
+            Some(fingerprint.clone())
+        );
+        assert_eq!(normalize_fingerprint(&fingerprint), Some(fingerprint));
+        assert_eq!(normalize_fingerprint("abab"), None);
+        assert_eq!(normalize_fingerprint(&"zz".repeat(32)), None);
+    }
+
+    #[test]
+    fn test_client_certificate_from_x509() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "LivingRoom").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let certificate = builder.build();
+
+        let client_certificate = ClientCertificate::from_x509(&certificate).unwrap();
+        assert_eq!(
+            client_certificate.common_name.as_deref(),
+            Some("LivingRoom")
+        );
+        assert_eq!(
+            normalize_fingerprint(&client_certificate.fingerprint),
+            Some(client_certificate.fingerprint)
+        );
+    }
+}
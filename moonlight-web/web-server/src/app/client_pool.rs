@@ -0,0 +1,192 @@
+//! Pool of configured moonlight clients
+//!
+//! Building a client with the paired certificates re-does the whole TLS setup, which adds
+//! noticeable latency when the info, app list and box art are requested in quick succession.
+//! Clients are therefore reused per host as long as the pair info of that host stays the same.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use tokio::sync::Mutex;
+
+use crate::app::{host::HostId, storage::StorageHostPairInfo};
+
+/// Duration after which an unused pooled client gets dropped
+pub const CLIENT_POOL_IDLE_SECS: u64 = 300;
+
+/// Identifies the certificates a pooled client was built with
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PairKey {
+    client_certificate: Vec<u8>,
+    server_certificate: Vec<u8>,
+}
+
+impl From<&StorageHostPairInfo> for PairKey {
+    fn from(value: &StorageHostPairInfo) -> Self {
+        Self {
+            client_certificate: value.client_certificate.contents().to_vec(),
+            server_certificate: value.server_certificate.contents().to_vec(),
+        }
+    }
+}
+
+struct PooledClient<Client> {
+    key: PairKey,
+    client: Client,
+    last_used: Instant,
+}
+
+pub struct ClientPool<Client> {
+    idle_timeout: Duration,
+    clients: Mutex<HashMap<HostId, PooledClient<Client>>>,
+}
+
+impl<Client> Default for ClientPool<Client> {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(CLIENT_POOL_IDLE_SECS))
+    }
+}
+
+impl<Client> ClientPool<Client>
+where
+    Client: Clone,
+{
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self {
+            idle_timeout,
+            clients: Default::default(),
+        }
+    }
+
+    /// Returns the pooled client of this host or creates a new one.
+    ///
+    /// If the pooled client was created with different pair info it gets replaced.
+    pub async fn get_or_create<E>(
+        &self,
+        host_id: HostId,
+        pair_info: &StorageHostPairInfo,
+        create: impl FnOnce() -> Result<Client, E>,
+    ) -> Result<Client, E> {
+        let key = PairKey::from(pair_info);
+        let now = Instant::now();
+
+        let mut clients = self.clients.lock().await;
+
+        let idle_timeout = self.idle_timeout;
+        clients.retain(|_, pooled| now.duration_since(pooled.last_used) < idle_timeout);
+
+        if let Some(pooled) = clients.get_mut(&host_id) {
+            if pooled.key == key {
+                pooled.last_used = now;
+                return Ok(pooled.client.clone());
+            }
+
+            debug!("[ClientPool]: pair info of host {host_id:?} changed, replacing client");
+        }
+
+        let client = create()?;
+        clients.insert(
+            host_id,
+            PooledClient {
+                key,
+                client: client.clone(),
+                last_used: now,
+            },
+        );
+
+        Ok(client)
+    }
+
+    /// Removes the pooled client of this host, e.g. because it got unpaired or deleted
+    pub async fn invalidate(&self, host_id: HostId) {
+        let mut clients = self.clients.lock().await;
+
+        if clients.remove(&host_id).is_some() {
+            debug!("[ClientPool]: invalidated client of host {host_id:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, time::Duration};
+
+    use moonlight_common::pair::generate_new_client;
+
+    use crate::app::{client_pool::ClientPool, host::HostId, storage::StorageHostPairInfo};
+
+    fn new_pair_info() -> StorageHostPairInfo {
+        let client = generate_new_client().expect("failed to generate client");
+        let server = generate_new_client().expect("failed to generate server");
+
+        StorageHostPairInfo {
+            client_private_key: client.private_key,
+            client_certificate: client.certificate,
+            server_certificate: server.certificate,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reuses_pooled_client() {
+        let pool = ClientPool::<u32>::default();
+        let pair_info = new_pair_info();
+        let mut created = 0;
+
+        for _ in 0..3 {
+            let client = pool
+                .get_or_create(HostId(0), &pair_info, || {
+                    created += 1;
+                    Ok::<_, Infallible>(created)
+                })
+                .await
+                .unwrap();
+
+            assert_eq!(client, 1);
+        }
+        assert_eq!(created, 1);
+    }
+
+    #[tokio::test]
+    async fn test_repairing_invalidates_client() {
+        let pool = ClientPool::<u32>::default();
+        let mut created = 0;
+
+        let first = pool
+            .get_or_create(HostId(0), &new_pair_info(), || {
+                created += 1;
+                Ok::<_, Infallible>(created)
+            })
+            .await
+            .unwrap();
+        let second = pool
+            .get_or_create(HostId(0), &new_pair_info(), || {
+                created += 1;
+                Ok::<_, Infallible>(created)
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(first, 1);
+        assert_eq!(second, 2);
+    }
+
+    #[tokio::test]
+    async fn test_idle_client_expires() {
+        let pool = ClientPool::<u32>::new(Duration::ZERO);
+        let pair_info = new_pair_info();
+        let mut created = 0;
+
+        for _ in 0..2 {
+            pool.get_or_create(HostId(0), &pair_info, || {
+                created += 1;
+                Ok::<_, Infallible>(created)
+            })
+            .await
+            .unwrap();
+        }
+        assert_eq!(created, 2);
+    }
+}
@@ -0,0 +1,71 @@
+//! Connection logs of the streams whose peer connection failed, they're only kept in memory
+
+use std::collections::VecDeque;
+
+use common::api_bindings::FailedConnectionLog;
+use tokio::sync::Mutex;
+
+/// The oldest logs are dropped above this
+const MAX_FAILED_CONNECTION_LOGS: usize = 32;
+
+#[derive(Default)]
+pub struct FailedConnectionLogs {
+    logs: Mutex<VecDeque<FailedConnectionLog>>,
+}
+
+impl FailedConnectionLogs {
+    pub async fn push(&self, log: FailedConnectionLog) {
+        let mut logs = self.logs.lock().await;
+
+        if logs.len() >= MAX_FAILED_CONNECTION_LOGS {
+            logs.pop_front();
+        }
+        logs.push_back(log);
+    }
+
+    /// Newest first, None = the logs of all users
+    pub async fn list(&self, user_id: Option<u32>) -> Vec<FailedConnectionLog> {
+        let logs = self.logs.lock().await;
+
+        logs.iter()
+            .rev()
+            .filter(|log| user_id.is_none_or(|user_id| log.user_id == user_id))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::api_bindings::FailedConnectionLog;
+
+    use crate::app::connection_logs::{FailedConnectionLogs, MAX_FAILED_CONNECTION_LOGS};
+
+    fn log(user_id: u32, failed_at: u64) -> FailedConnectionLog {
+        FailedConnectionLog {
+            user_id,
+            host_id: 0,
+            app_title: "Desktop".to_string(),
+            failed_at,
+            entries: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_connection_logs() {
+        let logs = FailedConnectionLogs::default();
+
+        for index in 0..MAX_FAILED_CONNECTION_LOGS as u64 + 1 {
+            logs.push(log((index % 2) as u32, index)).await;
+        }
+
+        let all = logs.list(None).await;
+        assert_eq!(all.len(), MAX_FAILED_CONNECTION_LOGS);
+        assert_eq!(all[0].failed_at, MAX_FAILED_CONNECTION_LOGS as u64);
+        assert_eq!(all.last().map(|log| log.failed_at), Some(1));
+
+        let user = logs.list(Some(1)).await;
+        assert!(user.iter().all(|log| log.user_id == 1));
+        assert_eq!(user.len(), MAX_FAILED_CONNECTION_LOGS / 2);
+    }
+}
@@ -0,0 +1,374 @@
+//! Discovery of Sunshine hosts on the local network with mDNS
+//!
+//! Sunshine advertises itself as `_nvstream._tcp.local`. A PTR query for that service is sent out of every IPv4 interface.
+//! The query is sent from a random port instead of 5353, so responders answer directly to that socket
+//! (legacy unicast, RFC 6762 section 6.7) and we don't have to share the mDNS port with the responder of the OS.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::{Duration, Instant},
+};
+
+use common::api_bindings::DiscoveredHost;
+use futures::future::join_all;
+use log::{debug, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use sysinfo::Networks;
+use tokio::{net::UdpSocket, sync::Mutex, time::timeout_at};
+
+const SERVICE_NAME: &str = "_nvstream._tcp.local";
+const MDNS_ADDRESS: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353);
+
+/// How long responses are collected
+const BROWSE_DURATION: Duration = Duration::from_secs(3);
+/// Results younger than this are returned without browsing again
+const CACHE_DURATION: Duration = Duration::from_secs(30);
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+#[derive(Default)]
+pub struct HostDiscovery {
+    cache: Mutex<Option<(Instant, Vec<DiscoveredHost>)>>,
+}
+
+impl HostDiscovery {
+    /// Browses the local network unless recent results are cached
+    pub async fn discover(&self) -> Vec<DiscoveredHost> {
+        // Holding the lock while browsing lets concurrent requests wait for the same results
+        let mut cache = self.cache.lock().await;
+
+        if let Some((discovered_at, hosts)) = cache.as_ref()
+            && discovered_at.elapsed() < CACHE_DURATION
+        {
+            return hosts.clone();
+        }
+
+        let hosts = browse(BROWSE_DURATION).await;
+        debug!("[Discovery]: found {} hosts", hosts.len());
+
+        *cache = Some((Instant::now(), hosts.clone()));
+
+        hosts
+    }
+}
+
+async fn browse(duration: Duration) -> Vec<DiscoveredHost> {
+    let query = build_query();
+    let query = &query;
+    let deadline = tokio::time::Instant::now() + duration;
+
+    let mut interfaces = interface_addresses();
+    if interfaces.is_empty() {
+        // Let the OS decide
+        interfaces.push(Ipv4Addr::UNSPECIFIED);
+    }
+
+    let browsers = interfaces
+        .into_iter()
+        .filter_map(|interface| match bind_socket(interface) {
+            Ok(socket) => Some(socket),
+            Err(err) => {
+                warn!("[Discovery]: failed to bind mDNS socket on {interface}: {err:?}");
+                None
+            }
+        })
+        .map(|socket| async move {
+            if let Err(err) = socket.send_to(query, MDNS_ADDRESS).await {
+                debug!("[Discovery]: failed to send mDNS query: {err:?}");
+                return Vec::new();
+            }
+
+            let mut hosts = Vec::new();
+            let mut buffer = [0u8; 9000];
+            while let Ok(Ok((length, source))) =
+                timeout_at(deadline, socket.recv_from(&mut buffer)).await
+            {
+                if let Some(response) = parse_response(&buffer[..length]) {
+                    hosts.extend(response.hosts(source.ip()));
+                }
+            }
+
+            hosts
+        });
+
+    let mut hosts = join_all(browsers)
+        .await
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+
+    // Hosts answer on every interface they're reachable from
+    hosts.sort_by(|a, b| (&a.address, a.http_port).cmp(&(&b.address, b.http_port)));
+    hosts.dedup_by(|a, b| a.address == b.address && a.http_port == b.http_port);
+
+    hosts
+}
+
+fn interface_addresses() -> Vec<Ipv4Addr> {
+    let networks = Networks::new_with_refreshed_list();
+
+    let mut addresses = networks
+        .list()
+        .values()
+        .flat_map(|network| network.ip_networks())
+        .filter_map(|network| match network.addr {
+            IpAddr::V4(address) if !address.is_loopback() && !address.is_link_local() => {
+                Some(address)
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    addresses.sort();
+    addresses.dedup();
+
+    addresses
+}
+
+fn bind_socket(interface: Ipv4Addr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    if !interface.is_unspecified() {
+        socket.set_multicast_if_v4(&interface)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((interface, 0)).into())?;
+
+    UdpSocket::from_std(socket.into())
+}
+
+fn build_query() -> Vec<u8> {
+    let mut packet = Vec::with_capacity(64);
+
+    // Id, flags, one question, no answer, authority or additional records
+    packet.extend_from_slice(&[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in SERVICE_NAME.split('.') {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+
+    packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    packet
+}
+
+#[derive(Debug, Default)]
+struct MdnsResponse {
+    /// Names of the advertised `_nvstream._tcp` instances
+    instances: Vec<String>,
+    /// Instance name -> (target hostname, port)
+    services: HashMap<String, (String, u16)>,
+    /// Hostname -> address
+    addresses: HashMap<String, Ipv4Addr>,
+}
+
+impl MdnsResponse {
+    /// Falls back to the address which sent the response if it didn't contain an A record for the host
+    fn hosts(&self, source: IpAddr) -> Vec<DiscoveredHost> {
+        self.instances
+            .iter()
+            .filter_map(|instance| {
+                let (hostname, port) = self.services.get(instance)?;
+
+                let address = self
+                    .addresses
+                    .get(hostname)
+                    .map(|address| IpAddr::V4(*address))
+                    .unwrap_or(source);
+
+                let name = instance
+                    .strip_suffix(SERVICE_NAME)
+                    .map(|name| name.trim_end_matches('.'))
+                    .unwrap_or(instance);
+
+                Some(DiscoveredHost {
+                    name: name.to_string(),
+                    hostname: hostname.clone(),
+                    address: address.to_string(),
+                    http_port: *port,
+                })
+            })
+            .collect()
+    }
+}
+
+fn read_u16(packet: &[u8], position: usize) -> Option<u16> {
+    let bytes = packet.get(position..position + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Returns the name and the position after it
+fn read_name(packet: &[u8], start: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut position = start;
+    // The name ends at the first compression pointer
+    let mut end = None;
+
+    // Limits the amount of labels and pointers so malicious pointer loops end
+    for _ in 0..128 {
+        let length = *packet.get(position)? as usize;
+
+        if length == 0 {
+            return Some((labels.join("."), end.unwrap_or(position + 1)));
+        } else if length & 0xC0 == 0xC0 {
+            let pointer = ((length & 0x3F) << 8) | *packet.get(position + 1)? as usize;
+            end.get_or_insert(position + 2);
+            position = pointer;
+        } else {
+            let label = packet.get(position + 1..position + 1 + length)?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            position += 1 + length;
+        }
+    }
+
+    None
+}
+
+fn parse_response(packet: &[u8]) -> Option<MdnsResponse> {
+    let flags = read_u16(packet, 2)?;
+    if flags & 0x8000 == 0 {
+        // Query of another browser
+        return None;
+    }
+
+    let question_count = read_u16(packet, 4)?;
+    let record_count = read_u16(packet, 6)? as usize
+        + read_u16(packet, 8)? as usize
+        + read_u16(packet, 10)? as usize;
+
+    let mut position = 12;
+    for _ in 0..question_count {
+        let (_, end) = read_name(packet, position)?;
+        // Type and class
+        position = end + 4;
+    }
+
+    let mut response = MdnsResponse::default();
+    for _ in 0..record_count {
+        let (name, end) = read_name(packet, position)?;
+
+        // Type, class, ttl, data length
+        let record_type = read_u16(packet, end)?;
+        let data_length = read_u16(packet, end + 8)? as usize;
+        let data_start = end + 10;
+        let data = packet.get(data_start..data_start + data_length)?;
+
+        match record_type {
+            TYPE_PTR if name.eq_ignore_ascii_case(SERVICE_NAME) => {
+                let (instance, _) = read_name(packet, data_start)?;
+                response.instances.push(instance);
+            }
+            TYPE_SRV => {
+                // Priority, weight, port, target
+                let port = read_u16(packet, data_start + 4)?;
+                let (target, _) = read_name(packet, data_start + 6)?;
+                response.services.insert(name, (target, port));
+            }
+            TYPE_A if data.len() == 4 => {
+                response
+                    .addresses
+                    .insert(name, Ipv4Addr::new(data[0], data[1], data[2], data[3]));
+            }
+            _ => {}
+        }
+
+        position = data_start + data_length;
+    }
+
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use crate::app::discovery::{
+        CLASS_IN, SERVICE_NAME, TYPE_A, TYPE_PTR, TYPE_SRV, build_query, parse_response, read_name,
+    };
+
+    fn push_name(packet: &mut Vec<u8>, name: &str) {
+        for label in name.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.push(0);
+    }
+
+    fn push_record(packet: &mut Vec<u8>, name: &[u8], record_type: u16, data: &[u8]) {
+        packet.extend_from_slice(name);
+        packet.extend_from_slice(&record_type.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet.extend_from_slice(&120u32.to_be_bytes());
+        packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        packet.extend_from_slice(data);
+    }
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        push_name(&mut bytes, name);
+        bytes
+    }
+
+    #[test]
+    fn test_query_asks_for_service() {
+        let query = build_query();
+
+        let (name, end) = read_name(&query, 12).unwrap();
+        assert_eq!(name, SERVICE_NAME);
+        assert_eq!(end + 4, query.len());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        // Header: response, 1 answer, 2 additional records
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 2];
+
+        // PTR answer, the instance name points back to the service name of the answer
+        let service_position = packet.len() as u16;
+        let mut instance = vec![6];
+        instance.extend_from_slice(b"Gaming");
+        instance.extend_from_slice(&(0xC000 | service_position).to_be_bytes());
+        push_record(&mut packet, &name(SERVICE_NAME), TYPE_PTR, &instance);
+
+        let mut srv = vec![0, 0, 0, 0];
+        srv.extend_from_slice(&47989u16.to_be_bytes());
+        srv.extend_from_slice(&name("gaming-pc.local"));
+        push_record(
+            &mut packet,
+            &name("Gaming._nvstream._tcp.local"),
+            TYPE_SRV,
+            &srv,
+        );
+
+        push_record(
+            &mut packet,
+            &name("gaming-pc.local"),
+            TYPE_A,
+            &[192, 168, 1, 20],
+        );
+
+        let response = parse_response(&packet).unwrap();
+        let hosts = response.hosts(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].name, "Gaming");
+        assert_eq!(hosts[0].hostname, "gaming-pc.local");
+        assert_eq!(hosts[0].address, "192.168.1.20");
+        assert_eq!(hosts[0].http_port, 47989);
+    }
+
+    #[test]
+    fn test_ignores_queries_and_pointer_loops() {
+        assert!(parse_response(&build_query()).is_none());
+
+        // The name points to itself
+        let packet = [0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0xC0, 12];
+        assert!(parse_response(&packet).is_none());
+    }
+}
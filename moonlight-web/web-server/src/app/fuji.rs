@@ -29,6 +29,8 @@ pub enum FujiError {
     OtpFailed(u16),
     #[error("Invalid OTP response")]
     InvalidResponse,
+    #[error("Logs request failed with status: {0}")]
+    LogsFailed(u16),
 }
 
 /// Check if a host is a Fuji host by attempting to access the OTP endpoint
@@ -123,6 +125,32 @@ pub async fn request_fuji_otp(
     Ok(otp)
 }
 
+/// Fetch the log file of Fuji's bundled Sunshine
+///
+/// Standard Sunshine has the same endpoint, but its credentials are unknown to us.
+pub async fn fetch_fuji_logs(https_hostport: &str) -> Result<String, FujiError> {
+    let client = Client::builder()
+        .danger_accept_invalid_certs(true)
+        .timeout(std::time::Duration::from_secs(10))
+        .build()?;
+
+    let url = format!("https://{}/api/logs", https_hostport);
+
+    debug!("Requesting Fuji logs from: {}", https_hostport);
+
+    let response = client
+        .get(&url)
+        .basic_auth(FUJI_DEFAULT_USERNAME, Some(FUJI_DEFAULT_PASSWORD))
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(FujiError::LogsFailed(response.status().as_u16()));
+    }
+
+    Ok(response.text().await?)
+}
+
 /// Submit PIN to Sunshine API to confirm pairing
 ///
 /// This simulates the user entering the PIN on the Sunshine web UI.
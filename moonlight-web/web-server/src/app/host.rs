@@ -1,28 +1,38 @@
 use std::{
     fmt::{Debug, Formatter},
+    net::{Ipv4Addr, Ipv6Addr},
     str::FromStr,
+    time::Duration,
 };
 
 use actix_web::web::Bytes;
-use common::api_bindings::{self, DetailedHost, HostOwner, HostState, HostType, PairStatus, UndetailedHost};
+use common::api_bindings::{
+    self, DetailedHost, HostOwner, HostState, HostStreamSettings, HostType, PairStatus,
+    UndetailedHost,
+};
 use log::{debug, warn};
 use moonlight_common::{
     PairPin, ServerState,
-    high::broadcast_magic_packet,
+    high::send_magic_packets,
     network::{
         self, ApiError, ClientAppBoxArtRequest, ClientInfo, HostInfo, host_app_box_art,
         host_app_list, host_cancel, host_info,
+        pair::host_unpair,
         request_client::{RequestClient, RequestError},
     },
     pair::{PairSuccess, generate_new_client, host_pair, host_pair_with_otp, OtpCredentials},
 };
+use tokio::{net::TcpStream, time::timeout};
 use uuid::Uuid;
 
-use crate::app::{
-    AppError, AppInner, AppRef, MoonlightClient,
-    fuji::request_fuji_otp,
-    storage::{StorageHost, StorageHostModify, StorageHostPairInfo},
-    user::{AuthenticatedUser, Role, UserId},
+use crate::{
+    app::{
+        AppError, AppInner, AppRef, MoonlightClient,
+        fuji::{fetch_fuji_logs, request_fuji_otp},
+        storage::{StorageHost, StorageHostModify, StorageHostPairInfo},
+        user::{AuthenticatedUser, Role, UserId},
+    },
+    resolve::resolve_host_address,
 };
 
 /// Parse a 4-digit PIN string (e.g., "1234") into a PairPin
@@ -39,6 +49,60 @@ fn parse_pin_string(pin_str: &str) -> Option<PairPin> {
     }
 }
 
+/// Only the end of the host log is returned, the whole file can be several megabytes
+const MAX_HOST_LOGS_SIZE: usize = 256 * 1024;
+
+/// The last lines of the log which fit into the max size and if lines were cut off
+fn tail_log(text: &str, max_size: usize) -> (String, bool) {
+    if text.len() <= max_size {
+        return (text.to_string(), false);
+    }
+
+    let mut start = text.len() - max_size;
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    // Don't start in the middle of a line
+    if let Some(line_end) = text[start..].find('\n') {
+        start += line_end + 1;
+    }
+
+    (text[start..].to_string(), true)
+}
+
+/// How long connecting to the https port may take before pairing is refused
+const PAIR_HTTPS_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The host only reports its ip without a netmask, so assume a /24 subnet
+fn subnet_broadcast_address(local_ip: Ipv4Addr) -> Ipv4Addr {
+    let [a, b, c, _] = local_ip.octets();
+
+    Ipv4Addr::new(a, b, c, 255)
+}
+
+/// An ip or a hostname without scheme, port or path, ipv6 addresses must be in brackets
+pub fn is_valid_host_address(address: &str) -> bool {
+    if let Some(ipv6) = address
+        .strip_prefix('[')
+        .and_then(|address| address.strip_suffix(']'))
+    {
+        return ipv6.parse::<Ipv6Addr>().is_ok();
+    }
+    if address.parse::<Ipv4Addr>().is_ok() {
+        return true;
+    }
+
+    !address.is_empty()
+        && address.len() <= 253
+        && address.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct HostId(pub u32);
 
@@ -108,6 +172,14 @@ impl Host {
         let app = self.app.access()?;
 
         self.cache_storage = None;
+        // The info belongs to the old address
+        if modify.address.is_some() || modify.http_port.is_some() {
+            self.cache_host_info = None;
+        }
+
+        if modify.pair_info.is_some() || modify.address.is_some() || modify.http_port.is_some() {
+            app.client_pool.invalidate(self.id).await;
+        }
 
         app.storage.modify_host(self.id, modify).await?;
 
@@ -167,6 +239,12 @@ impl Host {
     ) -> Result<R, AppError> {
         let user_unique_id = user.host_unique_id().await?;
         let host_data = self.storage_host(app).await?;
+        let address = resolve_host_address(
+            &host_data.address,
+            host_data.http_port,
+            app.config.ip_preference,
+        )
+        .await;
 
         let (mut client, https_capable) = if pairing {
             (
@@ -175,12 +253,16 @@ impl Host {
             )
         } else if let Some(pair_info) = host_data.pair_info {
             (
-                MoonlightClient::with_certificates(
-                    &pair_info.client_private_key,
-                    &pair_info.client_certificate,
-                    &pair_info.server_certificate,
-                )
-                .map_err(ApiError::RequestClient)?,
+                app.client_pool
+                    .get_or_create(self.id, &pair_info, || {
+                        MoonlightClient::with_certificates(
+                            &pair_info.client_private_key,
+                            &pair_info.client_certificate,
+                            &pair_info.server_certificate,
+                        )
+                    })
+                    .await
+                    .map_err(ApiError::RequestClient)?,
                 true,
             )
         } else {
@@ -199,7 +281,7 @@ impl Host {
             self,
             https_capable,
             &mut client,
-            &host_data.address,
+            &address,
             host_data.http_port,
             info,
         )
@@ -230,6 +312,19 @@ impl Host {
         Ok((host.address, host.http_port))
     }
 
+    pub async fn default_stream_settings(
+        &self,
+        user: &mut AuthenticatedUser,
+    ) -> Result<Option<HostStreamSettings>, AppError> {
+        self.can_use(user).await?;
+
+        let app = self.app.access()?;
+
+        let host = app.storage.get_host(self.id).await?;
+
+        Ok(host.default_stream_settings)
+    }
+
     pub async fn pair_info(
         &self,
         user: &mut AuthenticatedUser,
@@ -372,10 +467,20 @@ impl Host {
             Err(err) => Err(err),
         }
     }
+
     pub async fn detailed_host(
         &mut self,
         user: &mut AuthenticatedUser,
     ) -> Result<DetailedHost, AppError> {
+        self.probe_detailed_host(user).await.map(|(host, _)| host)
+    }
+
+    /// Like [Self::detailed_host], additionally returns if the host answered the host info request.
+    /// The server state isn't enough for that, it's also missing if the host sent an unknown state.
+    pub async fn probe_detailed_host(
+        &mut self,
+        user: &mut AuthenticatedUser,
+    ) -> Result<(DetailedHost, bool), AppError> {
         self.can_use(user).await?;
 
         let app = self.app.access()?;
@@ -405,7 +510,7 @@ impl Host {
                     Some(HostType::Standard)
                 };
 
-                Ok(DetailedHost {
+                let host = DetailedHost {
                     host_id: self.id.0,
                     owner,
                     name: info.host_name,
@@ -425,7 +530,10 @@ impl Host {
                     max_luma_pixels_hevc: info.max_luma_pixels_hevc,
                     server_codec_mode_support: info.server_codec_mode_support,
                     remote_access: None, // Populated by API layer
-                })
+                    default_stream_settings: storage.default_stream_settings,
+                };
+
+                Ok((host, true))
             }
             Ok(None) => {
                 let paired = if storage.pair_info.is_some() {
@@ -434,7 +542,7 @@ impl Host {
                     PairStatus::NotPaired
                 };
 
-                Ok(DetailedHost {
+                let host = DetailedHost {
                     host_id: self.id.0,
                     owner,
                     name: storage.cache.name,
@@ -454,7 +562,10 @@ impl Host {
                     max_luma_pixels_hevc: 0,
                     server_codec_mode_support: 0,
                     remote_access: None, // Populated by API layer
-                })
+                    default_stream_settings: storage.default_stream_settings,
+                };
+
+                Ok((host, false))
             }
             Err(err) => Err(err),
         }
@@ -474,6 +585,47 @@ impl Host {
         }
     }
 
+    /// Checks that the host answers over http and accepts connections on its https port.
+    ///
+    /// Pairing needs both, so this runs before a pin is shown. The certificate of the host
+    /// is only known after pairing, so the https port is checked with a tcp connection.
+    pub async fn check_pair_reachability(
+        &mut self,
+        user: &mut AuthenticatedUser,
+    ) -> Result<(), AppError> {
+        self.can_use(user).await?;
+
+        let app = self.app.access()?;
+
+        let info = self
+            .host_info(&app, user)
+            .await?
+            .ok_or(AppError::HostOffline)?;
+
+        if matches!(info.pair_status.into(), PairStatus::Paired) {
+            return Err(AppError::HostPaired);
+        }
+
+        let host = self.storage_host(&app).await?;
+        let address =
+            resolve_host_address(&host.address, info.https_port, app.config.ip_preference).await;
+        let https_address = Self::build_hostport(&address, info.https_port);
+
+        match timeout(PAIR_HTTPS_PROBE_TIMEOUT, TcpStream::connect(&https_address)).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(err)) => {
+                debug!("Host {self:?} refused the https connection to {https_address}: {err}");
+                Err(AppError::HostHttpsUnreachable(info.https_port))
+            }
+            Err(_) => {
+                debug!(
+                    "Host {self:?} didn't accept the https connection to {https_address} in time"
+                );
+                Err(AppError::HostHttpsUnreachable(info.https_port))
+            }
+        }
+    }
+
     pub async fn pair(
         &mut self,
         user: &mut AuthenticatedUser,
@@ -493,6 +645,8 @@ impl Host {
             return Err(AppError::HostPaired);
         }
 
+        let device_name = user.pair_device_name().await?;
+
         let modify = self
             .use_client(
                 &app,
@@ -510,7 +664,7 @@ impl Host {
                         client_info,
                         &auth.private_key,
                         &auth.certificate,
-                        &app.config.moonlight.pair_device_name,
+                        &device_name,
                         info.app_version,
                         pin,
                     )
@@ -518,7 +672,7 @@ impl Host {
 
 
                     // Store pair info
-                    let (name, mac) = match host_info(
+                    let (name, mac, local_ip) = match host_info(
                         &mut client,
                         true,
                         &Self::build_hostport(host, info.https_port),
@@ -529,11 +683,11 @@ impl Host {
                         Ok(info) => {
                             this.cache_host_info = Some((user_id, info.clone()));
 
-                            (Some(info.host_name), Some(info.mac))
+                            (Some(info.host_name), Some(info.mac), Some(info.local_ip.parse().ok()))
                         },
                         Err(err) => {
                             warn!("Failed to make https request to host {this:?} after pairing completed: {err}");
-                            (None, None)
+                            (None, None, None)
                         },
                     };
 
@@ -545,6 +699,7 @@ impl Host {
                         })),
                         cache_name: name,
                         cache_mac: mac,
+                        cache_local_ip: local_ip,
                         ..Default::default()
                     })
                 },
@@ -554,10 +709,50 @@ impl Host {
         self.modify(user, modify).await
     }
 
-    pub async fn unpair(&self, user: &mut AuthenticatedUser) -> Result<Host, AppError> {
+    /// Removes our certificate from the host and forgets the pair info.
+    ///
+    /// If the host is offline only the local pair info is removed.
+    pub async fn unpair(mut self, user: &mut AuthenticatedUser) -> Result<Host, AppError> {
         self.can_use(user).await?;
 
-        todo!()
+        let app = self.app.access()?;
+
+        let storage = self.storage_host(&app).await?;
+        if storage.pair_info.is_none() {
+            return Err(AppError::HostNotPaired);
+        }
+
+        self.use_client(
+            &app,
+            user,
+            false,
+            async |this, _https_capable, client, host, port, client_info| {
+                match this.is_offline(
+                    host_unpair(client, &Self::build_hostport(host, port), client_info).await,
+                ) {
+                    Ok(Some(())) => Ok(()),
+                    Ok(None) => {
+                        warn!("Host {this:?} is offline, only removing the local pair info");
+                        Ok(())
+                    }
+                    Err(err) => Err(err),
+                }
+            },
+        )
+        .await??;
+
+        self.cache_host_info = None;
+
+        self.modify(
+            user,
+            StorageHostModify {
+                pair_info: Some(None),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        Ok(self)
     }
 
     /// Detect if this host is a Backlight host (supports OTP auto-pairing)
@@ -581,6 +776,40 @@ impl Host {
         }
     }
 
+    /// The recent log of a Backlight host, None for other hosts because their log requires
+    /// the credentials of the Sunshine web ui
+    pub async fn logs(
+        &mut self,
+        user: &mut AuthenticatedUser,
+    ) -> Result<Option<(String, bool)>, AppError> {
+        let app = self.app.access()?;
+
+        // The log can contain the names of other users' devices, so shared hosts are admin only
+        let storage = self.storage_host(&app).await?;
+        if storage.owner != Some(user.id()) && !matches!(user.role().await?, Role::Admin) {
+            return Err(AppError::Forbidden);
+        }
+
+        let info = self
+            .host_info(&app, user)
+            .await?
+            .ok_or(AppError::HostOffline)?;
+        if !info.is_backlight {
+            return Ok(None);
+        }
+
+        // The Sunshine web ui is on HTTP port + 1, like the OTP endpoint
+        let port = storage.http_port + 1;
+        let address = resolve_host_address(&storage.address, port, app.config.ip_preference).await;
+        let hostport = Self::build_hostport(&address, port);
+        let text = fetch_fuji_logs(&hostport).await.map_err(|err| {
+            warn!("Failed to fetch the logs of host {:?}: {err}", self.id);
+            AppError::HostLogsFailed(err.to_string())
+        })?;
+
+        Ok(Some(tail_log(&text, MAX_HOST_LOGS_SIZE)))
+    }
+
     /// Auto-pair with a Backlight host using OTP
     ///
     /// This requests an OTP from the Backlight host and uses it to complete pairing
@@ -604,14 +833,16 @@ impl Host {
         
         // Backlight OTP endpoint is on HTTP port + 1 (e.g., 48989 + 1 = 48990)
         let otp_port = storage.http_port + 1;
-        let otp_hostport = Self::build_hostport(&storage.address, otp_port);
+        let address =
+            resolve_host_address(&storage.address, otp_port, app.config.ip_preference).await;
+        let otp_hostport = Self::build_hostport(&address, otp_port);
         debug!("Requesting OTP from Backlight at: {}", otp_hostport);
 
         // Request OTP from Backlight
         let passphrase = Uuid::new_v4().to_string();
-        let device_name = &app.config.moonlight.pair_device_name;
+        let device_name = user.pair_device_name().await?;
 
-        let otp = request_fuji_otp(&otp_hostport, &passphrase, device_name)
+        let otp = request_fuji_otp(&otp_hostport, &passphrase, &device_name)
             .await
             .map_err(|e| {
                 warn!("Failed to request Backlight OTP: {e}");
@@ -658,7 +889,7 @@ impl Host {
                         client_info,
                         &auth.private_key,
                         &auth.certificate,
-                        &app.config.moonlight.pair_device_name,
+                        &device_name,
                         info.app_version,
                         pin,
                         Some(otp_creds),
@@ -677,7 +908,7 @@ impl Host {
                     };
 
                     // Store pair info
-                    let (name, mac) = match host_info(
+                    let (name, mac, local_ip) = match host_info(
                         &mut client,
                         true,
                         &Self::build_hostport(host, info.https_port),
@@ -687,11 +918,11 @@ impl Host {
                     {
                         Ok(info) => {
                             this.cache_host_info = Some((user_id, info.clone()));
-                            (Some(info.host_name), Some(info.mac))
+                            (Some(info.host_name), Some(info.mac), Some(info.local_ip.parse().ok()))
                         }
                         Err(err) => {
                             warn!("Failed to make https request to host {this:?} after Backlight pairing completed: {err}");
-                            (None, None)
+                            (None, None, None)
                         }
                     };
 
@@ -703,6 +934,7 @@ impl Host {
                         })),
                         cache_name: name,
                         cache_mac: mac,
+                        cache_local_ip: local_ip,
                         ..Default::default()
                     })
                 },
@@ -712,19 +944,55 @@ impl Host {
         self.modify(user, modify).await
     }
 
-    pub async fn wake(&self, user: &mut AuthenticatedUser) -> Result<(), AppError> {
+    /// Returns how many magic packets were sent
+    pub async fn wake(&self, user: &mut AuthenticatedUser) -> Result<u32, AppError> {
         self.can_use(user).await?;
 
         let app = self.app.access()?;
 
         let storage = self.storage_host(&app).await?;
 
-        if let Some(mac) = storage.cache.mac {
-            broadcast_magic_packet(mac).await?;
-            Ok(())
-        } else {
-            Err(AppError::HostNotFound)
+        let Some(mac) = storage.cache.mac else {
+            return Err(AppError::HostNotFound);
+        };
+
+        let config = &app.config.wake_on_lan;
+
+        let mut broadcast_addresses = vec![Ipv4Addr::BROADCAST];
+        if let Some(address) = storage.wake_broadcast_address {
+            broadcast_addresses.push(address);
+        }
+        if config.target_local_subnet
+            && let Some(local_ip) = storage.cache.local_ip
+        {
+            broadcast_addresses.push(subnet_broadcast_address(local_ip));
+        }
+        broadcast_addresses.dedup();
+
+        let mut packets_sent = 0;
+        for address in broadcast_addresses {
+            match send_magic_packets(
+                mac,
+                address,
+                config.wake_packet_count,
+                config.wake_interval(),
+            )
+            .await
+            {
+                Ok(count) => packets_sent += count,
+                // The limited broadcast is always sent, so don't fail because of an unreachable address
+                Err(err) if address != Ipv4Addr::BROADCAST => {
+                    warn!(
+                        "Failed to send wake on lan packets to {address} for host {self:?}: {err}"
+                    );
+                }
+                Err(err) => return Err(err.into()),
+            }
         }
+
+        debug!("Sent {packets_sent} wake on lan packets to host {self:?}");
+
+        Ok(packets_sent)
     }
 
     pub async fn list_apps(&mut self, user: &mut AuthenticatedUser) -> Result<Vec<App>, AppError> {
@@ -760,6 +1028,18 @@ impl Host {
         )
         .await?
     }
+    /// The host can only list all apps, so this saves the clients from transferring all of them
+    pub async fn app(
+        &mut self,
+        user: &mut AuthenticatedUser,
+        app_id: AppId,
+    ) -> Result<App, AppError> {
+        let apps = self.list_apps(user).await?;
+
+        apps.into_iter()
+            .find(|app| app.id == app_id)
+            .ok_or(AppError::AppNotFound)
+    }
     pub async fn app_image(
         &mut self,
         user: &mut AuthenticatedUser,
@@ -771,24 +1051,64 @@ impl Host {
         // TODO: apollo doesn't like this for some reason
         let app = self.app.access()?;
 
-        let info = self
-            .host_info(&app, user)
-            .await?
-            .ok_or(AppError::HostOffline)?;
-
         let cache_key = (user.id(), self.id, app_id);
-        if !force_refresh {
-            {
-                let app_images = app.app_image_cache.read().await;
-                if let Some(app_image) = app_images.get(&cache_key) {
-                    return Ok(app_image.clone());
+        let cached = app.app_image_cache.get(cache_key).await;
+        if !force_refresh && let Some(app_image) = &cached {
+            return Ok(app_image.clone());
+        }
+
+        match self.fetch_app_image(&app, user, app_id).await {
+            Ok(app_image) => {
+                app.app_image_cache
+                    .record_fetch_success(self.id, app_id)
+                    .await;
+                app.app_image_cache
+                    .insert(cache_key, app_image.clone())
+                    .await;
+
+                Ok(app_image)
+            }
+            // The app grid should always render, so a failed fetch isn't an error for the client
+            Err(err) => {
+                let failures = app
+                    .app_image_cache
+                    .record_fetch_failure(self.id, app_id)
+                    .await;
+
+                match cached {
+                    Some(app_image) => {
+                        debug!(
+                            "Failed to fetch the image of app {app_id:?} of host {:?} ({failures} times in a row), serving the cached image: {err}",
+                            self.id
+                        );
+                        Ok(app_image)
+                    }
+                    None => {
+                        warn!(
+                            "Failed to fetch the image of app {app_id:?} of host {:?} ({failures} times in a row), serving the placeholder: {err}",
+                            self.id
+                        );
+                        Ok(app.app_image_cache.placeholder())
+                    }
                 }
             }
         }
+    }
+
+    async fn fetch_app_image(
+        &mut self,
+        app: &AppInner,
+        user: &mut AuthenticatedUser,
+        app_id: AppId,
+    ) -> Result<Bytes, AppError> {
+        let info = self
+            .host_info(app, user)
+            .await?
+            .ok_or(AppError::HostOffline)?;
 
         let app_image = self
             .use_client(
-                &app,
+                app,
                 user,
                 false,
                 async |_this, https_capable, client, host, _port, client_info| {
@@ -808,14 +1128,8 @@ impl Host {
                 },
             )
             .await??;
-        let app_image = Bytes::from_owner(app_image);
-
-        {
-            let mut app_images = app.app_image_cache.write().await;
-            app_images.insert(cache_key, app_image.clone());
-        }
 
-        Ok(app_image)
+        Ok(Bytes::from_owner(app_image))
     }
 
     pub async fn cancel_app(&mut self, user: &mut AuthenticatedUser) -> Result<bool, AppError> {
@@ -856,10 +1170,7 @@ impl Host {
         let host = app.storage.get_host(self.id).await?;
 
         if host.owner == Some(user.id()) || matches!(user.role().await?, Role::Admin) {
-            {
-                let mut app_images = app.app_image_cache.write().await;
-                app_images.retain(|(_, host_id, _), _| *host_id != self.id);
-            }
+            app.app_image_cache.remove_host(self.id).await;
 
             drop(app);
             self.delete_no_auth().await
@@ -870,8 +1181,60 @@ impl Host {
     pub async fn delete_no_auth(self) -> Result<(), AppError> {
         let app = self.app.access()?;
 
+        app.client_pool.invalidate(self.id).await;
+
         app.storage.remove_host(self.id).await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::app::host::{is_valid_host_address, subnet_broadcast_address, tail_log};
+
+    #[test]
+    fn test_tail_log() {
+        assert_eq!(tail_log("a\nb\n", 10), ("a\nb\n".to_string(), false));
+
+        // Cut after the first complete line in the size
+        assert_eq!(
+            tail_log("first line\nsecond\nthird\n", 12),
+            ("third\n".to_string(), true)
+        );
+
+        // A multi byte character at the cut isn't split
+        let (text, truncated) = tail_log("ääää", 3);
+        assert!(truncated);
+        assert_eq!(text, "ä");
+    }
+
+    #[test]
+    fn test_subnet_broadcast_address() {
+        assert_eq!(
+            subnet_broadcast_address(Ipv4Addr::new(192, 168, 1, 42)),
+            Ipv4Addr::new(192, 168, 1, 255)
+        );
+    }
+
+    #[test]
+    fn test_is_valid_host_address() {
+        for address in ["192.168.1.42", "[fe80::1]", "gaming-pc", "pc.example.com"] {
+            assert!(is_valid_host_address(address), "{address}");
+        }
+        for address in [
+            "",
+            "fe80::1",
+            "192.168.1.42:47989",
+            "http://pc",
+            "pc/path",
+            "-pc",
+            "pc..local",
+            "pc name",
+        ] {
+            assert!(!is_valid_host_address(address), "{address}");
+        }
+    }
+}
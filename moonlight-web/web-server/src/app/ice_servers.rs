@@ -0,0 +1,174 @@
+//! ICE servers handed to the clients and streamers
+//!
+//! TURN servers without a username and credential get time-limited credentials of the TURN REST api,
+//! which coturn supports with `use-auth-secret`: the username is the unix time at which it expires
+//! and the credential is `base64(hmac-sha1(shared secret, username))`.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use common::{
+    api_bindings::RtcIceServer,
+    config::{IceUrl, WebRtcConfig},
+};
+use log::warn;
+use openssl::{base64, error::ErrorStack, hash::MessageDigest, pkey::PKey, sign::Signer};
+use tokio::sync::Mutex;
+
+/// Generated credentials are handed out again for this long instead of signing new ones for every request
+const CACHE_DURATION: Duration = Duration::from_secs(60);
+
+/// The ice servers and how long their generated credentials stay valid
+#[derive(Debug, Clone)]
+pub struct IceServerList {
+    pub ice_servers: Vec<RtcIceServer>,
+    /// None if no credentials were generated
+    pub expires_in: Option<Duration>,
+}
+
+#[derive(Default)]
+pub struct IceServerCredentials {
+    cache: Mutex<Option<(Instant, Vec<RtcIceServer>)>>,
+}
+
+impl IceServerCredentials {
+    /// The configured ice servers, TURN servers get fresh credentials unless recent ones are cached
+    pub async fn ice_servers(&self, config: &WebRtcConfig) -> IceServerList {
+        let Some(secret) = &config.turn_shared_secret else {
+            return IceServerList {
+                ice_servers: config.ice_servers.clone(),
+                expires_in: None,
+            };
+        };
+
+        let ttl = Duration::from_secs(config.turn_credential_ttl_secs);
+        // Short lived credentials shouldn't be handed out shortly before they expire
+        let cache_duration = CACHE_DURATION.min(ttl / 2);
+
+        let mut cache = self.cache.lock().await;
+
+        if let Some((generated_at, ice_servers)) = cache.as_ref()
+            && generated_at.elapsed() < cache_duration
+        {
+            return IceServerList {
+                ice_servers: ice_servers.clone(),
+                expires_in: Some(ttl.saturating_sub(generated_at.elapsed())),
+            };
+        }
+
+        match generate_ice_servers(config, secret, SystemTime::now()) {
+            Ok(ice_servers) => {
+                *cache = Some((Instant::now(), ice_servers.clone()));
+
+                IceServerList {
+                    ice_servers,
+                    expires_in: Some(ttl),
+                }
+            }
+            Err(err) => {
+                warn!("[IceServers]: failed to generate TURN credentials: {err:?}");
+
+                IceServerList {
+                    ice_servers: config.ice_servers.clone(),
+                    expires_in: None,
+                }
+            }
+        }
+    }
+}
+
+fn generate_ice_servers(
+    config: &WebRtcConfig,
+    secret: &str,
+    now: SystemTime,
+) -> Result<Vec<RtcIceServer>, ErrorStack> {
+    let expires_at = now.duration_since(UNIX_EPOCH).unwrap_or_default()
+        + Duration::from_secs(config.turn_credential_ttl_secs);
+
+    let username = expires_at.as_secs().to_string();
+    let credential = turn_rest_credential(secret, &username)?;
+
+    Ok(config
+        .ice_servers
+        .iter()
+        .map(|server| {
+            let is_turn = server
+                .urls
+                .iter()
+                .any(|url| url.parse::<IceUrl>().is_ok_and(|url| url.is_relay()));
+
+            if is_turn && server.username.is_empty() && server.credential.is_empty() {
+                RtcIceServer {
+                    username: username.clone(),
+                    credential: credential.clone(),
+                    ..server.clone()
+                }
+            } else {
+                server.clone()
+            }
+        })
+        .collect())
+}
+
+fn turn_rest_credential(secret: &str, username: &str) -> Result<String, ErrorStack> {
+    let key = PKey::hmac(secret.as_bytes())?;
+
+    let mut signer = Signer::new(MessageDigest::sha1(), &key)?;
+    signer.update(username.as_bytes())?;
+
+    Ok(base64::encode_block(&signer.sign_to_vec()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use common::{api_bindings::RtcIceServer, config::WebRtcConfig};
+
+    use crate::app::ice_servers::{generate_ice_servers, turn_rest_credential};
+
+    #[test]
+    fn test_turn_rest_credential() {
+        // echo -n "1700000000" | openssl dgst -sha1 -hmac "secret" -binary | base64
+        assert_eq!(
+            turn_rest_credential("secret", "1700000000").unwrap(),
+            "WGw37+g43pfwVUmrc9tgArn/juE="
+        );
+    }
+
+    #[test]
+    fn test_generate_ice_servers() {
+        let config = WebRtcConfig {
+            ice_servers: vec![
+                RtcIceServer {
+                    urls: vec!["stun:stun.example.com:3478".to_string()],
+                    ..Default::default()
+                },
+                RtcIceServer {
+                    urls: vec!["turn:turn.example.com:3478".to_string()],
+                    ..Default::default()
+                },
+                RtcIceServer {
+                    urls: vec!["turns:static.example.com:443".to_string()],
+                    username: "user".to_string(),
+                    credential: "password".to_string(),
+                    ..Default::default()
+                },
+            ],
+            turn_shared_secret: Some("secret".to_string()),
+            turn_credential_ttl_secs: 3600,
+            ..Default::default()
+        };
+
+        let servers = generate_ice_servers(
+            &config,
+            "secret",
+            UNIX_EPOCH + Duration::from_secs(1_699_996_400),
+        )
+        .unwrap();
+
+        assert!(servers[0].username.is_empty());
+        assert_eq!(servers[1].username, "1700000000");
+        assert_eq!(servers[1].credential, "WGw37+g43pfwVUmrc9tgArn/juE=");
+        assert_eq!(servers[2].username, "user");
+    }
+}
@@ -0,0 +1,352 @@
+//! Two tier cache for the box art of apps
+//!
+//! Images are kept in memory up to a configured count and size, evicting the least recently used.
+//! If a disk path is configured every image is also written to disk so it survives restarts.
+//!
+//! Cached images are also the fallback if fetching a fresh image from the host fails, apps
+//! without a cached image get the placeholder. The placeholder is never cached, so the next
+//! request fetches again.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use actix_web::web::Bytes;
+use common::config::AppImageCacheConfig;
+use log::{debug, warn};
+use openssl::sha::sha256;
+use tokio::{fs, sync::Mutex};
+
+use crate::app::{
+    host::{AppId, HostId},
+    user::UserId,
+};
+
+pub type AppImageKey = (UserId, HostId, AppId);
+
+/// The same image the web client shows for apps without box art
+const DEFAULT_PLACEHOLDER: &[u8] = include_bytes!("../../web/resources/no_app_image.png");
+
+struct MemoryEntry {
+    image: Bytes,
+    last_used: u64,
+}
+
+/// In memory tier which evicts the least recently used images
+struct MemoryCache {
+    max_entries: usize,
+    max_bytes: usize,
+    entries: HashMap<AppImageKey, MemoryEntry>,
+    total_bytes: usize,
+    use_counter: u64,
+}
+
+impl MemoryCache {
+    fn new(max_entries: usize, max_bytes: usize) -> Self {
+        Self {
+            max_entries,
+            max_bytes,
+            entries: HashMap::new(),
+            total_bytes: 0,
+            use_counter: 0,
+        }
+    }
+
+    fn next_use(&mut self) -> u64 {
+        self.use_counter += 1;
+        self.use_counter
+    }
+
+    fn get(&mut self, key: &AppImageKey) -> Option<Bytes> {
+        let last_used = self.next_use();
+
+        let entry = self.entries.get_mut(key)?;
+        entry.last_used = last_used;
+
+        Some(entry.image.clone())
+    }
+
+    fn insert(&mut self, key: AppImageKey, image: Bytes) {
+        self.remove(&key);
+
+        // Images larger than the whole cache would evict everything else
+        if image.len() > self.max_bytes || self.max_entries == 0 {
+            return;
+        }
+
+        while self.entries.len() >= self.max_entries
+            || self.total_bytes + image.len() > self.max_bytes
+        {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| *key)
+            else {
+                break;
+            };
+
+            self.remove(&oldest);
+        }
+
+        let last_used = self.next_use();
+        self.total_bytes += image.len();
+        self.entries.insert(key, MemoryEntry { image, last_used });
+    }
+
+    fn remove(&mut self, key: &AppImageKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.total_bytes -= entry.image.len();
+        }
+    }
+
+    fn remove_host(&mut self, host_id: HostId) {
+        let keys = self
+            .entries
+            .keys()
+            .filter(|(_, entry_host_id, _)| *entry_host_id == host_id)
+            .copied()
+            .collect::<Vec<_>>();
+
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+}
+
+pub struct AppImageCache {
+    memory: Mutex<MemoryCache>,
+    disk_path: Option<PathBuf>,
+    placeholder: Bytes,
+    /// Failed fetches in a row per app, reset once a fetch succeeds
+    fetch_failures: Mutex<HashMap<(HostId, AppId), u32>>,
+    total_fetch_failures: AtomicU64,
+}
+
+impl AppImageCache {
+    pub async fn new(config: &AppImageCacheConfig) -> Self {
+        let placeholder = match &config.placeholder_path {
+            Some(path) => match fs::read(path).await {
+                Ok(image) => Bytes::from(image),
+                Err(err) => {
+                    warn!(
+                        "[AppImageCache]: failed to read the placeholder {path:?}, using the built-in one: {err}"
+                    );
+                    Bytes::from_static(DEFAULT_PLACEHOLDER)
+                }
+            },
+            None => Bytes::from_static(DEFAULT_PLACEHOLDER),
+        };
+
+        Self {
+            memory: Mutex::new(MemoryCache::new(
+                config.max_memory_entries,
+                config.max_memory_bytes,
+            )),
+            disk_path: config.disk_path.as_ref().map(PathBuf::from),
+            placeholder,
+            fetch_failures: Default::default(),
+            total_fetch_failures: AtomicU64::new(0),
+        }
+    }
+
+    pub fn placeholder(&self) -> Bytes {
+        self.placeholder.clone()
+    }
+
+    /// Returns how many fetches of the app's image failed in a row
+    pub async fn record_fetch_failure(&self, host_id: HostId, app_id: AppId) -> u32 {
+        self.total_fetch_failures.fetch_add(1, Ordering::Relaxed);
+
+        let mut fetch_failures = self.fetch_failures.lock().await;
+        let failures = fetch_failures.entry((host_id, app_id)).or_default();
+        *failures += 1;
+
+        *failures
+    }
+
+    pub async fn record_fetch_success(&self, host_id: HostId, app_id: AppId) {
+        let mut fetch_failures = self.fetch_failures.lock().await;
+        fetch_failures.remove(&(host_id, app_id));
+    }
+
+    /// Failed fetches since the web server started
+    pub fn total_fetch_failures(&self) -> u64 {
+        self.total_fetch_failures.load(Ordering::Relaxed)
+    }
+
+    /// Looks into memory first and falls back to the disk
+    pub async fn get(&self, key: AppImageKey) -> Option<Bytes> {
+        {
+            let mut memory = self.memory.lock().await;
+            if let Some(image) = memory.get(&key) {
+                return Some(image);
+            }
+        }
+
+        let path = disk_file_path(self.disk_path.as_ref()?, key);
+        let image = match fs::read(&path).await {
+            Ok(image) => Bytes::from(image),
+            Err(err) => {
+                debug!("[AppImageCache]: no image on disk at {path:?}: {err}");
+                return None;
+            }
+        };
+
+        let mut memory = self.memory.lock().await;
+        memory.insert(key, image.clone());
+
+        Some(image)
+    }
+
+    /// Overwrites both tiers
+    pub async fn insert(&self, key: AppImageKey, image: Bytes) {
+        {
+            let mut memory = self.memory.lock().await;
+            memory.insert(key, image.clone());
+        }
+
+        let Some(disk_path) = &self.disk_path else {
+            return;
+        };
+
+        if let Err(err) = fs::create_dir_all(disk_path).await {
+            warn!("[AppImageCache]: failed to create cache directory {disk_path:?}: {err}");
+            return;
+        }
+
+        let path = disk_file_path(disk_path, key);
+        if let Err(err) = fs::write(&path, &image).await {
+            warn!("[AppImageCache]: failed to write image to {path:?}: {err}");
+        }
+    }
+
+    pub async fn remove_host(&self, host_id: HostId) {
+        {
+            let mut memory = self.memory.lock().await;
+            memory.remove_host(host_id);
+        }
+        {
+            let mut fetch_failures = self.fetch_failures.lock().await;
+            fetch_failures.retain(|(failed_host_id, _), _| *failed_host_id != host_id);
+        }
+
+        let Some(disk_path) = &self.disk_path else {
+            return;
+        };
+
+        let mut entries = match fs::read_dir(disk_path).await {
+            Ok(entries) => entries,
+            Err(err) => {
+                debug!("[AppImageCache]: failed to read cache directory {disk_path:?}: {err}");
+                return;
+            }
+        };
+
+        let prefix = disk_file_prefix(host_id);
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if entry.file_name().to_string_lossy().starts_with(&prefix)
+                && let Err(err) = fs::remove_file(entry.path()).await
+            {
+                warn!(
+                    "[AppImageCache]: failed to remove image {:?}: {err}",
+                    entry.path()
+                );
+            }
+        }
+    }
+}
+
+/// The host id is kept readable so all images of a host can be removed
+fn disk_file_prefix(host_id: HostId) -> String {
+    format!("host{}-", host_id.0)
+}
+
+fn disk_file_path(disk_path: &Path, (user_id, host_id, app_id): AppImageKey) -> PathBuf {
+    let hash = sha256(format!("{}-{}-{}", user_id.0, host_id.0, app_id.0).as_bytes());
+
+    disk_path.join(format!(
+        "{}{}.img",
+        disk_file_prefix(host_id),
+        hex::encode(hash)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use actix_web::web::Bytes;
+
+    use crate::app::{
+        host::{AppId, HostId},
+        image_cache::{MemoryCache, disk_file_path},
+        user::UserId,
+    };
+
+    fn key(app_id: u32) -> (UserId, HostId, AppId) {
+        (UserId(0), HostId(0), AppId(app_id))
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut cache = MemoryCache::new(2, 1024);
+
+        cache.insert(key(1), Bytes::from_static(b"1"));
+        cache.insert(key(2), Bytes::from_static(b"2"));
+        assert!(cache.get(&key(1)).is_some());
+
+        cache.insert(key(3), Bytes::from_static(b"3"));
+        assert!(cache.get(&key(1)).is_some());
+        assert!(cache.get(&key(2)).is_none());
+        assert!(cache.get(&key(3)).is_some());
+    }
+
+    #[test]
+    fn test_evicts_by_size() {
+        let mut cache = MemoryCache::new(10, 4);
+
+        cache.insert(key(1), Bytes::from_static(b"11"));
+        cache.insert(key(2), Bytes::from_static(b"22"));
+        cache.insert(key(3), Bytes::from_static(b"33"));
+        assert_eq!(cache.total_bytes, 4);
+        assert!(cache.get(&key(1)).is_none());
+
+        // Larger than the whole cache
+        cache.insert(key(4), Bytes::from_static(b"44444"));
+        assert!(cache.get(&key(4)).is_none());
+        assert_eq!(cache.entries.len(), 2);
+    }
+
+    #[test]
+    fn test_overwrite_and_remove_host() {
+        let mut cache = MemoryCache::new(10, 1024);
+
+        cache.insert(key(1), Bytes::from_static(b"old"));
+        cache.insert(key(1), Bytes::from_static(b"new!"));
+        assert_eq!(cache.get(&key(1)), Some(Bytes::from_static(b"new!")));
+        assert_eq!(cache.total_bytes, 4);
+
+        cache.insert((UserId(0), HostId(1), AppId(1)), Bytes::from_static(b"1"));
+        cache.remove_host(HostId(0));
+        assert_eq!(cache.entries.len(), 1);
+        assert_eq!(cache.total_bytes, 1);
+    }
+
+    #[test]
+    fn test_disk_file_path() {
+        let path = disk_file_path(Path::new("cache"), (UserId(1), HostId(2), AppId(3)));
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+
+        assert!(name.starts_with("host2-"));
+        assert_ne!(
+            path,
+            disk_file_path(Path::new("cache"), (UserId(1), HostId(2), AppId(4)))
+        );
+    }
+}
@@ -0,0 +1,301 @@
+//! Brute force protection for the login
+//!
+//! Failed logins are counted per ip and per username. Once one of them reaches the configured
+//! maximum the login is locked, the lockout doubles with every further failure.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use common::config::{LogConfig, SecurityConfig};
+use log::{debug, info};
+use tokio::{spawn, sync::Mutex, time::interval};
+
+/// Interval for forgetting old failed logins
+pub const LOGIN_CLEANUP_INTERVAL_SECS: u64 = 60;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum LoginKey {
+    Ip(IpAddr),
+    Username(String),
+}
+
+impl LoginKey {
+    /// The ip is anonymized if the log config asks for it
+    fn log(&self, log_config: &LogConfig) -> String {
+        match self {
+            Self::Ip(ip) => format!("ip {}", log_config.ip(*ip)),
+            Self::Username(username) => format!("user \"{username}\""),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FailedLogins {
+    count: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct LoginAttempts {
+    failures: HashMap<LoginKey, FailedLogins>,
+}
+
+impl LoginAttempts {
+    /// Returns the remaining lockout if any key is locked
+    fn locked(&self, keys: &[LoginKey], now: Instant) -> Option<Duration> {
+        keys.iter()
+            .filter_map(|key| self.failures.get(key)?.locked_until)
+            .filter(|locked_until| *locked_until > now)
+            .map(|locked_until| locked_until - now)
+            .max()
+    }
+
+    fn record_failure(
+        &mut self,
+        config: &SecurityConfig,
+        log_config: &LogConfig,
+        keys: &[LoginKey],
+        now: Instant,
+    ) {
+        let reset_after = Duration::from_secs(config.login_attempts_reset_secs);
+
+        for key in keys {
+            let failures = self.failures.entry(key.clone()).or_insert(FailedLogins {
+                count: 0,
+                last_failure: now,
+                locked_until: None,
+            });
+
+            if now.saturating_duration_since(failures.last_failure) > reset_after {
+                failures.count = 0;
+            }
+
+            failures.count += 1;
+            failures.last_failure = now;
+
+            if config.login_max_attempts > 0 && failures.count >= config.login_max_attempts {
+                let lockout = lockout_duration(config, failures.count - config.login_max_attempts);
+                failures.locked_until = Some(now + lockout);
+
+                info!(
+                    "[LoginLimiter]: locked login for {} for {}s after {} failed attempts",
+                    key.log(log_config),
+                    lockout.as_secs(),
+                    failures.count
+                );
+            }
+        }
+    }
+
+    fn record_success(&mut self, keys: &[LoginKey]) {
+        for key in keys {
+            self.failures.remove(key);
+        }
+    }
+
+    fn cleanup(&mut self, config: &SecurityConfig, now: Instant) {
+        let reset_after = Duration::from_secs(config.login_attempts_reset_secs);
+
+        let before = self.failures.len();
+        self.failures.retain(|_, failures| {
+            let locked = failures
+                .locked_until
+                .is_some_and(|locked_until| locked_until > now);
+
+            locked || now.saturating_duration_since(failures.last_failure) <= reset_after
+        });
+
+        let removed = before - self.failures.len();
+        if removed > 0 {
+            debug!("[LoginLimiter]: forgot failed logins of {removed} ips / users");
+        }
+    }
+}
+
+/// The first lockout takes `login_lockout_secs`, every further failure doubles it
+fn lockout_duration(config: &SecurityConfig, extra_failures: u32) -> Duration {
+    let secs = config
+        .login_lockout_secs
+        .saturating_mul(1u64.checked_shl(extra_failures).unwrap_or(u64::MAX));
+
+    Duration::from_secs(secs.min(config.login_max_lockout_secs))
+}
+
+fn login_keys(ip: Option<IpAddr>, username: &str) -> Vec<LoginKey> {
+    let mut keys = Vec::with_capacity(2);
+    if let Some(ip) = ip {
+        keys.push(LoginKey::Ip(ip));
+    }
+    keys.push(LoginKey::Username(username.to_string()));
+
+    keys
+}
+
+#[derive(Debug)]
+pub struct LoginRateLimiter {
+    config: SecurityConfig,
+    log_config: LogConfig,
+    attempts: Arc<Mutex<LoginAttempts>>,
+}
+
+impl LoginRateLimiter {
+    /// Create a new rate limiter and start the cleanup task
+    pub fn new(config: SecurityConfig, log_config: LogConfig) -> Self {
+        let attempts = Arc::new(Mutex::new(LoginAttempts::default()));
+
+        spawn({
+            let config = config.clone();
+            let attempts = attempts.clone();
+
+            async move {
+                let mut cleanup_interval =
+                    interval(Duration::from_secs(LOGIN_CLEANUP_INTERVAL_SECS));
+                loop {
+                    cleanup_interval.tick().await;
+
+                    let mut attempts = attempts.lock().await;
+                    attempts.cleanup(&config, Instant::now());
+                }
+            }
+        });
+
+        Self {
+            config,
+            log_config,
+            attempts,
+        }
+    }
+
+    /// Returns Err with the duration after which the login can be retried if it's locked
+    pub async fn check(&self, ip: Option<IpAddr>, username: &str) -> Result<(), Duration> {
+        let attempts = self.attempts.lock().await;
+
+        match attempts.locked(&login_keys(ip, username), Instant::now()) {
+            Some(retry_after) => Err(retry_after),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn record_failure(&self, ip: Option<IpAddr>, username: &str) {
+        let mut attempts = self.attempts.lock().await;
+
+        attempts.record_failure(
+            &self.config,
+            &self.log_config,
+            &login_keys(ip, username),
+            Instant::now(),
+        );
+    }
+
+    pub async fn record_success(&self, ip: Option<IpAddr>, username: &str) {
+        let mut attempts = self.attempts.lock().await;
+
+        attempts.record_success(&login_keys(ip, username));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::{Duration, Instant},
+    };
+
+    use common::config::{LogConfig, SecurityConfig};
+
+    use crate::app::login_limiter::{LoginAttempts, LoginKey, lockout_duration, login_keys};
+
+    fn config() -> SecurityConfig {
+        SecurityConfig {
+            login_max_attempts: 3,
+            login_lockout_secs: 10,
+            login_max_lockout_secs: 60,
+            login_attempts_reset_secs: 100,
+        }
+    }
+
+    const IP: Option<IpAddr> = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+
+    #[test]
+    fn test_lockout_doubles() {
+        let config = config();
+
+        assert_eq!(lockout_duration(&config, 0), Duration::from_secs(10));
+        assert_eq!(lockout_duration(&config, 1), Duration::from_secs(20));
+        assert_eq!(lockout_duration(&config, 2), Duration::from_secs(40));
+        assert_eq!(lockout_duration(&config, 3), Duration::from_secs(60));
+        assert_eq!(lockout_duration(&config, 100), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_locked_after_max_attempts() {
+        let config = config();
+        let mut attempts = LoginAttempts::default();
+        let keys = login_keys(IP, "user");
+        let now = Instant::now();
+
+        attempts.record_failure(&config, &LogConfig::default(), &keys, now);
+        attempts.record_failure(&config, &LogConfig::default(), &keys, now);
+        assert_eq!(attempts.locked(&keys, now), None);
+
+        attempts.record_failure(&config, &LogConfig::default(), &keys, now);
+        assert_eq!(attempts.locked(&keys, now), Some(Duration::from_secs(10)));
+        assert_eq!(attempts.locked(&keys, now + Duration::from_secs(10)), None);
+
+        // The same username from another ip is still locked
+        let other_ip = login_keys(Some(IpAddr::V4(Ipv4Addr::LOCALHOST)), "user");
+        assert!(attempts.locked(&other_ip, now).is_some());
+    }
+
+    #[test]
+    fn test_success_resets() {
+        let config = config();
+        let mut attempts = LoginAttempts::default();
+        let keys = login_keys(IP, "user");
+        let now = Instant::now();
+
+        attempts.record_failure(&config, &LogConfig::default(), &keys, now);
+        attempts.record_failure(&config, &LogConfig::default(), &keys, now);
+        attempts.record_success(&keys);
+        attempts.record_failure(&config, &LogConfig::default(), &keys, now);
+
+        assert_eq!(attempts.locked(&keys, now), None);
+    }
+
+    #[test]
+    fn test_cleanup_forgets_old_failures() {
+        let config = config();
+        let mut attempts = LoginAttempts::default();
+        let keys = login_keys(IP, "user");
+        let now = Instant::now();
+
+        attempts.record_failure(&config, &LogConfig::default(), &keys, now);
+        attempts.cleanup(&config, now + Duration::from_secs(50));
+        assert_eq!(attempts.failures.len(), 2);
+
+        attempts.cleanup(&config, now + Duration::from_secs(101));
+        assert!(attempts.failures.is_empty());
+    }
+
+    #[test]
+    fn test_log_key() {
+        let log_config = LogConfig {
+            anonymize_ips: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            LoginKey::Ip(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))).log(&log_config),
+            "ip 10.0.0.0"
+        );
+        assert_eq!(
+            LoginKey::Username("user".to_string()).log(&log_config),
+            "user \"user\""
+        );
+    }
+}
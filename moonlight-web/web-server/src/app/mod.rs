@@ -1,12 +1,22 @@
 use std::{
-    collections::HashMap,
     io,
     ops::Deref,
-    sync::{Arc, Weak},
+    path::PathBuf,
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
-use actix_web::{ResponseError, http::StatusCode, web::Bytes};
-use common::config::Config;
+use actix_web::{
+    HttpResponse, ResponseError,
+    http::{StatusCode, header::RETRY_AFTER},
+};
+use common::{
+    api_bindings::{ApiErrorResponse, ConnectionLogEntry, FailedConnectionLog, StatsSample},
+    config::Config,
+};
 use hex::FromHexError;
 use log::{error, warn};
 use moonlight_common::{
@@ -15,23 +25,43 @@ use moonlight_common::{
 };
 use openssl::error::ErrorStack;
 use thiserror::Error;
-use tokio::sync::RwLock;
 
 use crate::app::{
+    audit::AuditLog,
     auth::{SessionToken, UserAuth},
-    host::{AppId, HostId},
+    client_pool::ClientPool,
+    connection_logs::FailedConnectionLogs,
+    discovery::HostDiscovery,
+    host::HostId,
+    ice_servers::{IceServerCredentials, IceServerList},
+    image_cache::AppImageCache,
+    login_limiter::LoginRateLimiter,
     password::StoragePassword,
     session::SessionManager,
-    storage::{Either, Storage, StorageHostModify, StorageUserAdd, create_storage},
+    stats_timelines::{StatsTimeline, StatsTimelines},
+    storage::{
+        Either, Storage, StorageHostModify, StorageQueryStreamHistory, StorageQueryUsers,
+        StorageStreamHistoryEntry, StorageUserAdd, create_storage,
+    },
+    streamer_manager::StreamerProcessManager,
     user::{Admin, AuthenticatedUser, Role, User, UserId},
 };
 
+pub mod audit;
 pub mod auth;
+pub mod client_pool;
+pub mod connection_logs;
+pub mod discovery;
 pub mod fuji;
 pub mod host;
+pub mod ice_servers;
+pub mod image_cache;
+pub mod login_limiter;
 pub mod password;
 pub mod session;
+pub mod stats_timelines;
 pub mod storage;
+pub mod streamer_manager;
 pub mod user;
 
 #[derive(Debug, Error)]
@@ -54,6 +84,16 @@ pub enum AppError {
     HostNotPaired,
     #[error("the host was offline, but the action requires that the host is online")]
     HostOffline,
+    #[error("the host answered over http, but its https port {0} couldn't be reached")]
+    HostHttpsUnreachable(u16),
+    #[error("the hybrid session was not found")]
+    HybridSessionNotFound,
+    #[error("the app was not found")]
+    AppNotFound,
+    #[error("the stats timeline was not found")]
+    StatsTimelineNotFound,
+    #[error("the client certificate already belongs to another user")]
+    ClientCertificateInUse,
     // -- Unauthorized
     #[error("the credentials don't exists")]
     CredentialsWrong,
@@ -66,6 +106,8 @@ pub enum AppError {
     // --
     #[error("the action is not allowed with the current privileges, 403")]
     Forbidden,
+    #[error("hosts can't be added at this address, see the security config")]
+    HostAddressNotAllowed,
     // -- Bad Request
     #[error("the authorization header is not a bearer")]
     AuthorizationNotBearer,
@@ -79,6 +121,9 @@ pub enum AppError {
     NameEmpty,
     #[error("the authorization header is not a bearer")]
     BadRequest,
+    // -- Too Many Requests
+    #[error("too many failed login attempts, retry in {0}s")]
+    TooManyLoginAttempts(u64),
     // --
     #[error("openssl error occured: {0}")]
     OpenSSL(#[from] ErrorStack),
@@ -92,9 +137,68 @@ pub enum AppError {
     Pairing(#[from] PairError<<MoonlightClient as RequestClient>::Error>),
     #[error("fuji pairing failed: {0}")]
     FujiPairingFailed(String),
+    #[error("fetching the host logs failed: {0}")]
+    HostLogsFailed(String),
+    #[error("webrtc error: {0}")]
+    WebRtc(#[from] webrtc::Error),
+}
+
+impl AppError {
+    /// Stable identifier which clients can match on, unlike the message it must never change
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::AppDestroyed => "app_destroyed",
+            Self::UserNotFound => "user_not_found",
+            Self::FirstUserAlreadyExists => "first_user_already_exists",
+            Self::FirstLoginCreateAdminNotSet => "first_login_create_admin_not_set",
+            Self::UserAlreadyExists => "user_already_exists",
+            Self::HostNotFound => "host_not_found",
+            Self::HostPaired => "host_paired",
+            Self::HostNotPaired => "host_not_paired",
+            Self::HostOffline => "host_offline",
+            Self::HostHttpsUnreachable(_) => "host_https_unreachable",
+            Self::HybridSessionNotFound => "hybrid_session_not_found",
+            Self::AppNotFound => "app_not_found",
+            Self::StatsTimelineNotFound => "stats_timeline_not_found",
+            Self::ClientCertificateInUse => "client_certificate_in_use",
+            Self::CredentialsWrong => "credentials_wrong",
+            Self::SessionTokenNotFound => "session_token_not_found",
+            Self::Unauthorized => "unauthorized",
+            Self::HeaderAuthDisabled => "header_auth_disabled",
+            Self::Forbidden => "forbidden",
+            Self::HostAddressNotAllowed => "host_address_not_allowed",
+            Self::AuthorizationNotBearer => "authorization_not_bearer",
+            Self::HeaderAuthMalformed => "header_auth_malformed",
+            Self::BearerMalformed => "bearer_malformed",
+            Self::PasswordEmpty => "password_empty",
+            Self::NameEmpty => "name_empty",
+            Self::BadRequest => "bad_request",
+            Self::TooManyLoginAttempts(_) => "too_many_login_attempts",
+            Self::OpenSSL(_) => "openssl",
+            Self::Hex(_) => "hex",
+            Self::Io(_) => "io",
+            Self::MoonlightApi(_) => "moonlight_api",
+            Self::Pairing(_) => "pairing",
+            Self::FujiPairingFailed(_) => "fuji_pairing_failed",
+            Self::HostLogsFailed(_) => "host_logs_failed",
+            Self::WebRtc(_) => "webrtc",
+        }
+    }
 }
 
 impl ResponseError for AppError {
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let Self::TooManyLoginAttempts(retry_after_secs) = self {
+            response.insert_header((RETRY_AFTER, retry_after_secs.to_string()));
+        }
+
+        response.json(ApiErrorResponse {
+            code: self.code().to_string(),
+            message: self.to_string(),
+        })
+    }
+
     fn status_code(&self) -> StatusCode {
         match self {
             Self::AppDestroyed => StatusCode::INTERNAL_SERVER_ERROR,
@@ -104,12 +208,18 @@ impl ResponseError for AppError {
             Self::HostNotPaired => StatusCode::FORBIDDEN,
             Self::HostPaired => StatusCode::NOT_MODIFIED,
             Self::HostOffline => StatusCode::GATEWAY_TIMEOUT,
+            Self::HostHttpsUnreachable(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::HybridSessionNotFound => StatusCode::NOT_FOUND,
+            Self::AppNotFound => StatusCode::NOT_FOUND,
+            Self::StatsTimelineNotFound => StatusCode::NOT_FOUND,
+            Self::ClientCertificateInUse => StatusCode::CONFLICT,
             Self::UserNotFound => StatusCode::NOT_FOUND,
             Self::UserAlreadyExists => StatusCode::CONFLICT,
             Self::CredentialsWrong => StatusCode::UNAUTHORIZED,
             Self::SessionTokenNotFound => StatusCode::UNAUTHORIZED,
             Self::Unauthorized => StatusCode::UNAUTHORIZED,
             Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::HostAddressNotAllowed => StatusCode::FORBIDDEN,
             Self::OpenSSL(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::HeaderAuthDisabled => StatusCode::UNAUTHORIZED,
             Self::Hex(_) => StatusCode::BAD_REQUEST,
@@ -119,10 +229,13 @@ impl ResponseError for AppError {
             Self::PasswordEmpty => StatusCode::BAD_REQUEST,
             Self::NameEmpty => StatusCode::BAD_REQUEST,
             Self::BadRequest => StatusCode::BAD_REQUEST,
+            Self::TooManyLoginAttempts(_) => StatusCode::TOO_MANY_REQUESTS,
             Self::MoonlightApi(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::Pairing(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::FujiPairingFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::HostLogsFailed(_) => StatusCode::BAD_GATEWAY,
+            Self::WebRtc(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
@@ -141,9 +254,22 @@ impl AppRef {
 struct AppInner {
     config: Config,
     storage: Arc<dyn Storage + Send + Sync>,
-    app_image_cache: RwLock<HashMap<(UserId, HostId, AppId), Bytes>>,
+    app_image_cache: AppImageCache,
+    /// Clients configured with the pair certificates of each host
+    client_pool: ClientPool<MoonlightClient>,
     /// Session manager for hybrid streaming mode
     session_manager: SessionManager,
+    /// The streamer processes of all running streams
+    streamer_manager: StreamerProcessManager,
+    login_limiter: LoginRateLimiter,
+    host_discovery: HostDiscovery,
+    ice_server_credentials: IceServerCredentials,
+    audit_log: AuditLog,
+    failed_connection_logs: FailedConnectionLogs,
+    stats_timelines: StatsTimelines,
+    started_at: Instant,
+    /// Set once UPnP is initialized or if it's disabled
+    upnp_ready: AtomicBool,
 }
 
 pub type MoonlightClient = ReqwestClient;
@@ -154,11 +280,30 @@ pub struct App {
 
 impl App {
     pub async fn new(config: Config) -> Result<Self, anyhow::Error> {
+        let streamer_manager =
+            StreamerProcessManager::new(PathBuf::from(&config.streamer_pidfile_dir));
+
+        let login_limiter = LoginRateLimiter::new(config.security.clone(), config.log.clone());
+        let app_image_cache = AppImageCache::new(&config.app_image_cache).await;
+        let session_manager = SessionManager::new(&config.session_limits);
+        let audit_log = AuditLog::open(&config.audit_log).await?;
+        let stats_timelines = StatsTimelines::new(&config.stats_timeline);
+
         let app = AppInner {
             storage: create_storage(config.data_storage.clone()).await?,
             config,
-            app_image_cache: Default::default(),
-            session_manager: SessionManager::new(),
+            app_image_cache,
+            client_pool: Default::default(),
+            session_manager,
+            streamer_manager,
+            login_limiter,
+            host_discovery: Default::default(),
+            ice_server_credentials: Default::default(),
+            audit_log,
+            failed_connection_logs: Default::default(),
+            stats_timelines,
+            started_at: Instant::now(),
+            upnp_ready: AtomicBool::new(false),
         };
 
         Ok(Self {
@@ -171,6 +316,48 @@ impl App {
         &self.inner.session_manager
     }
 
+    pub fn streamer_manager(&self) -> &StreamerProcessManager {
+        &self.inner.streamer_manager
+    }
+
+    pub fn login_limiter(&self) -> &LoginRateLimiter {
+        &self.inner.login_limiter
+    }
+
+    pub fn host_discovery(&self) -> &HostDiscovery {
+        &self.inner.host_discovery
+    }
+
+    pub fn audit_log(&self) -> &AuditLog {
+        &self.inner.audit_log
+    }
+
+    pub fn app_image_cache(&self) -> &AppImageCache {
+        &self.inner.app_image_cache
+    }
+
+    /// The ice servers for clients and streamers with valid TURN credentials
+    pub async fn ice_servers(&self) -> IceServerList {
+        self.inner
+            .ice_server_credentials
+            .ice_servers(&self.inner.config.webrtc)
+            .await
+    }
+
+    pub fn started_at(&self) -> Instant {
+        self.inner.started_at
+    }
+
+    pub fn set_upnp_ready(&self) {
+        self.inner.upnp_ready.store(true, Ordering::Release);
+    }
+
+    /// The storage is created together with the app, so only UPnP and binding the web server
+    /// can still be pending
+    pub fn is_ready(&self) -> bool {
+        self.inner.upnp_ready.load(Ordering::Acquire)
+    }
+
     fn new_ref(&self) -> AppRef {
         AppRef {
             inner: Arc::downgrade(&self.inner),
@@ -318,6 +505,39 @@ impl App {
                     Err(err) => return Err(err),
                 };
 
+                user.authenticate(&auth).await
+            }
+            UserAuth::ClientCertificate(ref certificate) => {
+                let match_common_name = self
+                    .config()
+                    .web_server
+                    .client_certificate_auth
+                    .as_ref()
+                    .is_some_and(|config| config.match_common_name);
+
+                let user = match self
+                    .user_by_client_certificate(&certificate.fingerprint)
+                    .await
+                {
+                    Ok(user) => user,
+                    Err(AppError::UserNotFound) => {
+                        let Some(common_name) = certificate
+                            .common_name
+                            .as_ref()
+                            .filter(|_| match_common_name)
+                        else {
+                            return Err(AppError::Unauthorized);
+                        };
+
+                        match self.user_by_name(common_name).await {
+                            Ok(user) => user,
+                            Err(AppError::UserNotFound) => return Err(AppError::Unauthorized),
+                            Err(err) => return Err(err),
+                        }
+                    }
+                    Err(err) => return Err(err),
+                };
+
                 user.authenticate(&auth).await
             }
         }
@@ -341,6 +561,19 @@ impl App {
             cache_storage: user,
         })
     }
+    pub async fn user_by_client_certificate(&self, fingerprint: &str) -> Result<User, AppError> {
+        let (user_id, user) = self
+            .inner
+            .storage
+            .get_user_by_client_certificate(fingerprint)
+            .await?;
+
+        Ok(User {
+            app: self.new_ref(),
+            id: user_id,
+            cache_storage: user,
+        })
+    }
     pub async fn user_by_session(
         &self,
         session: SessionToken,
@@ -360,8 +593,13 @@ impl App {
         })
     }
 
-    pub async fn all_users(&self, _: Admin) -> Result<Vec<User>, AppError> {
-        let users = self.inner.storage.list_users().await?;
+    /// Returns the page of users and the amount of users matching the query
+    pub async fn users(
+        &self,
+        _: Admin,
+        query: StorageQueryUsers,
+    ) -> Result<(Vec<User>, usize), AppError> {
+        let (users, total) = self.inner.storage.list_users(query).await?;
 
         let users = match users {
             Either::Left(user_ids) => user_ids
@@ -382,10 +620,271 @@ impl App {
                 .collect::<Vec<_>>(),
         };
 
-        Ok(users)
+        Ok((users, total))
     }
 
     pub async fn delete_session(&self, session: SessionToken) -> Result<(), AppError> {
         self.inner.storage.remove_session_token(session).await
     }
+
+    /// Records an ended stream and removes the entries over the configured limits
+    pub async fn record_stream_history(
+        &self,
+        user_id: UserId,
+        host_id: HostId,
+        app_title: String,
+        session_id: String,
+        started_at: SystemTime,
+        end_reason: &str,
+    ) -> Result<(), AppError> {
+        let host_name = match self.inner.storage.get_host(host_id).await {
+            Ok(host) => host.cache.name,
+            // e.g. the loopback host or the host was deleted while streaming
+            Err(AppError::HostNotFound) => "Unknown".to_string(),
+            Err(err) => return Err(err),
+        };
+
+        let ended_at = SystemTime::now();
+
+        self.inner
+            .storage
+            .add_stream_history(StorageStreamHistoryEntry {
+                user_id,
+                host_id,
+                host_name,
+                app_title,
+                session_id: Some(session_id),
+                started_at: unix_secs(started_at),
+                ended_at: unix_secs(ended_at),
+                end_reason: end_reason.to_string(),
+            })
+            .await?;
+
+        let config = &self.inner.config.stream_history;
+        let ended_after = config
+            .max_age()
+            .map(|max_age| unix_secs(ended_at.checked_sub(max_age).unwrap_or(UNIX_EPOCH)));
+
+        self.inner
+            .storage
+            .prune_stream_history(config.max_entries, ended_after)
+            .await
+    }
+
+    /// Admins see the streams of all users
+    pub async fn stream_history(
+        &self,
+        user: &mut AuthenticatedUser,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<StorageStreamHistoryEntry>, usize), AppError> {
+        let user_id = match user.role().await? {
+            Role::Admin => None,
+            Role::User => Some(user.id()),
+        };
+
+        self.inner
+            .storage
+            .list_stream_history(StorageQueryStreamHistory {
+                user_id,
+                offset,
+                limit,
+            })
+            .await
+    }
+
+    pub async fn record_failed_connection(
+        &self,
+        user_id: UserId,
+        host_id: HostId,
+        app_title: String,
+        entries: Vec<ConnectionLogEntry>,
+    ) {
+        self.inner
+            .failed_connection_logs
+            .push(FailedConnectionLog {
+                user_id: user_id.0,
+                host_id: host_id.0,
+                app_title,
+                failed_at: unix_secs(SystemTime::now()),
+                entries,
+            })
+            .await;
+    }
+
+    /// Admins see the failed connections of all users, newest first
+    pub async fn failed_connection_logs(
+        &self,
+        user: &mut AuthenticatedUser,
+    ) -> Result<Vec<FailedConnectionLog>, AppError> {
+        let user_id = match user.role().await? {
+            Role::Admin => None,
+            Role::User => Some(user.id().0),
+        };
+
+        Ok(self.inner.failed_connection_logs.list(user_id).await)
+    }
+
+    pub async fn record_stats_timeline(
+        &self,
+        session_id: String,
+        user_id: UserId,
+        samples: Vec<StatsSample>,
+    ) {
+        self.inner
+            .stats_timelines
+            .push(StatsTimeline {
+                session_id,
+                user_id,
+                ended_at: Instant::now(),
+                samples,
+            })
+            .await;
+    }
+
+    /// Users only see the timelines of their own streams, admins see all of them
+    pub async fn stats_timeline(
+        &self,
+        user: &mut AuthenticatedUser,
+        session_id: &str,
+    ) -> Result<Vec<StatsSample>, AppError> {
+        let (user_id, samples) = self
+            .inner
+            .stats_timelines
+            .get(session_id, Instant::now())
+            .await
+            .ok_or(AppError::StatsTimelineNotFound)?;
+
+        if user_id != user.id() && !matches!(user.role().await?, Role::Admin) {
+            // Don't reveal that the timeline exists
+            return Err(AppError::StatsTimelineNotFound);
+        }
+
+        Ok(samples)
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use actix_web::{
+        ResponseError,
+        body::MessageBody,
+        http::{StatusCode, header::RETRY_AFTER},
+    };
+    use common::api_bindings::ApiErrorResponse;
+    use hex::FromHexError;
+    use moonlight_common::{network::ApiError, pair::PairError};
+    use openssl::error::ErrorStack;
+
+    use crate::app::AppError;
+
+    #[test]
+    fn test_error_codes() {
+        let errors = [
+            (AppError::AppDestroyed, "app_destroyed"),
+            (AppError::UserNotFound, "user_not_found"),
+            (
+                AppError::FirstUserAlreadyExists,
+                "first_user_already_exists",
+            ),
+            (
+                AppError::FirstLoginCreateAdminNotSet,
+                "first_login_create_admin_not_set",
+            ),
+            (AppError::UserAlreadyExists, "user_already_exists"),
+            (AppError::HostNotFound, "host_not_found"),
+            (AppError::HostPaired, "host_paired"),
+            (AppError::HostNotPaired, "host_not_paired"),
+            (AppError::HostOffline, "host_offline"),
+            (AppError::HostHttpsUnreachable(47984), "host_https_unreachable"),
+            (AppError::HybridSessionNotFound, "hybrid_session_not_found"),
+            (AppError::AppNotFound, "app_not_found"),
+            (AppError::StatsTimelineNotFound, "stats_timeline_not_found"),
+            (
+                AppError::ClientCertificateInUse,
+                "client_certificate_in_use",
+            ),
+            (AppError::CredentialsWrong, "credentials_wrong"),
+            (AppError::SessionTokenNotFound, "session_token_not_found"),
+            (AppError::Unauthorized, "unauthorized"),
+            (AppError::HeaderAuthDisabled, "header_auth_disabled"),
+            (AppError::Forbidden, "forbidden"),
+            (AppError::AuthorizationNotBearer, "authorization_not_bearer"),
+            (AppError::HeaderAuthMalformed, "header_auth_malformed"),
+            (AppError::BearerMalformed, "bearer_malformed"),
+            (AppError::PasswordEmpty, "password_empty"),
+            (AppError::NameEmpty, "name_empty"),
+            (AppError::BadRequest, "bad_request"),
+            (
+                AppError::TooManyLoginAttempts(30),
+                "too_many_login_attempts",
+            ),
+            (AppError::OpenSSL(ErrorStack::get()), "openssl"),
+            (AppError::Hex(FromHexError::OddLength), "hex"),
+            (AppError::Io(io::Error::other("test")), "io"),
+            (
+                AppError::MoonlightApi(ApiError::XmlRootNotFound),
+                "moonlight_api",
+            ),
+            (AppError::Pairing(PairError::IncorrectPin), "pairing"),
+            (
+                AppError::FujiPairingFailed("test".to_string()),
+                "fuji_pairing_failed",
+            ),
+            (
+                AppError::WebRtc(webrtc::Error::ErrConnectionClosed),
+                "webrtc",
+            ),
+        ];
+
+        for (error, code) in errors {
+            assert_eq!(error.code(), code);
+        }
+    }
+
+    #[test]
+    fn test_error_response_body() {
+        let response = AppError::HostOffline.error_response();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        let body = response
+            .into_body()
+            .try_into_bytes()
+            .expect("failed to get body bytes");
+        let body = serde_json::from_slice::<ApiErrorResponse>(&body)
+            .expect("failed to deserialize error response");
+
+        assert_eq!(body.code, "host_offline");
+        assert_eq!(body.message, AppError::HostOffline.to_string());
+    }
+
+    #[test]
+    fn test_error_response_retry_after() {
+        let response = AppError::TooManyLoginAttempts(30).error_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok()),
+            Some("30")
+        );
+
+        let body = response
+            .into_body()
+            .try_into_bytes()
+            .expect("failed to get body bytes");
+        let body = serde_json::from_slice::<ApiErrorResponse>(&body)
+            .expect("failed to deserialize error response");
+
+        assert_eq!(body.code, "too_many_login_attempts");
+    }
 }
@@ -9,16 +9,19 @@ use std::{
     time::{Duration, Instant},
 };
 
-use common::api_bindings::StreamSignalingMessage;
+use actix_ws::{MessageStream, Session};
+use common::{
+    api_bindings::{HybridSessionStatus, StreamSignalingMessage},
+    config::SessionLimitsConfig,
+};
 use log::{debug, info, warn};
 use tokio::{
     spawn,
     sync::{Mutex, mpsc::{Receiver, Sender, channel}},
-    time::interval,
+    time::{interval, timeout},
 };
 
-/// Duration after which a session token expires if input connection doesn't join
-pub const TOKEN_EXPIRATION_SECS: u64 = 30;
+use crate::app::user::UserId;
 
 /// Interval for cleaning up expired sessions
 pub const CLEANUP_INTERVAL_SECS: u64 = 10;
@@ -32,17 +35,26 @@ pub type SessionId = String;
 /// Token used by the input connection to join a session
 pub type SessionToken = String;
 
+/// Identifies the input connection of a session, every join gets a new one
+pub type InputId = u32;
+
 /// Represents a hybrid streaming session
 #[derive(Debug)]
 pub struct HybridSession {
     /// Unique session identifier
     pub id: SessionId,
+    /// User who started the stream
+    pub owner: UserId,
     /// Token for input connection to join (consumed after use)
     pub token: Option<SessionToken>,
     /// When the token expires
     pub token_expires_at: Instant,
     /// Whether the input connection has joined
     pub input_connected: bool,
+    /// The input connection which joined last
+    pub input_id: InputId,
+    /// The token hands the session over from the connected input to a new one
+    pub input_handoff: bool,
     /// Channel to notify primary stream of events (e.g., input disconnected)
     pub primary_notify: Option<Sender<SessionEvent>>,
     /// Channel to notify input connection of events (e.g., primary disconnected)
@@ -53,6 +65,28 @@ pub struct HybridSession {
     pub streamer_to_input_tx: Option<Sender<StreamerToInputMessage>>,
     /// Created timestamp for debugging
     pub created_at: Instant,
+    /// How often the input connection disconnected and got a new token
+    pub input_reconnections: u32,
+}
+
+impl HybridSession {
+    fn status(&self, now: Instant) -> HybridSessionStatus {
+        fn secs(duration: Duration) -> u32 {
+            duration.as_secs().try_into().unwrap_or(u32::MAX)
+        }
+
+        HybridSessionStatus {
+            session_id: self.id.clone(),
+            running_secs: secs(now.saturating_duration_since(self.created_at)),
+            input_connected: self.input_connected,
+            // The token is consumed once the input joined
+            token_expires_in_secs: self
+                .token
+                .as_ref()
+                .map(|_| secs(self.token_expires_at.saturating_duration_since(now))),
+            input_reconnections: self.input_reconnections,
+        }
+    }
 }
 
 /// Events that can be sent between primary and input connections
@@ -66,6 +100,8 @@ pub enum SessionEvent {
     InputJoined,
     /// A new reconnection token is available (sent to primary)
     ReconnectionTokenAvailable(SessionToken),
+    /// The input connection reconnected too often, the session was closed (sent to primary)
+    InputReconnectLimitReached,
 }
 
 /// Messages from the input connection to be forwarded to the streamer
@@ -75,6 +111,8 @@ pub enum InputToStreamerMessage {
     Joined,
     /// WebRTC signaling message from input client
     Signaling(StreamSignalingMessage),
+    /// The input client pushed a clipboard text
+    Clipboard(String),
     /// Input connection disconnected
     Disconnected,
 }
@@ -86,6 +124,10 @@ pub enum StreamerToInputMessage {
     Signaling(StreamSignalingMessage),
     /// Input peer connection is ready
     Ready,
+    /// ICE of the input peer connection failed
+    Failed,
+    /// Another input connection took over the session
+    HandedOff,
 }
 
 /// Errors that can occur during session operations
@@ -120,6 +162,114 @@ impl std::fmt::Display for SessionError {
 
 impl std::error::Error for SessionError {}
 
+/// A new primary WebSocket which takes over the stream of a lost one
+pub struct PrimaryReattach {
+    pub session: Session,
+    pub stream: MessageStream,
+}
+
+/// Registered by a running stream so its primary WebSocket can be replaced
+#[derive(Debug)]
+struct PrimaryReconnect {
+    owner: UserId,
+    reattach: Sender<PrimaryReattach>,
+}
+
+/// Whether the input connection is gone or closes within the grace period
+async fn input_closed(input: Option<Sender<StreamerToInputMessage>>, grace: Duration) -> bool {
+    let Some(input) = input else {
+        return true;
+    };
+
+    timeout(grace, input.closed()).await.is_ok()
+}
+
+/// Maximum number of spectators watching a single stream
+pub const MAX_SPECTATORS_PER_STREAM: usize = 8;
+
+/// A WebSocket which wants to watch a running stream
+pub struct SpectatorJoin {
+    pub session: Session,
+    pub stream: MessageStream,
+}
+
+/// Reason sent to the client when a session reached the configured maximum duration
+pub const MAX_DURATION_REASON: &str = "max_duration";
+
+/// Events produced by a [SessionDeadline]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadlineEvent {
+    /// The session will end soon
+    Warning { remaining: Duration },
+    /// The session reached its maximum duration and must be ended
+    Expired,
+}
+
+/// Tracks when a stream session started and when it has to be warned / ended
+#[derive(Debug)]
+pub struct SessionDeadline {
+    started_at: Instant,
+    max_duration: Duration,
+    warning: Duration,
+    warned: bool,
+    expired: bool,
+}
+
+impl SessionDeadline {
+    pub fn new(started_at: Instant, max_duration: Duration, warning: Duration) -> Self {
+        Self {
+            started_at,
+            max_duration,
+            warning,
+            warned: false,
+            expired: false,
+        }
+    }
+
+    fn warning_at(&self) -> Instant {
+        self.started_at + self.max_duration.saturating_sub(self.warning)
+    }
+    fn expires_at(&self) -> Instant {
+        self.started_at + self.max_duration
+    }
+
+    /// The instant at which the next event is due, None if the deadline already expired
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if self.expired {
+            None
+        } else if self.warned {
+            Some(self.expires_at())
+        } else {
+            Some(self.warning_at())
+        }
+    }
+
+    /// Returns the event that is due at `now`, every event is only returned once
+    pub fn poll(&mut self, now: Instant) -> Option<DeadlineEvent> {
+        if self.expired {
+            return None;
+        }
+
+        let expires_at = self.expires_at();
+        if now >= expires_at {
+            self.warned = true;
+            self.expired = true;
+
+            return Some(DeadlineEvent::Expired);
+        }
+
+        if !self.warned && now >= self.warning_at() {
+            self.warned = true;
+
+            return Some(DeadlineEvent::Warning {
+                remaining: expires_at - now,
+            });
+        }
+
+        None
+    }
+}
+
 /// Manager for hybrid streaming sessions
 #[derive(Debug)]
 pub struct SessionManager {
@@ -127,17 +277,25 @@ pub struct SessionManager {
     sessions: Arc<Mutex<HashMap<SessionId, HybridSession>>>,
     /// Index from token to session ID for quick lookup
     token_index: Arc<Mutex<HashMap<SessionToken, SessionId>>>,
+    /// Running streams whose primary WebSocket can be replaced, keyed by reconnection token
+    primary_reconnects: Mutex<HashMap<SessionToken, PrimaryReconnect>>,
+    /// Running streams which allow spectators, keyed by spectator token
+    spectators: Mutex<HashMap<SessionToken, Sender<SpectatorJoin>>>,
+    /// Duration after which a session token expires if input connection doesn't join
+    token_expiration: Duration,
+    max_input_reconnections: Option<u32>,
+    duplicate_input_grace: Duration,
 }
 
 impl Default for SessionManager {
     fn default() -> Self {
-        Self::new()
+        Self::new(&SessionLimitsConfig::default())
     }
 }
 
 impl SessionManager {
     /// Create a new session manager and start the cleanup task
-    pub fn new() -> Self {
+    pub fn new(config: &SessionLimitsConfig) -> Self {
         let sessions = Arc::new(Mutex::new(HashMap::new()));
         let token_index = Arc::new(Mutex::new(HashMap::new()));
 
@@ -155,6 +313,11 @@ impl SessionManager {
         Self {
             sessions,
             token_index,
+            primary_reconnects: Default::default(),
+            spectators: Default::default(),
+            token_expiration: config.input_token_expiration(),
+            max_input_reconnections: config.max_input_reconnections,
+            duplicate_input_grace: config.duplicate_input_grace(),
         }
     }
 
@@ -181,21 +344,26 @@ impl SessionManager {
         }
 
         if !expired_sessions.is_empty() {
-            let mut sessions_lock = sessions.lock().await;
-            let mut token_index_lock = token_index.lock().await;
-
-            for id in &expired_sessions {
-                if let Some(session) = sessions_lock.remove(id) {
-                    // Notify primary that the token expired (input never joined)
-                    if let Some(ref notify) = session.primary_notify {
-                        let _ = notify.send(SessionEvent::InputDisconnected).await;
+            let mut notifies = Vec::new();
+            {
+                let mut sessions_lock = sessions.lock().await;
+                let mut token_index_lock = token_index.lock().await;
+
+                for id in &expired_sessions {
+                    if let Some(session) = sessions_lock.remove(id) {
+                        notifies.extend(session.primary_notify);
                     }
+                    debug!("[SessionManager] Cleaned up expired session {}", id);
+                }
+
+                for token in &expired_tokens {
+                    token_index_lock.remove(token);
                 }
-                debug!("[SessionManager] Cleaned up expired session {}", id);
             }
 
-            for token in &expired_tokens {
-                token_index_lock.remove(token);
+            // Notify primary that the token expired (input never joined)
+            for notify in notifies {
+                let _ = notify.send(SessionEvent::InputDisconnected).await;
             }
 
             info!(
@@ -210,24 +378,29 @@ impl SessionManager {
     pub async fn register_session(
         &self,
         token: SessionToken,
+        owner: UserId,
     ) -> (SessionId, SessionToken, Receiver<InputToStreamerMessage>) {
         let session_id = uuid::Uuid::new_v4().to_string();
         let now = Instant::now();
-        let expires_at = now + Duration::from_secs(TOKEN_EXPIRATION_SECS);
+        let expires_at = now + self.token_expiration;
 
         // Create channel for input -> streamer messages
         let (input_to_streamer_tx, input_to_streamer_rx) = channel(32);
 
         let session = HybridSession {
             id: session_id.clone(),
+            owner,
             token: Some(token.clone()),
             token_expires_at: expires_at,
             input_connected: false,
+            input_id: 0,
+            input_handoff: false,
             primary_notify: None,
             input_notify: None,
             input_to_streamer_tx: Some(input_to_streamer_tx),
             streamer_to_input_tx: None,
             created_at: now,
+            input_reconnections: 0,
         };
 
         {
@@ -241,19 +414,27 @@ impl SessionManager {
         }
 
         info!(
-            "[SessionManager] Registered session {} with token {} (expires in {}s)",
-            session_id, token, TOKEN_EXPIRATION_SECS
+            "[SessionManager] Registered session {} with token {} (expires in {:?})",
+            session_id, token, self.token_expiration
         );
 
         (session_id, token, input_to_streamer_rx)
     }
 
     /// Attempt to claim a session using the provided token
-    /// Returns the session ID and channels for communication if successful
+    /// Returns the session ID, the id of this input connection and channels for communication if successful
     pub async fn claim_session(
         &self,
         token: &str,
-    ) -> Result<(SessionId, Sender<InputToStreamerMessage>, Receiver<StreamerToInputMessage>), SessionError> {
+    ) -> Result<
+        (
+            SessionId,
+            InputId,
+            Sender<InputToStreamerMessage>,
+            Receiver<StreamerToInputMessage>,
+        ),
+        SessionError,
+    > {
         let session_id = {
             let token_index = self.token_index.lock().await;
             token_index.get(token).cloned()
@@ -283,13 +464,44 @@ impl SessionManager {
             return Err(SessionError::TokenExpired);
         }
 
-        // Check if input is already connected
-        if session.input_connected {
+        // Another claim could have consumed the token before it was removed from the index
+        if session.token.as_deref() != Some(token) {
             warn!(
-                "[SessionManager] Input already connected for session {}",
-                session_id
+                "[SessionManager] Token already used for session {}: {}",
+                session_id, token
             );
-            return Err(SessionError::InputAlreadyConnected);
+            return Err(SessionError::TokenInvalid);
+        }
+
+        // Check if input is already connected
+        if session.input_connected && !session.input_handoff {
+            let input_id = session.input_id;
+            let current_input = session.streamer_to_input_tx.clone();
+            drop(sessions);
+
+            if !input_closed(current_input, self.duplicate_input_grace).await {
+                warn!(
+                    "[SessionManager] Input already connected for session {}",
+                    session_id
+                );
+                return Err(SessionError::InputAlreadyConnected);
+            }
+
+            // The current input is gone without disconnecting, it's replaced like in a handoff
+            info!(
+                "[SessionManager] Input {} of session {} is gone, reclaiming the session",
+                input_id, session_id
+            );
+            let mut sessions = self.sessions.lock().await;
+            if let Some(session) = sessions.get_mut(&session_id)
+                && session.input_connected
+                && session.input_id == input_id
+            {
+                session.input_handoff = true;
+            }
+            drop(sessions);
+
+            return Box::pin(self.claim_session(token)).await;
         }
 
         // Get or create the input_to_streamer sender
@@ -298,6 +510,14 @@ impl SessionManager {
             .clone()
             .ok_or(SessionError::SessionNotFound)?;
 
+        // The session switches to the new input while holding the lock so only one input is ever active,
+        // the current input is told to leave once the lock is released
+        let handed_off_input = if session.input_connected {
+            Some(session.streamer_to_input_tx.take())
+        } else {
+            None
+        };
+
         // Create channel for streamer -> input messages
         let (streamer_to_input_tx, streamer_to_input_rx) = channel(32);
         session.streamer_to_input_tx = Some(streamer_to_input_tx);
@@ -305,22 +525,39 @@ impl SessionManager {
         // Consume the token
         session.token = None;
         session.input_connected = true;
+        session.input_handoff = false;
+        session.input_id = session.input_id.wrapping_add(1);
+        let input_id = session.input_id;
+
+        let primary_notify = session.primary_notify.clone();
 
         // Remove from token index (need to drop sessions lock first)
         let session_id_clone = session_id.clone();
         drop(sessions);
-        
+
+        // The current input leaves and its peer is closed before the new one gets its channels
+        if let Some(handed_off_input) = handed_off_input {
+            if let Some(tx) = handed_off_input {
+                let _ = tx.send(StreamerToInputMessage::HandedOff).await;
+            }
+            let _ = input_to_streamer_tx
+                .send(InputToStreamerMessage::Disconnected)
+                .await;
+
+            info!(
+                "[SessionManager] Handing session {} over to a new input connection",
+                session_id_clone
+            );
+        }
+
         {
             let mut token_index = self.token_index.lock().await;
             token_index.remove(token);
         }
 
         // Notify primary that input joined
-        let sessions = self.sessions.lock().await;
-        if let Some(session) = sessions.get(&session_id_clone) {
-            if let Some(ref notify) = session.primary_notify {
-                let _ = notify.send(SessionEvent::InputJoined).await;
-            }
+        if let Some(notify) = primary_notify {
+            let _ = notify.send(SessionEvent::InputJoined).await;
         }
 
         info!(
@@ -328,18 +565,57 @@ impl SessionManager {
             session_id_clone
         );
 
-        Ok((session_id_clone, input_to_streamer_tx, streamer_to_input_rx))
+        Ok((
+            session_id_clone,
+            input_id,
+            input_to_streamer_tx,
+            streamer_to_input_rx,
+        ))
+    }
+
+    /// Issue a token which lets a new input connection take over the session.
+    /// The current input connection stays active until the token is claimed.
+    /// Returns the token and how long it can be used
+    pub async fn start_input_handoff(&self, session_id: &str) -> Option<(SessionToken, Duration)> {
+        let mut sessions = self.sessions.lock().await;
+        let session = sessions.get_mut(session_id)?;
+
+        let token = uuid::Uuid::new_v4().to_string();
+        let old_token = session.token.replace(token.clone());
+        session.token_expires_at = Instant::now() + self.token_expiration;
+        // Without a connected input this is an ordinary join
+        session.input_handoff = session.input_connected;
+        drop(sessions);
+
+        {
+            let mut token_index = self.token_index.lock().await;
+            if let Some(ref old_token) = old_token {
+                token_index.remove(old_token);
+            }
+            token_index.insert(token.clone(), session_id.to_string());
+        }
+
+        info!(
+            "[SessionManager] Generated input handoff token for session {}: {}",
+            session_id, token
+        );
+
+        Some((token, self.token_expiration))
     }
 
     /// Send a message from streamer to the input connection
     pub async fn send_to_input(&self, session_id: &str, message: StreamerToInputMessage) -> bool {
-        let sessions = self.sessions.lock().await;
-        if let Some(session) = sessions.get(session_id) {
-            if let Some(ref tx) = session.streamer_to_input_tx {
-                return tx.send(message).await.is_ok();
-            }
+        let tx = {
+            let sessions = self.sessions.lock().await;
+            sessions
+                .get(session_id)
+                .and_then(|session| session.streamer_to_input_tx.clone())
+        };
+
+        match tx {
+            Some(tx) => tx.send(message).await.is_ok(),
+            None => false,
         }
-        false
     }
 
     /// Set the notification channel for the primary stream
@@ -394,31 +670,75 @@ impl SessionManager {
 
     /// Called when the input connection disconnects
     /// Returns a new token if reconnection is allowed
-    pub async fn input_disconnected(&self, session_id: &str) -> Option<SessionToken> {
+    pub async fn input_disconnected(
+        &self,
+        session_id: &str,
+        input_id: InputId,
+    ) -> Option<SessionToken> {
         let mut sessions = self.sessions.lock().await;
         let session = sessions.get_mut(session_id)?;
 
+        if !session.input_connected || session.input_id != input_id {
+            debug!(
+                "[SessionManager] Input {} of session {} was already handed over",
+                input_id, session_id
+            );
+            return None;
+        }
+
         session.input_connected = false;
+        session.input_handoff = false;
         session.input_notify = None;
         session.streamer_to_input_tx = None;
 
+        // The streamer is notified once the lock is released so it closes the input peer
+        let input_to_streamer_tx = session.input_to_streamer_tx.clone();
+
         info!(
             "[SessionManager] Input disconnected from session {}",
             session_id
         );
 
-        // Generate a new token to allow reconnection
+        session.input_reconnections += 1;
+        if let Some(max_input_reconnections) = self.max_input_reconnections
+            && session.input_reconnections > max_input_reconnections
+        {
+            warn!(
+                "[SessionManager] Input of session {session_id} reconnected more than {max_input_reconnections} times, closing the session"
+            );
+
+            let session = sessions.remove(session_id)?;
+            drop(sessions);
+
+            if let Some(tx) = input_to_streamer_tx {
+                let _ = tx.send(InputToStreamerMessage::Disconnected).await;
+            }
+            if let Some(ref token) = session.token {
+                let mut token_index = self.token_index.lock().await;
+                token_index.remove(token);
+            }
+            if let Some(ref notify) = session.primary_notify {
+                let _ = notify.send(SessionEvent::InputReconnectLimitReached).await;
+            }
+
+            return None;
+        }
+
+        // Generate a new token to allow reconnection, this replaces an unused handoff token
         let new_token = uuid::Uuid::new_v4().to_string();
         let now = Instant::now();
-        session.token = Some(new_token.clone());
-        session.token_expires_at = now + Duration::from_secs(TOKEN_EXPIRATION_SECS);
+        let old_token = session.token.replace(new_token.clone());
+        session.token_expires_at = now + self.token_expiration;
+
+        let primary_notify = session.primary_notify.clone();
+        drop(sessions);
 
-        // Create new channel for input -> streamer messages
-        let (input_to_streamer_tx, _input_to_streamer_rx) = channel(32);
-        session.input_to_streamer_tx = Some(input_to_streamer_tx);
+        if let Some(tx) = input_to_streamer_tx {
+            let _ = tx.send(InputToStreamerMessage::Disconnected).await;
+        }
 
         // Notify primary stream of disconnection and new token
-        if let Some(ref notify) = session.primary_notify {
+        if let Some(notify) = primary_notify {
             let _ = notify.send(SessionEvent::InputDisconnected).await;
             let _ = notify
                 .send(SessionEvent::ReconnectionTokenAvailable(new_token.clone()))
@@ -426,9 +746,11 @@ impl SessionManager {
         }
 
         // Update token index
-        drop(sessions);
         {
             let mut token_index = self.token_index.lock().await;
+            if let Some(ref old_token) = old_token {
+                token_index.remove(old_token);
+            }
             token_index.insert(new_token.clone(), session_id.to_string());
         }
 
@@ -530,9 +852,435 @@ impl SessionManager {
         }
     }
 
+    /// Register a running stream so a new primary WebSocket of the same user can take it over.
+    /// Returns the reconnection token and the receiver for new WebSockets.
+    pub async fn register_primary_reconnect(
+        &self,
+        owner: UserId,
+    ) -> (SessionToken, Receiver<PrimaryReattach>) {
+        let token = uuid::Uuid::new_v4().to_string();
+        let (reattach, reattach_rx) = channel(1);
+
+        let mut primary_reconnects = self.primary_reconnects.lock().await;
+        primary_reconnects.insert(token.clone(), PrimaryReconnect { owner, reattach });
+
+        debug!("[SessionManager] Registered primary reconnection token {token}");
+
+        (token, reattach_rx)
+    }
+
+    /// Hands the WebSocket to the stream registered with this token
+    pub async fn reattach_primary(
+        &self,
+        token: &str,
+        user: UserId,
+        reattach: PrimaryReattach,
+    ) -> Result<(), SessionError> {
+        let primary_reconnects = self.primary_reconnects.lock().await;
+
+        let Some(primary_reconnect) = primary_reconnects.get(token) else {
+            warn!("[SessionManager] Primary reconnection token not found: {token}");
+            return Err(SessionError::SessionNotFound);
+        };
+        if primary_reconnect.owner != user {
+            warn!("[SessionManager] Primary reconnection token {token} used by another user");
+            return Err(SessionError::TokenInvalid);
+        }
+
+        primary_reconnect
+            .reattach
+            .try_send(reattach)
+            .map_err(|_| SessionError::SessionShuttingDown)?;
+
+        info!("[SessionManager] Primary reattached using token {token}");
+
+        Ok(())
+    }
+
+    /// Called when the stream ended, the token can no longer be used
+    pub async fn remove_primary_reconnect(&self, token: &str) {
+        let mut primary_reconnects = self.primary_reconnects.lock().await;
+        primary_reconnects.remove(token);
+    }
+
+    /// Register a running stream so other WebSockets can watch it.
+    /// Returns the spectator token and the receiver for joining WebSockets.
+    pub async fn register_spectators(&self) -> (SessionToken, Receiver<SpectatorJoin>) {
+        let token = uuid::Uuid::new_v4().to_string();
+        let (join, join_rx) = channel(MAX_SPECTATORS_PER_STREAM);
+
+        let mut spectators = self.spectators.lock().await;
+        spectators.insert(token.clone(), join);
+
+        debug!("[SessionManager] Registered spectator token {token}");
+
+        (token, join_rx)
+    }
+
+    /// Hands the WebSocket to the stream registered with this spectator token
+    pub async fn join_spectator(
+        &self,
+        token: &str,
+        join: SpectatorJoin,
+    ) -> Result<(), SessionError> {
+        let spectators = self.spectators.lock().await;
+
+        let Some(spectator_join) = spectators.get(token) else {
+            warn!("[SessionManager] Spectator token not found: {token}");
+            return Err(SessionError::SessionNotFound);
+        };
+
+        spectator_join
+            .try_send(join)
+            .map_err(|_| SessionError::SessionShuttingDown)?;
+
+        info!("[SessionManager] Spectator joined using token {token}");
+
+        Ok(())
+    }
+
+    /// Called when the stream ended, no more spectators can join
+    pub async fn remove_spectators(&self, token: &str) {
+        let mut spectators = self.spectators.lock().await;
+        spectators.remove(token);
+    }
+
     /// Get the count of active sessions (for debugging/monitoring)
     pub async fn session_count(&self) -> usize {
         let sessions = self.sessions.lock().await;
         sessions.len()
     }
+
+    pub async fn session_statuses(&self, now: Instant) -> Vec<HybridSessionStatus> {
+        let sessions = self.sessions.lock().await;
+
+        let mut statuses = sessions
+            .values()
+            .map(|session| session.status(now))
+            .collect::<Vec<_>>();
+        statuses.sort_by_key(|status| std::cmp::Reverse(status.running_secs));
+
+        statuses
+    }
+
+    /// Returns the owner and status of the session
+    pub async fn session_status(
+        &self,
+        session_id: &str,
+        now: Instant,
+    ) -> Option<(UserId, HybridSessionStatus)> {
+        let sessions = self.sessions.lock().await;
+
+        sessions
+            .get(session_id)
+            .map(|session| (session.owner, session.status(now)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use actix_web::{http::header, test::TestRequest};
+    use common::config::SessionLimitsConfig;
+    use tokio::sync::mpsc::channel;
+
+    use crate::app::{
+        session::{
+            DeadlineEvent, InputToStreamerMessage, MAX_SPECTATORS_PER_STREAM, PrimaryReattach,
+            SessionDeadline, SessionError, SessionEvent, SessionManager, SpectatorJoin,
+            StreamerToInputMessage,
+        },
+        user::UserId,
+    };
+
+    #[test]
+    fn test_deadline_warns_then_expires() {
+        let start = Instant::now();
+        let mut deadline =
+            SessionDeadline::new(start, Duration::from_secs(600), Duration::from_secs(60));
+
+        assert_eq!(deadline.next_deadline(), Some(start + Duration::from_secs(540)));
+        assert_eq!(deadline.poll(start + Duration::from_secs(300)), None);
+
+        assert_eq!(
+            deadline.poll(start + Duration::from_secs(540)),
+            Some(DeadlineEvent::Warning {
+                remaining: Duration::from_secs(60)
+            })
+        );
+        assert_eq!(deadline.poll(start + Duration::from_secs(550)), None);
+        assert_eq!(deadline.next_deadline(), Some(start + Duration::from_secs(600)));
+
+        assert_eq!(
+            deadline.poll(start + Duration::from_secs(600)),
+            Some(DeadlineEvent::Expired)
+        );
+        assert_eq!(deadline.poll(start + Duration::from_secs(700)), None);
+        assert_eq!(deadline.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_deadline_expires_without_warning_when_late() {
+        let start = Instant::now();
+        let mut deadline =
+            SessionDeadline::new(start, Duration::from_secs(30), Duration::from_secs(60));
+
+        assert_eq!(deadline.next_deadline(), Some(start));
+        assert_eq!(
+            deadline.poll(start + Duration::from_secs(31)),
+            Some(DeadlineEvent::Expired)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_input_reconnect_limit() {
+        let manager = SessionManager::new(&SessionLimitsConfig {
+            max_input_reconnections: Some(1),
+            ..Default::default()
+        });
+
+        let (session_id, token, _input_rx) = manager
+            .register_session("token".to_string(), UserId(0))
+            .await;
+        let (primary_tx, mut primary_rx) = channel(32);
+        manager.set_primary_notify(&session_id, primary_tx).await;
+
+        let (_, input_id, _, _) = manager.claim_session(&token).await.unwrap();
+        let token = manager
+            .input_disconnected(&session_id, input_id)
+            .await
+            .expect("the first reconnection is allowed");
+        assert_eq!(
+            manager.session_statuses(Instant::now()).await[0].input_reconnections,
+            1
+        );
+
+        let (_, input_id, _, _) = manager.claim_session(&token).await.unwrap();
+        assert!(
+            manager
+                .input_disconnected(&session_id, input_id)
+                .await
+                .is_none()
+        );
+        assert!(!manager.is_session_active(&session_id).await);
+
+        let mut last_event = None;
+        while let Ok(event) = primary_rx.try_recv() {
+            last_event = Some(event);
+        }
+        assert!(matches!(
+            last_event,
+            Some(SessionEvent::InputReconnectLimitReached)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_input_handoff() {
+        let manager = SessionManager::default();
+
+        let (session_id, token, mut input_rx) = manager
+            .register_session("token".to_string(), UserId(0))
+            .await;
+        let (_, old_input_id, _, mut old_streamer_rx) =
+            manager.claim_session(&token).await.unwrap();
+
+        let (handoff_token, _) = manager.start_input_handoff(&session_id).await.unwrap();
+        assert!(manager.is_input_connected(&session_id).await);
+
+        let (_, new_input_id, _, _) = manager.claim_session(&handoff_token).await.unwrap();
+        assert_ne!(old_input_id, new_input_id);
+        assert!(matches!(
+            old_streamer_rx.try_recv(),
+            Ok(StreamerToInputMessage::HandedOff)
+        ));
+        assert!(matches!(
+            input_rx.try_recv(),
+            Ok(InputToStreamerMessage::Disconnected)
+        ));
+
+        // The old input leaving doesn't affect the new one
+        assert!(
+            manager
+                .input_disconnected(&session_id, old_input_id)
+                .await
+                .is_none()
+        );
+        assert!(manager.is_input_connected(&session_id).await);
+        assert!(input_rx.try_recv().is_err());
+
+        // Both tokens are used up
+        assert!(manager.claim_session(&handoff_token).await.is_err());
+        assert!(manager.claim_session(&token).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_input() {
+        let manager = SessionManager::new(&SessionLimitsConfig {
+            duplicate_input_grace_secs: 0,
+            ..Default::default()
+        });
+
+        let (session_id, token, _input_rx) = manager
+            .register_session("token".to_string(), UserId(0))
+            .await;
+        let (_, old_input_id, _, old_streamer_rx) = manager.claim_session(&token).await.unwrap();
+
+        // A token while the input is connected which isn't a handoff
+        let (duplicate_token, _) = manager.start_input_handoff(&session_id).await.unwrap();
+        manager
+            .sessions
+            .lock()
+            .await
+            .get_mut(&session_id)
+            .unwrap()
+            .input_handoff = false;
+
+        assert!(manager.claim_session(&duplicate_token).await.is_err());
+
+        // The current input is gone without disconnecting
+        drop(old_streamer_rx);
+        let (_, new_input_id, _, _) = manager.claim_session(&duplicate_token).await.unwrap();
+        assert_ne!(old_input_id, new_input_id);
+        assert!(manager.is_input_connected(&session_id).await);
+    }
+
+    #[tokio::test]
+    async fn test_session_status() {
+        let manager = SessionManager::new(&SessionLimitsConfig {
+            input_token_expiration_secs: 30,
+            ..Default::default()
+        });
+
+        let (session_id, token, _input_rx) = manager
+            .register_session("token".to_string(), UserId(3))
+            .await;
+        assert!(manager.session_status("unknown", Instant::now()).await.is_none());
+
+        let (owner, status) = manager
+            .session_status(&session_id, Instant::now())
+            .await
+            .unwrap();
+        assert_eq!(owner, UserId(3));
+        assert_eq!(status.session_id, session_id);
+        assert!(!status.input_connected);
+        assert!(status.token_expires_in_secs.is_some_and(|secs| secs <= 30));
+
+        assert!(manager.claim_session(&token).await.is_ok());
+        let (_, status) = manager
+            .session_status(&session_id, Instant::now())
+            .await
+            .unwrap();
+        assert!(status.input_connected);
+        assert_eq!(status.token_expires_in_secs, None);
+    }
+
+    fn primary_reattach() -> PrimaryReattach {
+        let (request, payload) = TestRequest::default()
+            .insert_header((header::UPGRADE, "websocket"))
+            .insert_header((header::CONNECTION, "Upgrade"))
+            .insert_header((header::SEC_WEBSOCKET_VERSION, "13"))
+            .insert_header((header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="))
+            .to_http_parts();
+        let (_, session, stream) = actix_ws::handle(&request, payload).unwrap();
+
+        PrimaryReattach { session, stream }
+    }
+
+    fn spectator_join() -> SpectatorJoin {
+        let PrimaryReattach { session, stream } = primary_reattach();
+
+        SpectatorJoin { session, stream }
+    }
+
+    #[actix_web::test]
+    async fn test_reattach_primary() {
+        let manager = SessionManager::default();
+
+        let (token, mut reattach_rx) = manager.register_primary_reconnect(UserId(1)).await;
+
+        assert!(matches!(
+            manager
+                .reattach_primary("unknown", UserId(1), primary_reattach())
+                .await,
+            Err(SessionError::SessionNotFound)
+        ));
+        assert!(matches!(
+            manager
+                .reattach_primary(&token, UserId(2), primary_reattach())
+                .await,
+            Err(SessionError::TokenInvalid)
+        ));
+        assert!(reattach_rx.try_recv().is_err());
+
+        assert!(
+            manager
+                .reattach_primary(&token, UserId(1), primary_reattach())
+                .await
+                .is_ok()
+        );
+        assert!(reattach_rx.try_recv().is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_reattach_primary_after_stream_ended() {
+        let manager = SessionManager::default();
+
+        let (token, reattach_rx) = manager.register_primary_reconnect(UserId(1)).await;
+
+        // The stream is gone but didn't remove its token yet
+        drop(reattach_rx);
+        assert!(matches!(
+            manager
+                .reattach_primary(&token, UserId(1), primary_reattach())
+                .await,
+            Err(SessionError::SessionShuttingDown)
+        ));
+
+        manager.remove_primary_reconnect(&token).await;
+        assert!(matches!(
+            manager
+                .reattach_primary(&token, UserId(1), primary_reattach())
+                .await,
+            Err(SessionError::SessionNotFound)
+        ));
+    }
+    #[actix_web::test]
+    async fn test_join_spectator() {
+        let manager = SessionManager::default();
+
+        assert!(matches!(
+            manager.join_spectator("unknown", spectator_join()).await,
+            Err(SessionError::SessionNotFound)
+        ));
+
+        let (token, mut join_rx) = manager.register_spectators().await;
+        assert!(
+            manager
+                .join_spectator(&token, spectator_join())
+                .await
+                .is_ok()
+        );
+        assert!(join_rx.try_recv().is_ok());
+
+        // The stream handles the joins one after another, too many waiting ones are refused
+        for _ in 0..MAX_SPECTATORS_PER_STREAM {
+            assert!(
+                manager
+                    .join_spectator(&token, spectator_join())
+                    .await
+                    .is_ok()
+            );
+        }
+        assert!(matches!(
+            manager.join_spectator(&token, spectator_join()).await,
+            Err(SessionError::SessionShuttingDown)
+        ));
+
+        manager.remove_spectators(&token).await;
+        assert!(matches!(
+            manager.join_spectator(&token, spectator_join()).await,
+            Err(SessionError::SessionNotFound)
+        ));
+    }
 }
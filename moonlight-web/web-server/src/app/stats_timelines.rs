@@ -0,0 +1,170 @@
+//! Stats timelines of ended streams, they're only kept in memory
+
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+use common::{api_bindings::StatsSample, config::StatsTimelineConfig};
+use tokio::sync::Mutex;
+
+use crate::app::user::UserId;
+
+pub struct StatsTimeline {
+    pub session_id: String,
+    pub user_id: UserId,
+    pub ended_at: Instant,
+    /// Oldest first
+    pub samples: Vec<StatsSample>,
+}
+
+pub struct StatsTimelines {
+    max_entries: usize,
+    max_age: Option<Duration>,
+    timelines: Mutex<VecDeque<StatsTimeline>>,
+}
+
+impl StatsTimelines {
+    pub fn new(config: &StatsTimelineConfig) -> Self {
+        Self {
+            max_entries: config.max_entries,
+            max_age: config.max_age(),
+            timelines: Default::default(),
+        }
+    }
+
+    pub async fn push(&self, timeline: StatsTimeline) {
+        let now = timeline.ended_at;
+
+        let mut timelines = self.timelines.lock().await;
+        timelines.push_back(timeline);
+        self.prune(&mut timelines, now);
+    }
+
+    /// The user who started the stream and its samples
+    pub async fn get(&self, session_id: &str, now: Instant) -> Option<(UserId, Vec<StatsSample>)> {
+        let mut timelines = self.timelines.lock().await;
+        self.prune(&mut timelines, now);
+
+        timelines
+            .iter()
+            .find(|timeline| timeline.session_id == session_id)
+            .map(|timeline| (timeline.user_id, timeline.samples.clone()))
+    }
+
+    fn prune(&self, timelines: &mut VecDeque<StatsTimeline>, now: Instant) {
+        while timelines.len() > self.max_entries {
+            timelines.pop_front();
+        }
+
+        if let Some(max_age) = self.max_age {
+            timelines.retain(|timeline| now.duration_since(timeline.ended_at) <= max_age);
+        }
+    }
+}
+
+/// One line per sample with a header, unknown values are left empty
+pub fn stats_timeline_csv(samples: &[StatsSample]) -> String {
+    let mut csv = String::from(
+        "elapsed_ms,bitrate_kbps,packet_loss_percent,rtt_ms,queued_frames,queue_delay_ms,frames_dropped\n",
+    );
+
+    for sample in samples {
+        let _ = writeln!(
+            csv,
+            "{},{},{},{},{},{},{}",
+            sample.elapsed_ms,
+            sample.bitrate_kbps,
+            sample
+                .packet_loss_percent
+                .map(|loss| loss.to_string())
+                .unwrap_or_default(),
+            sample.rtt_ms.map(|rtt| rtt.to_string()).unwrap_or_default(),
+            sample.queued_frames,
+            sample.queue_delay_ms,
+            sample.frames_dropped
+        );
+    }
+
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use common::{api_bindings::StatsSample, config::StatsTimelineConfig};
+
+    use crate::app::{
+        stats_timelines::{StatsTimeline, StatsTimelines, stats_timeline_csv},
+        user::UserId,
+    };
+
+    fn sample(elapsed_ms: u64) -> StatsSample {
+        StatsSample {
+            elapsed_ms,
+            bitrate_kbps: 20000,
+            packet_loss_percent: None,
+            rtt_ms: Some(4.5),
+            queued_frames: 1,
+            queue_delay_ms: 2.0,
+            frames_dropped: 3,
+        }
+    }
+
+    fn timeline(session_id: &str, ended_at: Instant) -> StatsTimeline {
+        StatsTimeline {
+            session_id: session_id.to_string(),
+            user_id: UserId(0),
+            ended_at,
+            samples: vec![sample(0), sample(5000)],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stats_timelines_max_entries() {
+        let timelines = StatsTimelines::new(&StatsTimelineConfig {
+            max_entries: 2,
+            max_age_hours: None,
+        });
+        let now = Instant::now();
+
+        for session_id in ["a", "b", "c"] {
+            timelines.push(timeline(session_id, now)).await;
+        }
+
+        assert!(timelines.get("a", now).await.is_none());
+        let (user_id, samples) = timelines.get("c", now).await.unwrap();
+        assert_eq!(user_id, UserId(0));
+        assert_eq!(samples.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_stats_timelines_max_age() {
+        let timelines = StatsTimelines::new(&StatsTimelineConfig {
+            max_entries: 10,
+            max_age_hours: Some(1),
+        });
+        let now = Instant::now();
+
+        timelines.push(timeline("a", now)).await;
+        assert!(timelines.get("a", now).await.is_some());
+        assert!(
+            timelines
+                .get("a", now + Duration::from_secs(2 * 60 * 60))
+                .await
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_stats_timeline_csv() {
+        let csv = stats_timeline_csv(&[sample(0)]);
+
+        assert_eq!(
+            csv,
+            "elapsed_ms,bitrate_kbps,packet_loss_percent,rtt_ms,queued_frames,queue_delay_ms,frames_dropped\n0,20000,,4.5,1,2,3\n"
+        );
+    }
+}
@@ -8,6 +8,7 @@ use std::{
 
 use anyhow::anyhow;
 use async_trait::async_trait;
+use common::api_bindings::HostStreamSettings;
 use futures::future::join_all;
 use log::{debug, error};
 use openssl::rand::rand_bytes;
@@ -29,10 +30,11 @@ use crate::app::{
     password::StoragePassword,
     storage::{
         Either, Storage, StorageHost, StorageHostAdd, StorageHostCache, StorageHostModify,
-        StorageHostPairInfo, StorageQueryHosts, StorageUser, StorageUserAdd, StorageUserModify,
+        StorageHostPairInfo, StorageQueryHosts, StorageQueryStreamHistory, StorageQueryUsers,
+        StorageStreamHistoryEntry, StorageUser, StorageUserAdd, StorageUserModify,
         json::versions::{
-            Json, V2, V2Host, V2HostCache, V2HostPairInfo, V2User, V2UserPassword,
-            migrate_to_latest,
+            Json, V2, V2Host, V2HostCache, V2HostPairInfo, V2HostStreamSettings,
+            V2StreamHistoryEntry, V2User, V2UserPassword, migrate_to_latest,
         },
     },
     user::UserId,
@@ -48,6 +50,7 @@ pub struct JsonStorage {
     users: RwLock<HashMap<u32, RwLock<V2User>>>,
     hosts: RwLock<HashMap<u32, RwLock<V2Host>>>,
     sessions: RwLock<HashMap<SessionToken, Session>>,
+    stream_history: RwLock<Vec<V2StreamHistoryEntry>>,
 }
 
 impl Drop for JsonStorage {
@@ -104,6 +107,7 @@ impl JsonStorage {
             hosts: Default::default(),
             users: Default::default(),
             sessions: Default::default(),
+            stream_history: Default::default(),
         };
         let this = Arc::new(this);
 
@@ -153,6 +157,7 @@ impl JsonStorage {
         {
             let mut users = self.users.write().await;
             let mut hosts = self.hosts.write().await;
+            let mut stream_history = self.stream_history.write().await;
 
             *users = data
                 .users
@@ -164,6 +169,7 @@ impl JsonStorage {
                 .into_iter()
                 .map(|(id, host)| (id, RwLock::new(host)))
                 .collect();
+            *stream_history = data.stream_history;
         }
 
         Ok(())
@@ -187,9 +193,12 @@ impl JsonStorage {
                 hosts_json.insert(*key, (*value).clone());
             }
 
+            let stream_history = self.stream_history.read().await.clone();
+
             Json::V2(V2 {
                 users: users_json,
                 hosts: hosts_json,
+                stream_history,
             })
         };
 
@@ -217,6 +226,14 @@ async fn file_writer(mut store_receiver: Receiver<()>, json: Arc<JsonStorage>) {
     }
 }
 
+fn user_matches(user: &V2User, query: &StorageQueryUsers) -> bool {
+    query.role.is_none_or(|role| user.role == role)
+        && query
+            .name_contains
+            .as_ref()
+            .is_none_or(|name| user.name.to_lowercase().contains(&name.to_lowercase()))
+}
+
 fn user_from_json(user_id: UserId, user: &V2User) -> StorageUser {
     StorageUser {
         id: user_id,
@@ -227,6 +244,8 @@ fn user_from_json(user_id: UserId, user: &V2User) -> StorageUser {
         }),
         role: user.role,
         client_unique_id: user.client_unique_id.clone(),
+        pair_device_name: user.pair_device_name.clone(),
+        client_certificate_fingerprints: user.client_certificate_fingerprints.clone(),
     }
 }
 
@@ -236,6 +255,7 @@ fn host_from_json(host_id: HostId, host: &V2Host) -> StorageHost {
         owner: host.owner.map(UserId),
         address: host.address.clone(),
         http_port: host.http_port,
+        wake_broadcast_address: host.wake_broadcast_address,
         pair_info: host.pair_info.clone().map(|pair_info| StorageHostPairInfo {
             client_certificate: pair_info.client_certificate,
             client_private_key: pair_info.client_private_key,
@@ -244,10 +264,67 @@ fn host_from_json(host_id: HostId, host: &V2Host) -> StorageHost {
         cache: StorageHostCache {
             name: host.cache.name.clone(),
             mac: host.cache.mac,
+            local_ip: host.cache.local_ip,
         },
+        default_stream_settings: host
+            .default_stream_settings
+            .clone()
+            .map(stream_settings_from_json),
+    }
+}
+
+fn stream_settings_from_json(settings: V2HostStreamSettings) -> HostStreamSettings {
+    HostStreamSettings {
+        bitrate: settings.bitrate,
+        packet_size: settings.packet_size,
+        fps: settings.fps,
+        width: settings.width,
+        height: settings.height,
+        video_frame_queue_size: settings.video_frame_queue_size,
+        audio_sample_queue_size: settings.audio_sample_queue_size,
+        play_audio_local: settings.play_audio_local,
+    }
+}
+fn stream_settings_to_json(settings: HostStreamSettings) -> V2HostStreamSettings {
+    V2HostStreamSettings {
+        bitrate: settings.bitrate,
+        packet_size: settings.packet_size,
+        fps: settings.fps,
+        width: settings.width,
+        height: settings.height,
+        video_frame_queue_size: settings.video_frame_queue_size,
+        audio_sample_queue_size: settings.audio_sample_queue_size,
+        play_audio_local: settings.play_audio_local,
+    }
+}
+
+fn stream_history_from_json(entry: &V2StreamHistoryEntry) -> StorageStreamHistoryEntry {
+    StorageStreamHistoryEntry {
+        user_id: UserId(entry.user_id),
+        host_id: HostId(entry.host_id),
+        host_name: entry.host_name.clone(),
+        app_title: entry.app_title.clone(),
+        session_id: entry.session_id.clone(),
+        started_at: entry.started_at,
+        ended_at: entry.ended_at,
+        end_reason: entry.end_reason.clone(),
     }
 }
 
+/// The history is sorted oldest first
+fn prune_history(
+    history: &mut Vec<V2StreamHistoryEntry>,
+    max_entries: usize,
+    ended_after: Option<u64>,
+) {
+    if let Some(ended_after) = ended_after {
+        history.retain(|entry| entry.ended_at >= ended_after);
+    }
+
+    let excess = history.len().saturating_sub(max_entries);
+    history.drain(..excess);
+}
+
 #[async_trait]
 impl Storage for JsonStorage {
     async fn add_user(&self, user: StorageUserAdd) -> Result<StorageUser, AppError> {
@@ -259,6 +336,8 @@ impl Storage for JsonStorage {
                 hash: password.hash,
             }),
             client_unique_id: user.client_unique_id,
+            pair_device_name: None,
+            client_certificate_fingerprints: Vec::new(),
         };
 
         {
@@ -298,6 +377,8 @@ impl Storage for JsonStorage {
             }),
             role: user.role,
             client_unique_id: user.client_unique_id,
+            pair_device_name: user.pair_device_name,
+            client_certificate_fingerprints: user.client_certificate_fingerprints,
         })
     }
     async fn modify_user(
@@ -322,6 +403,12 @@ impl Storage for JsonStorage {
         if let Some(client_unique_id) = modify.client_unique_id {
             user.client_unique_id = client_unique_id;
         }
+        if let Some(pair_device_name) = modify.pair_device_name {
+            user.pair_device_name = pair_device_name;
+        }
+        if let Some(client_certificate_fingerprints) = modify.client_certificate_fingerprints {
+            user.client_certificate_fingerprints = client_certificate_fingerprints;
+        }
 
         drop(user);
         drop(users);
@@ -358,6 +445,30 @@ impl Storage for JsonStorage {
 
         user.ok_or(AppError::UserNotFound)
     }
+    async fn get_user_by_client_certificate(
+        &self,
+        fingerprint: &str,
+    ) -> Result<(UserId, Option<StorageUser>), AppError> {
+        let users = self.users.read().await;
+
+        let results = join_all(users.iter().map(|(user_id, user)| async move {
+            let user = user.read().await;
+
+            let user_id = UserId(*user_id);
+            let user = user
+                .client_certificate_fingerprints
+                .iter()
+                .any(|user_fingerprint| user_fingerprint == fingerprint)
+                .then(|| user_from_json(user_id, &user));
+
+            (user_id, user)
+        }))
+        .await;
+
+        let user = results.into_iter().find(|(_, user)| user.is_some());
+
+        user.ok_or(AppError::UserNotFound)
+    }
     async fn remove_user(&self, user_id: UserId) -> Result<(), AppError> {
         let mut users = self.users.write().await;
 
@@ -372,19 +483,31 @@ impl Storage for JsonStorage {
 
         result
     }
-    async fn list_users(&self) -> Result<Either<Vec<UserId>, Vec<StorageUser>>, AppError> {
+    async fn list_users(
+        &self,
+        query: StorageQueryUsers,
+    ) -> Result<(Either<Vec<UserId>, Vec<StorageUser>>, usize), AppError> {
         let users = self.users.read().await;
 
-        let futures = users.iter().map(|(id, value)| {
-            let id = *id;
-            async move {
-                let user = value.read().await.clone();
-                user_from_json(UserId(id), &user)
+        let mut user_ids = users.keys().copied().collect::<Vec<_>>();
+        user_ids.sort_unstable();
+
+        // Only the users of the requested page are copied
+        let mut total = 0;
+        let mut out = Vec::new();
+        for user_id in user_ids {
+            let user = users[&user_id].read().await;
+            if !user_matches(&user, &query) {
+                continue;
             }
-        });
 
-        let out = join_all(futures).await;
-        Ok(Either::Right(out))
+            if total >= query.offset && query.limit.is_none_or(|limit| out.len() < limit) {
+                out.push(user_from_json(UserId(user_id), &user));
+            }
+            total += 1;
+        }
+
+        Ok((Either::Right(out), total))
     }
     async fn any_user_exists(&self) -> Result<bool, AppError> {
         let users = self.users.read().await;
@@ -453,6 +576,7 @@ impl Storage for JsonStorage {
             owner: host.owner.map(|user_id| user_id.0),
             address: host.address,
             http_port: host.http_port,
+            wake_broadcast_address: host.wake_broadcast_address,
             pair_info: host.pair_info.map(|pair_info| V2HostPairInfo {
                 client_private_key: pair_info.client_private_key,
                 client_certificate: pair_info.client_certificate,
@@ -461,7 +585,9 @@ impl Storage for JsonStorage {
             cache: V2HostCache {
                 name: host.cache.name,
                 mac: host.cache.mac,
+                local_ip: host.cache.local_ip,
             },
+            default_stream_settings: None,
         };
 
         let mut hosts = self.hosts.write().await;
@@ -485,6 +611,7 @@ impl Storage for JsonStorage {
             owner: host.owner.map(UserId),
             address: host.address,
             http_port: host.http_port,
+            wake_broadcast_address: host.wake_broadcast_address,
             pair_info: host.pair_info.map(|pair_info| StorageHostPairInfo {
                 client_private_key: pair_info.client_private_key,
                 client_certificate: pair_info.client_certificate,
@@ -493,7 +620,9 @@ impl Storage for JsonStorage {
             cache: StorageHostCache {
                 name: host.cache.name,
                 mac: host.cache.mac,
+                local_ip: host.cache.local_ip,
             },
+            default_stream_settings: None,
         })
     }
     async fn modify_host(
@@ -515,6 +644,9 @@ impl Storage for JsonStorage {
         if let Some(new_http_port) = modify.http_port {
             host.http_port = new_http_port;
         }
+        if let Some(new_wake_broadcast_address) = modify.wake_broadcast_address {
+            host.wake_broadcast_address = new_wake_broadcast_address;
+        }
         if let Some(new_pair_info) = modify.pair_info {
             host.pair_info = new_pair_info.map(|new_pair_info| V2HostPairInfo {
                 client_private_key: new_pair_info.client_private_key,
@@ -528,6 +660,12 @@ impl Storage for JsonStorage {
         if let Some(new_cache_mac) = modify.cache_mac {
             host.cache.mac = new_cache_mac;
         }
+        if let Some(new_cache_local_ip) = modify.cache_local_ip {
+            host.cache.local_ip = new_cache_local_ip;
+        }
+        if let Some(new_default_stream_settings) = modify.default_stream_settings {
+            host.default_stream_settings = new_default_stream_settings.map(stream_settings_to_json);
+        }
 
         self.force_write();
 
@@ -571,4 +709,171 @@ impl Storage for JsonStorage {
 
         Ok(user_hosts)
     }
+    async fn add_stream_history(&self, entry: StorageStreamHistoryEntry) -> Result<(), AppError> {
+        let mut stream_history = self.stream_history.write().await;
+
+        stream_history.push(V2StreamHistoryEntry {
+            user_id: entry.user_id.0,
+            host_id: entry.host_id.0,
+            host_name: entry.host_name,
+            app_title: entry.app_title,
+            session_id: entry.session_id,
+            started_at: entry.started_at,
+            ended_at: entry.ended_at,
+            end_reason: entry.end_reason,
+        });
+
+        drop(stream_history);
+
+        self.force_write();
+
+        Ok(())
+    }
+    async fn prune_stream_history(
+        &self,
+        max_entries: usize,
+        ended_after: Option<u64>,
+    ) -> Result<(), AppError> {
+        let mut stream_history = self.stream_history.write().await;
+
+        let old_len = stream_history.len();
+        prune_history(&mut stream_history, max_entries, ended_after);
+        let changed = stream_history.len() != old_len;
+
+        drop(stream_history);
+
+        if changed {
+            self.force_write();
+        }
+
+        Ok(())
+    }
+    async fn list_stream_history(
+        &self,
+        query: StorageQueryStreamHistory,
+    ) -> Result<(Vec<StorageStreamHistoryEntry>, usize), AppError> {
+        let stream_history = self.stream_history.read().await;
+
+        let matching = || {
+            stream_history.iter().rev().filter(|entry| {
+                query
+                    .user_id
+                    .is_none_or(|user_id| UserId(entry.user_id) == user_id)
+            })
+        };
+
+        let total = matching().count();
+        let entries = matching()
+            .skip(query.offset)
+            .take(query.limit)
+            .map(stream_history_from_json)
+            .collect();
+
+        Ok((entries, total))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::{
+        host::HostId,
+        storage::{
+            StorageQueryUsers,
+            json::{
+                host_from_json, prune_history, user_matches,
+                versions::{V2Host, V2StreamHistoryEntry, V2User},
+            },
+        },
+        user::Role,
+    };
+
+    fn history(ended_at: &[u64]) -> Vec<V2StreamHistoryEntry> {
+        ended_at
+            .iter()
+            .map(|ended_at| V2StreamHistoryEntry {
+                user_id: 0,
+                host_id: 0,
+                host_name: "Host".to_string(),
+                app_title: "Desktop".to_string(),
+                session_id: None,
+                started_at: ended_at - 10,
+                ended_at: *ended_at,
+                end_reason: "streamer_stopped".to_string(),
+            })
+            .collect()
+    }
+
+    fn ended_at(history: &[V2StreamHistoryEntry]) -> Vec<u64> {
+        history.iter().map(|entry| entry.ended_at).collect()
+    }
+
+    #[test]
+    fn test_prune_history_max_entries() {
+        let mut entries = history(&[100, 200, 300, 400]);
+
+        prune_history(&mut entries, 2, None);
+        assert_eq!(ended_at(&entries), vec![300, 400]);
+    }
+
+    #[test]
+    fn test_prune_history_age() {
+        let mut entries = history(&[100, 200, 300, 400]);
+
+        prune_history(&mut entries, 10, Some(250));
+        assert_eq!(ended_at(&entries), vec![300, 400]);
+    }
+
+    #[test]
+    fn test_host_without_default_stream_settings() {
+        // Hosts stored before per host stream settings existed
+        let host: V2Host = serde_json::from_str(
+            r#"{
+                "owner": null,
+                "address": "192.168.1.10",
+                "http_port": 47989,
+                "pair_info": null,
+                "cache": { "name": "Host", "mac": null }
+            }"#,
+        )
+        .unwrap();
+
+        let host = host_from_json(HostId(1), &host);
+        assert!(host.default_stream_settings.is_none());
+    }
+
+    #[test]
+    fn test_user_matches_query() {
+        let user = V2User {
+            role: Role::User,
+            name: "LivingRoom".to_string(),
+            password: None,
+            client_unique_id: "0123456789ABCDEF".to_string(),
+            pair_device_name: None,
+            client_certificate_fingerprints: Vec::new(),
+        };
+
+        assert!(user_matches(&user, &StorageQueryUsers::default()));
+        assert!(user_matches(
+            &user,
+            &StorageQueryUsers {
+                role: Some(Role::User),
+                name_contains: Some("room".to_string()),
+                ..Default::default()
+            }
+        ));
+        assert!(!user_matches(
+            &user,
+            &StorageQueryUsers {
+                role: Some(Role::Admin),
+                ..Default::default()
+            }
+        ));
+        assert!(!user_matches(
+            &user,
+            &StorageQueryUsers {
+                name_contains: Some("kitchen".to_string()),
+                ..Default::default()
+            }
+        ));
+    }
 }
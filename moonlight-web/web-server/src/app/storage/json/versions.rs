@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, net::Ipv4Addr};
 
 use log::error;
 use moonlight_common::mac::MacAddress;
@@ -61,6 +61,8 @@ pub fn migrate_v1_to_v2(old: V1) -> V2 {
             owner: None,
             address: old_host.address,
             http_port: old_host.http_port,
+            wake_broadcast_address: None,
+            default_stream_settings: None,
             pair_info: old_host
                 .paired
                 .and_then(|v1| match migrate_certificates_v1_to_v2(v1) {
@@ -73,6 +75,7 @@ pub fn migrate_v1_to_v2(old: V1) -> V2 {
             cache: V2HostCache {
                 name: old_host.cache.name.unwrap_or_else(|| "Unknown".to_string()),
                 mac: old_host.cache.mac,
+                local_ip: None,
             },
         };
 
@@ -82,6 +85,7 @@ pub fn migrate_v1_to_v2(old: V1) -> V2 {
     V2 {
         users: Default::default(),
         hosts: v2_hosts,
+        stream_history: Default::default(),
     }
 }
 
@@ -95,6 +99,9 @@ pub struct V2 {
     pub users: HashMap<u32, V2User>,
     #[serde(deserialize_with = "de_int_key")]
     pub hosts: HashMap<u32, V2Host>,
+    /// Oldest first
+    #[serde(default)]
+    pub stream_history: Vec<V2StreamHistoryEntry>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -103,6 +110,10 @@ pub struct V2User {
     pub name: String,
     pub password: Option<V2UserPassword>,
     pub client_unique_id: String,
+    #[serde(default)]
+    pub pair_device_name: Option<String>,
+    #[serde(default)]
+    pub client_certificate_fingerprints: Vec<String>,
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct V2UserPassword {
@@ -117,8 +128,12 @@ pub struct V2Host {
     pub owner: Option<u32>,
     pub address: String,
     pub http_port: u16,
+    #[serde(default)]
+    pub wake_broadcast_address: Option<Ipv4Addr>,
     pub pair_info: Option<V2HostPairInfo>,
     pub cache: V2HostCache,
+    #[serde(default)]
+    pub default_stream_settings: Option<V2HostStreamSettings>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -128,10 +143,45 @@ pub struct V2HostPairInfo {
     pub server_certificate: Pem,
 }
 
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct V2HostStreamSettings {
+    #[serde(default)]
+    pub bitrate: Option<u32>,
+    #[serde(default)]
+    pub packet_size: Option<u32>,
+    #[serde(default)]
+    pub fps: Option<u32>,
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+    #[serde(default)]
+    pub video_frame_queue_size: Option<u32>,
+    #[serde(default)]
+    pub audio_sample_queue_size: Option<u32>,
+    #[serde(default)]
+    pub play_audio_local: Option<bool>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct V2HostCache {
     pub name: String,
     pub mac: Option<MacAddress>,
+    #[serde(default)]
+    pub local_ip: Option<Ipv4Addr>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct V2StreamHistoryEntry {
+    pub user_id: u32,
+    pub host_id: u32,
+    pub host_name: String,
+    pub app_title: String,
+    #[serde(default)]
+    pub session_id: Option<String>,
+    pub started_at: u64,
+    pub ended_at: u64,
+    pub end_reason: String,
 }
 
 pub fn migrate_to_latest(json: Json) -> Result<V2, anyhow::Error> {
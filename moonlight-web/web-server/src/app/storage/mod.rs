@@ -1,7 +1,7 @@
-use std::{sync::Arc, time::Duration};
+use std::{net::Ipv4Addr, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
-use common::config::StorageConfig;
+use common::{api_bindings::HostStreamSettings, config::StorageConfig};
 use moonlight_common::mac::MacAddress;
 use pem::Pem;
 
@@ -41,6 +41,10 @@ pub struct StorageUser {
     pub password: Option<StoragePassword>,
     pub role: Role,
     pub client_unique_id: String,
+    // None = use the configured pair device name
+    pub pair_device_name: Option<String>,
+    // Normalized with normalize_fingerprint
+    pub client_certificate_fingerprints: Vec<String>,
 }
 #[derive(Clone)]
 pub struct StorageUserAdd {
@@ -54,6 +58,17 @@ pub struct StorageUserModify {
     pub role: Option<Role>,
     pub password: Option<Option<StoragePassword>>,
     pub client_unique_id: Option<String>,
+    pub pair_device_name: Option<Option<String>>,
+    pub client_certificate_fingerprints: Option<Vec<String>>,
+}
+
+#[derive(Clone, Default)]
+pub struct StorageQueryUsers {
+    pub offset: usize,
+    // None = all users after the offset
+    pub limit: Option<usize>,
+    pub role: Option<Role>,
+    pub name_contains: Option<String>,
 }
 
 #[derive(Clone)]
@@ -63,14 +78,17 @@ pub struct StorageHost {
     pub owner: Option<UserId>,
     pub address: String,
     pub http_port: u16,
+    pub wake_broadcast_address: Option<Ipv4Addr>,
     pub pair_info: Option<StorageHostPairInfo>,
     pub cache: StorageHostCache,
+    pub default_stream_settings: Option<HostStreamSettings>,
 }
 #[derive(Clone)]
 pub struct StorageHostAdd {
     pub owner: Option<UserId>,
     pub address: String,
     pub http_port: u16,
+    pub wake_broadcast_address: Option<Ipv4Addr>,
     pub pair_info: Option<StorageHostPairInfo>,
     pub cache: StorageHostCache,
 }
@@ -78,6 +96,7 @@ pub struct StorageHostAdd {
 pub struct StorageHostCache {
     pub name: String,
     pub mac: Option<MacAddress>,
+    pub local_ip: Option<Ipv4Addr>,
 }
 #[derive(Clone)]
 pub struct StorageHostPairInfo {
@@ -90,9 +109,12 @@ pub struct StorageHostModify {
     pub owner: Option<Option<UserId>>,
     pub address: Option<String>,
     pub http_port: Option<u16>,
+    pub wake_broadcast_address: Option<Option<Ipv4Addr>>,
     pub pair_info: Option<Option<StorageHostPairInfo>>,
     pub cache_name: Option<String>,
     pub cache_mac: Option<Option<MacAddress>>,
+    pub cache_local_ip: Option<Option<Ipv4Addr>>,
+    pub default_stream_settings: Option<Option<HostStreamSettings>>,
 }
 
 #[derive(Clone)]
@@ -100,6 +122,27 @@ pub struct StorageQueryHosts {
     pub user_id: UserId,
 }
 
+/// A stream which ended, timestamps are unix timestamps in seconds
+#[derive(Clone)]
+pub struct StorageStreamHistoryEntry {
+    pub user_id: UserId,
+    pub host_id: HostId,
+    pub host_name: String,
+    pub app_title: String,
+    /// The id of the stats timeline, None for entries recorded before it existed
+    pub session_id: Option<String>,
+    pub started_at: u64,
+    pub ended_at: u64,
+    pub end_reason: String,
+}
+#[derive(Clone)]
+pub struct StorageQueryStreamHistory {
+    // None = the history of all users
+    pub user_id: Option<UserId>,
+    pub offset: usize,
+    pub limit: usize,
+}
+
 pub enum Either<L, R> {
     Left(L),
     Right(R),
@@ -114,9 +157,21 @@ pub trait Storage {
     /// The returned tuple can contain a StorageUser if the Storage thinks it's more efficient to query all data directly
     async fn get_user_by_name(&self, name: &str)
     -> Result<(UserId, Option<StorageUser>), AppError>;
+    /// The user which has a client certificate with this fingerprint
+    ///
+    /// The returned tuple can contain a StorageUser if the Storage thinks it's more efficient to query all data directly
+    async fn get_user_by_client_certificate(
+        &self,
+        fingerprint: &str,
+    ) -> Result<(UserId, Option<StorageUser>), AppError>;
     async fn remove_user(&self, user_id: UserId) -> Result<(), AppError>;
+    /// Returns the matching users ordered by id and the amount of users matching the query
+    ///
     /// The returned tuple can contain a Vec<UserId> or Vec<StorageUser> if the Storage thinks it's more efficient to query all data directly
-    async fn list_users(&self) -> Result<Either<Vec<UserId>, Vec<StorageUser>>, AppError>;
+    async fn list_users(
+        &self,
+        query: StorageQueryUsers,
+    ) -> Result<(Either<Vec<UserId>, Vec<StorageUser>>, usize), AppError>;
     async fn any_user_exists(&self) -> Result<bool, AppError>;
 
     async fn create_session_token(
@@ -144,4 +199,17 @@ pub trait Storage {
         &self,
         query: StorageQueryHosts,
     ) -> Result<Vec<(HostId, Option<StorageHost>)>, AppError>;
+
+    async fn add_stream_history(&self, entry: StorageStreamHistoryEntry) -> Result<(), AppError>;
+    /// Removes entries which ended before `ended_after` and then the oldest entries until at most `max_entries` are left
+    async fn prune_stream_history(
+        &self,
+        max_entries: usize,
+        ended_after: Option<u64>,
+    ) -> Result<(), AppError>;
+    /// Returns the entries newest first and the amount of entries matching the query
+    async fn list_stream_history(
+        &self,
+        query: StorageQueryStreamHistory,
+    ) -> Result<(Vec<StorageStreamHistoryEntry>, usize), AppError>;
 }
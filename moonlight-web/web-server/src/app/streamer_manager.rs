@@ -0,0 +1,573 @@
+//! Bookkeeping of the running streamer processes
+//!
+//! Every stream spawns its own streamer. The OS only tells us when such a process died,
+//! so each stream additionally pings its streamer over ipc and stops the stream once it stops answering,
+//! e.g. because it deadlocked. The hung streamer is killed and not respawned, the client has to start a new stream.
+//!
+//! Streamers which outlived a previous web server are found with their pidfiles, see [StreamerPidFile].
+
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use common::{api_bindings::DetailedStreamer, pidfile::StreamerPidFile};
+use log::{debug, info, warn};
+use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System};
+use tokio::{
+    fs,
+    sync::{Mutex, watch},
+    time::sleep,
+};
+
+/// Reason sent to the client when the stream was stopped because the streamer stopped answering pings
+pub const STREAMER_UNRESPONSIVE_REASON: &str = "streamer_unresponsive";
+/// Reason sent to the client when the stream was stopped because the web server shuts down
+pub const SERVER_SHUTDOWN_REASON: &str = "server_shutdown";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StreamerId(pub u32);
+
+#[derive(Debug, Clone)]
+pub struct StreamerProcessInfo {
+    pub pid: Option<u32>,
+    pub session_id: String,
+    pub started_at: Instant,
+    /// None if the streamer never answered a ping
+    pub last_pong: Option<Instant>,
+    /// As reported with the last pong
+    pub bytes_sent: u64,
+}
+
+/// Counters since the web server started, exported by the metrics endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamerMetrics {
+    pub active: usize,
+    pub started: u64,
+    pub spawn_failures: u64,
+    /// Sent by the transports of running and stopped streamers
+    pub bytes_sent: u64,
+}
+
+#[derive(Debug)]
+pub struct StreamerProcessManager {
+    pidfile_dir: PathBuf,
+    next_id: Mutex<u32>,
+    processes: Mutex<HashMap<StreamerId, StreamerProcessInfo>>,
+    /// Set once the server shuts down, every stream should stop its streamer
+    shutdown: watch::Sender<bool>,
+    started: AtomicU64,
+    spawn_failures: AtomicU64,
+    /// Bytes sent by streamers which were already removed
+    removed_bytes_sent: AtomicU64,
+}
+
+impl StreamerProcessManager {
+    pub fn new(pidfile_dir: PathBuf) -> Self {
+        Self {
+            pidfile_dir,
+            next_id: Default::default(),
+            processes: Default::default(),
+            shutdown: watch::Sender::new(false),
+            started: AtomicU64::new(0),
+            spawn_failures: AtomicU64::new(0),
+            removed_bytes_sent: AtomicU64::new(0),
+        }
+    }
+
+    /// Tells all streams to stop their streamers, no new streams should be started after this
+    pub fn begin_shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Completes once [Self::begin_shutdown] was called
+    pub async fn wait_for_shutdown(&self) {
+        let mut receiver = self.shutdown.subscribe();
+        // The sender lives as long as self so this can't fail
+        let _ = receiver.wait_for(|shutdown| *shutdown).await;
+    }
+
+    /// Waits until all streamers are removed or the timeout passed.
+    /// Returns how many streamers are still running.
+    pub async fn wait_until_empty(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let count = self.process_count().await;
+            if count == 0 || Instant::now() >= deadline {
+                return count;
+            }
+
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    pub fn pidfile_dir(&self) -> &Path {
+        &self.pidfile_dir
+    }
+
+    pub async fn register(&self, pid: Option<u32>, session_id: String) -> StreamerId {
+        let id = {
+            let mut next_id = self.next_id.lock().await;
+            let id = StreamerId(*next_id);
+            *next_id = next_id.wrapping_add(1);
+            id
+        };
+
+        let mut processes = self.processes.lock().await;
+        processes.insert(
+            id,
+            StreamerProcessInfo {
+                pid,
+                session_id,
+                started_at: Instant::now(),
+                last_pong: None,
+                bytes_sent: 0,
+            },
+        );
+        self.started.fetch_add(1, Ordering::Relaxed);
+
+        debug!("[StreamerManager]: registered streamer {id:?} with pid {pid:?}");
+
+        id
+    }
+
+    pub async fn record_pong(&self, id: StreamerId, now: Instant, bytes_sent: u64) {
+        let mut processes = self.processes.lock().await;
+
+        if let Some(process) = processes.get_mut(&id) {
+            process.last_pong = Some(now);
+            process.bytes_sent = bytes_sent;
+        }
+    }
+
+    pub fn record_spawn_failure(&self) {
+        self.spawn_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Called once the streamer was killed or exited.
+    /// A killed streamer can't remove its own pidfile so this is done here.
+    pub async fn remove(&self, id: StreamerId) {
+        let mut processes = self.processes.lock().await;
+
+        if let Some(process) = processes.remove(&id) {
+            debug!("[StreamerManager]: removed streamer {id:?}");
+            self.removed_bytes_sent
+                .fetch_add(process.bytes_sent, Ordering::Relaxed);
+
+            let path = StreamerPidFile::path(&self.pidfile_dir, &process.session_id);
+            remove_pidfile(&path).await;
+        }
+    }
+
+    /// Kills all streamers listed in the pidfile directory which don't belong to a running stream.
+    ///
+    /// A pid is only killed if the process is alive and its executable is named like the streamer,
+    /// because the OS might have reused the pid of a streamer which exited long ago.
+    pub async fn kill_orphaned_streamers(&self, streamer_path: &str) {
+        let mut entries = match fs::read_dir(&self.pidfile_dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return,
+            Err(err) => {
+                warn!("[StreamerManager]: failed to read pidfile directory: {err:?}");
+                return;
+            }
+        };
+
+        let mut pidfiles = Vec::new();
+        loop {
+            match entries.next_entry().await {
+                Ok(Some(entry)) => {
+                    let path = entry.path();
+                    if StreamerPidFile::is_pidfile(&path) {
+                        pidfiles.push(path);
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    warn!("[StreamerManager]: failed to read pidfile directory: {err:?}");
+                    break;
+                }
+            }
+        }
+
+        let known_sessions = {
+            let processes = self.processes.lock().await;
+            processes
+                .values()
+                .map(|process| process.session_id.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let streamer_name = Path::new(streamer_path).file_stem();
+        let mut system = System::new();
+
+        for path in pidfiles {
+            let pidfile = match StreamerPidFile::read(&path) {
+                Ok(pidfile) => pidfile,
+                Err(err) => {
+                    warn!("[StreamerManager]: failed to read pidfile {path:?}: {err:?}");
+                    continue;
+                }
+            };
+
+            if known_sessions.contains(&pidfile.session_id) {
+                continue;
+            }
+
+            let pid = Pid::from_u32(pidfile.pid);
+            system.refresh_processes_specifics(
+                ProcessesToUpdate::Some(&[pid]),
+                true,
+                ProcessRefreshKind::nothing(),
+            );
+
+            match system.process(pid) {
+                Some(process) if is_streamer(process.name(), streamer_name) => {
+                    info!(
+                        "[StreamerManager]: killing orphaned streamer with pid {} of session {}",
+                        pidfile.pid, pidfile.session_id
+                    );
+
+                    if !process.kill() {
+                        warn!(
+                            "[StreamerManager]: failed to kill orphaned streamer with pid {}",
+                            pidfile.pid
+                        );
+                        continue;
+                    }
+                }
+                Some(_) => {
+                    debug!(
+                        "[StreamerManager]: pid {} of a stale pidfile belongs to another process",
+                        pidfile.pid
+                    );
+                }
+                None => {
+                    debug!(
+                        "[StreamerManager]: streamer with pid {} already exited",
+                        pidfile.pid
+                    );
+                }
+            }
+
+            remove_pidfile(&path).await;
+        }
+    }
+
+    pub async fn process_count(&self) -> usize {
+        let processes = self.processes.lock().await;
+        processes.len()
+    }
+
+    pub async fn metrics(&self) -> StreamerMetrics {
+        let processes = self.processes.lock().await;
+
+        StreamerMetrics {
+            active: processes.len(),
+            started: self.started.load(Ordering::Relaxed),
+            spawn_failures: self.spawn_failures.load(Ordering::Relaxed),
+            bytes_sent: self.removed_bytes_sent.load(Ordering::Relaxed)
+                + processes
+                    .values()
+                    .map(|process| process.bytes_sent)
+                    .sum::<u64>(),
+        }
+    }
+
+    pub async fn detailed_streamers(&self, now: Instant) -> Vec<DetailedStreamer> {
+        let processes = self.processes.lock().await;
+
+        let mut streamers = processes
+            .iter()
+            .map(|(id, process)| DetailedStreamer {
+                id: id.0,
+                pid: process.pid,
+                running_secs: secs_since(now, process.started_at),
+                last_pong_secs_ago: process
+                    .last_pong
+                    .map(|last_pong| secs_since(now, last_pong)),
+            })
+            .collect::<Vec<_>>();
+        streamers.sort_by_key(|streamer| streamer.id);
+
+        streamers
+    }
+}
+
+/// Compares without the extension because the process name includes ".exe" on windows
+fn is_streamer(process_name: &OsStr, streamer_name: Option<&OsStr>) -> bool {
+    Path::new(process_name).file_stem() == streamer_name
+}
+
+async fn remove_pidfile(path: &Path) {
+    match fs::remove_file(path).await {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => {
+            warn!("[StreamerManager]: failed to remove pidfile {path:?}: {err:?}");
+        }
+    }
+}
+
+fn secs_since(now: Instant, earlier: Instant) -> u32 {
+    now.saturating_duration_since(earlier)
+        .as_secs()
+        .try_into()
+        .unwrap_or(u32::MAX)
+}
+
+/// What should happen on a heartbeat tick
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeartbeatTick {
+    /// Send a ping with this sequence to the streamer
+    Ping { sequence: u32 },
+    /// Too many pings in a row weren't answered, the streamer is considered hung
+    Unresponsive { missed: u32 },
+}
+
+/// Tracks the pings of a single streamer
+#[derive(Debug)]
+pub struct StreamerHeartbeat {
+    max_missed_pongs: u32,
+    next_sequence: u32,
+    /// The most recent ping which wasn't answered yet
+    awaiting: Option<u32>,
+    missed: u32,
+}
+
+impl StreamerHeartbeat {
+    pub fn new(max_missed_pongs: u32) -> Self {
+        Self {
+            max_missed_pongs: max_missed_pongs.max(1),
+            next_sequence: 0,
+            awaiting: None,
+            missed: 0,
+        }
+    }
+
+    pub fn tick(&mut self) -> HeartbeatTick {
+        if self.awaiting.is_some() {
+            self.missed += 1;
+
+            if self.missed >= self.max_missed_pongs {
+                return HeartbeatTick::Unresponsive {
+                    missed: self.missed,
+                };
+            }
+        }
+
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        self.awaiting = Some(sequence);
+
+        HeartbeatTick::Ping { sequence }
+    }
+
+    /// Returns true if the pong belongs to one of the outstanding pings
+    pub fn pong(&mut self, sequence: u32) -> bool {
+        let Some(awaiting) = self.awaiting else {
+            return false;
+        };
+
+        // A late pong of an earlier ping still proves that the streamer is alive
+        if awaiting.wrapping_sub(sequence) > self.missed {
+            return false;
+        }
+
+        self.missed = 0;
+        if awaiting == sequence {
+            self.awaiting = None;
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        ffi::OsStr,
+        path::Path,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use common::pidfile::StreamerPidFile;
+
+    use crate::app::streamer_manager::{
+        HeartbeatTick, StreamerHeartbeat, StreamerMetrics, StreamerProcessManager, is_streamer,
+    };
+
+    #[test]
+    fn test_answered_pings_stay_responsive() {
+        let mut heartbeat = StreamerHeartbeat::new(2);
+
+        for expected in 0..10 {
+            let HeartbeatTick::Ping { sequence } = heartbeat.tick() else {
+                panic!("streamer got unresponsive");
+            };
+            assert_eq!(sequence, expected);
+            assert!(heartbeat.pong(sequence));
+        }
+    }
+
+    #[test]
+    fn test_missed_pongs_make_unresponsive() {
+        let mut heartbeat = StreamerHeartbeat::new(3);
+
+        assert_eq!(heartbeat.tick(), HeartbeatTick::Ping { sequence: 0 });
+        assert_eq!(heartbeat.tick(), HeartbeatTick::Ping { sequence: 1 });
+        assert_eq!(heartbeat.tick(), HeartbeatTick::Ping { sequence: 2 });
+        assert_eq!(heartbeat.tick(), HeartbeatTick::Unresponsive { missed: 3 });
+    }
+
+    #[test]
+    fn test_late_pong_resets_missed() {
+        let mut heartbeat = StreamerHeartbeat::new(3);
+
+        heartbeat.tick();
+        heartbeat.tick();
+        heartbeat.tick();
+
+        // Answer to the first ping arrives late
+        assert!(heartbeat.pong(0));
+        assert_eq!(heartbeat.tick(), HeartbeatTick::Ping { sequence: 3 });
+        assert_eq!(heartbeat.tick(), HeartbeatTick::Ping { sequence: 4 });
+    }
+
+    #[test]
+    fn test_unknown_pong_ignored() {
+        let mut heartbeat = StreamerHeartbeat::new(3);
+
+        assert!(!heartbeat.pong(0));
+
+        heartbeat.tick();
+        assert!(!heartbeat.pong(7));
+        assert!(heartbeat.pong(0));
+        assert!(!heartbeat.pong(0));
+    }
+
+    #[tokio::test]
+    async fn test_manager_records_pongs() {
+        let manager =
+            StreamerProcessManager::new(env::temp_dir().join("moonlight-web-test-records"));
+
+        let first = manager.register(Some(100), "first".to_string()).await;
+        let second = manager.register(None, "second".to_string()).await;
+        assert_eq!(manager.process_count().await, 2);
+
+        let now = Instant::now();
+        manager.record_pong(first, now, 1000).await;
+
+        let streamers = manager
+            .detailed_streamers(now + Duration::from_secs(5))
+            .await;
+        assert_eq!(streamers[0].pid, Some(100));
+        assert_eq!(streamers[0].last_pong_secs_ago, Some(5));
+        assert_eq!(streamers[1].last_pong_secs_ago, None);
+
+        manager.remove(second).await;
+        assert_eq!(manager.process_count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_manager_metrics() {
+        let manager =
+            StreamerProcessManager::new(env::temp_dir().join("moonlight-web-test-metrics"));
+
+        let first = manager.register(None, "first".to_string()).await;
+        let second = manager.register(None, "second".to_string()).await;
+        manager.record_spawn_failure();
+
+        let now = Instant::now();
+        manager.record_pong(first, now, 1000).await;
+        manager.record_pong(second, now, 500).await;
+        manager.remove(first).await;
+        manager.record_pong(second, now, 700).await;
+
+        assert_eq!(
+            manager.metrics().await,
+            StreamerMetrics {
+                active: 1,
+                started: 2,
+                spawn_failures: 1,
+                bytes_sent: 1700,
+            }
+        );
+    }
+
+    #[test]
+    fn test_is_streamer() {
+        let streamer_name = Path::new("./streamer").file_stem();
+
+        assert!(is_streamer(OsStr::new("streamer"), streamer_name));
+        assert!(is_streamer(OsStr::new("streamer.exe"), streamer_name));
+        assert!(!is_streamer(OsStr::new("web-server"), streamer_name));
+    }
+
+    #[tokio::test]
+    async fn test_stale_pidfiles_removed() {
+        let dir = env::temp_dir().join("moonlight-web-test-stale-pidfiles");
+        let manager = StreamerProcessManager::new(dir.clone());
+
+        // The current process is alive but isn't a streamer, so it must not be killed
+        let path = StreamerPidFile {
+            pid: std::process::id(),
+            session_id: "stale".to_string(),
+        }
+        .write(&dir)
+        .expect("failed to write pidfile");
+
+        manager.kill_orphaned_streamers("./streamer").await;
+        assert!(!path.exists());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_streamers() {
+        let manager = Arc::new(StreamerProcessManager::new(
+            env::temp_dir().join("moonlight-web-test-shutdown"),
+        ));
+        assert!(!manager.is_shutting_down());
+
+        let id = manager.register(None, "draining".to_string()).await;
+
+        // Simulates a stream which stops its streamer once the shutdown begins
+        let stream = tokio::spawn({
+            let manager = manager.clone();
+            async move {
+                manager.wait_for_shutdown().await;
+                manager.remove(id).await;
+            }
+        });
+
+        manager.begin_shutdown();
+        assert!(manager.is_shutting_down());
+
+        assert_eq!(manager.wait_until_empty(Duration::from_secs(5)).await, 0);
+        stream.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drain_timeout() {
+        let manager = StreamerProcessManager::new(env::temp_dir().join("moonlight-web-test-stuck"));
+        manager.register(None, "stuck".to_string()).await;
+
+        manager.begin_shutdown();
+        assert_eq!(
+            manager.wait_until_empty(Duration::from_millis(200)).await,
+            1
+        );
+    }
+}
@@ -1,25 +1,31 @@
 use std::{
     fmt::{Debug, Formatter},
+    net::IpAddr,
     ops::{Deref, DerefMut},
     time::Duration,
 };
 
 use common::api_bindings::{self, DetailedUser};
+use log::info;
 use moonlight_common::network::{
-    ApiError, ClientInfo, host_info,
+    ApiError, ClientInfo, HostInfo, host_info,
     request_client::{RequestClient, RequestError},
 };
 use serde::{Deserialize, Serialize};
+use tokio::net::lookup_host;
 use uuid::Uuid;
 
-use crate::app::{
-    AppError, AppRef, MoonlightClient,
-    auth::{SessionToken, UserAuth},
-    host::{Host, HostId},
-    password::StoragePassword,
-    storage::{
-        StorageHostAdd, StorageHostCache, StorageQueryHosts, StorageUser, StorageUserModify,
+use crate::{
+    app::{
+        AppError, AppRef, MoonlightClient,
+        auth::{SessionToken, UserAuth},
+        host::{Host, HostId},
+        password::StoragePassword,
+        storage::{
+            StorageHostAdd, StorageHostCache, StorageQueryHosts, StorageUser, StorageUserModify,
+        },
     },
+    resolve::resolve_host_address,
 };
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
@@ -109,6 +115,8 @@ impl User {
             name: storage.name,
             role: storage.role.into(),
             client_unique_id: storage.client_unique_id,
+            pair_device_name: storage.pair_device_name,
+            client_certificate_fingerprints: storage.client_certificate_fingerprints,
         })
     }
 
@@ -177,6 +185,25 @@ impl User {
                     Err(AppError::Forbidden)
                 }
             }
+            UserAuth::ClientCertificate(certificate) => {
+                let app = self.app.access()?;
+
+                let Some(config) = &app.config.web_server.client_certificate_auth else {
+                    return Err(AppError::Unauthorized);
+                };
+
+                let storage = self.storage_user().await?;
+                if storage
+                    .client_certificate_fingerprints
+                    .contains(&certificate.fingerprint)
+                    || (config.match_common_name
+                        && certificate.common_name.as_deref() == Some(storage.name.as_str()))
+                {
+                    Ok(AuthenticatedUser { inner: self })
+                } else {
+                    Err(AppError::Forbidden)
+                }
+            }
             _ => Err(AppError::Unauthorized),
         }
     }
@@ -246,6 +273,18 @@ impl AuthenticatedUser {
         Ok(user.client_unique_id.clone())
     }
 
+    /// The name shown in the device list of a host after pairing
+    pub async fn pair_device_name(&mut self) -> Result<String, AppError> {
+        let user = self.storage_user().await?;
+        if let Some(pair_device_name) = user.pair_device_name {
+            return Ok(pair_device_name);
+        }
+
+        let app = self.app.access()?;
+
+        Ok(app.config.moonlight.pair_device_name.clone())
+    }
+
     pub async fn hosts(&mut self) -> Result<Vec<Host>, AppError> {
         let app = self.app.access()?;
 
@@ -282,14 +321,21 @@ impl AuthenticatedUser {
         }
     }
 
-    pub async fn host_add(&mut self, address: String, http_port: u16) -> Result<Host, AppError> {
-        let app = self.app.access()?;
-
+    /// Requests the info of the host at this address, fails with HostNotFound if it's unreachable
+    pub async fn probe_host(
+        &mut self,
+        address: &str,
+        http_port: u16,
+    ) -> Result<HostInfo, AppError> {
         let unique_id = self.host_unique_id().await?;
+        let address = {
+            let app = self.app.access()?;
+            resolve_host_address(address, http_port, app.config.ip_preference).await
+        };
 
         let mut client = MoonlightClient::with_defaults().map_err(ApiError::RequestClient)?;
 
-        let info = match host_info(
+        match host_info(
             &mut client,
             false,
             &format!("{}:{}", address, http_port),
@@ -300,12 +346,66 @@ impl AuthenticatedUser {
         )
         .await
         {
-            Ok(info) => info,
-            Err(ApiError::RequestClient(err)) if err.is_connect() => {
-                return Err(AppError::HostNotFound);
-            }
-            Err(err) => return Err(err.into()),
+            Ok(info) => Ok(info),
+            Err(ApiError::RequestClient(err)) if err.is_connect() => Err(AppError::HostNotFound),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Checks all ips the address resolves to against the allowed host addresses of the config,
+    /// so users can't probe the internal network with the host info request.
+    /// Returns the address to connect to: if the address is restricted it's the checked ip,
+    /// a hostname could resolve to another ip on the next lookup.
+    pub async fn check_host_address(
+        &mut self,
+        address: &str,
+        http_port: u16,
+    ) -> Result<String, AppError> {
+        let app = self.app.access()?;
+
+        let security = &app.config.security;
+        if !security.restricts_host_addresses()
+            || (security.host_address_admin_bypass && self.role().await? == Role::Admin)
+        {
+            return Ok(address.to_string());
+        }
+
+        let host = address
+            .strip_prefix('[')
+            .and_then(|address| address.strip_suffix(']'))
+            .unwrap_or(address);
+        let ips = lookup_host((host, http_port))
+            .await
+            .map_err(|_| AppError::HostNotFound)?
+            .map(|address| address.ip())
+            .collect::<Vec<_>>();
+
+        let Some(pinned_ip) = ips.first().copied() else {
+            return Err(AppError::HostNotFound);
         };
+        if let Some(ip) = ips
+            .into_iter()
+            .find(|ip| !security.is_host_address_allowed(*ip))
+        {
+            info!(
+                "[Host]: user {:?} tried to add a host at {address} ({ip}), which isn't allowed",
+                self.id
+            );
+            return Err(AppError::HostAddressNotAllowed);
+        }
+
+        Ok(match pinned_ip {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("[{ip}]"),
+        })
+    }
+
+    pub async fn host_add(&mut self, address: String, http_port: u16) -> Result<Host, AppError> {
+        let address = self.check_host_address(&address, http_port).await?;
+
+        let app = self.app.access()?;
+
+        let info = self.probe_host(&address, http_port).await?;
 
         let host = app
             .storage
@@ -313,10 +413,12 @@ impl AuthenticatedUser {
                 owner: Some(self.id),
                 address,
                 http_port,
+                wake_broadcast_address: None,
                 pair_info: None,
                 cache: StorageHostCache {
                     name: info.host_name,
                     mac: info.mac,
+                    local_ip: info.local_ip.parse().ok(),
                 },
             })
             .await?;
@@ -1,17 +1,121 @@
 use std::{
+    collections::HashMap,
     env,
     net::{IpAddr, SocketAddr},
 };
 
+use anyhow::Context;
 use clap::{Args, Parser, Subcommand};
 use common::{
     api_bindings::RtcIceServer,
     config::{
-        Config, ConfigSsl, ForwardedHeaders, PortRange, WebRtcNat1To1IceCandidateType,
-        WebRtcNat1To1Mapping, WebRtcNetworkType,
+        BindAddresses, Config, ConfigSsl, ForwardedHeaders, PortRange,
+        WebRtcNat1To1IceCandidateType, WebRtcNat1To1Mapping, WebRtcNetworkType,
     },
 };
 use log::LevelFilter;
+use serde_json::{Map, Value};
+
+/// Environment variables with this prefix overwrite values in the config.
+/// Nested values are separated with `__`, e.g. `MOONLIGHT_WEBRTC__PORT_RANGE__MIN`.
+pub const CONFIG_ENV_PREFIX: &str = "MOONLIGHT_";
+
+/// Overlays all `MOONLIGHT_*` environment variables onto the config.
+/// Variables which don't name a config value are skipped with a warning, other variables
+/// with the prefix might belong to something else.
+/// This should run after the config file is loaded and before [CliConfig::apply].
+pub fn apply_env(config: &mut Config) -> Result<(), anyhow::Error> {
+    apply_env_vars(config, env::vars())
+}
+
+fn apply_env_vars(
+    config: &mut Config,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<(), anyhow::Error> {
+    let original = serde_json::to_value(&*config)?;
+    let mut json = original.clone();
+    let mut changed = false;
+    let mut ice_servers_changed = false;
+    // Values which were unset in the config, their type is unknown
+    let mut untyped_values = HashMap::new();
+
+    'vars: for (key, raw_value) in vars {
+        let Some(path) = key.strip_prefix(CONFIG_ENV_PREFIX) else {
+            continue;
+        };
+
+        let path = path.to_lowercase();
+
+        // Nothing is changed until a value is created, so an unknown value can be skipped
+        let mut target = &mut json;
+        let mut pointer = String::new();
+        for segment in path.split("__") {
+            // Values which are null in the config, e.g. `webrtc.port_range`, can be created
+            let created = original.pointer(&pointer).is_none_or(Value::is_null);
+            if target.is_null() {
+                *target = Value::Object(Map::new());
+            }
+
+            let Some(object) = target.as_object_mut() else {
+                eprintln!("ignoring {key}: \"{segment}\" is not inside of an object");
+                continue 'vars;
+            };
+            if !created && !object.contains_key(segment) {
+                eprintln!("ignoring {key}: the config has no value \"{segment}\"");
+                continue 'vars;
+            }
+
+            target = object.entry(segment).or_insert(Value::Null);
+            pointer.push('/');
+            pointer.push_str(segment);
+        }
+
+        // Strings stay strings, e.g. a numeric TURN credential.
+        // Everything else that isn't valid json is used as a string.
+        *target = match &*target {
+            Value::String(_) => Value::String(raw_value),
+            Value::Null => {
+                let value =
+                    serde_json::from_str(&raw_value).unwrap_or(Value::String(raw_value.clone()));
+                untyped_values.insert(path.replace("__", "."), raw_value);
+                value
+            }
+            _ => serde_json::from_str(&raw_value).unwrap_or(Value::String(raw_value)),
+        };
+        changed = true;
+        ice_servers_changed |= path.starts_with("webrtc__ice_servers");
+    }
+
+    if changed {
+        let mut new_config: Config = loop {
+            let err = match serde_path_to_error::deserialize(&json) {
+                Ok(new_config) => break new_config,
+                Err(err) => err,
+            };
+
+            // An unset value which was parsed, but the config expects a string there
+            let path = err.path().to_string();
+            if let Some(raw_value) = untyped_values.remove(&path)
+                && let Some(target) = json.pointer_mut(&format!("/{}", path.replace('.', "/")))
+                && !target.is_string()
+            {
+                *target = Value::String(raw_value);
+                continue;
+            }
+
+            return Err(err).context("the environment variables contain invalid config values");
+        };
+
+        // `is_default` of the ice servers doesn't survive serialization
+        if !ice_servers_changed {
+            new_config.webrtc.ice_servers = std::mem::take(&mut config.webrtc.ice_servers);
+        }
+
+        *config = new_config;
+    }
+
+    Ok(())
+}
 
 impl Cli {
     pub fn load() -> Self {
@@ -81,9 +185,9 @@ pub struct CliConfig {
     /// Overwrites `webrtc.include_loopback_candidates`.
     #[arg(long, env = "WEBRTC_INCLUDE_LOOPBACK_CANDIDATES")]
     pub webrtc_include_loopback_candidates: Option<bool>,
-    /// Overwrites `web_server.bind_address`.
-    #[arg(long, env = "BIND_ADDRESS")]
-    pub bind_address: Option<SocketAddr>,
+    /// Overwrites `web_server.bind_address`. Example: "0.0.0.0:8080,[::]:8080"
+    #[arg(long, env = "BIND_ADDRESS", value_delimiter = ',')]
+    pub bind_address: Option<Vec<SocketAddr>>,
     /// Overwrites `web_server.certificate.certificate_pem`.
     #[arg(long, env = "SSL_CERTIFICATE")]
     pub ssl_certificate: Option<String>,
@@ -135,13 +239,15 @@ impl CliConfig {
             config.webrtc.include_loopback_candidates = webrtc_include_loopback_candidates;
         }
         if let Some(bind_address) = self.bind_address {
-            config.web_server.bind_address = bind_address;
+            config.web_server.bind_address = BindAddresses::List(bind_address);
         }
         match (self.ssl_certificate, self.ssl_private_key) {
             (Some(certificate), Some(private_key)) => {
                 config.web_server.certificate = Some(ConfigSsl {
-                    certificate_pem: certificate,
-                    private_key_pem: private_key,
+                    certificate_pem: Some(certificate),
+                    private_key_pem: Some(private_key),
+                    certificate_pem_inline: None,
+                    private_key_pem_inline: None,
                 })
             }
             (None, None) => {}
@@ -181,3 +287,115 @@ impl CliConfig {
         config.webrtc.ice_servers.extend(self.webrtc_ice_servers);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use common::config::{Config, PortRange};
+
+    use crate::cli::apply_env_vars;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_apply_env() {
+        let mut config = Config::default();
+
+        apply_env_vars(
+            &mut config,
+            vars(&[
+                ("MOONLIGHT_STREAMER_PATH", "/usr/bin/streamer"),
+                ("MOONLIGHT_WEB_SERVER__BIND_ADDRESS", "127.0.0.1:9000"),
+                (
+                    "MOONLIGHT_WEBRTC__PORT_RANGE",
+                    r#"{"min":40000,"max":40100}"#,
+                ),
+                ("UNRELATED", "value"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(config.streamer_path, "/usr/bin/streamer");
+        assert_eq!(
+            config.web_server.bind_address.addresses(),
+            ["127.0.0.1:9000".parse().unwrap()]
+        );
+        assert_eq!(
+            config.webrtc.port_range,
+            Some(PortRange {
+                min: 40000,
+                max: 40100
+            })
+        );
+    }
+
+    #[test]
+    fn test_apply_env_nested_port_range() {
+        let mut config = Config::default();
+
+        apply_env_vars(
+            &mut config,
+            vars(&[
+                ("MOONLIGHT_WEBRTC__PORT_RANGE__MIN", "40000"),
+                ("MOONLIGHT_WEBRTC__PORT_RANGE__MAX", "40100"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.webrtc.port_range,
+            Some(PortRange {
+                min: 40000,
+                max: 40100
+            })
+        );
+    }
+
+    #[test]
+    fn test_apply_env_numeric_string() {
+        let mut config = Config::default();
+
+        apply_env_vars(
+            &mut config,
+            vars(&[
+                ("MOONLIGHT_STREAMER_PATH", "true"),
+                // Unset in the default config
+                ("MOONLIGHT_WEBRTC__TURN_SHARED_SECRET", "123456"),
+                ("MOONLIGHT_WEBRTC__TURN_CREDENTIAL_TTL_SECS", "600"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(config.streamer_path, "true");
+        assert_eq!(config.webrtc.turn_shared_secret.as_deref(), Some("123456"));
+        assert_eq!(config.webrtc.turn_credential_ttl_secs, 600);
+    }
+
+    #[test]
+    fn test_apply_env_unknown_value() {
+        let mut config = Config::default();
+
+        // Unknown values are skipped
+        apply_env_vars(
+            &mut config,
+            vars(&[
+                ("MOONLIGHT_NOT_A_VALUE", "1"),
+                ("MOONLIGHT_STREAMER_PATH__NESTED", "1"),
+                ("MOONLIGHT_STREAMER_PATH", "/usr/bin/streamer"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.streamer_path, "/usr/bin/streamer");
+
+        assert!(
+            apply_env_vars(
+                &mut config,
+                vars(&[("MOONLIGHT_WEBRTC__PORT_RANGE__MIN", "not a number")])
+            )
+            .is_err()
+        );
+    }
+}
@@ -1,22 +1,35 @@
 use common::config::Config;
-use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
-use std::{io::ErrorKind, path::PathBuf, str::FromStr};
-use tokio::fs::{self, File};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io::{self, ErrorKind},
+    net::{SocketAddr, TcpListener},
+    path::PathBuf,
+    str::FromStr,
+};
+#[cfg(unix)]
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::{
+    fs::{self, File},
+    signal::ctrl_c,
+};
 
 use actix_web::{
     App as ActixApp, HttpServer,
+    dev::ServerHandle,
     middleware::{self, Logger},
+    rt::spawn,
     web::{Data, scope},
 };
-use log::{Level, error, info};
+use log::{Level, error, info, warn};
 use simplelog::{ColorChoice, CombinedLogger, SharedLogger, TermLogger, TerminalMode, WriteLogger};
 
 use crate::{
-    api::api_service,
+    api::{api_service, metrics::metrics},
     app::App,
-    cli::{Cli, Command},
+    cli::{Cli, Command, apply_env},
     human_json::preprocess_human_json,
     remote_access::RemoteAccessProvider,
+    tls::{https_acceptor, keep_client_certificate, reload_on_change, ssl_contexts},
     upnp::{UpnpManager, detect_local_ip},
     web::{web_config_js_service, web_service},
 };
@@ -28,7 +41,9 @@ mod web;
 mod cli;
 mod human_json;
 mod remote_access;
+mod resolve;
 mod stun;
+mod tls;
 mod upnp;
 
 #[actix_web::main]
@@ -41,14 +56,24 @@ async fn main() {
         Ok(mut value) => {
             value = preprocess_human_json(value);
 
-            let mut config = serde_json::from_str(&value).expect("invalid file");
+            let mut config = match Config::parse(&value) {
+                Ok(config) => config,
+                Err(err) => {
+                    eprintln!("failed to load config \"{}\": {err}", config_path.display());
+                    std::process::exit(1);
+                }
+            };
+            if let Err(err) = apply_env(&mut config) {
+                eprintln!("failed to apply config environment variables: {err:#}");
+                std::process::exit(1);
+            }
             cli.options.apply(&mut config);
             config
         }
         Err(err) if err.kind() == ErrorKind::NotFound => {
             let mut new_config = Config::default();
-            cli.options.apply(&mut new_config);
 
+            // Only the defaults are written, the environment and cli can contain secrets
             let value_str =
                 serde_json::to_string_pretty(&new_config).expect("failed to serialize file");
 
@@ -61,11 +86,22 @@ async fn main() {
                 .await
                 .expect("failed to write default file");
 
+            if let Err(err) = apply_env(&mut new_config) {
+                eprintln!("failed to apply config environment variables: {err:#}");
+                std::process::exit(1);
+            }
+            cli.options.apply(&mut new_config);
+
             new_config
         }
         Err(err) => panic!("failed to read file: {err}"),
     };
 
+    if let Err(err) = config.validate() {
+        eprintln!("{err}");
+        std::process::exit(1);
+    }
+
     match cli.command {
         Some(Command::PrintConfig) => {
             let json =
@@ -78,7 +114,6 @@ async fn main() {
         }
     }
 
-    // TODO: log config: anonymize ips when enabled in file
     // TODO: https://www.reddit.com/r/csharp/comments/166xgcl/comment/jynybpe/
 
     let mut log_config = simplelog::ConfigBuilder::default();
@@ -121,16 +156,17 @@ async fn start(config: Config) -> Result<(), anyhow::Error> {
     let app = App::new(config.clone()).await?;
     let app = Data::new(app);
 
-    let bind_address = app.config().web_server.bind_address;
+    app.streamer_manager()
+        .kill_orphaned_streamers(&config.streamer_path)
+        .await;
+
+    let bind_address = app.config().web_server.bind_address.clone();
 
     // Initialize UPnP if enabled
     let (upnp_manager, upnp_status) = if config.upnp.enabled {
         let local_ip = detect_local_ip().unwrap_or_else(|| {
             info!("[UPnP] Could not detect local IP, using bind address");
-            match bind_address {
-                std::net::SocketAddr::V4(addr) => *addr.ip(),
-                std::net::SocketAddr::V6(_) => std::net::Ipv4Addr::UNSPECIFIED,
-            }
+            bind_address.ipv4()
         });
 
         let server_port = bind_address.port();
@@ -167,6 +203,8 @@ async fn start(config: Config) -> Result<(), anyhow::Error> {
 
     let server = HttpServer::new({
         let url_path_prefix = config.web_server.url_path_prefix.clone();
+        let log_config = config.log.clone();
+        let metrics_config = config.metrics.clone();
         let app = app.clone();
         let upnp_manager = upnp_manager.clone();
         let remote_access_provider = remote_access_provider.clone();
@@ -177,7 +215,11 @@ async fn start(config: Config) -> Result<(), anyhow::Error> {
                     .app_data(app.clone())
                     .app_data(remote_access_provider.clone())
                     .wrap(
-                        Logger::new("%r took %D ms")
+                        Logger::new("%{client}xi %r took %D ms")
+                            .custom_request_replace("client", {
+                                let log_config = log_config.clone();
+                                move |request| log_config.peer(request.peer_addr())
+                            })
                             .log_target("http_server")
                             .log_level(Level::Debug),
                     )
@@ -191,6 +233,12 @@ async fn start(config: Config) -> Result<(), anyhow::Error> {
                             .add(("Pragma", "no-cache"))
                             .add(("Expires", "0")),
                     )
+                    .configure(|config| {
+                        // Served on the separate bind address instead if one is set
+                        if metrics_config.enabled && metrics_config.bind_address.is_none() {
+                            config.service(metrics);
+                        }
+                    })
                     .service(api_service())
                     .service(web_config_js_service())
                     .service(web_service()),
@@ -205,22 +253,164 @@ async fn start(config: Config) -> Result<(), anyhow::Error> {
         }
     });
 
-    if let Some(certificate) = app.config().web_server.certificate.as_ref() {
+    // Only fails if none of the addresses could be bound
+    let listeners = bind_listeners(bind_address.addresses())?;
+
+    let server = if let Some(certificate) = app.config().web_server.certificate.as_ref() {
         info!("[Server]: Running Https Server with ssl tls");
 
-        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())
-            .expect("failed to create ssl tls acceptor");
-        builder
-            .set_private_key_file(&certificate.private_key_pem, SslFiletype::PEM)
-            .expect("failed to set private key");
-        builder
-            .set_certificate_chain_file(&certificate.certificate_pem)
-            .expect("failed to set certificate");
+        let sni_certificates = &app.config().web_server.sni_certificates;
+        let client_certificate_auth = app.config().web_server.client_certificate_auth.as_ref();
+        if client_certificate_auth.is_some() {
+            info!("[Server]: Verifying tls client certificates");
+        }
+
+        let contexts = ssl_contexts(certificate, sni_certificates, client_certificate_auth)?;
+        spawn(reload_on_change(
+            certificate.clone(),
+            sni_certificates.clone(),
+            client_certificate_auth.cloned(),
+            contexts.clone(),
+        ));
 
-        server.bind_openssl(bind_address, builder)?.run().await?;
+        let mut server = server.on_connect(keep_client_certificate);
+        for listener in listeners {
+            let acceptor = https_acceptor(certificate, client_certificate_auth, &contexts)?;
+            server = server.listen_openssl(listener, acceptor)?;
+        }
+        server
     } else {
-        server.bind(bind_address)?.run().await?;
+        let mut server = server;
+        for listener in listeners {
+            server = server.listen(listener)?;
+        }
+        server
+    };
+
+    for address in server.addrs() {
+        info!("[Server]: Listening on {address}");
+    }
+
+    // UPnP was initialized and the port is bound, so the readiness probe can pass
+    app.set_upnp_ready();
+
+    let server = server
+        // Signals are handled by us so that running streams can stop first
+        .disable_signals()
+        .run();
+
+    if config.metrics.enabled
+        && let Some(metrics_address) = config.metrics.bind_address
+    {
+        info!("[Server]: Serving metrics at http://{metrics_address}/metrics");
+
+        let metrics_server = HttpServer::new({
+            let app = app.clone();
+            let upnp_manager = upnp_manager.clone();
+
+            move || {
+                let mut actix_app = ActixApp::new().app_data(app.clone()).service(metrics);
+                if let Some(ref upnp) = upnp_manager {
+                    actix_app = actix_app.app_data(upnp.clone());
+                }
+
+                actix_app
+            }
+        })
+        .workers(1)
+        .bind(metrics_address)?
+        .disable_signals()
+        .run();
+
+        spawn(metrics_server);
     }
 
+    spawn(drain_on_shutdown_signal(app.clone(), server.handle()));
+
+    server.await?;
+
     Ok(())
 }
+
+/// Binds a listener for every address and skips the ones which fail.
+///
+/// IPv6 listeners are IPv6 only, otherwise `[::]` would already take the port of `0.0.0.0` on
+/// dual stack systems and the second bind of the same port would fail.
+fn bind_listeners(addresses: &[SocketAddr]) -> io::Result<Vec<TcpListener>> {
+    let mut listeners = Vec::new();
+    let mut last_err = None;
+
+    for address in addresses {
+        match bind_listener(*address) {
+            Ok(listener) => listeners.push(listener),
+            Err(err) => {
+                warn!("[Server]: Failed to listen on {address}: {err}");
+                last_err = Some(err);
+            }
+        }
+    }
+
+    match last_err {
+        Some(err) if listeners.is_empty() => Err(err),
+        _ => Ok(listeners),
+    }
+}
+
+fn bind_listener(address: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+
+    Ok(socket.into())
+}
+
+/// Stops all streams on SIGTERM / SIGINT before the http server shuts down
+async fn drain_on_shutdown_signal(app: Data<App>, server_handle: ServerHandle) {
+    shutdown_signal().await;
+
+    let streamer_manager = app.streamer_manager();
+    let active = streamer_manager.process_count().await;
+    info!("[Server]: Shutting down, stopping {active} active streams");
+
+    streamer_manager.begin_shutdown();
+    let remaining = streamer_manager
+        .wait_until_empty(app.config().shutdown.drain_timeout())
+        .await;
+    if remaining > 0 {
+        warn!("[Server]: {remaining} streams didn't stop in time");
+    }
+    info!(
+        "[Server]: Drained {} of {active} streams",
+        active - remaining.min(active)
+    );
+
+    server_handle.stop(true).await;
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = ctrl_c() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        if let Err(err) = ctrl_c().await {
+            error!("[Server]: failed to listen for ctrl c: {err:?}");
+        }
+    }
+}
@@ -6,7 +6,7 @@
 
 use common::{
     api_bindings::{RemoteAccessInfo, RtcIceServer},
-    config::{Config, RemoteConfig},
+    config::{Config, IpPreference, RemoteConfig},
 };
 use log::info;
 
@@ -69,7 +69,8 @@ fn build_remote_access_info(
     let remote_config = &config.remote;
 
     // Determine external IP and discovery method
-    let (external_ip, discovery_method) = discover_external_ip(remote_config, upnp_status);
+    let (external_ip, discovery_method) =
+        discover_external_ip(remote_config, config.ip_preference, upnp_status);
 
     // Get hostname from config (user-provided)
     let hostname = remote_config.hostname.clone();
@@ -93,7 +94,7 @@ fn build_remote_access_info(
     }
 
     // Detect NAT type and TURN recommendation
-    let (nat_type, turn_recommended) = detect_nat_info(remote_config);
+    let (nat_type, turn_recommended) = detect_nat_info(remote_config, config.ip_preference);
 
     // Build ICE servers list (include TURN if configured)
     let ice_servers = build_ice_servers(config);
@@ -113,6 +114,7 @@ fn build_remote_access_info(
 /// Discover external IP using UPnP and/or STUN.
 fn discover_external_ip(
     remote_config: &RemoteConfig,
+    ip_preference: IpPreference,
     upnp_status: Option<&UpnpStatus>,
 ) -> (Option<String>, String) {
     // First, check UPnP
@@ -124,7 +126,7 @@ fn discover_external_ip(
 
     // Fall back to STUN if enabled
     if remote_config.stun_discovery {
-        let stun_client = StunClient::new();
+        let stun_client = StunClient::new().with_ip_preference(ip_preference);
         match stun_client.get_external_address() {
             Ok(result) => {
                 return (Some(result.external_ip.to_string()), "stun".to_string());
@@ -139,12 +141,12 @@ fn discover_external_ip(
 }
 
 /// Detect NAT type and whether TURN is recommended.
-fn detect_nat_info(remote_config: &RemoteConfig) -> (String, bool) {
+fn detect_nat_info(remote_config: &RemoteConfig, ip_preference: IpPreference) -> (String, bool) {
     if !remote_config.stun_discovery {
         return ("unknown".to_string(), false);
     }
 
-    let stun_client = StunClient::new();
+    let stun_client = StunClient::new().with_ip_preference(ip_preference);
     let result = stun_client.detect_nat_type();
 
     if result.success {
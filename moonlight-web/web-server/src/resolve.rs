@@ -0,0 +1,245 @@
+//! Resolves host addresses with the configured ip family preference, see IpPreference
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use common::config::IpPreference;
+use futures::{StreamExt, stream::FuturesUnordered};
+use log::debug;
+use tokio::{
+    net::{TcpStream, lookup_host},
+    time::{sleep, timeout},
+};
+
+/// The head start of a connection attempt over the next one, like in RFC 8305
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Happy eyeballs gives up after this and the address is used unresolved
+const HAPPY_EYEBALLS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The address which connected first is reused for this long,
+/// so not every host request waits for the connection attempts
+const HAPPY_EYEBALLS_CACHE_DURATION: Duration = Duration::from_secs(5 * 60);
+/// If no address connected the host is probably offline, it's not tried again for this long
+const HAPPY_EYEBALLS_FAILURE_CACHE_DURATION: Duration = Duration::from_secs(30);
+
+struct HappyEyeballsWinner {
+    /// None if no address connected
+    address: Option<SocketAddr>,
+    expires_at: Instant,
+}
+
+/// The winners by hostname and port
+static HAPPY_EYEBALLS_WINNERS: LazyLock<Mutex<HashMap<(String, u16), HappyEyeballsWinner>>> =
+    LazyLock::new(Default::default);
+
+/// Orders the addresses by the preference, happy eyeballs alternates the families
+/// starting with the family of the first resolved address
+pub fn order_addresses(addresses: Vec<SocketAddr>, preference: IpPreference) -> Vec<SocketAddr> {
+    let prefer_ipv4 = match preference {
+        IpPreference::Ipv4 => true,
+        IpPreference::Ipv6 => false,
+        IpPreference::HappyEyeballs => addresses.first().is_none_or(SocketAddr::is_ipv4),
+    };
+
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addresses
+        .into_iter()
+        .partition(|address| address.is_ipv4() == prefer_ipv4);
+
+    if preference != IpPreference::HappyEyeballs {
+        preferred.append(&mut other);
+        return preferred;
+    }
+
+    let mut ordered = Vec::with_capacity(preferred.len() + other.len());
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (first, second) => ordered.extend(first.into_iter().chain(second)),
+        }
+    }
+
+    ordered
+}
+
+/// Resolves an ip or a hostname, ipv6 addresses may be in brackets
+pub async fn resolve(address: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let host = address
+        .strip_prefix('[')
+        .and_then(|address| address.strip_suffix(']'))
+        .unwrap_or(address);
+
+    Ok(lookup_host((host, port)).await?.collect())
+}
+
+/// Resolves the address of a host to the ip which is connected to, in the same format as the address.
+/// The address is returned unchanged if it's already an ip or if it couldn't be resolved
+pub async fn resolve_host_address(address: &str, port: u16, preference: IpPreference) -> String {
+    let host = address
+        .strip_prefix('[')
+        .and_then(|address| address.strip_suffix(']'))
+        .unwrap_or(address);
+    if host.parse::<IpAddr>().is_ok() {
+        return address.to_string();
+    }
+
+    let addresses = match resolve(host, port).await {
+        Ok(addresses) => order_addresses(addresses, preference),
+        Err(err) => {
+            debug!("Failed to resolve host address {address}: {err}");
+            return address.to_string();
+        }
+    };
+
+    let resolved = match preference {
+        IpPreference::HappyEyeballs => cached_happy_eyeballs(host, port, &addresses).await,
+        IpPreference::Ipv4 | IpPreference::Ipv6 => addresses.first().copied(),
+    };
+
+    match resolved {
+        Some(resolved) => {
+            debug!("Resolved host address {address} to {}", resolved.ip());
+            format_host(resolved.ip())
+        }
+        None => address.to_string(),
+    }
+}
+
+/// Returns the last winner of the host if it's still resolved, otherwise races the addresses again
+async fn cached_happy_eyeballs(
+    host: &str,
+    port: u16,
+    addresses: &[SocketAddr],
+) -> Option<SocketAddr> {
+    let key = (host.to_string(), port);
+
+    if let Ok(winners) = HAPPY_EYEBALLS_WINNERS.lock()
+        && let Some(winner) = winners.get(&key)
+        && Instant::now() < winner.expires_at
+        && winner
+            .address
+            .is_none_or(|address| addresses.contains(&address))
+    {
+        return winner.address;
+    }
+
+    let address = happy_eyeballs(addresses).await;
+
+    let now = Instant::now();
+    let cache_duration = match address {
+        Some(_) => HAPPY_EYEBALLS_CACHE_DURATION,
+        None => HAPPY_EYEBALLS_FAILURE_CACHE_DURATION,
+    };
+    if let Ok(mut winners) = HAPPY_EYEBALLS_WINNERS.lock() {
+        winners.retain(|_, winner| now < winner.expires_at);
+        winners.insert(
+            key,
+            HappyEyeballsWinner {
+                address,
+                expires_at: now + cache_duration,
+            },
+        );
+    }
+
+    address
+}
+
+/// Connects to the addresses one after another without waiting for the previous attempt to fail,
+/// the address of the first connection is returned
+async fn happy_eyeballs(addresses: &[SocketAddr]) -> Option<SocketAddr> {
+    let mut attempts = addresses
+        .iter()
+        .enumerate()
+        .map(|(index, address)| async move {
+            sleep(CONNECTION_ATTEMPT_DELAY * index as u32).await;
+            TcpStream::connect(address).await.map(|_| *address)
+        })
+        .collect::<FuturesUnordered<_>>();
+
+    timeout(HAPPY_EYEBALLS_TIMEOUT, async {
+        while let Some(result) = attempts.next().await {
+            match result {
+                Ok(address) => return Some(address),
+                Err(err) => debug!("Happy eyeballs connection attempt failed: {err}"),
+            }
+        }
+        None
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+fn format_host(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{ip}]"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use common::config::IpPreference;
+    use tokio::net::TcpListener;
+
+    use crate::resolve::{order_addresses, resolve_host_address};
+
+    fn addresses(addresses: &[&str]) -> Vec<SocketAddr> {
+        addresses
+            .iter()
+            .map(|address| address.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_order_addresses() {
+        let resolved = addresses(&["[::1]:80", "[::2]:80", "127.0.0.1:80", "127.0.0.2:80"]);
+
+        assert_eq!(
+            order_addresses(resolved.clone(), IpPreference::Ipv4),
+            addresses(&["127.0.0.1:80", "127.0.0.2:80", "[::1]:80", "[::2]:80"])
+        );
+        assert_eq!(
+            order_addresses(resolved.clone(), IpPreference::Ipv6),
+            resolved
+        );
+        assert_eq!(
+            order_addresses(resolved, IpPreference::HappyEyeballs),
+            addresses(&["[::1]:80", "127.0.0.1:80", "[::2]:80", "127.0.0.2:80"])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_host_address() {
+        // Ips aren't resolved
+        assert_eq!(
+            resolve_host_address("[::1]", 80, IpPreference::Ipv4).await,
+            "[::1]"
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // Only the ipv4 address of localhost accepts connections
+        assert_eq!(
+            resolve_host_address("localhost", port, IpPreference::HappyEyeballs).await,
+            "127.0.0.1"
+        );
+
+        // The winner is reused without connecting again
+        drop(listener);
+        assert_eq!(
+            resolve_host_address("localhost", port, IpPreference::HappyEyeballs).await,
+            "127.0.0.1"
+        );
+    }
+}
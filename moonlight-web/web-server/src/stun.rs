@@ -5,12 +5,16 @@
 //! connections are possible.
 
 use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::atomic::{AtomicU64, Ordering},
     time::Duration,
 };
 
 use bytecodec::{DecodeExt, EncodeExt};
+use common::{api_bindings::RtcIceServer, config::IpPreference};
 use log::{debug, info, warn};
+use serde::Deserialize;
 use stun_codec::{
     rfc5389::{
         attributes::Software,
@@ -19,6 +23,13 @@ use stun_codec::{
     },
     Message, MessageClass, MessageDecoder, MessageEncoder, TransactionId,
 };
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+use crate::resolve::order_addresses;
 
 /// Default STUN servers to use for NAT detection
 pub const DEFAULT_STUN_SERVERS: &[&str] = &[
@@ -28,6 +39,43 @@ pub const DEFAULT_STUN_SERVERS: &[&str] = &[
     "stun.cloudflare.com:3478",
 ];
 
+/// Port used if a `stun:` url doesn't contain one
+const DEFAULT_STUN_PORT: u16 = 3478;
+
+// Every binding request since the start, exported by the metrics endpoint
+static BINDING_REQUESTS: AtomicU64 = AtomicU64::new(0);
+static FAILED_BINDING_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns how many binding requests were sent and how many of them failed
+pub fn binding_request_counts() -> (u64, u64) {
+    (
+        BINDING_REQUESTS.load(Ordering::Relaxed),
+        FAILED_BINDING_REQUESTS.load(Ordering::Relaxed),
+    )
+}
+
+/// Extracts the `host:port` of every `stun:` url of the ice servers
+pub fn stun_servers_from_ice_servers(ice_servers: &[RtcIceServer]) -> Vec<String> {
+    ice_servers
+        .iter()
+        .flat_map(|server| server.urls.iter())
+        .filter_map(|url| url.strip_prefix("stun:"))
+        .map(|address| {
+            // Remove query parameters like ?transport=udp
+            let address = address.split('?').next().unwrap_or(address);
+
+            let has_port = address
+                .rsplit_once(':')
+                .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+            if has_port {
+                address.to_string()
+            } else {
+                format!("{address}:{DEFAULT_STUN_PORT}")
+            }
+        })
+        .collect()
+}
+
 /// NAT type classification based on RFC 3489
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum NatType {
@@ -114,9 +162,11 @@ pub struct NatDetectionResult {
 }
 
 /// STUN client for NAT detection
+#[derive(Clone)]
 pub struct StunClient {
     timeout: Duration,
     stun_servers: Vec<String>,
+    ip_preference: IpPreference,
 }
 
 impl Default for StunClient {
@@ -134,6 +184,7 @@ impl StunClient {
                 .iter()
                 .map(|s| s.to_string())
                 .collect(),
+            ip_preference: IpPreference::Ipv4,
         }
     }
 
@@ -142,6 +193,29 @@ impl StunClient {
         Self {
             timeout: Duration::from_secs(3),
             stun_servers: servers,
+            ip_preference: IpPreference::Ipv4,
+        }
+    }
+
+    /// Which address is queried first if a server resolves to ipv4 and ipv6 addresses.
+    /// The NAT detection compares the mapped addresses of several servers, so they're all queried
+    /// over ipv4 unless ipv6 is configured explicitly
+    pub fn with_ip_preference(mut self, ip_preference: IpPreference) -> Self {
+        self.ip_preference = match ip_preference {
+            IpPreference::Ipv6 => IpPreference::Ipv6,
+            IpPreference::Ipv4 | IpPreference::HappyEyeballs => IpPreference::Ipv4,
+        };
+        self
+    }
+
+    /// Create a STUN client with the STUN servers of the configured ice servers,
+    /// uses the default servers if none are configured
+    pub fn from_ice_servers(ice_servers: &[RtcIceServer]) -> Self {
+        let servers = stun_servers_from_ice_servers(ice_servers);
+        if servers.is_empty() {
+            Self::new()
+        } else {
+            Self::with_servers(servers)
         }
     }
 
@@ -160,31 +234,71 @@ impl StunClient {
     }
 
     /// Perform STUN binding request to a specific server
-    fn binding_request(&self, server: &str) -> Result<StunResult, String> {
-        // Resolve server address (prefer IPv4)
-        let server_addr: SocketAddr = server
-            .parse()
-            .or_else(|_| {
-                // Try to resolve hostname, preferring IPv4
-                use std::net::ToSocketAddrs;
-                let addrs: Vec<_> = server
-                    .to_socket_addrs()
-                    .map_err(|e| format!("Failed to resolve {}: {}", server, e))?
-                    .collect();
-                
-                // Prefer IPv4 addresses
-                addrs
-                    .iter()
-                    .find(|a| a.is_ipv4())
-                    .or(addrs.first())
-                    .copied()
-                    .ok_or_else(|| format!("No addresses found for {}", server))
-            })
-            .map_err(|e| format!("Invalid server address {}: {}", server, e))?;
-
-        // Create UDP socket
-        let socket = UdpSocket::bind("0.0.0.0:0")
-            .map_err(|e| format!("Failed to bind UDP socket: {}", e))?;
+    pub fn binding_request(&self, server: &str) -> Result<StunResult, String> {
+        BINDING_REQUESTS.fetch_add(1, Ordering::Relaxed);
+
+        let result = self.send_binding_request(server);
+        if result.is_err() {
+            FAILED_BINDING_REQUESTS.fetch_add(1, Ordering::Relaxed);
+        }
+
+        result
+    }
+
+    fn send_binding_request(&self, server: &str) -> Result<StunResult, String> {
+        // The first address of every family, in the order of the preference
+        let mut server_addrs: Vec<SocketAddr> = Vec::new();
+        for addr in self.resolve_server(server)? {
+            if !server_addrs
+                .iter()
+                .any(|server_addr| server_addr.is_ipv4() == addr.is_ipv4())
+            {
+                server_addrs.push(addr);
+            }
+        }
+
+        let mut last_error = format!("No addresses found for {}", server);
+        for server_addr in server_addrs {
+            match self.query_server(server, server_addr) {
+                Ok(result) => return Ok(result),
+                Err(e) => {
+                    debug!(
+                        "[STUN] Failed to query {} at {}: {}",
+                        server, server_addr, e
+                    );
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// The addresses of the server, ordered by the ip preference
+    fn resolve_server(&self, server: &str) -> Result<Vec<SocketAddr>, String> {
+        let addrs: Vec<SocketAddr> = match server.parse() {
+            Ok(addr) => vec![addr],
+            Err(_) => server
+                .to_socket_addrs()
+                .map_err(|e| format!("Failed to resolve {}: {}", server, e))?
+                .collect(),
+        };
+
+        if addrs.is_empty() {
+            return Err(format!("No addresses found for {}", server));
+        }
+
+        Ok(order_addresses(addrs, self.ip_preference))
+    }
+
+    fn query_server(&self, server: &str, server_addr: SocketAddr) -> Result<StunResult, String> {
+        // Create UDP socket of the same family as the server
+        let bind_addr = if server_addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket =
+            UdpSocket::bind(bind_addr).map_err(|e| format!("Failed to bind UDP socket: {}", e))?;
 
         socket
             .set_read_timeout(Some(self.timeout))
@@ -264,79 +378,72 @@ impl StunClient {
             }
         };
 
-        // Check for CGNAT (100.64.0.0/10)
-        if is_cgnat_address(first_result.external_ip) {
-            info!("[STUN] Detected Carrier-Grade NAT (CGNAT)");
-            return NatDetectionResult {
-                nat_type: NatType::CarrierGradeNat,
-                external_ip: Some(first_result.external_ip),
-                external_port: Some(first_result.external_port),
-                success: true,
-                error: None,
-            };
-        }
+        // Step 2: Query a second STUN server to detect Symmetric NAT
+        let second_result = self
+            .stun_servers
+            .get(1)
+            .and_then(|second_server| self.binding_request(second_server).ok());
 
-        // Check if we have a direct public IP (no NAT)
-        if let Some(local_ip) = detect_local_ip() {
-            if local_ip == first_result.external_ip {
-                info!("[STUN] No NAT detected - direct public IP");
-                return NatDetectionResult {
-                    nat_type: NatType::None,
-                    external_ip: Some(first_result.external_ip),
-                    external_port: Some(first_result.external_port),
-                    success: true,
-                    error: None,
-                };
-            }
-        }
+        classify_nat(&first_result, second_result.as_ref())
+    }
 
-        // Step 2: Query a second STUN server to detect Symmetric NAT
-        if self.stun_servers.len() >= 2 {
-            let second_server = &self.stun_servers[1];
-            if let Ok(second_result) = self.binding_request(second_server) {
-                // If external port differs between servers, it's Symmetric NAT
-                if second_result.external_port != first_result.external_port {
-                    info!(
-                        "[STUN] Symmetric NAT detected - ports differ: {} vs {}",
-                        first_result.external_port, second_result.external_port
-                    );
-                    return NatDetectionResult {
-                        nat_type: NatType::Symmetric,
-                        external_ip: Some(first_result.external_ip),
-                        external_port: Some(first_result.external_port),
-                        success: true,
-                        error: None,
-                    };
-                }
+    /// The servers which are queried, in order
+    pub fn servers(&self) -> &[String] {
+        &self.stun_servers
+    }
+}
 
-                // If external IP differs, might be load-balanced or complex NAT
-                if second_result.external_ip != first_result.external_ip {
-                    info!(
-                        "[STUN] External IPs differ between servers - may be Double NAT or load balanced"
-                    );
-                    return NatDetectionResult {
-                        nat_type: NatType::DoubleNat,
-                        external_ip: Some(first_result.external_ip),
-                        external_port: Some(first_result.external_port),
-                        success: true,
-                        error: None,
-                    };
-                }
-            }
+/// Classifies the NAT with the external address of one server and optionally of a second server
+pub fn classify_nat(
+    first_result: &StunResult,
+    second_result: Option<&StunResult>,
+) -> NatDetectionResult {
+    let detected = |nat_type| NatDetectionResult {
+        nat_type,
+        external_ip: Some(first_result.external_ip),
+        external_port: Some(first_result.external_port),
+        success: true,
+        error: None,
+    };
+
+    // Check for CGNAT (100.64.0.0/10)
+    if is_cgnat_address(first_result.external_ip) {
+        info!("[STUN] Detected Carrier-Grade NAT (CGNAT)");
+        return detected(NatType::CarrierGradeNat);
+    }
+
+    // Check if we have a direct public IP (no NAT)
+    if let Some(local_ip) = detect_local_ip()
+        && local_ip == first_result.external_ip
+    {
+        info!("[STUN] No NAT detected - direct public IP");
+        return detected(NatType::None);
+    }
+
+    if let Some(second_result) = second_result {
+        // If external port differs between servers, it's Symmetric NAT
+        if second_result.external_port != first_result.external_port {
+            info!(
+                "[STUN] Symmetric NAT detected - ports differ: {} vs {}",
+                first_result.external_port, second_result.external_port
+            );
+            return detected(NatType::Symmetric);
         }
 
-        // If we got here with consistent results, assume Port Restricted (most common)
-        // Full detection of Full Cone vs Restricted vs Port Restricted requires
-        // CHANGE-REQUEST which most modern STUN servers don't support
-        info!("[STUN] NAT detected - assuming Port Restricted (most common)");
-        NatDetectionResult {
-            nat_type: NatType::PortRestricted,
-            external_ip: Some(first_result.external_ip),
-            external_port: Some(first_result.external_port),
-            success: true,
-            error: None,
+        // If external IP differs, might be load-balanced or complex NAT
+        if second_result.external_ip != first_result.external_ip {
+            info!(
+                "[STUN] External IPs differ between servers - may be Double NAT or load balanced"
+            );
+            return detected(NatType::DoubleNat);
         }
     }
+
+    // If we got here with consistent results, assume Port Restricted (most common)
+    // Full detection of Full Cone vs Restricted vs Port Restricted requires
+    // CHANGE-REQUEST which most modern STUN servers don't support
+    info!("[STUN] NAT detected - assuming Port Restricted (most common)");
+    detected(NatType::PortRestricted)
 }
 
 /// Extract mapped address from STUN response
@@ -419,14 +526,169 @@ fn detect_local_ip() -> Option<Ipv4Addr> {
     }
 }
 
+/// Timeout used for every step of the port accessibility check
+const PORT_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Check if a port is accessible from the internet
-/// This makes an HTTP request to a port-checking service
-pub async fn check_port_accessible(ip: Ipv4Addr, port: u16) -> Result<bool, String> {
-    // For now, we'll just return unknown - implementing a reliable port check
-    // requires either a callback service or specific port-check API
-    // This is a placeholder for future implementation
-    debug!("[STUN] Port accessibility check not yet implemented for {}:{}", ip, port);
-    Ok(false)
+///
+/// If a `callback_url` is set the external service is asked to connect back to us.
+/// The placeholders `{ip}` and `{port}` in the url are replaced and the service must
+/// answer with json of the form `{ "open": bool }`.
+///
+/// Otherwise the port is checked by connecting to our own external ip (loopback via the router).
+/// This only works if the router supports NAT loopback / hairpinning, so a `false` may be
+/// inconclusive.
+pub async fn check_port_accessible(
+    ip: IpAddr,
+    port: u16,
+    callback_url: Option<&str>,
+) -> Result<bool, String> {
+    let unspecified = match ip {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+
+    // Keep a short-lived listener open so the port answers even if nothing is bound to it yet.
+    // If something is already bound to it (e.g. the web server itself) we rely on that listener.
+    let listener = match TcpListener::bind(SocketAddr::new(unspecified, port)).await {
+        Ok(listener) => Some(listener),
+        Err(err) if err.kind() == ErrorKind::AddrInUse => None,
+        Err(err) => {
+            return Err(format!(
+                "Failed to bind port check listener on {port}: {err}"
+            ));
+        }
+    };
+
+    let check = async {
+        match callback_url {
+            Some(callback_url) => check_port_via_callback(callback_url, ip, port).await,
+            None => check_port_via_loopback(ip, port, listener.is_some()).await,
+        }
+    };
+
+    let accessible = match &listener {
+        Some(listener) => {
+            tokio::select! {
+                result = check => result?,
+                err = answer_port_checks(listener) => {
+                    return Err(format!("Port check listener failed to accept: {err}"));
+                }
+            }
+        }
+        None => check.await?,
+    };
+
+    info!(
+        "[STUN] Port {} is {}",
+        SocketAddr::new(ip, port),
+        if accessible { "accessible" } else { "not accessible" }
+    );
+
+    Ok(accessible)
+}
+
+/// Sent by the temporary listener so the loopback check knows it reached us
+const PORT_CHECK_MAGIC: &[u8] = b"moonlight-web-port-check";
+
+/// Answers every connection with the magic until accepting fails
+async fn answer_port_checks(listener: &TcpListener) -> io::Error {
+    loop {
+        match listener.accept().await {
+            Ok((mut stream, addr)) => {
+                debug!("[STUN] Port check connection from {}", addr);
+                let _ = stream.write_all(PORT_CHECK_MAGIC).await;
+            }
+            Err(err) => return err,
+        }
+    }
+}
+
+/// Connects to our own external address.
+///
+/// If the temporary listener runs it must answer with the magic, otherwise the connection could
+/// have reached another device. A service which already listened on the port can't answer with
+/// it, so reaching the port is all that can be checked then.
+async fn check_port_via_loopback(
+    ip: IpAddr,
+    port: u16,
+    expect_magic: bool,
+) -> Result<bool, String> {
+    let address = SocketAddr::new(ip, port);
+
+    let mut stream = match timeout(PORT_CHECK_TIMEOUT, TcpStream::connect(address)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(err)) => {
+            debug!("[STUN] Loopback port check to {} failed: {}", address, err);
+            return Ok(false);
+        }
+        Err(_) => {
+            debug!("[STUN] Loopback port check to {} timed out", address);
+            return Ok(false);
+        }
+    };
+
+    if !expect_magic {
+        return Ok(true);
+    }
+
+    let mut buffer = [0u8; PORT_CHECK_MAGIC.len()];
+    match timeout(PORT_CHECK_TIMEOUT, stream.read_exact(&mut buffer)).await {
+        Ok(Ok(_)) if buffer == PORT_CHECK_MAGIC => Ok(true),
+        Ok(Ok(_)) => {
+            debug!(
+                "[STUN] Port check reached a different service on {}",
+                address
+            );
+            Ok(false)
+        }
+        Ok(Err(err)) => {
+            debug!("[STUN] Port check to {} got no answer: {}", address, err);
+            Ok(false)
+        }
+        Err(_) => {
+            debug!(
+                "[STUN] Port check to {} timed out waiting for an answer",
+                address
+            );
+            Ok(false)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PortCheckResponse {
+    open: bool,
+}
+
+async fn check_port_via_callback(
+    callback_url: &str,
+    ip: IpAddr,
+    port: u16,
+) -> Result<bool, String> {
+    let url = callback_url
+        .replace("{ip}", &ip.to_string())
+        .replace("{port}", &port.to_string());
+
+    let client = reqwest::Client::builder()
+        .timeout(PORT_CHECK_TIMEOUT * 2)
+        .build()
+        .map_err(|e| format!("Failed to create port check client: {}", e))?;
+
+    let response = client
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| format!("Port check request to {} failed: {}", url, e))?
+        .error_for_status()
+        .map_err(|e| format!("Port check service returned an error: {}", e))?;
+
+    let response = response
+        .json::<PortCheckResponse>()
+        .await
+        .map_err(|e| format!("Port check service returned invalid json: {}", e))?;
+
+    Ok(response.open)
 }
 
 #[cfg(test)]
@@ -442,11 +704,96 @@ mod tests {
         assert!(!is_cgnat_address(Ipv4Addr::new(192, 168, 1, 1)));
     }
 
+    #[test]
+    fn test_stun_servers_from_ice_servers() {
+        let ice_servers = vec![
+            RtcIceServer {
+                is_default: false,
+                urls: vec![
+                    "stun:stun.example.com:19302".to_string(),
+                    "stun:stun2.example.com".to_string(),
+                ],
+                username: String::new(),
+                credential: String::new(),
+            },
+            RtcIceServer {
+                is_default: false,
+                urls: vec![
+                    "turn:turn.example.com:3478?transport=udp".to_string(),
+                    "turns:turn.example.com:5349".to_string(),
+                ],
+                username: "user".to_string(),
+                credential: "password".to_string(),
+            },
+        ];
+
+        assert_eq!(
+            stun_servers_from_ice_servers(&ice_servers),
+            vec![
+                "stun.example.com:19302".to_string(),
+                "stun2.example.com:3478".to_string()
+            ]
+        );
+        assert!(stun_servers_from_ice_servers(&[]).is_empty());
+    }
+
     #[test]
     fn test_nat_type_str() {
         assert_eq!(NatType::None.as_str(), "none");
         assert_eq!(NatType::Symmetric.as_str(), "symmetric");
         assert_eq!(NatType::CarrierGradeNat.as_str(), "carrier_grade_nat");
     }
+
+    #[tokio::test]
+    async fn test_port_check_verifies_answer() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        // Only our temporary listener answers with the magic
+        let own = TcpListener::bind((localhost, 0)).await.unwrap();
+        let own_port = own.local_addr().unwrap().port();
+        let other = TcpListener::bind((localhost, 0)).await.unwrap();
+        let other_port = other.local_addr().unwrap().port();
+        tokio::spawn(async move { answer_port_checks(&own).await });
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = other.accept().await {
+                let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+            }
+        });
+
+        assert!(
+            check_port_via_loopback(localhost, own_port, true)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !check_port_via_loopback(localhost, other_port, true)
+                .await
+                .unwrap()
+        );
+        // A service which was already listening can't know the magic
+        assert!(
+            check_port_via_loopback(localhost, other_port, false)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_port_check_with_temporary_listener() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        let free_port = TcpListener::bind((localhost, 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        assert!(
+            check_port_accessible(localhost, free_port, None)
+                .await
+                .unwrap()
+        );
+    }
 }
 
@@ -0,0 +1,380 @@
+//! Https certificate loading and reloading
+//!
+//! The acceptor given to actix can't be replaced, so every handshake swaps in the latest context
+//! in the servername callback, which OpenSSL also calls if the client doesn't send a server name.
+//! The same callback picks the certificate of the SNI hostname, if one is configured for it.
+//! Connections which are already established keep the certificate they were created with.
+//!
+//! If client certificate auth is configured, every context verifies the client certificates
+//! against the configured CA and the verified certificate is kept with the connection.
+
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+    fs,
+    path::{PathBuf, absolute},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::{dev::Extensions, rt::net::TcpStream};
+use anyhow::{Context, anyhow};
+use common::config::{ClientCertificateAuth, ConfigSsl, PemSource, SniCertificate};
+use log::{debug, info, warn};
+use notify::{RecursiveMode, Watcher, recommended_watcher};
+use openssl::{
+    base64,
+    pkey::PKey,
+    ssl::{
+        NameType, SslAcceptor, SslAcceptorBuilder, SslContext, SslFiletype, SslMethod,
+        SslVerifyMode,
+    },
+    x509::X509,
+};
+use tokio::{
+    sync::mpsc::unbounded_channel,
+    time::{Instant, sleep_until},
+};
+
+use crate::app::auth::ClientCertificate;
+
+/// Certificates are often written in multiple steps, so reloading waits until no more changes arrive
+const RELOAD_DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// The contexts which are used for new connections
+pub struct SslContexts {
+    default: SslContext,
+    /// Keys are lowercase
+    by_hostname: HashMap<String, SslContext>,
+}
+
+impl SslContexts {
+    fn load(
+        certificate: &ConfigSsl,
+        sni_certificates: &[SniCertificate],
+        client_certificate_auth: Option<&ClientCertificateAuth>,
+    ) -> Result<Self, anyhow::Error> {
+        let default = load_acceptor(certificate, client_certificate_auth)?
+            .build()
+            .into_context();
+
+        let mut by_hostname = HashMap::new();
+        for sni_certificate in sni_certificates {
+            let context = load_acceptor(&sni_certificate.certificate, client_certificate_auth)
+                .with_context(|| {
+                    format!(
+                        "failed to load the certificate for \"{}\"",
+                        sni_certificate.hostname
+                    )
+                })?
+                .build()
+                .into_context();
+
+            by_hostname.insert(
+                sni_certificate.hostname.trim().to_ascii_lowercase(),
+                context,
+            );
+        }
+
+        Ok(Self {
+            default,
+            by_hostname,
+        })
+    }
+
+    /// Unknown or missing server names get the default certificate
+    fn select(&self, server_name: Option<&str>) -> &SslContext {
+        server_name
+            .and_then(|server_name| self.by_hostname.get(&server_name.to_ascii_lowercase()))
+            .unwrap_or(&self.default)
+    }
+}
+
+/// Loads the contexts which are used for new connections, they're shared by all listeners
+pub fn ssl_contexts(
+    certificate: &ConfigSsl,
+    sni_certificates: &[SniCertificate],
+    client_certificate_auth: Option<&ClientCertificateAuth>,
+) -> Result<Arc<RwLock<SslContexts>>, anyhow::Error> {
+    Ok(Arc::new(RwLock::new(SslContexts::load(
+        certificate,
+        sni_certificates,
+        client_certificate_auth,
+    )?)))
+}
+
+/// Creates the acceptor of one actix listener, every connection uses the latest shared context
+pub fn https_acceptor(
+    certificate: &ConfigSsl,
+    client_certificate_auth: Option<&ClientCertificateAuth>,
+    contexts: &Arc<RwLock<SslContexts>>,
+) -> Result<SslAcceptorBuilder, anyhow::Error> {
+    // The verify mode of a connection is taken from this acceptor, even if the context is swapped
+    let mut builder = load_acceptor(certificate, client_certificate_auth)?;
+    builder.set_servername_callback({
+        let contexts = contexts.clone();
+        move |ssl, _| {
+            let context = contexts
+                .read()
+                .expect("poisoned ssl context")
+                .select(ssl.servername(NameType::HOST_NAME))
+                .clone();
+            if let Err(err) = ssl.set_ssl_context(&context) {
+                warn!("[Tls]: failed to use the latest certificate for a connection: {err}");
+            }
+            Ok(())
+        }
+    });
+
+    Ok(builder)
+}
+
+fn load_acceptor(
+    certificate: &ConfigSsl,
+    client_certificate_auth: Option<&ClientCertificateAuth>,
+) -> Result<SslAcceptorBuilder, anyhow::Error> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())
+        .context("failed to create ssl tls acceptor")?;
+
+    // The config was validated, so both sources are set
+    match certificate.private_key() {
+        Some(PemSource::File(path)) => builder
+            .set_private_key_file(path, SslFiletype::PEM)
+            .with_context(|| format!("failed to set private key from \"{path}\""))?,
+        Some(PemSource::Inline(pem)) => {
+            let private_key = PKey::private_key_from_pem(&inline_pem(pem)?)
+                .context("failed to parse inline private key")?;
+            builder
+                .set_private_key(&private_key)
+                .context("failed to set private key")?
+        }
+        None => return Err(anyhow!("the private key isn't set")),
+    }
+    match certificate.certificate() {
+        Some(PemSource::File(path)) => builder
+            .set_certificate_chain_file(path)
+            .with_context(|| format!("failed to set certificate from \"{path}\""))?,
+        Some(PemSource::Inline(pem)) => {
+            let mut chain = X509::stack_from_pem(&inline_pem(pem)?)
+                .context("failed to parse inline certificate")?
+                .into_iter();
+            let leaf = chain
+                .next()
+                .ok_or_else(|| anyhow!("the inline certificate is empty"))?;
+            builder
+                .set_certificate(&leaf)
+                .context("failed to set certificate")?;
+            for intermediate in chain {
+                builder
+                    .add_extra_chain_cert(intermediate)
+                    .context("failed to add certificate to the chain")?;
+            }
+        }
+        None => return Err(anyhow!("the certificate isn't set")),
+    }
+
+    builder
+        .check_private_key()
+        .context("the private key doesn't belong to the certificate")?;
+
+    if let Some(client_certificate_auth) = client_certificate_auth {
+        verify_client_certificates(&mut builder, client_certificate_auth)?;
+    }
+
+    Ok(builder)
+}
+
+fn verify_client_certificates(
+    builder: &mut SslAcceptorBuilder,
+    client_certificate_auth: &ClientCertificateAuth,
+) -> Result<(), anyhow::Error> {
+    let ca_pem = match client_certificate_auth.ca() {
+        Some(PemSource::File(path)) => fs::read(path)
+            .with_context(|| format!("failed to read the client ca from \"{path}\""))?,
+        Some(PemSource::Inline(pem)) => inline_pem(pem)?,
+        None => return Err(anyhow!("the client ca isn't set")),
+    };
+
+    let ca_certificates =
+        X509::stack_from_pem(&ca_pem).context("failed to parse the client ca certificates")?;
+    if ca_certificates.is_empty() {
+        return Err(anyhow!("the client ca doesn't contain a certificate"));
+    }
+    for ca_certificate in ca_certificates {
+        // Tells the client which certificates are accepted
+        builder
+            .add_client_ca(&ca_certificate)
+            .context("failed to add the client ca")?;
+        builder
+            .cert_store_mut()
+            .add_cert(ca_certificate)
+            .context("failed to trust the client ca")?;
+    }
+
+    let mut mode = SslVerifyMode::PEER;
+    if client_certificate_auth.required {
+        mode |= SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+    }
+    builder.set_verify(mode);
+    // Resuming sessions fails without it once peers are verified
+    builder
+        .set_session_id_context(b"moonlight-web")
+        .context("failed to set the session id context")?;
+
+    Ok(())
+}
+
+/// Keeps the verified client certificate of https connections, handlers read it with `conn_data`
+pub fn keep_client_certificate(connection: &dyn Any, data: &mut Extensions) {
+    let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+    let Some(certificate) = stream.ssl().peer_certificate() else {
+        return;
+    };
+
+    match ClientCertificate::from_x509(&certificate) {
+        Ok(client_certificate) => {
+            data.insert(client_certificate);
+        }
+        Err(err) => warn!("[Tls]: failed to read the client certificate: {err}"),
+    }
+}
+
+/// Inline PEMs can be base64 encoded so they fit into a single line
+fn inline_pem(value: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let value = value.trim();
+    if value.starts_with("-----BEGIN") {
+        return Ok(value.as_bytes().to_vec());
+    }
+
+    let encoded = value
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>();
+    base64::decode_block(&encoded).context("the inline pem is neither a pem nor base64 encoded")
+}
+
+/// Reloads the certificate files when they change, the old certificates stay in use if a new one is invalid
+pub async fn reload_on_change(
+    certificate: ConfigSsl,
+    sni_certificates: Vec<SniCertificate>,
+    client_certificate_auth: Option<ClientCertificateAuth>,
+    contexts: Arc<RwLock<SslContexts>>,
+) {
+    let files = [&certificate]
+        .into_iter()
+        .chain(
+            sni_certificates
+                .iter()
+                .map(|sni_certificate| &sni_certificate.certificate),
+        )
+        .flat_map(|certificate| [certificate.private_key(), certificate.certificate()])
+        .chain(
+            client_certificate_auth
+                .as_ref()
+                .map(|client_certificate_auth| client_certificate_auth.ca()),
+        )
+        .filter_map(|source| match source {
+            Some(PemSource::File(path)) => absolute(path).ok(),
+            _ => None,
+        })
+        .collect::<HashSet<PathBuf>>();
+    if files.is_empty() {
+        return;
+    }
+
+    let (sender, mut receiver) = unbounded_channel();
+    let mut watcher = match recommended_watcher(move |event| {
+        let _ = sender.send(event);
+    }) {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            warn!("[Tls]: failed to watch the certificate files, they won't be reloaded: {err}");
+            return;
+        }
+    };
+
+    // Renewals often replace the file or a symlink to it, which isn't seen when watching the file itself
+    let directories = files
+        .iter()
+        .filter_map(|file| file.parent())
+        .collect::<HashSet<_>>();
+    for directory in directories {
+        if let Err(err) = watcher.watch(directory, RecursiveMode::NonRecursive) {
+            warn!(
+                "[Tls]: failed to watch \"{}\", changes of the certificate won't be reloaded: {err}",
+                directory.display()
+            );
+        }
+    }
+
+    while let Some(event) = receiver.recv().await {
+        if !is_certificate_change(&files, event) {
+            continue;
+        }
+
+        // Wait for the remaining changes of this renewal
+        let mut deadline = Instant::now() + RELOAD_DEBOUNCE;
+        loop {
+            tokio::select! {
+                _ = sleep_until(deadline) => break,
+                event = receiver.recv() => match event {
+                    Some(event) => {
+                        if is_certificate_change(&files, event) {
+                            deadline = Instant::now() + RELOAD_DEBOUNCE;
+                        }
+                    }
+                    None => return,
+                },
+            }
+        }
+
+        match SslContexts::load(
+            &certificate,
+            &sni_certificates,
+            client_certificate_auth.as_ref(),
+        ) {
+            Ok(new_contexts) => {
+                *contexts.write().expect("poisoned ssl context") = new_contexts;
+                info!("[Tls]: reloaded the https certificates");
+            }
+            Err(err) => {
+                warn!(
+                    "[Tls]: failed to reload the https certificates, keeping the old ones: {err:#}"
+                );
+            }
+        }
+    }
+}
+
+fn is_certificate_change(files: &HashSet<PathBuf>, event: notify::Result<notify::Event>) -> bool {
+    match event {
+        Ok(event) => !event.kind.is_access() && event.paths.iter().any(|path| files.contains(path)),
+        Err(err) => {
+            debug!("[Tls]: certificate watch error: {err}");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::inline_pem;
+
+    #[test]
+    fn test_inline_pem() {
+        let pem = "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----";
+
+        assert_eq!(inline_pem(&format!("  {pem}\n")).unwrap(), pem.as_bytes());
+        assert_eq!(
+            inline_pem(
+                "LS0tLS1CRUdJTiBDRVJUSUZJQ0FURS0tLS0tCkFBQUEKLS0tLS1FTkQgQ0VSVElGSUNBVEUtLS0tLQ=="
+            )
+            .unwrap(),
+            pem.as_bytes()
+        );
+        assert!(inline_pem("not base64!").is_err());
+    }
+}
@@ -1,4 +1,4 @@
-import { App, DeleteHostQuery, DeleteUserRequest, DetailedHost, DetailedUser, GetAppImageQuery, GetAppsQuery, GetAppsResponse, GetHostQuery, GetHostResponse, GetHostsResponse, GetUserQuery, GetUsersResponse, PatchUserRequest, PostCancelRequest, PostCancelResponse, PostLoginRequest, PostPairRequest, PostPairResponse1, PostPairResponse2, PostUserRequest, PostWakeUpRequest, PostHostRequest, PostHostResponse, UndetailedHost, PatchHostRequest } from "./api_bindings.js";
+import { ApiErrorResponse, App, DeleteHostQuery, DeletePairQuery, DeleteUserRequest, DetailedHost, DetailedUser, GetAppImageQuery, GetAppQuery, GetAppResponse, GetAppsQuery, GetAppsResponse, GetHostQuery, GetHostResponse, GetHostsResponse, GetUserQuery, GetUsersQuery, GetUsersResponse, PatchUserRequest, PostCancelRequest, PostCancelResponse, PostLoginRequest, PostPairRequest, PostPairResponse1, PostPairResponse2, PostUserRequest, PostWakeUpRequest, PostWakeUpResponse, PostHostTestRequest, HostTestResponse, GetStreamHistoryQuery, GetStreamHistoryResponse, PostHostRequest, PostHostResponse, UndetailedHost, PatchHostRequest, DiscoveredHost, GetDiscoveredHostsResponse, HybridSessionStatus, PostInputHandoffRequest, PostInputHandoffResponse } from "./api_bindings.js";
 import { showErrorPopup } from "./component/error.js";
 import { showMessage, showModal } from "./component/modal/index.js";
 import { ApiUserPasswordPrompt } from "./component/modal/login.js";
@@ -205,7 +205,10 @@ export async function fetchApi(api: Api, endpoint: string, method: string = GET,
     }
 
     if (!response.ok) {
-        throw new FetchError("failed", endpoint, method, response)
+        // The body is cloned so the caller can still read it
+        const error: ApiErrorResponse | null = await response.clone().json().catch(() => null)
+
+        throw new FetchError("failed", endpoint, method, response, error?.message)
     }
 
     if (init?.response == "ignore") {
@@ -244,6 +247,10 @@ export async function apiLogin(api: Api, request: PostLoginRequest): Promise<boo
 
             if (response && (response.status == 401 || response.status == 404)) {
                 return false
+            } else if (response && response.status == 429) {
+                const retryAfter = response.headers.get("Retry-After")
+                showErrorPopup(`Too many failed logins, try again in ${retryAfter ?? "a few"} seconds`)
+                return false
             } else {
                 showErrorPopup(e.message)
                 return false
@@ -302,8 +309,8 @@ export async function apiGetUser(api: Api, query?: GetUserQuery): Promise<Detail
 
     return response as DetailedUser
 }
-export async function apiGetUsers(api: Api): Promise<GetUsersResponse> {
-    const response = await fetchApi(api, "/users", GET)
+export async function apiGetUsers(api: Api, query?: GetUsersQuery): Promise<GetUsersResponse> {
+    const response = await fetchApi(api, "/users", GET, { query })
 
     return response as GetUsersResponse
 }
@@ -328,6 +335,11 @@ export async function apiDeleteUser(api: Api, data: DeleteUserRequest): Promise<
 export async function apiGetHosts(api: Api): Promise<StreamedJsonResponse<GetHostsResponse, UndetailedHost>> {
     return await fetchApi<GetHostsResponse, UndetailedHost>(api, "/hosts", GET, { response: "jsonStreaming" })
 }
+export async function apiDiscoverHosts(api: Api): Promise<Array<DiscoveredHost>> {
+    const response = await fetchApi(api, "/hosts/discover", GET)
+
+    return (response as GetDiscoveredHostsResponse).hosts
+}
 export async function apiGetHost(api: Api, query: GetHostQuery): Promise<DetailedHost> {
     const response = await fetchApi(api, "/host", GET, { query })
 
@@ -356,11 +368,34 @@ export async function apiPostPair(api: Api, request: PostPairRequest): Promise<S
     })
 }
 
-export async function apiWakeUp(api: Api, request: PostWakeUpRequest): Promise<void> {
-    await fetchApi(api, "/host/wake", "post", {
+export async function apiDeletePair(api: Api, query: DeletePairQuery): Promise<void> {
+    await fetchApi(api, "/pair", "delete", { query, response: "ignore" })
+}
+
+export async function apiWakeUp(api: Api, request: PostWakeUpRequest): Promise<PostWakeUpResponse> {
+    return await fetchApi(api, "/host/wake", "post", {
         json: request,
-        response: "ignore"
-    })
+    }) as PostWakeUpResponse
+}
+
+export async function apiTestHost(api: Api, request: PostHostTestRequest): Promise<HostTestResponse> {
+    return await fetchApi(api, "/host/test", "post", {
+        json: request,
+    }) as HostTestResponse
+}
+
+export async function apiGetHybridSession(api: Api, sessionId: string): Promise<HybridSessionStatus> {
+    const response = await fetchApi(api, `/session/hybrid/${encodeURIComponent(sessionId)}`, GET)
+
+    return response as HybridSessionStatus
+}
+export async function apiPostInputHandoff(api: Api, request: PostInputHandoffRequest): Promise<PostInputHandoffResponse> {
+    return await fetchApi(api, "/session/input/handoff", POST, {
+        json: request,
+    }) as PostInputHandoffResponse
+}
+export async function apiGetStreamHistory(api: Api, query: GetStreamHistoryQuery): Promise<GetStreamHistoryResponse> {
+    return await fetchApi(api, "/sessions/history", GET, { query }) as GetStreamHistoryResponse
 }
 
 export async function apiGetApps(api: Api, query: GetAppsQuery): Promise<Array<App>> {
@@ -369,6 +404,10 @@ export async function apiGetApps(api: Api, query: GetAppsQuery): Promise<Array<A
     return response.apps
 }
 
+export async function apiGetApp(api: Api, query: GetAppQuery): Promise<GetAppResponse> {
+    return await fetchApi(api, "/app", GET, { query }) as GetAppResponse
+}
+
 export async function apiGetAppImage(api: Api, query: GetAppImageQuery): Promise<Blob> {
     const response = await fetchApi(api, "/app/image", GET, {
         query,
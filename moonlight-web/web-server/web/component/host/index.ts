@@ -203,6 +203,13 @@ export class Host implements Component {
             host_id: this.hostId,
             change_owner: true,
             owner: null,
+            change_wake_broadcast_address: false,
+            wake_broadcast_address: null,
+            change_default_stream_settings: false,
+            default_stream_settings: null,
+            address: null,
+            http_port: null,
+            probe_address: false,
         })
 
         if (this.cache) {
@@ -216,6 +223,13 @@ export class Host implements Component {
             host_id: this.hostId,
             change_owner: true,
             owner: user.id,
+            change_wake_broadcast_address: false,
+            wake_broadcast_address: null,
+            change_default_stream_settings: false,
+            default_stream_settings: null,
+            address: null,
+            http_port: null,
+            probe_address: false,
         })
 
         if (this.cache) {
@@ -261,6 +275,10 @@ export class Host implements Component {
         } else if (typeof responseStream.response === "object" && "Pin" in responseStream.response) {
             // Standard Sunshine: show PIN for manual entry
             showMessage(`Please pair your host ${this.getCache()?.name} with this pin:\nPin: ${responseStream.response.Pin}`, { signal: messageAbort.signal })
+        } else if (responseStream.response === "HostOffline") {
+            throw `failed to pair: the host ${this.getCache()?.name} is offline`
+        } else if (responseStream.response === "HttpsUnreachable") {
+            throw `failed to pair: the host ${this.getCache()?.name} answered, but its https port isn't reachable. Check the firewall and port forwarding of the host.`
         } else if (responseStream.response === "InternalServerError" || responseStream.response === "PairError") {
             throw `failed to pair (stage 1): ${responseStream.response}`
         } else {
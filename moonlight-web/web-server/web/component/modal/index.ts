@@ -106,6 +106,31 @@ class PromptModal extends FormModal<string> {
     }
 }
 
+export async function showConfirm(message: string): Promise<boolean> {
+    const modal = new ConfirmModal(message)
+
+    return await showModal(modal) ?? false
+}
+
+class ConfirmModal extends FormModal<boolean> {
+    private message: HTMLElement = document.createElement("p")
+
+    constructor(message: string) {
+        super()
+
+        this.message.innerText = message
+    }
+
+    reset(): void { }
+    submit(): boolean | null {
+        return true
+    }
+
+    mountForm(form: HTMLFormElement): void {
+        form.appendChild(this.message)
+    }
+}
+
 type MessageInit = {
     signal?: AbortSignal
 }
@@ -9,6 +9,7 @@ export type StreamSettings = {
     bitrate: number
     packetSize: number
     videoFrameQueueSize: number
+    videoFramePacing: boolean
     videoSize: "720p" | "1080p" | "1440p" | "4k" | "native" | "custom"
     videoSizeCustom: {
         width: number
@@ -20,10 +21,13 @@ export type StreamSettings = {
     canvasRenderer: boolean
     playAudioLocal: boolean
     audioSampleQueueSize: number
+    audioJitterBufferMs: number
     mouseScrollMode: MouseScrollMode
     controllerConfig: ControllerConfig
     dataTransport: TransportType
     toggleFullscreenWithKeybind: boolean
+    allowSpectators: boolean
+    hdr: boolean
 }
 
 export type StreamCodec = "h264" | "auto" | "h265" | "av1"
@@ -36,6 +40,7 @@ export function defaultStreamSettings(): StreamSettings {
         packetSize: 2048,
         fps: 60,
         videoFrameQueueSize: 3,
+        videoFramePacing: false,
         videoSize: "custom",
         videoSizeCustom: {
             width: 1920,
@@ -46,6 +51,7 @@ export function defaultStreamSettings(): StreamSettings {
         canvasRenderer: false,
         playAudioLocal: false,
         audioSampleQueueSize: 20,
+        audioJitterBufferMs: 0,
         mouseScrollMode: "highres",
         controllerConfig: {
             invertAB: false,
@@ -53,7 +59,9 @@ export function defaultStreamSettings(): StreamSettings {
             sendIntervalOverride: null,
         },
         dataTransport: "auto",
-        toggleFullscreenWithKeybind: false
+        toggleFullscreenWithKeybind: false,
+        allowSpectators: false,
+        hdr: false
     }
 }
 
@@ -94,6 +102,7 @@ export class StreamSettingsComponent implements Component {
     private videoCodec: SelectComponent
     private videoForceCodec: InputComponent
     private canvasRenderer: InputComponent
+    private videoFramePacing: InputComponent
 
     private videoSize: SelectComponent
     private videoSizeWidth: InputComponent
@@ -104,6 +113,7 @@ export class StreamSettingsComponent implements Component {
     private audioHeader: HTMLHeadingElement = document.createElement("h2")
     private playAudioLocal: InputComponent
     private audioSampleQueueSize: InputComponent
+    private audioJitterBufferMs: InputComponent
 
     private mouseHeader: HTMLHeadingElement = document.createElement("h2")
     private mouseScrollMode: SelectComponent
@@ -116,6 +126,8 @@ export class StreamSettingsComponent implements Component {
     private otherHeader: HTMLHeadingElement = document.createElement("h2")
     private dataTransport: SelectComponent
     private toggleFullscreenWithKeybind: InputComponent
+    private allowSpectators: InputComponent
+    private hdr: InputComponent
 
     constructor(settings?: StreamSettings) {
         const defaultSettings = defaultStreamSettings()
@@ -215,6 +227,13 @@ export class StreamSettingsComponent implements Component {
         this.videoSampleQueueSize.addChangeListener(this.onSettingsChange.bind(this))
         this.videoSampleQueueSize.mount(this.divElement)
 
+        // Video Frame Pacing
+        this.videoFramePacing = new InputComponent("videoFramePacing", "checkbox", "Video Frame Pacing (adds up to a frame of latency)", {
+            checked: settings?.videoFramePacing
+        })
+        this.videoFramePacing.addChangeListener(this.onSettingsChange.bind(this))
+        this.videoFramePacing.mount(this.divElement)
+
         // Codec
         this.videoCodec = new SelectComponent("videoCodec", [
             { value: "h264", name: "H264 (Default)" },
@@ -261,6 +280,14 @@ export class StreamSettingsComponent implements Component {
         this.audioSampleQueueSize.addChangeListener(this.onSettingsChange.bind(this))
         this.audioSampleQueueSize.mount(this.divElement)
 
+        // Audio Jitter Buffer
+        this.audioJitterBufferMs = new InputComponent("audioJitterBufferMs", "number", "Audio Jitter Buffer (ms)", {
+            defaultValue: defaultSettings.audioJitterBufferMs.toString(),
+            value: settings?.audioJitterBufferMs?.toString()
+        })
+        this.audioJitterBufferMs.addChangeListener(this.onSettingsChange.bind(this))
+        this.audioJitterBufferMs.mount(this.divElement)
+
         // Mouse
         this.mouseHeader.innerText = "Mouse"
         this.divElement.appendChild(this.mouseHeader)
@@ -338,6 +365,18 @@ export class StreamSettingsComponent implements Component {
         this.toggleFullscreenWithKeybind.addChangeListener(this.onSettingsChange.bind(this))
         this.toggleFullscreenWithKeybind.mount(this.divElement)
 
+        this.allowSpectators = new InputComponent("allowSpectators", "checkbox", "Allow Spectators", {
+            checked: settings?.allowSpectators
+        })
+        this.allowSpectators.addChangeListener(this.onSettingsChange.bind(this))
+        this.allowSpectators.mount(this.divElement)
+
+        this.hdr = new InputComponent("hdr", "checkbox", "HDR (if supported by the host)", {
+            checked: settings?.hdr
+        })
+        this.hdr.addChangeListener(this.onSettingsChange.bind(this))
+        this.hdr.mount(this.divElement)
+
         this.onSettingsChange()
     }
 
@@ -373,12 +412,14 @@ export class StreamSettingsComponent implements Component {
             height: parseInt(this.videoSizeHeight.getValue())
         }
         settings.videoFrameQueueSize = parseInt(this.videoSampleQueueSize.getValue())
+        settings.videoFramePacing = this.videoFramePacing.isChecked()
         settings.videoCodec = this.videoCodec.getValue() as any
         settings.videoForceCodec = this.videoForceCodec.isChecked()
         settings.canvasRenderer = this.canvasRenderer.isChecked()
 
         settings.playAudioLocal = this.playAudioLocal.isChecked()
         settings.audioSampleQueueSize = parseInt(this.audioSampleQueueSize.getValue())
+        settings.audioJitterBufferMs = parseInt(this.audioJitterBufferMs.getValue())
 
         settings.mouseScrollMode = this.mouseScrollMode.getValue() as any
 
@@ -394,6 +435,10 @@ export class StreamSettingsComponent implements Component {
 
         settings.toggleFullscreenWithKeybind = this.toggleFullscreenWithKeybind.isChecked()
 
+        settings.allowSpectators = this.allowSpectators.isChecked()
+
+        settings.hdr = this.hdr.isChecked()
+
         return settings
     }
 
@@ -18,6 +18,8 @@ export class DetailedUserPage implements Component {
     private password: InputComponent
     private role: SelectComponent
     private clientUniqueId: InputComponent
+    private pairDeviceName: InputComponent
+    private clientCertificates: InputComponent
 
     private applyButton = document.createElement("button")
     private deleteButton = document.createElement("button")
@@ -56,6 +58,18 @@ export class DetailedUserPage implements Component {
         })
         this.clientUniqueId.mount(this.formRoot)
 
+        this.pairDeviceName = new InputComponent("userPairDeviceName", "text", "Pair Device Name", {
+            defaultValue: user.pair_device_name ?? "",
+            placeholer: "Server Default",
+        })
+        this.pairDeviceName.mount(this.formRoot)
+
+        this.clientCertificates = new InputComponent("userClientCertificates", "text", "Client Certificate Fingerprints", {
+            defaultValue: user.client_certificate_fingerprints.join(", "),
+            placeholer: "SHA-256, comma separated",
+        })
+        this.clientCertificates.mount(this.formRoot)
+
         this.applyButton.innerText = "Apply"
         this.applyButton.type = "submit"
         this.formRoot.appendChild(this.applyButton)
@@ -81,7 +95,13 @@ export class DetailedUserPage implements Component {
             id: this.id,
             role: this.role.getValue() as UserRole,
             password,
-            client_unique_id: this.clientUniqueId.getValue()
+            client_unique_id: this.clientUniqueId.getValue(),
+            // An empty name resets to the server default
+            pair_device_name: this.pairDeviceName.getValue(),
+            client_certificate_fingerprints: this.clientCertificates.getValue()
+                .split(",")
+                .map(fingerprint => fingerprint.trim())
+                .filter(fingerprint => fingerprint.length > 0)
         };
 
         await apiPatchUser(this.api, request)
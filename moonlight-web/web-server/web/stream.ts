@@ -3,7 +3,7 @@ import { Api, getApi } from "./api.js";
 import { Component } from "./component/index.js";
 import { showErrorPopup } from "./component/error.js";
 import { getStreamerSize, InfoEvent, Stream } from "./stream/index.js"
-import { getModalBackground, Modal, showMessage, showModal } from "./component/modal/index.js";
+import { getModalBackground, Modal, showConfirm, showMessage, showModal } from "./component/modal/index.js";
 import { getSidebarRoot, setSidebar, setSidebarExtended, setSidebarStyle, Sidebar } from "./component/sidebar/index.js";
 import { defaultStreamInputConfig, MouseMode, ScreenKeyboardSetVisibleEvent, StreamInputConfig, TouchMode } from "./stream/input.js";
 import { defaultStreamSettings, getLocalStreamSettings, StreamSettings } from "./component/settings_menu.js";
@@ -79,14 +79,16 @@ async function startApp() {
     const hostIdStr = queryParams.get("hostId")
     const appIdStr = queryParams.get("appId")
     const hybridStr = queryParams.get("hybrid")
-    if (hostIdStr == null || appIdStr == null) {
+    const spectatorToken = queryParams.get("spectate")
+    if (spectatorToken == null && (hostIdStr == null || appIdStr == null)) {
         await showMessage("No Host or no App Id found")
 
         window.close()
         return
     }
-    const hostId = Number.parseInt(hostIdStr)
-    const appId = Number.parseInt(appIdStr)
+    // Spectators join a running stream, its host and app are already known by the server
+    const hostId = Number.parseInt(hostIdStr ?? "0")
+    const appId = Number.parseInt(appIdStr ?? "0")
     const hybridMode = hybridStr === "true"
 
     if (hybridMode) {
@@ -105,7 +107,7 @@ async function startApp() {
     }
 
     // Start and Mount App
-    const app = new ViewerApp(api, hostId, appId, hybridMode)
+    const app = new ViewerApp(api, hostId, appId, hybridMode, spectatorToken)
     app.mount(rootElement)
 
     // Initialize MoonlightBridge API for hybrid mode
@@ -279,6 +281,13 @@ async function startApp() {
                     console.info(`[MoonlightBridge] Sent text: "${text}"`)
                 }
             },
+            sendClipboardText: (text: string) => {
+                const input = app.getStream()?.getInput()
+                if (input) {
+                    input.sendClipboardText(text)
+                    console.info(`[MoonlightBridge] Sent clipboard text with ${text.length} characters`)
+                }
+            },
             sendKey: (isDown: boolean, keyCode: number, modifiers: number = 0) => {
                 const input = app.getStream()?.getInput()
                 if (input) {
@@ -312,6 +321,7 @@ class ViewerApp implements Component {
 
     private statsDiv = document.createElement("div")
     private stream: Stream | null = null
+    private connectionInfo: ConnectionInfoModal | null = null
 
     private settings: StreamSettings
 
@@ -320,13 +330,15 @@ class ViewerApp implements Component {
     private toggleFullscreenWithKeybind: boolean
     private hasShownFullscreenEscapeWarning = false
     private hybridMode: boolean
+    private spectatorToken: string | null
 
-    constructor(api: Api, hostId: number, appId: number, hybridMode: boolean = false) {
+    constructor(api: Api, hostId: number, appId: number, hybridMode: boolean = false, spectatorToken: string | null = null) {
         this.api = api
         this.hybridMode = hybridMode
+        this.spectatorToken = spectatorToken
 
         // Configure sidebar (hide in hybrid mode as native handles input)
-        this.sidebar = new ViewerSidebar(this)
+        this.sidebar = new ViewerSidebar(this, spectatorToken != null)
         if (!hybridMode) {
             setSidebar(this.sidebar)
         }
@@ -373,6 +385,9 @@ class ViewerApp implements Component {
         document.addEventListener("visibilitychange", () => {
             if (document.visibilityState !== "visible") {
                 this.stream?.getInput().raiseAllKeys()
+            } else {
+                // Browsers throttle the decoding of hidden pages, a keyframe repairs the picture right away
+                this.stream?.requestKeyframe()
             }
         })
 
@@ -407,6 +422,9 @@ class ViewerApp implements Component {
         element.addEventListener("wheel", this.onMouseWheel.bind(this), { passive: false })
         element.addEventListener("contextmenu", this.onContextMenu.bind(this), { passive: false })
 
+        element.addEventListener("dragover", this.onDragOver.bind(this), { passive: false })
+        element.addEventListener("drop", this.onDrop.bind(this), { passive: false })
+
         element.addEventListener("touchstart", this.onTouchStart.bind(this), { passive: false })
         element.addEventListener("touchend", this.onTouchEnd.bind(this), { passive: false })
         element.addEventListener("touchcancel", this.onTouchCancel.bind(this), { passive: false })
@@ -474,7 +492,7 @@ class ViewerApp implements Component {
             throw "Couldn't find any supported video format. Change the codec option to H264 in the settings if you're unsure which codecs are supported."
         }
 
-        this.stream = new Stream(this.api, hostId, appId, settings, supportedVideoFormats, browserSize, this.hybridMode)
+        this.stream = new Stream(this.api, hostId, appId, settings, supportedVideoFormats, browserSize, this.hybridMode, this.spectatorToken)
 
         // Add app info listener
         this.stream.addInfoListener(this.onInfo.bind(this))
@@ -483,6 +501,7 @@ class ViewerApp implements Component {
         if (!this.hybridMode) {
             // Create connection info modal
             const connectionInfo = new ConnectionInfoModal()
+            this.connectionInfo = connectionInfo
             this.stream.addInfoListener(connectionInfo.onInfo.bind(connectionInfo))
             showModal(connectionInfo)
         } else {
@@ -504,6 +523,16 @@ class ViewerApp implements Component {
             document.title = `Stream: ${app.title}`
         } else if (data.type == "connectionComplete") {
             this.sidebar.onCapabilitiesChange(data.capabilities)
+        } else if (data.type == "spectatorToken") {
+            this.sidebar.onSpectatorToken(data.spectatorToken)
+        } else if (data.type == "gameRunningConflict") {
+            const title = data.currentTitle ?? "A different app"
+            const confirm = await showConfirm(`${title} is already running on the host. Quit it and start this app?`)
+
+            this.stream?.confirmCancel(confirm)
+            if (confirm && this.connectionInfo) {
+                showModal(this.connectionInfo)
+            }
         }
     }
 
@@ -617,6 +646,36 @@ class ViewerApp implements Component {
         event.stopPropagation()
     }
 
+    // File Transfer
+    private canTransferFiles(): boolean {
+        return this.stream?.getCapabilities()?.file_transfer ?? false
+    }
+    onDragOver(event: DragEvent) {
+        if (!this.canTransferFiles()) {
+            return
+        }
+
+        event.preventDefault()
+    }
+    async onDrop(event: DragEvent) {
+        if (!this.canTransferFiles()) {
+            return
+        }
+
+        event.preventDefault()
+        event.stopPropagation()
+
+        const files = Array.from(event.dataTransfer?.files ?? [])
+        for (const file of files) {
+            try {
+                await this.stream?.getInput().sendFile(file)
+                console.info(`Uploaded ${file.name}`)
+            } catch (err) {
+                await showMessage(`Failed to upload ${file.name}: ${err}`)
+            }
+        }
+    }
+
     // Touch
     onTouchStart(event: TouchEvent) {
         this.onUserInteraction()
@@ -956,10 +1015,13 @@ class ViewerSidebar implements Component, Sidebar {
 
     private statsButton = document.createElement("button")
 
+    private spectatorLinkButton = document.createElement("button")
+    private spectatorToken: string | null = null
+
     private mouseMode: SelectComponent
     private touchMode: SelectComponent
 
-    constructor(app: ViewerApp) {
+    constructor(app: ViewerApp, spectator: boolean = false) {
         this.app = app
 
         // Configure divs
@@ -980,14 +1042,12 @@ class ViewerSidebar implements Component, Sidebar {
             this.app.getStream()?.getInput().sendKey(true, key, 0)
             this.app.getStream()?.getInput().sendKey(false, key, 0)
         })
-        this.buttonDiv.appendChild(this.sendKeycodeButton)
 
         // Pointer Lock
         this.lockMouseButton.innerText = "Lock Mouse"
         this.lockMouseButton.addEventListener("click", async () => {
             await this.app.requestPointerLock(true)
         })
-        this.buttonDiv.appendChild(this.lockMouseButton)
 
         // Pop up keyboard
         this.keyboardButton.innerText = "Keyboard"
@@ -995,7 +1055,13 @@ class ViewerSidebar implements Component, Sidebar {
             setSidebarExtended(false)
             this.screenKeyboard.show()
         })
-        this.buttonDiv.appendChild(this.keyboardButton)
+
+        // Spectators can only watch, their input is never sent to the host
+        if (!spectator) {
+            this.buttonDiv.appendChild(this.sendKeycodeButton)
+            this.buttonDiv.appendChild(this.lockMouseButton)
+            this.buttonDiv.appendChild(this.keyboardButton)
+        }
 
         this.screenKeyboard.addKeyDownListener(this.onKeyDown.bind(this))
         this.screenKeyboard.addKeyUpListener(this.onKeyUp.bind(this))
@@ -1024,6 +1090,21 @@ class ViewerSidebar implements Component, Sidebar {
         })
         this.buttonDiv.appendChild(this.statsButton)
 
+        // Spectator Link, shown once the server handed out a spectator token
+        this.spectatorLinkButton.innerText = "Copy Spectator Link"
+        this.spectatorLinkButton.addEventListener("click", async () => {
+            if (this.spectatorToken == null) {
+                return
+            }
+
+            const url = `${location.origin}${location.pathname}?spectate=${encodeURIComponent(this.spectatorToken)}`
+            try {
+                await navigator.clipboard.writeText(url)
+            } catch (e) {
+                await showMessage(url)
+            }
+        })
+
         // Select Mouse Mode
         this.mouseMode = new SelectComponent("mouseMode", [
             { value: "relative", name: "Relative" },
@@ -1053,6 +1134,14 @@ class ViewerSidebar implements Component, Sidebar {
         this.touchMode.setOptionEnabled("touch", capabilities.touch)
     }
 
+    onSpectatorToken(spectatorToken: string) {
+        this.spectatorToken = spectatorToken
+
+        if (this.spectatorLinkButton.parentElement == null) {
+            this.buttonDiv.appendChild(this.spectatorLinkButton)
+        }
+    }
+
     getScreenKeyboard(): ScreenKeyboard {
         return this.screenKeyboard
     }
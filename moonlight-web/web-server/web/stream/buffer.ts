@@ -16,7 +16,9 @@ export class ByteBuffer {
         this.littleEndian = littleEndian ?? false
 
         if (value instanceof Uint8Array) {
+            // Ready to read the received data
             this.buffer = value
+            this.limit = value.length
         } else {
             this.buffer = new Uint8Array(value ?? 0)
         }
@@ -65,6 +67,18 @@ export class ByteBuffer {
         view.setUint32(this.position, data, this.littleEndian)
         this.bytesUsed(4, false)
     }
+    // Without BigInt this is exact up to Number.MAX_SAFE_INTEGER
+    putU64(data: number) {
+        const high = Math.floor(data / 0x100000000)
+        const low = data % 0x100000000
+        if (this.littleEndian) {
+            this.putU32(low)
+            this.putU32(high)
+        } else {
+            this.putU32(high)
+            this.putU32(low)
+        }
+    }
 
     putUtf8Raw(text: string) {
         const encoder = new TextEncoder()
@@ -99,6 +113,12 @@ export class ByteBuffer {
         this.bytesUsed(2, true)
         return byte
     }
+    getU32(): number {
+        const view = new DataView(this.buffer.buffer)
+        const value = view.getUint32(this.position, this.littleEndian)
+        this.bytesUsed(4, true)
+        return value
+    }
     getBool(): boolean {
         return this.getU8() != 0
     }
@@ -0,0 +1,28 @@
+const CRC32_TABLE = (() => {
+    const table = new Uint32Array(256)
+    for (let i = 0; i < table.length; i++) {
+        let crc = i
+        for (let bit = 0; bit < 8; bit++) {
+            crc = crc & 1 ? (crc >>> 1) ^ 0xEDB88320 : crc >>> 1
+        }
+        table[i] = crc
+    }
+    return table
+})()
+
+// Crc32 (IEEE) as used by zip and png, same as Crc32 of the streamer
+export class Crc32 {
+    private crc = 0xFFFFFFFF
+
+    update(data: Uint8Array) {
+        let crc = this.crc
+        for (let i = 0; i < data.length; i++) {
+            crc = CRC32_TABLE[(crc ^ data[i]) & 0xFF] ^ (crc >>> 8)
+        }
+        this.crc = crc
+    }
+
+    finish(): number {
+        return (this.crc ^ 0xFFFFFFFF) >>> 0
+    }
+}
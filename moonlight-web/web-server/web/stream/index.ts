@@ -1,9 +1,10 @@
 import { Api } from "../api.js"
-import { App, ConnectionStatus, StreamCapabilities, StreamClientMessage, StreamServerMessage, TransportChannelId } from "../api_bindings.js"
+import { App, ConnectionStatus, DisconnectReason, GeneralClientMessage, StreamCapabilities, StreamClientMessage, StreamServerMessage, TransportChannelId } from "../api_bindings.js"
 import { Component } from "../component/index.js"
 import { StreamSettings } from "../component/settings_menu.js"
 import { AudioElementPlayer } from "./audio/audio_element.js"
 import { AudioPlayer } from "./audio/index.js"
+import { ByteBuffer } from "./buffer.js"
 import { defaultStreamInputConfig, StreamInput } from "./input.js"
 import { Logger } from "./log.js"
 import { StreamStats } from "./stats.js"
@@ -21,6 +22,8 @@ export type InfoEvent = CustomEvent<
     { type: "connectionComplete", capabilities: StreamCapabilities } |
     { type: "connectionStatus", status: ConnectionStatus } |
     { type: "connectionTerminated", errorCode: number } |
+    { type: "gameRunningConflict", currentTitle: string | null } |
+    { type: "spectatorToken", spectatorToken: string } |
     { type: "addDebugLine", line: string, additional?: "fatal" | "recover" }
 >
 export type InfoEventListener = (event: InfoEvent) => void
@@ -53,6 +56,19 @@ export function getStreamerSize(settings: StreamSettings, viewerScreenSize: [num
 export type SessionTokenEvent = CustomEvent<{ sessionToken: string }>
 export type SessionTokenEventListener = (event: SessionTokenEvent) => void
 
+// Reconnecting to a running stream after the web socket was lost
+const RECONNECT_DELAY_MS = 1000
+const MAX_RECONNECT_ATTEMPTS = 10
+
+const DISCONNECT_REASON_TEXT: Record<DisconnectReason, string> = {
+    user_requested: "the stream was stopped",
+    host_offline: "the host didn't send any video",
+    timeout: "the session timed out",
+    error: "an error occurred",
+    replaced_by_new_session: "the stream was continued somewhere else",
+    game_ended: "the game was closed",
+}
+
 export class Stream implements Component {
     private logger: Logger = new Logger()
 
@@ -61,6 +77,8 @@ export class Stream implements Component {
     private hostId: number
     private appId: number
     private hybridMode: boolean
+    // Spectators watch the stream of someone else and can't send input
+    private spectator: boolean
 
     private settings: StreamSettings
 
@@ -68,8 +86,12 @@ export class Stream implements Component {
     private eventTarget = new EventTarget()
 
     private ws: WebSocket
+    private reconnectionToken: string | null = null
+    private reconnectAttempts = 0
     private iceServers: Array<RTCIceServer> | null = null
+    private forceRelay = false
     private sessionToken: string | null = null
+    private spectatorToken: string | null = null
 
     private videoRenderer: VideoRenderer | null = null
     private audioPlayer: AudioPlayer | null = null
@@ -80,7 +102,7 @@ export class Stream implements Component {
 
     private streamerSize: [number, number]
 
-    constructor(api: Api, hostId: number, appId: number, settings: StreamSettings, supportedVideoFormats: VideoCodecSupport, viewerScreenSize: [number, number], hybridMode: boolean = false) {
+    constructor(api: Api, hostId: number, appId: number, settings: StreamSettings, supportedVideoFormats: VideoCodecSupport, viewerScreenSize: [number, number], hybridMode: boolean = false, spectatorToken: string | null = null) {
         this.logger.addInfoListener((info, type) => {
             this.debugLog(info, type ?? undefined)
         })
@@ -90,19 +112,15 @@ export class Stream implements Component {
         this.hostId = hostId
         this.appId = appId
         this.hybridMode = hybridMode
+        this.spectator = spectatorToken != null
 
         this.settings = settings
 
         this.streamerSize = getStreamerSize(settings, viewerScreenSize)
 
         // Configure web socket
-        const wsApiHost = api.host_url.replace(/^http(s)?:/, "ws$1:")
         // TODO: firstly try out WebTransport
-        this.ws = new WebSocket(`${wsApiHost}/host/stream`)
-        this.ws.addEventListener("error", this.onError.bind(this))
-        this.ws.addEventListener("open", this.onWsOpen.bind(this))
-        this.ws.addEventListener("close", this.onWsClose.bind(this))
-        this.ws.addEventListener("message", this.onRawWsMessage.bind(this))
+        this.ws = this.connectWebSocket()
 
         const fps = this.settings.fps
 
@@ -110,24 +128,42 @@ export class Stream implements Component {
             this.debugLog("Hybrid mode enabled - input will be handled by native client")
         }
 
-        this.sendWsMessage({
-            Init: {
-                host_id: this.hostId,
-                app_id: this.appId,
-                bitrate: this.settings.bitrate,
-                packet_size: this.settings.packetSize,
-                fps,
-                width: this.streamerSize[0],
-                height: this.streamerSize[1],
-                video_frame_queue_size: this.settings.videoFrameQueueSize,
-                play_audio_local: this.settings.playAudioLocal,
-                audio_sample_queue_size: this.settings.audioSampleQueueSize,
-                video_supported_formats: createSupportedVideoFormatsBits(supportedVideoFormats),
-                video_colorspace: "Rec709", // TODO <---
-                video_color_range_full: true, // TODO <---
-                hybrid_mode: this.hybridMode,
-            }
-        })
+        if (spectatorToken != null) {
+            this.debugLog("Joining the stream as spectator, input is disabled")
+
+            this.sendWsMessage({
+                Spectate: {
+                    spectator_token: spectatorToken
+                }
+            })
+        } else {
+            this.sendWsMessage({
+                Init: {
+                    host_id: this.hostId,
+                    app_id: this.appId,
+                    bitrate: this.settings.bitrate,
+                    packet_size: this.settings.packetSize,
+                    fps,
+                    width: this.streamerSize[0],
+                    height: this.streamerSize[1],
+                    video_frame_queue_size: this.settings.videoFrameQueueSize,
+                    video_frame_pacing: this.settings.videoFramePacing ?? false,
+                    play_audio_local: this.settings.playAudioLocal,
+                    audio_sample_queue_size: this.settings.audioSampleQueueSize,
+                    audio_jitter_buffer_ms: this.settings.audioJitterBufferMs ?? 0,
+                    video_supported_formats: createSupportedVideoFormatsBits(supportedVideoFormats),
+                    video_colorspace: "Rec709", // TODO <---
+                    video_color_range_full: true, // TODO <---
+                    hybrid_mode: this.hybridMode,
+                    allow_spectators: this.settings.allowSpectators ?? false,
+                    force_relay: null,
+                    hdr_enabled: this.settings.hdr ?? false,
+                    video_codec_preference: null,
+                    launch_options: null,
+                    cancel_running_app: false,
+                }
+            })
+        }
 
         // Stream Input
         const streamInputConfig = defaultStreamInputConfig()
@@ -176,7 +212,21 @@ export class Stream implements Component {
     }
 
     private async onMessage(message: StreamServerMessage) {
-        if (typeof message == "string") {
+        if (message == "Reconnected") {
+            this.reconnectAttempts = 0
+            this.debugLog("Reconnected to the running stream", "recover")
+        } else if (message == "ReconnectFailed") {
+            this.reconnectionToken = null
+            this.debugLog("Failed to reconnect to the running stream", "fatal")
+        } else if (message == "InputFailed") {
+            this.debugLog("The input connection failed, reconnect the input client to try again")
+        } else if (message == "PeerFailed") {
+            this.debugLog("The connection to the streamer failed, restart the stream to try again", "fatal")
+        } else if (message == "SpectateFailed") {
+            this.debugLog("The stream can't be watched, it ended or already has too many spectators", "fatal")
+        } else if (message == "AlreadyStreaming") {
+            this.debugLog("The host is already streaming to another client and its app couldn't be quit, stop the other stream and try again", "fatal")
+        } else if (typeof message == "string") {
             const event: InfoEvent = new CustomEvent("stream-info", {
                 detail: { type: "serverMessage", message }
             })
@@ -224,6 +274,51 @@ export class Stream implements Component {
             window.dispatchEvent(new CustomEvent('streamError', {
                 detail: { message: errorMsg }
             }))
+        } else if ("NegotiatedFormat" in message) {
+            const format = message.NegotiatedFormat
+            this.debugLog(`Negotiated video codec ${format.codec}${format.profile ? ` (${format.profile})` : ""}`)
+        } else if ("VideoChanged" in message) {
+            const { format: formatRaw, width, height, fps } = message.VideoChanged
+
+            const format = getSelectedVideoFormat(formatRaw)
+            this.debugLog(`The host changed the video to ${format ?? formatRaw} at ${width}x${height}x${fps}`)
+
+            this.input.onStreamResize([width, height])
+            this.videoRenderer?.resize(width, height)
+            this.stats.setVideoInfo(format ?? "Unknown", width, height, fps)
+
+            ;(window as any).streamWidth = width
+            ;(window as any).streamHeight = height
+            ;(window as any).streamFps = fps
+        } else if ("GameRunningConflict" in message) {
+            const event: InfoEvent = new CustomEvent("stream-info", {
+                detail: { type: "gameRunningConflict", currentTitle: message.GameRunningConflict.current_title }
+            })
+
+            this.eventTarget.dispatchEvent(event)
+        } else if ("SettingsAdjusted" in message) {
+            const adjusted = message.SettingsAdjusted
+            this.debugLog(`The server adjusted the stream settings to its limits: ${adjusted.width}x${adjusted.height} at ${adjusted.fps} fps, bitrate ${adjusted.bitrate}, packet size ${adjusted.packet_size}, hdr ${adjusted.hdr_enabled ? "on" : "off"}`)
+        } else if ("SessionEnding" in message) {
+            this.debugLog(`The session ends in ${message.SessionEnding.remaining_secs} seconds (${message.SessionEnding.reason})`)
+        } else if ("Ended" in message) {
+            this.reconnectionToken = null
+            this.debugLog(`The session was ended by the server (${message.Ended.reason})`, "fatal")
+        } else if ("Disconnected" in message) {
+            this.reconnectionToken = null
+            this.debugLog(`Disconnected: ${DISCONNECT_REASON_TEXT[message.Disconnected.reason]}`, "fatal")
+        } else if ("StreamReconnectionToken" in message) {
+            this.reconnectionToken = message.StreamReconnectionToken.reconnection_token
+        } else if ("SpectatorToken" in message) {
+            const spectatorToken = message.SpectatorToken.spectator_token
+            this.spectatorToken = spectatorToken
+            this.debugLog("Spectators can now join this stream")
+
+            const event: InfoEvent = new CustomEvent("stream-info", {
+                detail: { type: "spectatorToken", spectatorToken }
+            })
+
+            this.eventTarget.dispatchEvent(event)
         } else if ("UpdateApp" in message) {
             const event: InfoEvent = new CustomEvent("stream-info", {
                 detail: { type: "app", app: message.UpdateApp.app }
@@ -250,8 +345,8 @@ export class Stream implements Component {
 
             this.eventTarget.dispatchEvent(event)
 
-            // In hybrid mode, skip input setup as native client handles it
-            if (!this.hybridMode) {
+            // In hybrid mode, skip input setup as native client handles it, spectators have no input
+            if (!this.hybridMode && !this.spectator) {
                 this.input.onStreamStart(capabilities, [width, height])
             }
 
@@ -302,10 +397,14 @@ export class Stream implements Component {
             const sessionToken = message.Setup.session_token
 
             this.iceServers = iceServers
+            this.forceRelay = message.Setup.force_relay
 
             this.debugLog(`Using WebRTC Ice Servers: ${createPrettyList(
                 iceServers.map(server => server.urls).reduce((list, url) => list.concat(url), [])
             )}`)
+            if (this.forceRelay) {
+                this.debugLog("Only using TURN relay candidates")
+            }
 
             // Handle session token for hybrid mode
             if (sessionToken) {
@@ -365,8 +464,11 @@ export class Stream implements Component {
 
         this.transport = transport
 
-        this.input.setTransport(this.transport)
-        this.stats.setTransport(this.transport)
+        // The transport of spectators has no data channels
+        if (!this.spectator) {
+            this.input.setTransport(this.transport)
+            this.stats.setTransport(this.transport)
+        }
     }
 
     private async tryWebSocketTransport() {
@@ -382,11 +484,12 @@ export class Stream implements Component {
             return
         }
 
-        const transport = new WebRTCTransport(this.logger)
+        const transport = new WebRTCTransport(this.logger, this.spectator)
         transport.onsendmessage = (message) => this.sendWsMessage({ WebRtc: message })
 
         transport.initPeer({
-            iceServers: this.iceServers
+            iceServers: this.iceServers,
+            iceTransportPolicy: this.forceRelay ? "relay" : "all"
         })
         this.setTransport(transport)
     }
@@ -483,6 +586,18 @@ export class Stream implements Component {
     // -- Raw Web Socket stuff
     private wsSendBuffer: Array<string> = []
 
+    private connectWebSocket(): WebSocket {
+        const wsApiHost = this.api.host_url.replace(/^http(s)?:/, "ws$1:")
+
+        const ws = new WebSocket(`${wsApiHost}/host/stream`)
+        ws.addEventListener("error", this.onError.bind(this))
+        ws.addEventListener("open", this.onWsOpen.bind(this))
+        ws.addEventListener("close", this.onWsClose.bind(this))
+        ws.addEventListener("message", this.onRawWsMessage.bind(this))
+
+        return ws
+    }
+
     private onWsOpen() {
         this.debugLog(`Web Socket Open`)
 
@@ -492,6 +607,22 @@ export class Stream implements Component {
     }
     private onWsClose() {
         this.debugLog(`Web Socket Closed`)
+
+        const reconnectionToken = this.reconnectionToken
+        if (reconnectionToken == null || this.reconnectAttempts >= MAX_RECONNECT_ATTEMPTS) {
+            return
+        }
+        this.reconnectAttempts += 1
+
+        this.debugLog(`Trying to reconnect to the running stream (attempt ${this.reconnectAttempts})`)
+        setTimeout(() => {
+            this.ws = this.connectWebSocket()
+            this.sendWsMessage({
+                Reconnect: {
+                    reconnection_token: reconnectionToken
+                }
+            })
+        }, RECONNECT_DELAY_MS)
     }
     private onError(event: Event) {
         this.debugLog(`Web Socket or WebRtcPeer Error`)
@@ -499,6 +630,13 @@ export class Stream implements Component {
         console.error(`Web Socket or WebRtcPeer Error`, event)
     }
 
+    // Answer to a gameRunningConflict info event
+    confirmCancel(confirm: boolean) {
+        this.sendWsMessage({
+            ConfirmCancel: { confirm }
+        })
+    }
+
     private sendWsMessage(message: StreamClientMessage) {
         const raw = JSON.stringify(message)
         if (this.ws.readyState == WebSocket.OPEN) {
@@ -548,14 +686,46 @@ export class Stream implements Component {
         return this.sessionToken
     }
 
+    getSpectatorToken(): string | null {
+        return this.spectatorToken
+    }
+
     isHybridMode(): boolean {
         return this.hybridMode
     }
 
+    isSpectator(): boolean {
+        return this.spectator
+    }
+
     getCapabilities(): StreamCapabilities | null {
         return this.capabilities
     }
 
+    // The keyframe arrives after about one round trip to the host plus one frame
+    requestKeyframe() {
+        // The picture loss reports of spectators already request keyframes from the streamer
+        if (this.spectator) {
+            return
+        }
+
+        const channel = this.transport?.getChannel(TransportChannelId.GENERAL)
+        if (!channel || channel.type != "data") {
+            this.debugLog("Failed to request keyframe because the general channel isn't available")
+            return
+        }
+
+        const message: GeneralClientMessage = "RequestKeyframe"
+        const text = JSON.stringify(message)
+
+        const buffer = new ByteBuffer(2 + text.length)
+        buffer.putU16(text.length)
+        buffer.putUtf8Raw(text)
+
+        buffer.flip()
+        channel.send(buffer.getRemainingBuffer().buffer)
+    }
+
     async getStreamHealth(): Promise<StreamHealthData> {
         const stats = this.stats.getCurrentStats()
         const connectionInfo = await this.transport?.getConnectionInfo() ?? { connectionType: "unknown", isRelay: false, rttMs: -1 }
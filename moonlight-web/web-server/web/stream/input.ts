@@ -1,5 +1,6 @@
 import { StreamCapabilities, StreamControllerCapabilities, StreamMouseButton, TransportChannelId } from "../api_bindings.js"
 import { ByteBuffer, I16_MAX, U16_MAX, U8_MAX } from "./buffer.js"
+import { Crc32 } from "./crc32.js"
 import { ControllerConfig, extractGamepadState, GamepadState, SUPPORTED_BUTTONS } from "./gamepad.js"
 import { convertToKey, convertToModifiers } from "./keyboard.js"
 import { convertToButton } from "./mouse.js"
@@ -20,6 +21,17 @@ const TOUCHES_AS_KEYBOARD_DISTANCE = 100
 
 const CONTROLLER_RUMBLE_INTERVAL_MS = 60
 
+// Same as CLIPBOARD_CHUNK_SIZE of the streamer
+const CLIPBOARD_CHUNK_SIZE = 16 * 1024
+// Same as FILE_TRANSFER_CHUNK_SIZE of the streamer
+const FILE_TRANSFER_CHUNK_SIZE = 16 * 1024
+// The file is read in blocks of this size and sending waits while more than this is buffered
+const FILE_TRANSFER_BLOCK_SIZE = 1024 * 1024
+
+const FILE_TRANSFER_STATUS_ACCEPTED = 0
+const FILE_TRANSFER_STATUS_COMPLETED = 1
+const FILE_TRANSFER_STATUS_FAILED = 2
+
 function trySendChannel(channel: DataTransportChannel | null, buffer: ByteBuffer) {
     if (!channel) {
         return
@@ -79,6 +91,8 @@ export class StreamInput {
     private touch: DataTransportChannel | null = null
     private controllers: DataTransportChannel | null = null
     private controllerInputs: Array<DataTransportChannel | null> = []
+    private clipboard: DataTransportChannel | null = null
+    private fileTransfer: DataTransportChannel | null = null
 
     private touchSupported: boolean | null = null
 
@@ -121,6 +135,22 @@ export class StreamInput {
 
             this.controllerInputs[i] = this.getDataChannel(transport, channelId)
         }
+
+        if (this.clipboard) {
+            this.clipboard.removeReceiveListener(this.onClipboardData)
+        }
+        this.clipboardChunks.length = 0
+        this.clipboard = this.getDataChannel(transport, TransportChannelId.CLIPBOARD)
+        this.clipboard.addReceiveListener(this.onClipboardData)
+
+        if (this.fileTransfer) {
+            this.fileTransfer.removeReceiveListener(this.onFileTransferData)
+        }
+        for (const listener of this.fileTransferListeners.values()) {
+            listener(FILE_TRANSFER_STATUS_FAILED, "the connection changed")
+        }
+        this.fileTransfer = this.getDataChannel(transport, TransportChannelId.FILE_TRANSFER)
+        this.fileTransfer.addReceiveListener(this.onFileTransferData)
     }
 
     setConfig(config: StreamInputConfig) {
@@ -151,6 +181,9 @@ export class StreamInput {
         this.streamerSize = streamerSize
         this.registerBufferedControllers()
     }
+    onStreamResize(streamerSize: [number, number]) {
+        this.streamerSize = streamerSize
+    }
 
     // -- Keyboard
     private pressedKeys: Set<number> = new Set()
@@ -223,6 +256,152 @@ export class StreamInput {
         trySendChannel(this.keyboard, this.buffer)
     }
 
+    // -- Clipboard
+    private clipboardBuffer: ByteBuffer = new ByteBuffer(3 + CLIPBOARD_CHUNK_SIZE)
+    private clipboardChunks: Array<Uint8Array> = []
+
+    sendClipboardText(text: string) {
+        const data = new TextEncoder().encode(text)
+
+        let offset = 0
+        do {
+            const chunk = data.subarray(offset, offset + CLIPBOARD_CHUNK_SIZE)
+            offset += chunk.length
+
+            this.clipboardBuffer.reset()
+
+            // Flags: bit 0 is set on the last chunk
+            this.clipboardBuffer.putU8(offset >= data.length ? 1 : 0)
+            this.clipboardBuffer.putU16(chunk.length)
+            this.clipboardBuffer.putU8Array(chunk)
+
+            trySendChannel(this.clipboard, this.clipboardBuffer)
+        } while (offset < data.length)
+    }
+
+    private onClipboardData = (data: ArrayBuffer) => {
+        const buffer = new ByteBuffer(new Uint8Array(data))
+
+        const last = (buffer.getU8() & 1) != 0
+        const length = buffer.getU16()
+
+        const chunk = new Uint8Array(length)
+        buffer.get(chunk, 0, length)
+        this.clipboardChunks.push(chunk)
+
+        if (!last) {
+            return
+        }
+
+        const text = new Uint8Array(this.clipboardChunks.reduce((total, chunk) => total + chunk.length, 0))
+        let offset = 0
+        for (const chunk of this.clipboardChunks) {
+            text.set(chunk, offset)
+            offset += chunk.length
+        }
+        this.clipboardChunks.length = 0
+
+        navigator.clipboard.writeText(new TextDecoder().decode(text))
+            .catch(err => console.warn("failed to write the clipboard of the host", err))
+    }
+
+    // -- File Transfer
+    private nextFileTransferId = 0
+    private fileTransferListeners: Map<number, (status: number, reason: string | null) => void> = new Map()
+
+    private waitForFileTransferStatus(id: number, expectedStatus: number): Promise<void> {
+        return new Promise((resolve, reject) => {
+            this.fileTransferListeners.set(id, (status, reason) => {
+                if (status == FILE_TRANSFER_STATUS_FAILED) {
+                    this.fileTransferListeners.delete(id)
+                    reject(reason)
+                } else if (status == expectedStatus) {
+                    this.fileTransferListeners.delete(id)
+                    resolve()
+                }
+            })
+        })
+    }
+
+    private onFileTransferData = (data: ArrayBuffer) => {
+        const buffer = new ByteBuffer(new Uint8Array(data))
+
+        const status = buffer.getU8()
+        const id = buffer.getU32()
+        let reason = null
+        if (status == FILE_TRANSFER_STATUS_FAILED) {
+            reason = buffer.getUtf8Raw(buffer.getU16())
+        }
+
+        this.fileTransferListeners.get(id)?.(status, reason)
+    }
+
+    // Uploads the file into one of the allowed directories of the streamer (empty = the default one), check the file_transfer capability first
+    async sendFile(file: File, directory: string = ""): Promise<void> {
+        const channel = this.fileTransfer
+        if (!channel) {
+            throw "the file transfer channel isn't available"
+        }
+
+        const id = this.nextFileTransferId++
+        const encoder = new TextEncoder()
+        const directoryBytes = encoder.encode(directory)
+        const fileName = encoder.encode(file.name)
+
+        // Start
+        const accepted = this.waitForFileTransferStatus(id, FILE_TRANSFER_STATUS_ACCEPTED)
+
+        const startBuffer = new ByteBuffer(5 + 8 + 2 + directoryBytes.length + 2 + fileName.length)
+        startBuffer.putU8(0)
+        startBuffer.putU32(id)
+        startBuffer.putU64(file.size)
+        startBuffer.putU16(directoryBytes.length)
+        startBuffer.putU8Array(directoryBytes)
+        startBuffer.putU16(fileName.length)
+        startBuffer.putU8Array(fileName)
+        trySendChannel(channel, startBuffer)
+
+        await accepted
+
+        // A failure while sending the chunks is received as the result of the completion
+        let failed = false
+        const completed = this.waitForFileTransferStatus(id, FILE_TRANSFER_STATUS_COMPLETED)
+        completed.catch(() => failed = true)
+
+        const checksum = new Crc32()
+        const chunkBuffer = new ByteBuffer(5 + 2 + FILE_TRANSFER_CHUNK_SIZE)
+        for (let blockOffset = 0; blockOffset < file.size && !failed; blockOffset += FILE_TRANSFER_BLOCK_SIZE) {
+            while ((channel.estimatedBufferedBytes() ?? 0) > FILE_TRANSFER_BLOCK_SIZE && !failed) {
+                await new Promise(resolve => setTimeout(resolve, 10))
+            }
+
+            const block = new Uint8Array(await file.slice(blockOffset, blockOffset + FILE_TRANSFER_BLOCK_SIZE).arrayBuffer())
+            checksum.update(block)
+
+            for (let offset = 0; offset < block.length; offset += FILE_TRANSFER_CHUNK_SIZE) {
+                const chunk = block.subarray(offset, offset + FILE_TRANSFER_CHUNK_SIZE)
+
+                chunkBuffer.reset()
+                chunkBuffer.putU8(1)
+                chunkBuffer.putU32(id)
+                chunkBuffer.putU16(chunk.length)
+                chunkBuffer.putU8Array(chunk)
+                trySendChannel(channel, chunkBuffer)
+            }
+        }
+
+        // End
+        if (!failed) {
+            const endBuffer = new ByteBuffer(5 + 4)
+            endBuffer.putU8(2)
+            endBuffer.putU32(id)
+            endBuffer.putU32(checksum.finish())
+            trySendChannel(channel, endBuffer)
+        }
+
+        await completed
+    }
+
     // -- Mouse
     onMouseDown(event: MouseEvent, rect: DOMRect) {
         const button = convertToButton(event)
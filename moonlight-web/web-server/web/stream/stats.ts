@@ -1,4 +1,4 @@
-import { StreamerStatsUpdate, TransportChannelId } from "../api_bindings.js"
+import { StatsReception, StreamerStatsUpdate, TransportChannelId } from "../api_bindings.js"
 import { ByteBuffer } from "./buffer.js"
 import { Logger } from "./log.js"
 import { DataTransportChannel, Transport } from "./transport/index.js"
@@ -17,6 +17,16 @@ export type StreamStatsData = {
     minStreamerProcessingTimeMs: number | null
     maxStreamerProcessingTimeMs: number | null
     avgStreamerProcessingTimeMs: number | null
+    streamerFramesDropped: number | null
+    streamerIdrsForced: number | null
+    streamerQueuedBytes: number | null
+    streamerQueuedFrames: number | null
+    streamerQueueDelayMs: number | null
+    // What this client reported back to the streamer, spectators aren't included
+    reportedVideoReception: StatsReception | null
+    reportedAudioReception: StatsReception | null
+    fileTransfer: { fileName: string, transferredBytes: number, size: number } | null
+    benchmark: { targetKbps: number, achievedKbps: number, framesSent: number, framesDropped: number, avgEnqueueLatencyMs: number, maxEnqueueLatencyMs: number } | null
     transport: Record<string, string>
 }
 
@@ -75,6 +85,23 @@ rtt: ${formatMs(statsData.streamerRttMs)} (var: ${formatMs(statsData.streamerRtt
 host latency: ${formatMs(statsData.avgHostProcessingLatencyMs)}
 streamer latency: ${formatMs(statsData.avgStreamerProcessingTimeMs)}
 `
+    if (statsData.streamerFramesDropped != null) {
+        text += `streamer dropped frames: ${statsData.streamerFramesDropped} (forced idrs: ${statsData.streamerIdrsForced})\n`
+        text += `streamer queued video: ${statsData.streamerQueuedFrames ?? 0} frames, ${((statsData.streamerQueuedBytes ?? 0) / 1024).toFixed(1)} KiB, delay ${formatMs(statsData.streamerQueueDelayMs)}\n`
+    }
+    for (const [kind, reception] of [["video", statsData.reportedVideoReception], ["audio", statsData.reportedAudioReception]] as const) {
+        if (reception) {
+            text += `reported ${kind} reception: loss ${reception.fraction_lost_percent.toFixed(1)}% (total lost: ${reception.cumulative_lost}), jitter ${formatMs(reception.jitter_ms)}\n`
+        }
+    }
+    if (statsData.benchmark) {
+        const { targetKbps, achievedKbps, framesSent, framesDropped, avgEnqueueLatencyMs, maxEnqueueLatencyMs } = statsData.benchmark
+        text += `benchmark: ${(achievedKbps / 1000).toFixed(1)} of ${(targetKbps / 1000).toFixed(1)} Mbps, ${framesSent} frames/s (dropped: ${framesDropped}), enqueue ${formatMs(avgEnqueueLatencyMs)} (max ${formatMs(maxEnqueueLatencyMs)})\n`
+    }
+    if (statsData.fileTransfer) {
+        const { fileName, transferredBytes, size } = statsData.fileTransfer
+        text += `file transfer: ${fileName} ${size > 0 ? (transferredBytes / size * 100).toFixed(0) : 100}%\n`
+    }
     const webrtcFps = statsData.transport.webrtcFps
     const packetsLost = statsData.transport.webrtcPacketsLost
     const packetsReceived = statsData.transport.webrtcPacketsReceived
@@ -377,6 +404,15 @@ export class StreamStats {
         minStreamerProcessingTimeMs: null,
         maxStreamerProcessingTimeMs: null,
         avgStreamerProcessingTimeMs: null,
+        streamerFramesDropped: null,
+        streamerIdrsForced: null,
+        streamerQueuedBytes: null,
+        streamerQueuedFrames: null,
+        streamerQueueDelayMs: null,
+        reportedVideoReception: null,
+        reportedAudioReception: null,
+        fileTransfer: null,
+        benchmark: null,
         transport: {}
     }
 
@@ -457,6 +493,30 @@ export class StreamStats {
             this.statsData.minStreamerProcessingTimeMs = msg.Video.min_streamer_processing_time_ms
             this.statsData.maxStreamerProcessingTimeMs = msg.Video.max_streamer_processing_time_ms
             this.statsData.avgStreamerProcessingTimeMs = msg.Video.avg_streamer_processing_time_ms
+        } else if ("VideoQueue" in msg) {
+            this.statsData.streamerFramesDropped = msg.VideoQueue.frames_dropped
+            this.statsData.streamerIdrsForced = msg.VideoQueue.idrs_forced
+            this.statsData.streamerQueuedBytes = msg.VideoQueue.queued_bytes
+            this.statsData.streamerQueuedFrames = msg.VideoQueue.queued_frames
+            this.statsData.streamerQueueDelayMs = msg.VideoQueue.queue_delay_ms
+        } else if ("Reception" in msg) {
+            this.statsData.reportedVideoReception = msg.Reception.video[0] ?? null
+            this.statsData.reportedAudioReception = msg.Reception.audio[0] ?? null
+        } else if ("Benchmark" in msg) {
+            this.statsData.benchmark = {
+                targetKbps: msg.Benchmark.target_kbps,
+                achievedKbps: msg.Benchmark.achieved_kbps,
+                framesSent: msg.Benchmark.frames_sent,
+                framesDropped: msg.Benchmark.frames_dropped,
+                avgEnqueueLatencyMs: msg.Benchmark.avg_enqueue_latency_ms,
+                maxEnqueueLatencyMs: msg.Benchmark.max_enqueue_latency_ms,
+            }
+        } else if ("FileTransfer" in msg) {
+            this.statsData.fileTransfer = {
+                fileName: msg.FileTransfer.file_name,
+                transferredBytes: Number(msg.FileTransfer.transferred_bytes),
+                size: Number(msg.FileTransfer.size),
+            }
         }
     }
 
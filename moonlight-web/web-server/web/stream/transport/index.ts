@@ -53,6 +53,8 @@ export const TRANSPORT_CHANNEL_OPTIONS: Record<keyof typeof TransportChannelId,
     CONTROLLER13: { reliable: false, ordered: true },
     CONTROLLER14: { reliable: false, ordered: true },
     CONTROLLER15: { reliable: false, ordered: true },
+    CLIPBOARD: { reliable: true, ordered: true },
+    FILE_TRANSFER: { reliable: true, ordered: true },
 }
 
 export type TransportShutdown = "failednoconnect" | "failed" | "disconnect"
@@ -8,9 +8,12 @@ export class WebRTCTransport implements Transport {
     private logger: Logger | null
 
     private peer: RTCPeerConnection | null = null
+    // Spectators only receive the video and audio, the streamer sends the offer
+    private watchOnly: boolean
 
-    constructor(logger?: Logger) {
+    constructor(logger?: Logger, watchOnly: boolean = false) {
         this.logger = logger ?? null
+        this.watchOnly = watchOnly
     }
 
     async initPeer(configuration?: RTCConfiguration) {
@@ -39,7 +42,7 @@ export class WebRTCTransport implements Transport {
         // Maybe we already received data
         if (this.remoteDescription) {
             await this.handleRemoteDescription(this.remoteDescription)
-        } else {
+        } else if (!this.watchOnly) {
             await this.onNegotiationNeeded()
         }
         await this.tryDequeueIceCandidates()
@@ -279,6 +282,9 @@ export class WebRTCTransport implements Transport {
                 this.channels[TransportChannelId.HOST_AUDIO] = channel
                 continue
             }
+            if (this.watchOnly) {
+                continue
+            }
 
             const id = TransportChannelId[channel]
             const dataChannel = this.peer.createDataChannel(channel.toLowerCase(), {
@@ -26,6 +26,9 @@ export class CanvasVideoRenderer extends FrameVideoRenderer {
             this.animationFrameRequest = requestAnimationFrame(this.onAnimationFrame.bind(this))
         }
     }
+    resize(width: number, height: number): void {
+        this.videoSize = [width, height]
+    }
 
     cleanup(): void {
         this.context = null
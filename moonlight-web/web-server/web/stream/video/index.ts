@@ -17,6 +17,8 @@ export abstract class VideoRenderer implements Component {
 
     /// Returns the success
     abstract setup(setup: VideoRendererSetup): void
+    /// The host changed the resolution mid-stream
+    abstract resize(width: number, height: number): void
     abstract cleanup(): void
 
     abstract onUserInteraction(): void
@@ -63,6 +63,9 @@ export class MediaStreamTrackProcessorPipe<T extends FrameVideoRenderer> extends
 
         this.base.setup(setup)
     }
+    resize(width: number, height: number): void {
+        this.base.resize(width, height)
+    }
     cleanup(): void {
         this.running = false
         try {
@@ -99,6 +99,9 @@ export class VideoElementRenderer extends TrackVideoRenderer {
     setup(setup: VideoRendererSetup): void {
         this.size = [setup.width, setup.height]
     }
+    resize(width: number, height: number): void {
+        this.size = [width, height]
+    }
     cleanup(): void {
         if (this.oldTrack) {
             this.stream.removeTrack(this.oldTrack)